@@ -0,0 +1,60 @@
+/// Gamma and brightness correction of captured frames (`--gamma`,
+/// `--brightness`), as one 256-entry table shared by the three colour
+/// channels.
+pub struct Lut {
+    table: [u8; 256],
+}
+
+impl Lut {
+    /// Table raising each channel to `1 / gamma`, then scaling it by
+    /// `brightness`. `None` when both are 1 and the frame stays as it is.
+    pub fn new(gamma: f32, brightness: f32) -> Option<Self> {
+        if gamma == 1.0 && brightness == 1.0 {
+            return None;
+        }
+        let mut table = [0u8; 256];
+        for (i, out) in table.iter_mut().enumerate() {
+            let v = (i as f32 / 255.0).powf(1.0 / gamma) * brightness;
+            *out = (v * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+        Some(Self { table })
+    }
+
+    /// Adjust a frame in place. The fourth byte of each BGRA pixel is left
+    /// alone.
+    pub fn apply_in_place(&self, bgra: &mut [u8]) {
+        for px in bgra.chunks_exact_mut(4) {
+            for c in &mut px[..3] {
+                *c = self.table[*c as usize];
+            }
+        }
+    }
+
+    /// Write the adjusted `src` frame into `dst`.
+    pub fn apply(&self, src: &[u8], dst: &mut Vec<u8>) {
+        dst.clear();
+        dst.extend_from_slice(src);
+        self.apply_in_place(dst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_need_no_table() {
+        assert!(Lut::new(1.0, 1.0).is_none());
+
+        let brighter = Lut::new(1.0, 2.0).unwrap();
+        let mut frame = vec![0x10, 0x40, 0xA0, 0x7F];
+        brighter.apply_in_place(&mut frame);
+        assert_eq!(frame, [0x20, 0x80, 0xFF, 0x7F]);
+
+        // Gamma 2 lifts the midtones and keeps black and white
+        let gamma = Lut::new(2.0, 1.0).unwrap();
+        let mut frame = vec![0x00, 0x40, 0xFF, 0x00];
+        gamma.apply_in_place(&mut frame);
+        assert_eq!(frame, [0x00, 0x80, 0xFF, 0x00]);
+    }
+}
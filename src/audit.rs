@@ -0,0 +1,224 @@
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+
+/// Append-only JSON-lines record of client connections (`--audit-log`).
+/// The file is reopened for every line, so when it is rotated away the next
+/// line starts a new one.
+pub struct AuditLog {
+    path: PathBuf,
+}
+
+impl AuditLog {
+    /// Check that `path` can be appended to, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let log = Self {
+            path: path.to_path_buf(),
+        };
+        log.file()
+            .with_context(|| format!("Cannot open audit log {}", path.display()))?;
+        Ok(log)
+    }
+
+    fn file(&self) -> std::io::Result<std::fs::File> {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+
+    /// Append one line. Failures are logged rather than ending the session.
+    fn append(&self, line: &str) {
+        // One write per line, so concurrent sessions don't interleave
+        let result = self.file().and_then(|mut f| f.write_all(line.as_bytes()));
+        if let Err(e) = result {
+            tracing::warn!("Cannot write audit log {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// One client's entries in the audit log: an "auth" line once the security
+/// handshake is decided and a "close" line when the connection ends. Does
+/// nothing without a log.
+pub struct AuditSession {
+    log: Option<Arc<AuditLog>>,
+    peer: String,
+    transport: &'static str,
+    started: Instant,
+    /// RFB minor version the client asked for.
+    pub rfb_minor: Option<u16>,
+    /// Security type the client ended up with.
+    pub auth_type: Option<&'static str>,
+    auth_result: Option<&'static str>,
+    closed: bool,
+}
+
+impl AuditSession {
+    pub fn new(log: Option<Arc<AuditLog>>, peer: &str, websocket: bool) -> Self {
+        Self {
+            log,
+            peer: peer.to_string(),
+            transport: if websocket { "websocket" } else { "tcp" },
+            started: Instant::now(),
+            rfb_minor: None,
+            auth_type: None,
+            auth_result: None,
+            closed: false,
+        }
+    }
+
+    /// Address of the client, as it appears in the log.
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Record how authentication went: "ok", "failed" (wrong credentials)
+    /// or "error" (the handshake broke off).
+    pub fn authenticated(&mut self, result: &'static str) {
+        self.auth_result = Some(result);
+        self.write("auth", None);
+    }
+
+    /// Record the end of the connection. Only the first call counts.
+    pub fn close(&mut self, reason: &str) {
+        if !self.closed {
+            self.closed = true;
+            self.write("close", Some(reason));
+        }
+    }
+
+    fn write(&self, event: &str, reason: Option<&str>) {
+        let Some(log) = &self.log else { return };
+        let mut line = format!(
+            "{{\"ts\":{},\"event\":{},\"peer\":{},\"transport\":{},\"rfb_minor\":{},\"auth_type\":{},\"auth_result\":{}",
+            json_string(&rfc3339(SystemTime::now())),
+            json_string(event),
+            json_string(&self.peer),
+            json_string(self.transport),
+            self.rfb_minor.map_or("null".to_string(), |m| m.to_string()),
+            self.auth_type.map_or("null".to_string(), json_string),
+            self.auth_result.map_or("null".to_string(), json_string),
+        );
+        if let Some(reason) = reason {
+            line += &format!(
+                ",\"duration_secs\":{:.3},\"reason\":{}",
+                self.started.elapsed().as_secs_f64(),
+                json_string(reason)
+            );
+        }
+        line += "}\n";
+        log.append(&line);
+    }
+}
+
+impl Drop for AuditSession {
+    /// Sessions still open when the server stops are closed by it.
+    fn drop(&mut self) {
+        self.close("server stopped");
+    }
+}
+
+/// `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// UTC timestamp with milliseconds, e.g. `2023-11-14T22:13:20.000Z`.
+fn rfc3339(t: SystemTime) -> String {
+    let since_epoch = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rem) = (secs / 86400, secs % 86400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn timestamps_are_utc_rfc3339() {
+        let t = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(rfc3339(t), "2023-11-14T22:13:20.123Z");
+        let leap = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(rfc3339(leap), "2000-02-29T00:00:00.000Z");
+        assert_eq!(rfc3339(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+
+    #[test]
+    fn session_lines_survive_rotation() {
+        let dir = std::env::temp_dir().join(format!("kmsvnc-audit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let log = Arc::new(AuditLog::open(&path).unwrap());
+
+        let mut session = AuditSession::new(Some(log.clone()), "10.0.0.1:5000", false);
+        session.rfb_minor = Some(8);
+        session.auth_type = Some("vnc");
+        session.authenticated("ok");
+        std::fs::rename(&path, dir.join("audit.log.1")).unwrap();
+        session.close("client closed");
+        drop(session);
+
+        let rotated = std::fs::read_to_string(dir.join("audit.log.1")).unwrap();
+        let current = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(rotated.starts_with("{\"ts\":\""), "{rotated}");
+        assert!(
+            rotated.ends_with(
+                "\"event\":\"auth\",\"peer\":\"10.0.0.1:5000\",\"transport\":\"tcp\",\
+                 \"rfb_minor\":8,\"auth_type\":\"vnc\",\"auth_result\":\"ok\"}\n"
+            ),
+            "{rotated}"
+        );
+        // Closed once: dropping the session adds nothing
+        assert_eq!(current.lines().count(), 1, "{current}");
+        assert!(current.contains("\"event\":\"close\""), "{current}");
+        assert!(
+            current.ends_with(",\"reason\":\"client closed\"}\n"),
+            "{current}"
+        );
+    }
+}
@@ -1,6 +1,12 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
-#[derive(Parser, Debug)]
+use crate::acl::Cidr;
+use crate::frame_diff::{DEFAULT_TILE_SIZE, TILE_SIZES};
+use crate::kms::edid::MonitorId;
+use crate::vnc::repeater::RepeaterId;
+use crate::vnc::server::FullRefreshThreshold;
+
+#[derive(Parser, Debug, Clone)]
 #[command(
     name = "kmsvnc",
     about = "KMS-based VNC server with touch & keyboard input"
@@ -10,6 +16,41 @@ pub struct Config {
     #[arg(short, long)]
     pub device: Option<String>,
 
+    /// Capture backend. `auto` tries DRM and falls back to fbdev; `drm` and
+    /// `fbdev` use only that backend and fail if it is unavailable.
+    /// `test-pattern` serves synthetic 1024x768 frames without any hardware.
+    #[arg(long, value_enum, default_value_t = Backend::Auto)]
+    pub backend: Backend,
+
+    /// Also capture outputs whose connector reports disconnected
+    /// (virtual/headless displays such as vkms)
+    #[arg(long)]
+    pub allow_disconnected: bool,
+
+    /// Capture this CRTC ID regardless of connector state
+    #[arg(long, value_name = "ID")]
+    pub force_crtc: Option<u32>,
+
+    /// Capture the output whose monitor has this EDID manufacturer ID and,
+    /// optionally, serial number (e.g. DEL or DEL:7XK2M33), whatever
+    /// connector it is plugged into. The first output is used when none
+    /// matches.
+    #[arg(long, value_name = "MFG[:SERIAL]")]
+    pub monitor: Option<MonitorId>,
+
+    /// Keep DRM master instead of releasing it on open. Only for when
+    /// kmsvnc is the sole DRM user: a compositor or EGLFS app started
+    /// afterwards cannot take the display.
+    #[arg(long)]
+    pub keep_master: bool,
+
+    /// If no capture device is usable at startup, serve a "waiting for
+    /// display" placeholder and probe again every few seconds instead of
+    /// exiting. Once a display appears, clients are disconnected so they
+    /// reconnect at its resolution.
+    #[arg(long)]
+    pub wait_for_display: bool,
+
     /// VNC listen port
     #[arg(short, long, default_value_t = 5900)]
     pub port: u16,
@@ -18,11 +59,357 @@ pub struct Config {
     #[arg(short, long, default_value_t = 30)]
     pub fps: u32,
 
-    /// VNC listen address
-    #[arg(short, long, default_value = "0.0.0.0")]
-    pub listen: String,
+    /// When to capture. `adaptive` captures on each client request and
+    /// switches to polling at --fps while requests arrive rapidly;
+    /// `on-demand` never polls (easiest on battery and thermals); `polling`
+    /// captures at --fps continuously.
+    #[arg(long, value_enum, default_value_t = CapturePolicy::Adaptive)]
+    pub capture_mode: CapturePolicy,
+
+    /// Pace polled captures: schedule each one a fixed interval after the
+    /// previous deadline rather than after the last wake-up, so client
+    /// requests and scheduling delays don't stretch the frame spacing.
+    #[arg(long)]
+    pub pace: bool,
+
+    /// Probe the capture device again and rebuild the capturer once
+    /// captures have been failing for this many seconds, e.g. after
+    /// suspend/resume or a GPU reset (0 = never).
+    #[arg(long, default_value_t = 10, value_name = "SECS")]
+    pub capture_watchdog: u64,
+
+    /// Send each client at most this many updates per second (0 = as fast
+    /// as it asks). Changes made in between are merged into the next update,
+    /// so one fast viewer cannot monopolise encoding on a shared server.
+    #[arg(long, default_value_t = 0, value_name = "FPS")]
+    pub max_client_fps: u32,
+
+    /// Keep each client under this many kilobits per second (0 = no cap).
+    /// Updates wait while a client is over it, merging the changes made in
+    /// the meantime, so a slow link gets fewer frames instead of a backlog.
+    #[arg(long, default_value_t = 0, value_name = "KBPS")]
+    pub max_client_kbps: u32,
+
+    /// Edge length in pixels of the tiles used to detect changed regions:
+    /// 16, 32, 64 or 128. Smaller tiles send less for small changes at the
+    /// cost of more rectangles.
+    #[arg(long, default_value_t = DEFAULT_TILE_SIZE, value_parser = parse_tile_size)]
+    pub tile_size: u32,
+
+    /// Bits per pixel for clients that keep the server's pixel format: 32,
+    /// 24 (packed, not in the RFB spec; only for clients that take it) or
+    /// 16 (RGB565, half the bandwidth at some cost in colour)
+    #[arg(long, default_value_t = 32, value_parser = parse_depth)]
+    pub depth: u8,
+
+    /// Hash this many evenly spaced scanlines before each DRM capture and
+    /// skip the full compare while they are unchanged (0 = always compare).
+    /// Cuts CPU on static screens; a change outside the sampled rows may
+    /// show up to a second late.
+    #[arg(long, default_value_t = 0, value_name = "N")]
+    pub sample_rows: u32,
+
+    /// Read the DRM framebuffer at most once per this many milliseconds,
+    /// however often captures are asked for; the frame counts as unchanged
+    /// in between. Guards platforms where reading scanout memory is slow.
+    /// Defaults to half the --fps frame interval.
+    #[arg(long, value_name = "MS")]
+    pub min_capture_interval: Option<u64>,
+
+    /// Try mapping or syncing a DRM framebuffer this many more times when
+    /// the driver interrupts it (EINTR/EAGAIN, e.g. under heavy GPU load)
+    /// before the frame counts as failed.
+    #[arg(long, default_value_t = 3, value_name = "N")]
+    pub capture_retries: u32,
+
+    /// Take 10-bit DRM framebuffers as HDR10 (PQ, BT.2020) and tone-map
+    /// them to sRGB. Without it their top 8 bits are sent as-is, which
+    /// looks washed out for HDR content. Lossy; leave off for SDR 10-bit.
+    #[arg(long)]
+    pub tonemap: bool,
+
+    /// Gamma correction applied to every captured frame: above 1 lightens
+    /// the midtones, e.g. to read a dim panel remotely. A viewing aid: it
+    /// alters the image clients, screenshots and recordings get.
+    #[arg(long, default_value_t = 1.0, value_parser = parse_factor)]
+    pub gamma: f32,
+
+    /// Factor every captured colour channel is scaled by after --gamma
+    /// (e.g. 1.5 for half as bright again). Alters the transmitted image
+    /// like --gamma.
+    #[arg(long, default_value_t = 1.0, value_parser = parse_factor)]
+    pub brightness: f32,
+
+    /// Hold an incremental update request for up to this many milliseconds
+    /// while nothing has changed, instead of answering at once with an empty
+    /// update (0 = answer immediately). Calms clients that re-request in a
+    /// tight loop.
+    #[arg(long, default_value_t = 0, value_name = "MS")]
+    pub defer_update: u64,
+
+    /// After a screen change, wait this many milliseconds for further
+    /// changes before building the update, so bursts of activity go out as
+    /// fewer, larger updates (0 = send at once). Adds that much latency;
+    /// meant for bandwidth-sensitive links rather than interactive use.
+    #[arg(long, default_value_t = 0, value_name = "MS")]
+    pub coalesce_ms: u64,
+
+    /// Send full-frame updates (a client's first frame, or a refresh it
+    /// asks for) as bands of this many rows, each encoded and written on its
+    /// own so other clients are served in between (0 = all in one go).
+    /// Eases the multi-megabyte first frame of large displays.
+    #[arg(long, default_value_t = 0, value_name = "ROWS")]
+    pub band_height: u16,
+
+    /// Send an incremental update as one rect, the bounding box of its
+    /// changed rects, once they cover more than this share of it (`50%`) or
+    /// number more than this many (`200`). Bounds the per-rect overhead of
+    /// changes scattered over an area; 0 turns it off.
+    #[arg(long, default_value = "50%", value_name = "N%|RECTS")]
+    pub full_refresh_threshold: FullRefreshThreshold,
+
+    /// VNC listen addresses, comma-separated or repeated. Each address is
+    /// bound on --port (and --websocket-port, if given).
+    #[arg(short, long, default_value = "0.0.0.0", value_delimiter = ',')]
+    pub listen: Vec<String>,
+
+    /// Only accept connections from this network (CIDR, e.g. 192.168.1.0/24
+    /// or fd00::/8; a bare address is one host). Repeatable; all clients
+    /// are accepted if omitted.
+    #[arg(long, value_name = "CIDR")]
+    pub allow: Vec<Cidr>,
+
+    /// Drop a client that hasn't finished the handshake (WebSocket upgrade,
+    /// version exchange, authentication and ClientInit) this many seconds
+    /// after connecting, so stalled connections don't pile up (0 = wait
+    /// forever). It includes the time a user takes to type the password.
+    /// Repeater connections, idle until a viewer arrives, are exempt.
+    #[arg(long, default_value_t = 60, value_name = "SECS")]
+    pub handshake_timeout: u64,
+
+    /// Also accept WebSocket connections (e.g. noVNC) on this port
+    #[arg(long, value_name = "PORT")]
+    pub websocket_port: Option<u16>,
+
+    /// Also serve viewers through the UltraVNC repeater at this address:
+    /// connect out to it, register under --repeater-id and connect again
+    /// after each session. Reaches machines behind NAT.
+    #[arg(long, value_name = "HOST:PORT", requires = "repeater_id")]
+    pub repeater: Option<String>,
+
+    /// ID to register with the --repeater; viewers ask for the same ID
+    #[arg(long, value_name = "ID", requires = "repeater")]
+    pub repeater_id: Option<RepeaterId>,
+
+    /// Desktop name shown by clients. `{hostname}`, `{output}`, `{width}`
+    /// and `{height}` are replaced by the host name, the captured connector
+    /// (or device, without DRM) and the resolution, e.g. "{hostname} {output}".
+    #[arg(long, default_value = "kmsvnc")]
+    pub name: String,
 
     /// VNC password for authentication (Type 2). No auth if omitted.
     #[arg(long)]
     pub password: Option<String>,
+
+    /// Security types offered to clients, in order of preference,
+    /// comma-separated: `none`, `vnc` (DES challenge-response), `ard`
+    /// (Apple Remote Desktop), `tight` (TightVNC's wrapper around VNC
+    /// Authentication or None). Types that need a password are only offered
+    /// with --password, and `none` only without one. E.g. `ard` refuses
+    /// clients that would fall back to DES.
+    #[arg(
+        long,
+        value_enum,
+        value_delimiter = ',',
+        default_value = "none,vnc,ard,tight",
+        value_name = "TYPES"
+    )]
+    pub security_types: Vec<SecurityType>,
+
+    /// Display-only mode: never create the virtual keyboard/touchscreen
+    /// (no /dev/uinput access needed) and discard all client input.
+    #[arg(long)]
+    pub view_only: bool,
+
+    /// Never use input on this machine: no uinput devices are created and
+    /// no /dev/uinput warnings are printed, for systems without uinput.
+    /// Client input is silently dropped, as with --view-only.
+    #[arg(long)]
+    pub no_input: bool,
+
+    /// Log client key events and pointer button changes at info level.
+    /// `redacted` (the default) logs key events without their keysym or
+    /// key code and button changes without the pointer position, `full`
+    /// logs all of it.
+    #[arg(
+        long,
+        value_enum,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "redacted",
+        value_name = "MODE"
+    )]
+    pub log_input: Option<InputLog>,
+
+    /// Capture one frame to this PNG file ("-" for stdout) and exit
+    /// without starting the server. A ".bgra" path writes the raw frame.
+    #[arg(long, value_name = "PATH")]
+    pub screenshot: Option<String>,
+
+    /// While serving, also record the screen at --fps to this file. A
+    /// ".bgra" path gets raw frames after a 20-byte header (see README);
+    /// anything else is encoded by an `ffmpeg` child process, in the format
+    /// its extension names (e.g. .mp4, .mkv).
+    #[arg(long, value_name = "PATH")]
+    pub record: Option<String>,
+
+    /// Print the detected capture backend, format and mapping method, then exit
+    #[arg(long)]
+    pub print_capture_info: bool,
+
+    /// Print the framebuffer pixel formats kmsvnc can capture, as DRM
+    /// fourcc codes, then exit
+    #[arg(long)]
+    pub list_formats: bool,
+
+    /// Keymap file with `keysym = keycode[+modifiers]` lines, consulted
+    /// before the built-in US layout table
+    #[arg(long, value_name = "PATH")]
+    pub keymap: Option<std::path::PathBuf>,
+
+    /// How client keys reach the virtual keyboard. `keysym` maps the X11
+    /// keysym of each key event through --keymap and the built-in US table;
+    /// `scancode` asks clients for QEMU extended key events and presses the
+    /// physical key they report, so the layout configured on this machine
+    /// applies. Key events without a scancode still go through the keysym.
+    #[arg(long, value_enum, default_value_t = KeyboardMode::Keysym)]
+    pub keyboard_mode: KeyboardMode,
+
+    /// Turn a touch held still for 600 ms into a right click, for clients
+    /// without a right button. Touches reach the screen only once they
+    /// move or lift, or the hold turns into the right click.
+    #[arg(long)]
+    pub emulate_right_click_hold: bool,
+
+    /// Append a JSON line per client to this file: when it authenticated
+    /// (peer, RFB version, security type and result) and when it
+    /// disconnected (duration and reason). Safe to rotate.
+    #[arg(long, value_name = "PATH")]
+    pub audit_log: Option<std::path::PathBuf>,
+
+    /// Accept runtime commands (list, kick, bell, stats, reload) on a Unix
+    /// socket at this path, owner-only
+    #[arg(long, value_name = "PATH")]
+    pub control_socket: Option<std::path::PathBuf>,
+
+    /// Fork into the background and detach from the terminal, for init
+    /// systems that don't supervise foreground processes. The command
+    /// returns once the server is listening, or fails with the startup
+    /// error. Log output is discarded once detached unless --log-file is set.
+    #[arg(long)]
+    pub daemonize: bool,
+
+    /// Append log output to this file instead of writing it to the
+    /// terminal, e.g. to keep the logs of a --daemonize'd server
+    #[arg(long, value_name = "PATH")]
+    pub log_file: Option<std::path::PathBuf>,
+
+    /// Write the server's process ID to this file, locked while the server
+    /// runs and removed again on exit. Startup fails while another running
+    /// instance holds it.
+    #[arg(long, value_name = "PATH")]
+    pub pidfile: Option<std::path::PathBuf>,
+
+    /// Serve a single client, then exit when it disconnects
+    #[arg(long)]
+    pub once: bool,
+
+    /// Name prefix for the virtual uinput devices ("<name>-touch", "<name>-keyboard")
+    #[arg(long, default_value = "kmsvnc")]
+    pub input_name: String,
+
+    /// USB vendor ID reported by the virtual uinput devices (hex or decimal)
+    #[arg(long, default_value = "0x1234", value_parser = parse_u16)]
+    pub input_vendor: u16,
+
+    /// USB product ID of the virtual touchscreen; the keyboard uses this + 1
+    #[arg(long, default_value = "0x5678", value_parser = parse_u16)]
+    pub input_product: u16,
+}
+
+/// Capture backend selection for `--backend`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    Auto,
+    Drm,
+    Fbdev,
+    /// Synthetic frames, no hardware needed (tests, CI, demos).
+    TestPattern,
+}
+
+/// RFB security type for `--security-types`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SecurityType {
+    None,
+    Vnc,
+    Ard,
+    Tight,
+}
+
+/// What `--log-input` logs of key and pointer events.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum InputLog {
+    Redacted,
+    Full,
+}
+
+/// Key handling for `--keyboard-mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum KeyboardMode {
+    Keysym,
+    Scancode,
+}
+
+/// Capture scheduling for `--capture-mode`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CapturePolicy {
+    Adaptive,
+    OnDemand,
+    Polling,
+}
+
+/// Parse a u16 given either as hex (`0x1234`) or decimal.
+fn parse_u16(s: &str) -> Result<u16, String> {
+    let r = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u16::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    r.map_err(|e| format!("invalid 16-bit ID {s:?}: {e}"))
+}
+
+/// Parse `--depth`: 16, 24 or 32.
+fn parse_depth(s: &str) -> Result<u8, String> {
+    match s.parse() {
+        Ok(depth @ (16 | 24 | 32)) => Ok(depth),
+        _ => Err(format!("depth must be 16, 24 or 32, not {s:?}")),
+    }
+}
+
+/// Parse `--gamma` or `--brightness`: a positive factor.
+fn parse_factor(s: &str) -> Result<f32, String> {
+    match s.parse::<f32>() {
+        Ok(v) if v > 0.0 && v.is_finite() => Ok(v),
+        _ => Err(format!("expected a positive number, not {s:?}")),
+    }
+}
+
+/// Parse a tile size, accepting only the values in `TILE_SIZES`.
+fn parse_tile_size(s: &str) -> Result<u32, String> {
+    let size = s
+        .parse()
+        .map_err(|e| format!("invalid tile size {s:?}: {e}"))?;
+    if !TILE_SIZES.contains(&size) {
+        return Err(format!("tile size must be one of {TILE_SIZES:?}"));
+    }
+    Ok(size)
 }
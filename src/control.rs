@@ -0,0 +1,300 @@
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::frame_hub::FrameHub;
+use crate::input::keymap::Keymap;
+use crate::server::KeymapReload;
+
+/// Connected clients, as the control socket lists them.
+#[derive(Default)]
+pub struct Clients {
+    list: Mutex<Vec<ClientEntry>>,
+    /// Clients accepted since startup; also numbers them.
+    accepted: AtomicU64,
+}
+
+struct ClientEntry {
+    id: u64,
+    peer: String,
+    websocket: bool,
+    since: Instant,
+}
+
+impl Clients {
+    /// List a client for as long as the returned guard lives.
+    pub fn register(self: &Arc<Self>, peer: &str, websocket: bool) -> Registration {
+        let id = self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.list.lock().unwrap().push(ClientEntry {
+            id,
+            peer: peer.to_string(),
+            websocket,
+            since: Instant::now(),
+        });
+        Registration {
+            clients: self.clone(),
+            id,
+        }
+    }
+
+    fn contains(&self, peer: &str) -> bool {
+        self.list.lock().unwrap().iter().any(|c| c.peer == peer)
+    }
+}
+
+/// A client's place in `Clients`, given up on drop.
+pub struct Registration {
+    clients: Arc<Clients>,
+    id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut list = self.clients.list.lock().unwrap();
+        list.retain(|c| c.id != self.id);
+    }
+}
+
+/// What the control socket's commands act on.
+pub struct Control {
+    pub hub: Arc<FrameHub>,
+    pub clients: Arc<Clients>,
+    pub started: Instant,
+    /// The `--keymap` file and the input loop to hand it to on `reload`.
+    pub keymap: Option<(PathBuf, mpsc::Sender<KeymapReload>)>,
+}
+
+/// The listening control socket. Dropping it stops serving commands and
+/// removes the socket file.
+pub struct ControlSocket {
+    path: PathBuf,
+    task: JoinHandle<()>,
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        self.task.abort();
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl Control {
+    /// Serve commands on a Unix socket at `path`, readable and writable by
+    /// the owner only. A stale socket left by an earlier run is replaced.
+    pub fn listen(self, path: &Path) -> Result<ControlSocket> {
+        if let Ok(meta) = std::fs::symlink_metadata(path) {
+            if !meta.file_type().is_socket() {
+                bail!("{} exists and is not a socket", path.display());
+            }
+            if std::os::unix::net::UnixStream::connect(path).is_ok() {
+                bail!("{} is in use by another process", path.display());
+            }
+            std::fs::remove_file(path)
+                .with_context(|| format!("Cannot remove stale socket {}", path.display()))?;
+        }
+        // Created 0600 rather than restricted after the fact, which would
+        // leave it open to others in between. The umask is per process, so
+        // files other threads create meanwhile come out stricter too.
+        // SAFETY: umask has no memory-safety preconditions.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        // SAFETY: as above.
+        unsafe { libc::umask(umask) };
+        let listener = listener.with_context(|| format!("Cannot listen on {}", path.display()))?;
+        tracing::info!("Control socket listening on {}", path.display());
+
+        let control = Arc::new(self);
+        let task = tokio::spawn(async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        tracing::warn!("Control socket stopped accepting: {e}");
+                        break;
+                    }
+                };
+                let control = control.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = control.serve(stream).await {
+                        tracing::debug!("Control connection ended: {e:#}");
+                    }
+                });
+            }
+        });
+        Ok(ControlSocket {
+            path: path.to_path_buf(),
+            task,
+        })
+    }
+
+    /// Answer commands, one per line, until the peer hangs up.
+    async fn serve(&self, stream: UnixStream) -> Result<()> {
+        let (reader, mut writer) = stream.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        while let Some(line) = lines.next_line().await.context("read command")? {
+            let reply = self.execute(&line).await;
+            writer
+                .write_all(reply.as_bytes())
+                .await
+                .context("send reply")?;
+        }
+        Ok(())
+    }
+
+    /// Run one command line. The reply is any output lines followed by
+    /// "ok", or a single "error: <reason>" line; blank lines get none.
+    pub async fn execute(&self, line: &str) -> String {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let result = match words[..] {
+            [] => return String::new(),
+            ["list"] => Ok(self.list()),
+            ["kick", peer] => self.kick(peer),
+            ["bell"] => {
+                tracing::info!("Control socket: ringing bell");
+                self.hub.ring_bell();
+                Ok(String::new())
+            }
+            ["stats"] => Ok(self.stats()),
+            ["reload"] => self.reload().await,
+            _ => Err(anyhow!(
+                "unknown command {:?} (list, kick <peer>, bell, stats, reload)",
+                line.trim()
+            )),
+        };
+        match result {
+            Ok(output) => output + "ok\n",
+            // Keymap errors list one bad line per line; keep replies parseable
+            Err(e) => format!("error: {}\n", format!("{e:#}").replace('\n', "; ")),
+        }
+    }
+
+    /// One "<peer> <tcp|websocket> <seconds connected>" line per client.
+    fn list(&self) -> String {
+        let list = self.clients.list.lock().unwrap();
+        list.iter()
+            .map(|c| {
+                let transport = if c.websocket { "websocket" } else { "tcp" };
+                let secs = c.since.elapsed().as_secs();
+                format!("{} {transport} {secs}\n", c.peer)
+            })
+            .collect()
+    }
+
+    fn kick(&self, peer: &str) -> Result<String> {
+        if !self.clients.contains(peer) {
+            bail!("no client {peer}");
+        }
+        tracing::info!("Control socket: disconnecting {peer}");
+        self.hub.disconnect(peer);
+        Ok(String::new())
+    }
+
+    fn stats(&self) -> String {
+        let clients = self.clients.list.lock().unwrap().len();
+        format!(
+            "uptime_secs {}\nclients {clients}\naccepted {}\nframes {}\ncapture_fps {:.1}\n",
+            self.started.elapsed().as_secs(),
+            self.clients.accepted.load(Ordering::Relaxed),
+            self.hub.frames_published(),
+            self.hub.capture_fps(),
+        )
+    }
+
+    /// Read the `--keymap` file again and hand it to the keyboard.
+    async fn reload(&self) -> Result<String> {
+        let Some((path, keymap_tx)) = &self.keymap else {
+            bail!("nothing to reload: no --keymap with a virtual keyboard");
+        };
+        let keymap = Keymap::load(path)?;
+        let (reply_tx, reply_rx) = oneshot::channel();
+        keymap_tx
+            .send((keymap, reply_tx))
+            .await
+            .map_err(|_| anyhow!("input handling has stopped"))?;
+        reply_rx
+            .await
+            .map_err(|_| anyhow!("input handling has stopped"))??;
+        tracing::info!("Control socket: reloaded keymap {}", path.display());
+        Ok(String::new())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn control() -> Control {
+        Control {
+            hub: Arc::new(FrameHub::new(16, 16, 16, vec![0; 16 * 16 * 4])),
+            clients: Arc::default(),
+            started: Instant::now(),
+            keymap: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_act_on_clients_and_hub() {
+        let control = control();
+        let first = control.clients.register("10.0.0.1:5000", false);
+        let _second = control.clients.register("10.0.0.2:6000", true);
+        let mut disconnect_rx = control.hub.subscribe_disconnect();
+        let mut bell_rx = control.hub.subscribe_bell();
+
+        assert_eq!(
+            control.execute("list").await,
+            "10.0.0.1:5000 tcp 0\n10.0.0.2:6000 websocket 0\nok\n"
+        );
+        assert_eq!(control.execute(" kick 10.0.0.2:6000 ").await, "ok\n");
+        assert_eq!(
+            disconnect_rx.try_recv().unwrap().as_deref(),
+            Some("10.0.0.2:6000")
+        );
+        assert_eq!(
+            control.execute("kick 10.0.0.3:7000").await,
+            "error: no client 10.0.0.3:7000\n"
+        );
+        assert_eq!(control.execute("bell").await, "ok\n");
+        assert!(bell_rx.try_recv().is_ok());
+
+        drop(first);
+        assert_eq!(
+            control.execute("stats").await,
+            "uptime_secs 0\nclients 1\naccepted 2\nframes 0\ncapture_fps 0.0\nok\n"
+        );
+        assert!(control.execute("reload").await.starts_with("error: "));
+        assert!(control.execute("kick").await.starts_with("error: unknown"));
+        assert_eq!(control.execute("").await, "");
+    }
+
+    #[tokio::test]
+    async fn socket_answers_line_by_line() {
+        let path = std::env::temp_dir().join(format!("kmsvnc-control-{}", std::process::id()));
+        let socket = control().listen(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut stream = UnixStream::connect(&path).await.unwrap();
+        stream.write_all(b"bell\nlist\nfoo\n").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut reply = String::new();
+        tokio::io::AsyncReadExt::read_to_string(&mut stream, &mut reply)
+            .await
+            .unwrap();
+        assert_eq!(
+            reply,
+            "ok\nok\nerror: unknown command \"foo\" (list, kick <peer>, bell, stats, reload)\n"
+        );
+
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
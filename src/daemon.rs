@@ -0,0 +1,196 @@
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+/// Detach from the terminal for `--daemonize`: fork twice around `setsid`,
+/// so the daemon leads no session and can't acquire a controlling terminal
+/// again. The working directory is kept, so relative paths in the options
+/// still resolve.
+///
+/// The calling process stays behind until the daemon reports through the
+/// returned [`Daemon`], then exits with its status: 0 once it is serving,
+/// or 1 after printing why startup failed. Until then the daemon keeps the
+/// terminal as its stdio, so startup logs are still seen.
+///
+/// Must run before any threads are started (the tokio runtime in
+/// particular): only the forking thread survives in the child.
+pub fn daemonize() -> Result<Daemon> {
+    let mut fds = [0; 2];
+    // SAFETY: fds has room for the two descriptors pipe2 returns.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        bail!("pipe: {}", std::io::Error::last_os_error());
+    }
+    // SAFETY: both descriptors are fresh and owned by nothing else.
+    let (status_rx, status_tx) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+    if !fork()? {
+        drop(status_tx);
+        std::process::exit(wait_for_daemon(status_rx));
+    }
+    drop(status_rx);
+    // SAFETY: setsid has no memory-safety preconditions.
+    if unsafe { libc::setsid() } < 0 {
+        bail!("setsid: {}", std::io::Error::last_os_error());
+    }
+    if !fork()? {
+        // Skip destructors and atexit handlers, which belong to the daemon
+        // SAFETY: _exit has no memory-safety preconditions.
+        unsafe { libc::_exit(0) };
+    }
+    Ok(Daemon { status: status_tx })
+}
+
+/// Fork, returning whether this is the child.
+fn fork() -> Result<bool> {
+    // SAFETY: the process is still single-threaded (see `daemonize`).
+    match unsafe { libc::fork() } {
+        -1 => bail!("fork: {}", std::io::Error::last_os_error()),
+        pid => Ok(pid == 0),
+    }
+}
+
+/// What the launching process does after `daemonize`: wait for the daemon's
+/// report and turn it into an exit status.
+fn wait_for_daemon(mut status: File) -> i32 {
+    let mut report = String::new();
+    let _ = status.read_to_string(&mut report);
+    match report.as_str() {
+        READY => 0,
+        "" => {
+            eprintln!("kmsvnc exited during startup");
+            1
+        }
+        error => {
+            eprintln!("Error: {error}");
+            1
+        }
+    }
+}
+
+/// Report of a daemon that is serving.
+const READY: &str = "ready";
+
+/// A daemon still attached to the process that launched it, which waits for
+/// [`Daemon::ready`] or [`Daemon::fail`]. Dropping it reports a failure
+/// without a reason.
+pub struct Daemon {
+    status: File,
+}
+
+impl Daemon {
+    /// Let the launching process exit successfully, and point stdin, stdout
+    /// and stderr at /dev/null as the terminal goes away.
+    pub fn ready(mut self) -> Result<()> {
+        let null = File::options()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .context("open /dev/null")?;
+        for fd in 0..=2 {
+            // SAFETY: both descriptors are open; dup2 replaces fd atomically.
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+                bail!("redirect fd {fd}: {}", std::io::Error::last_os_error());
+            }
+        }
+        let _ = self.status.write_all(READY.as_bytes());
+        Ok(())
+    }
+
+    /// Have the launching process print `err` and exit with an error.
+    pub fn fail(mut self, err: &anyhow::Error) {
+        let _ = write!(self.status, "{err:#}");
+    }
+}
+
+/// `--pidfile`: the server's process ID, locked for as long as the server
+/// runs and removed again on drop.
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+}
+
+impl PidFile {
+    /// Write our process ID to `path`, refusing while another kmsvnc holds
+    /// the file locked or the process it names is still alive.
+    pub fn create(path: &Path) -> Result<Self> {
+        let shown = path.display();
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Cannot create {shown}"))?;
+        let mut recorded = String::new();
+        // SAFETY: flock has no memory-safety preconditions.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let _ = file.read_to_string(&mut recorded);
+            bail!(
+                "{shown} is locked by a running kmsvnc (pid {})",
+                recorded.trim()
+            );
+        }
+        file.read_to_string(&mut recorded)
+            .with_context(|| format!("read {shown}"))?;
+        let own = std::process::id() as libc::pid_t;
+        if let Ok(pid) = recorded.trim().parse::<libc::pid_t>() {
+            if pid > 0 && pid != own && is_alive(pid) {
+                bail!("{shown} names running process {pid} (remove it if that isn't kmsvnc)");
+            }
+        }
+        file.set_len(0).with_context(|| format!("write {shown}"))?;
+        file.rewind().with_context(|| format!("write {shown}"))?;
+        writeln!(file, "{own}").with_context(|| format!("write {shown}"))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Only if the path still leads to the file we locked, not one
+        // somebody put in its place
+        if let (Ok(at_path), Ok(held)) = (std::fs::metadata(&self.path), self.file.metadata()) {
+            if at_path.dev() == held.dev() && at_path.ino() == held.ino() {
+                let _ = std::fs::remove_file(&self.path);
+            }
+        }
+    }
+}
+
+/// Whether a process with ID `pid` exists.
+fn is_alive(pid: libc::pid_t) -> bool {
+    // SAFETY: signal 0 only checks that the process could be signalled.
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pidfile_is_held_until_dropped() {
+        let path = std::env::temp_dir().join(format!("kmsvnc-pid-{}", std::process::id()));
+        let pidfile = PidFile::create(&path).unwrap();
+        let recorded = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(recorded, format!("{}\n", std::process::id()));
+        // The lock is per open file, so a second open conflicts even here
+        assert!(PidFile::create(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), recorded);
+        drop(pidfile);
+        assert!(!path.exists());
+
+        // A stale file naming a live process (init) is refused and kept
+        std::fs::write(&path, "1\n").unwrap();
+        assert!(PidFile::create(&path).is_err());
+        std::fs::write(&path, "999999999\n").unwrap();
+        drop(PidFile::create(&path).unwrap());
+        assert!(!path.exists());
+    }
+}
@@ -1,8 +1,16 @@
 use std::sync::atomic::{AtomicU64, Ordering};
 
-pub const TILE_SIZE: u32 = 64;
+/// Default tile edge length in pixels.
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+
+/// Tile sizes accepted by `--tile-size`.
+pub const TILE_SIZES: [u32; 4] = [16, 32, 64, 128];
+
+/// Snapshot of the dirty tile bitmap (one bit per tile, 64 tiles per word).
+pub type TileMask = Vec<u64>;
 
 /// A dirty rectangle (coordinates only, no pixel data).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct DirtyRect {
     pub x: u16,
     pub y: u16,
@@ -10,13 +18,55 @@ pub struct DirtyRect {
     pub height: u16,
 }
 
+impl DirtyRect {
+    /// The overlapping part of two rects, or `None` if they don't overlap.
+    pub fn intersect(&self, other: &DirtyRect) -> Option<DirtyRect> {
+        let x0 = self.x.max(other.x);
+        let y0 = self.y.max(other.y);
+        let x1 = (self.x as u32 + self.width as u32).min(other.x as u32 + other.width as u32);
+        let y1 = (self.y as u32 + self.height as u32).min(other.y as u32 + other.height as u32);
+        if x1 <= x0 as u32 || y1 <= y0 as u32 {
+            return None;
+        }
+        Some(DirtyRect {
+            x: x0,
+            y: y0,
+            width: (x1 - x0 as u32) as u16,
+            height: (y1 - y0 as u32) as u16,
+        })
+    }
+
+    /// The smallest rect containing both.
+    pub fn union(&self, other: &DirtyRect) -> DirtyRect {
+        let x0 = self.x.min(other.x);
+        let y0 = self.y.min(other.y);
+        let x1 = (self.x + self.width).max(other.x + other.width);
+        let y1 = (self.y + self.height).max(other.y + other.height);
+        DirtyRect {
+            x: x0,
+            y: y0,
+            width: x1 - x0,
+            height: y1 - y0,
+        }
+    }
+}
+
 /// Lock-free dirty tile accumulator shared between capture and VNC threads.
 ///
-/// The capture thread sets bits for tiles that changed.
-/// The VNC server drains (reads + clears) accumulated bits to get dirty rects.
-/// Supports up to 512 tiles (e.g., 22×22 tiles for 1408×1408 at 64px tiles).
+/// The capture thread sets bits for tiles that changed; the frame hub fans
+/// each frame's mask out to one accumulator per client, which the client
+/// drains (reads + clears) to get its dirty rects. The bitmap is sized for
+/// the tile grid, so any resolution and tile size fit.
+///
+/// The grid is fixed for the accumulator's life and there is no resize:
+/// `set` and `mark` only need their atomics because the bitmap itself never
+/// moves. A capturer that comes back at another size is refused rather
+/// than followed (see `with_watchdog` in the server), so the capture thread
+/// never marks tiles of a different grid. Following a size change would
+/// take new accumulators built while the capture thread is stopped.
 pub struct DirtyTiles {
-    bits: [AtomicU64; 8],
+    bits: Box<[AtomicU64]>,
+    tile_size: u32,
     tiles_x: u32,
     tiles_y: u32,
     width: u32,
@@ -24,15 +74,13 @@ pub struct DirtyTiles {
 }
 
 impl DirtyTiles {
-    pub fn new(width: u32, height: u32) -> Self {
-        let tiles_x = width.div_ceil(TILE_SIZE);
-        let tiles_y = height.div_ceil(TILE_SIZE);
-        assert!(
-            (tiles_x * tiles_y) as usize <= 512,
-            "Too many tiles ({tiles_x}x{tiles_y}), max 512"
-        );
+    pub fn new(width: u32, height: u32, tile_size: u32) -> Self {
+        let tiles_x = width.div_ceil(tile_size);
+        let tiles_y = height.div_ceil(tile_size);
+        let words = (tiles_x * tiles_y).div_ceil(64) as usize;
         Self {
-            bits: std::array::from_fn(|_| AtomicU64::new(0)),
+            bits: (0..words).map(|_| AtomicU64::new(0)).collect(),
+            tile_size,
             tiles_x,
             tiles_y,
             width,
@@ -40,6 +88,21 @@ impl DirtyTiles {
         }
     }
 
+    /// Edge length of a tile in pixels.
+    pub fn tile_size(&self) -> u32 {
+        self.tile_size
+    }
+
+    /// Mask with no tile set, sized for this grid.
+    pub fn empty_mask(&self) -> TileMask {
+        vec![0; self.bits.len()]
+    }
+
+    /// Mask with every tile set. Bits past the last tile are ignored.
+    pub fn full_mask(&self) -> TileMask {
+        vec![u64::MAX; self.bits.len()]
+    }
+
     /// Mark a tile as dirty (by tile index).
     #[inline]
     pub fn set(&self, tile_idx: usize) {
@@ -61,32 +124,213 @@ impl DirtyTiles {
         }
     }
 
-    /// Atomically drain all dirty bits and convert to DirtyRect list.
-    pub fn drain_to_rects(&self) -> Vec<DirtyRect> {
-        // Atomically swap all words to 0
-        let mut words = [0u64; 8];
-        for (i, w) in words.iter_mut().enumerate() {
-            *w = self.bits[i].swap(0, Ordering::Relaxed);
+    /// Mark every tile overlapping `rect` as dirty.
+    pub fn mark_rect(&self, rect: DirtyRect) {
+        if rect.width == 0 || rect.height == 0 {
+            return;
+        }
+        let tx0 = rect.x as u32 / self.tile_size;
+        let ty0 = rect.y as u32 / self.tile_size;
+        let tx1 = ((rect.x as u32 + rect.width as u32 - 1) / self.tile_size).min(self.tiles_x - 1);
+        let ty1 = ((rect.y as u32 + rect.height as u32 - 1) / self.tile_size).min(self.tiles_y - 1);
+        for ty in ty0..=ty1 {
+            for tx in tx0..=tx1 {
+                self.set((ty * self.tiles_x + tx) as usize);
+            }
         }
+    }
 
-        let mut rects = Vec::new();
+    /// Atomically drain all dirty bits, returning them as a mask.
+    pub fn drain(&self) -> TileMask {
+        self.bits
+            .iter()
+            .map(|w| w.swap(0, Ordering::Relaxed))
+            .collect()
+    }
+
+    /// OR a mask of dirty tiles into the accumulated bits.
+    pub fn mark(&self, mask: &[u64]) {
+        for (bits, &w) in self.bits.iter().zip(mask) {
+            if w != 0 {
+                bits.fetch_or(w, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Clear the tiles in `mask` whose pixels are identical in `new` and
+    /// `old`, two full frames of `stride` bytes per row.
+    pub fn retain_changed(&self, mask: &mut [u64], new: &[u8], old: &[u8], stride: usize) {
         for ty in 0..self.tiles_y {
+            let y0 = ty * self.tile_size;
+            let y1 = (y0 + self.tile_size).min(self.height);
             for tx in 0..self.tiles_x {
                 let idx = (ty * self.tiles_x + tx) as usize;
-                let word = idx / 64;
-                let bit = idx % 64;
-                if words[word] & (1 << bit) != 0 {
-                    let x0 = tx * TILE_SIZE;
-                    let y0 = ty * TILE_SIZE;
-                    rects.push(DirtyRect {
-                        x: x0 as u16,
-                        y: y0 as u16,
-                        width: TILE_SIZE.min(self.width - x0) as u16,
-                        height: TILE_SIZE.min(self.height - y0) as u16,
-                    });
+                let bit = 1 << (idx % 64);
+                if mask[idx / 64] & bit == 0 {
+                    continue;
+                }
+                let x0 = (tx * self.tile_size * 4) as usize;
+                let x1 = (((tx + 1) * self.tile_size).min(self.width) * 4) as usize;
+                let same = (y0..y1).all(|y| {
+                    let row = y as usize * stride;
+                    new[row + x0..row + x1] == old[row + x0..row + x1]
+                });
+                if same {
+                    mask[idx / 64] &= !bit;
+                }
+            }
+        }
+    }
+
+    /// Rects covering the whole screen, in the same merged form as
+    /// `mask_to_rects` (one full-width band per tile row).
+    pub fn all_rects(&self) -> Vec<DirtyRect> {
+        self.mask_to_rects(&self.full_mask())
+    }
+
+    /// Convert a tile mask to rects, merging horizontal runs of dirty tiles
+    /// within each tile row into a single rect.
+    pub fn mask_to_rects(&self, mask: &[u64]) -> Vec<DirtyRect> {
+        let is_set = |idx: usize| mask[idx / 64] & (1 << (idx % 64)) != 0;
+
+        let mut rects = Vec::new();
+        for ty in 0..self.tiles_y {
+            let y0 = ty * self.tile_size;
+            let th = self.tile_size.min(self.height - y0);
+            let mut tx = 0;
+            while tx < self.tiles_x {
+                if !is_set((ty * self.tiles_x + tx) as usize) {
+                    tx += 1;
+                    continue;
                 }
+                let run_start = tx;
+                while tx < self.tiles_x && is_set((ty * self.tiles_x + tx) as usize) {
+                    tx += 1;
+                }
+                let x0 = run_start * self.tile_size;
+                let x1 = (tx * self.tile_size).min(self.width);
+                rects.push(DirtyRect {
+                    x: x0 as u16,
+                    y: y0 as u16,
+                    width: (x1 - x0) as u16,
+                    height: th as u16,
+                });
             }
         }
         rects
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kms::pixel_format::copy_rows_incremental;
+
+    /// Change a 3x2 block at (70, 40) in a 200x100 frame and collect the
+    /// resulting rects.
+    fn rects_for_change(tile_size: u32) -> Vec<DirtyRect> {
+        let (w, h) = (200u32, 100u32);
+        let mut prev = vec![0u8; (w * h * 4) as usize];
+        let mut next = prev.clone();
+        for y in 40..42 {
+            for x in 70..73 {
+                let off = ((y * w + x) * 4) as usize;
+                next[off..off + 4].copy_from_slice(&[0xFF, 0x80, 0x00, 0x00]);
+            }
+        }
+
+        let tiles = DirtyTiles::new(w, h, tile_size);
+        assert!(copy_rows_incremental(&mut prev, &next, w, h, w * 4, &tiles));
+        assert_eq!(prev, next);
+        tiles.mask_to_rects(&tiles.drain())
+    }
+
+    #[test]
+    fn same_change_maps_to_the_covering_tile() {
+        // 16px tiles: column 4 (64..80), row 2 (32..48)
+        assert_eq!(
+            rects_for_change(16),
+            [DirtyRect {
+                x: 64,
+                y: 32,
+                width: 16,
+                height: 16,
+            }]
+        );
+        // 128px tiles: column 0 (0..128), row 0 clipped to the frame height
+        assert_eq!(
+            rects_for_change(128),
+            [DirtyRect {
+                x: 0,
+                y: 0,
+                width: 128,
+                height: 100,
+            }]
+        );
+    }
+
+    #[test]
+    fn mark_rect_sets_overlapped_tiles() {
+        let tiles = DirtyTiles::new(200, 100, 64);
+        tiles.mark_rect(DirtyRect {
+            x: 60,
+            y: 10,
+            width: 10,
+            height: 90,
+        });
+        // Columns 0 and 1, both tile rows (the second clipped to 100)
+        assert_eq!(
+            tiles.mask_to_rects(&tiles.drain()),
+            [
+                DirtyRect {
+                    x: 0,
+                    y: 0,
+                    width: 128,
+                    height: 64,
+                },
+                DirtyRect {
+                    x: 0,
+                    y: 64,
+                    width: 128,
+                    height: 36,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn retain_changed_drops_identical_tiles() {
+        let (w, h) = (200u32, 100u32);
+        let old = vec![0u8; (w * h * 4) as usize];
+        let mut new = old.clone();
+        // Last pixel of the clipped bottom-right tile
+        let off = ((99 * w + 199) * 4) as usize;
+        new[off] = 1;
+
+        let tiles = DirtyTiles::new(w, h, 64);
+        let mut mask = tiles.full_mask();
+        tiles.retain_changed(&mut mask, &new, &old, (w * 4) as usize);
+        assert_eq!(
+            tiles.mask_to_rects(&mask),
+            [DirtyRect {
+                x: 192,
+                y: 64,
+                width: 8,
+                height: 36,
+            }]
+        );
+    }
+
+    #[test]
+    fn full_mask_covers_frame_at_any_tile_size() {
+        for &size in &TILE_SIZES {
+            let tiles = DirtyTiles::new(1920, 1080, size);
+            let area: u32 = tiles
+                .all_rects()
+                .iter()
+                .map(|r| r.width as u32 * r.height as u32)
+                .sum();
+            assert_eq!(area, 1920 * 1080, "tile size {size}");
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use tokio::sync::{broadcast, watch};
+
+use crate::frame_diff::{DirtyTiles, TileMask};
+
+/// A captured frame as shared with every connected client.
+pub struct Frame {
+    /// Full BGRA frame.
+    pub data: Vec<u8>,
+    /// Tiles that changed relative to the previously published frame.
+    /// Once reclaimed for reuse: tiles that differ from the published frame.
+    pub dirty: TileMask,
+    /// FramebufferUpdate message covering `dirty`, Raw-encoded in the
+    /// server's default pixel format. Empty if nobody was connected.
+    pub encoded: Vec<u8>,
+    /// When the capturer produced this frame.
+    pub captured_at: Instant,
+}
+
+/// Distributes captured frames to all clients.
+///
+/// The capture thread publishes one `Frame` per captured change. Each client
+/// owns a `DirtyTiles` accumulator that the hub ORs every frame's dirty mask
+/// into, so a client that skips frames still sees the union of what changed.
+/// Publishing and per-client draining happen under one lock, so the mask a
+/// client drains always matches the frame it reads pixels from.
+pub struct FrameHub {
+    width: u32,
+    height: u32,
+    tile_size: u32,
+    frame_tx: watch::Sender<Arc<Frame>>,
+    clients: Mutex<Vec<Weak<DirtyTiles>>>,
+    bell_tx: broadcast::Sender<()>,
+    /// Peers to disconnect, by address; `None` for every client.
+    disconnect_tx: broadcast::Sender<Option<String>>,
+    /// Frames published since startup.
+    published: AtomicU64,
+    /// Captures per second the capture loop last measured, as `f64` bits.
+    capture_fps: AtomicU64,
+    led_tx: watch::Sender<u8>,
+    name_tx: watch::Sender<String>,
+}
+
+impl FrameHub {
+    pub fn new(width: u32, height: u32, tile_size: u32, initial_data: Vec<u8>) -> Self {
+        let (frame_tx, _) = watch::channel(Arc::new(Frame {
+            data: initial_data,
+            dirty: DirtyTiles::new(width, height, tile_size).full_mask(),
+            encoded: Vec::new(),
+            captured_at: Instant::now(),
+        }));
+        let (bell_tx, _) = broadcast::channel(4);
+        let (disconnect_tx, _) = broadcast::channel(16);
+        let (led_tx, _) = watch::channel(0);
+        let (name_tx, _) = watch::channel("kmsvnc".to_string());
+        Self {
+            width,
+            height,
+            tile_size,
+            frame_tx,
+            clients: Mutex::new(Vec::new()),
+            bell_tx,
+            disconnect_tx,
+            published: AtomicU64::new(0),
+            capture_fps: AtomicU64::new(0),
+            led_tx,
+            name_tx,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// The most recently published frame.
+    pub fn current(&self) -> Arc<Frame> {
+        self.frame_tx.borrow().clone()
+    }
+
+    /// Register a new client: returns its frame receiver and dirty accumulator.
+    /// The client is dropped from the hub once its accumulator is dropped.
+    pub fn subscribe(&self) -> (watch::Receiver<Arc<Frame>>, Arc<DirtyTiles>) {
+        let tiles = Arc::new(DirtyTiles::new(self.width, self.height, self.tile_size));
+        let mut clients = self.clients.lock().unwrap();
+        clients.retain(|c| c.strong_count() > 0);
+        clients.push(Arc::downgrade(&tiles));
+        (self.frame_tx.subscribe(), tiles)
+    }
+
+    /// Whether any client is currently connected.
+    pub fn has_clients(&self) -> bool {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|c| c.strong_count() > 0)
+    }
+
+    /// Publish a new frame and mark its dirty tiles for every client.
+    /// Returns the previous frame so its buffers can be reused.
+    pub fn publish(&self, frame: Frame) -> Arc<Frame> {
+        let mut clients = self.clients.lock().unwrap();
+        let frame = Arc::new(frame);
+        let old = self.frame_tx.send_replace(frame.clone());
+        self.published.fetch_add(1, Ordering::Relaxed);
+        clients.retain(|c| match c.upgrade() {
+            Some(tiles) => {
+                tiles.mark(&frame.dirty);
+                true
+            }
+            None => false,
+        });
+        old
+    }
+
+    /// Wake waiting clients without changing the frame
+    /// (lets them answer a pending request with an empty update).
+    pub fn notify_unchanged(&self) {
+        self.frame_tx.send_modify(|_| {});
+    }
+
+    /// Ring the bell on every connected client.
+    pub fn ring_bell(&self) {
+        let _ = self.bell_tx.send(());
+    }
+
+    /// Receiver for bell rings, one per client.
+    pub fn subscribe_bell(&self) -> broadcast::Receiver<()> {
+        self.bell_tx.subscribe()
+    }
+
+    /// Number of frames published since startup.
+    pub fn frames_published(&self) -> u64 {
+        self.published.load(Ordering::Relaxed)
+    }
+
+    /// Successful captures per second, as last measured by the capture loop.
+    pub fn capture_fps(&self) -> f64 {
+        f64::from_bits(self.capture_fps.load(Ordering::Relaxed))
+    }
+
+    pub fn set_capture_fps(&self, fps: f64) {
+        self.capture_fps.store(fps.to_bits(), Ordering::Relaxed);
+    }
+
+    /// Disconnect the client at `peer` (its address as logged).
+    pub fn disconnect(&self, peer: &str) {
+        let _ = self.disconnect_tx.send(Some(peer.to_string()));
+    }
+
+    /// Disconnect every client.
+    pub fn disconnect_all(&self) {
+        let _ = self.disconnect_tx.send(None);
+    }
+
+    /// Receiver for disconnect requests, one per client.
+    pub fn subscribe_disconnect(&self) -> broadcast::Receiver<Option<String>> {
+        self.disconnect_tx.subscribe()
+    }
+
+    /// Update the keyboard lock LEDs shown by clients.
+    pub fn set_led_state(&self, leds: u8) {
+        self.led_tx.send_if_modified(|current| {
+            let changed = *current != leds;
+            *current = leds;
+            changed
+        });
+    }
+
+    /// Receiver for the keyboard lock LEDs, one per client.
+    pub fn subscribe_led(&self) -> watch::Receiver<u8> {
+        self.led_tx.subscribe()
+    }
+
+    /// Rename the desktop shown by clients.
+    pub fn set_desktop_name(&self, name: String) {
+        self.name_tx.send_if_modified(|current| {
+            let changed = *current != name;
+            *current = name;
+            changed
+        });
+    }
+
+    /// Receiver for the desktop name, one per client.
+    pub fn subscribe_desktop_name(&self) -> watch::Receiver<String> {
+        self.name_tx.subscribe()
+    }
+
+    /// Atomically drain a client's dirty tiles and grab the current frame.
+    pub fn snapshot(
+        &self,
+        frame_rx: &mut watch::Receiver<Arc<Frame>>,
+        tiles: &DirtyTiles,
+    ) -> (TileMask, Arc<Frame>) {
+        let _clients = self.clients.lock().unwrap();
+        let mask = tiles.drain();
+        let frame = frame_rx.borrow_and_update().clone();
+        (mask, frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(hub: &FrameHub, tiles: &[usize]) -> Frame {
+        let mask = DirtyTiles::new(hub.width(), hub.height(), hub.tile_size);
+        for &t in tiles {
+            mask.set(t);
+        }
+        Frame {
+            data: vec![0; (hub.width() * hub.height() * 4) as usize],
+            dirty: mask.drain(),
+            encoded: Vec::new(),
+            captured_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn every_client_sees_every_change() {
+        let hub = FrameHub::new(32, 32, 16, vec![0; 32 * 32 * 4]);
+        let (mut rx_a, tiles_a) = hub.subscribe();
+        let (mut rx_b, tiles_b) = hub.subscribe();
+        let mask = |tiles: &[usize]| frame(&hub, tiles).dirty;
+
+        hub.publish(frame(&hub, &[0]));
+        // A draining its tiles leaves B's alone
+        assert_eq!(hub.snapshot(&mut rx_a, &tiles_a).0, mask(&[0]));
+        hub.publish(frame(&hub, &[3]));
+        assert_eq!(hub.snapshot(&mut rx_a, &tiles_a).0, mask(&[3]));
+        // B skipped a frame and gets the union
+        assert_eq!(hub.snapshot(&mut rx_b, &tiles_b).0, mask(&[0, 3]));
+        assert_eq!(hub.snapshot(&mut rx_b, &tiles_b).0, mask(&[]));
+    }
+}
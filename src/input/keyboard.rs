@@ -1,15 +1,28 @@
+use std::collections::HashSet;
 use std::fs::OpenOptions;
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use input_linux::{EventKind, InputId, Key, UInputHandle};
 
+use super::keymap::Keymap;
+
 /// Virtual keyboard backed by uinput.
 pub struct VirtualKeyboard {
     handle: UInputHandle<std::fs::File>,
+    keymap: Keymap,
+    /// Codes enabled on the device beyond `KEYBOARD_KEYS`, for the keymap.
+    extra_codes: HashSet<u16>,
+    /// Lock key state (`LED_*` bits), tracked from the lock keys we press.
+    leds: u8,
 }
 
+/// Lock LED bits, in the order of the RFB LED State pseudo-encoding.
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
 impl VirtualKeyboard {
-    pub fn new() -> Result<Self> {
+    pub fn new(id: &InputId, name: &str, keymap: Keymap) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -20,42 +33,98 @@ impl VirtualKeyboard {
 
         handle.set_evbit(EventKind::Key).context("set EV_KEY")?;
 
-        for &key in &ALL_KEYS {
-            handle.set_keybit(key).context("set key bit")?;
+        // Unassigned codes in the range are skipped; keymap codes were
+        // validated when it was loaded
+        for code in KEYBOARD_KEYS.chain(keymap.codes()) {
+            if let Ok(key) = Key::from_code(code) {
+                handle.set_keybit(key).context("set key bit")?;
+            }
         }
 
-        let id = InputId {
-            bustype: 0x06, // BUS_VIRTUAL
-            vendor: 0x1234,
-            product: 0x5679,
-            version: 1,
-        };
-
         handle
-            .create(&id, b"kmsvnc-keyboard", 0, &[])
+            .create(id, name.as_bytes(), 0, &[])
             .context("create uinput keyboard device")?;
 
-        tracing::info!("Created virtual keyboard");
+        tracing::info!("Created virtual keyboard {name}");
 
         std::thread::sleep(std::time::Duration::from_millis(100));
 
-        Ok(Self { handle })
+        let extra_codes = keymap
+            .codes()
+            .filter(|c| !KEYBOARD_KEYS.contains(c))
+            .collect();
+        Ok(Self {
+            handle,
+            keymap,
+            extra_codes,
+            leds: 0,
+        })
+    }
+
+    /// Replace the keymap. Codes the device wasn't created with can't be
+    /// sent, so a keymap using them needs a restart instead.
+    pub fn set_keymap(&mut self, keymap: Keymap) -> Result<()> {
+        let mut missing: Vec<u16> = keymap
+            .codes()
+            .filter(|c| !KEYBOARD_KEYS.contains(c) && !self.extra_codes.contains(c))
+            .collect();
+        if !missing.is_empty() {
+            missing.sort_unstable();
+            missing.dedup();
+            bail!("key codes {missing:?} need a restart to be enabled");
+        }
+        self.keymap = keymap;
+        Ok(())
     }
 
     /// Process a VNC KeyEvent.
-    pub fn handle_key(&self, down: bool, keysym: u32) -> Result<()> {
-        let Some(code) = keysym_to_linux_key(keysym) else {
+    ///
+    /// The keymap is consulted first. Its modifiers are pressed before the
+    /// key goes down and released after it comes up. Returns the key code
+    /// the keysym resolved to, or `None` for an unknown keysym.
+    pub fn handle_key(&mut self, down: bool, keysym: u32) -> Result<Option<u16>> {
+        let Some((key_code, events)) = key_events(&self.keymap, down, keysym) else {
             tracing::debug!("Unknown keysym: 0x{keysym:04x}");
-            return Ok(());
+            return Ok(None);
+        };
+        self.write_keys(events)?;
+        Ok(Some(key_code))
+    }
+
+    /// Process a QEMU extended key event: press the key at XT scancode
+    /// `keycode` as is, without the keymap. A scancode with no Linux key
+    /// falls back to `keysym`. Returns the key code that was pressed.
+    pub fn handle_scancode(
+        &mut self,
+        down: bool,
+        keysym: u32,
+        keycode: u32,
+    ) -> Result<Option<u16>> {
+        let Some(code) = xt_to_linux_key(keycode) else {
+            tracing::debug!("Unknown scancode: 0x{keycode:02x}");
+            return self.handle_key(down, keysym);
         };
+        self.write_keys(vec![make_event(EV_KEY, code, i32::from(down))])?;
+        Ok(Some(code))
+    }
 
-        let events = [
-            make_event(EV_KEY, code, if down { 1 } else { 0 }),
-            make_event(EV_SYN, SYN_REPORT, 0),
-        ];
+    /// Write key events and a SYN_REPORT, keeping track of lock LEDs.
+    fn write_keys(&mut self, mut events: Vec<input_linux::sys::input_event>) -> Result<()> {
+        for ev in &events {
+            if ev.value == 1 {
+                self.leds ^= lock_led(ev.code);
+            }
+        }
+        events.push(make_event(EV_SYN, SYN_REPORT, 0));
         self.handle.write(&events).context("write key events")?;
         Ok(())
     }
+
+    /// Lock LEDs as set by the keys pressed so far (`LED_*` bits). Locks
+    /// are assumed off when the device is created.
+    pub fn led_state(&self) -> u8 {
+        self.leds
+    }
 }
 
 impl Drop for VirtualKeyboard {
@@ -70,6 +139,41 @@ const EV_SYN: u16 = input_linux::sys::EV_SYN as u16;
 const EV_KEY: u16 = input_linux::sys::EV_KEY as u16;
 const SYN_REPORT: u16 = input_linux::sys::SYN_REPORT as u16;
 
+/// Key events for a VNC KeyEvent, without the SYN_REPORT, and the key code
+/// the keysym resolved to. `None` for an unknown keysym.
+fn key_events(
+    keymap: &Keymap,
+    down: bool,
+    keysym: u32,
+) -> Option<(u16, Vec<input_linux::sys::input_event>)> {
+    let mut events = Vec::with_capacity(4);
+    if let Some(mapping) = keymap.get(keysym) {
+        let mods = mapping.modifiers.iter();
+        if down {
+            events.extend(mods.map(|&m| make_event(EV_KEY, m, 1)));
+            events.push(make_event(EV_KEY, mapping.code, 1));
+        } else {
+            events.push(make_event(EV_KEY, mapping.code, 0));
+            events.extend(mods.rev().map(|&m| make_event(EV_KEY, m, 0)));
+        }
+        Some((mapping.code, events))
+    } else {
+        let code = keysym_to_linux_key(keysym)?;
+        events.push(make_event(EV_KEY, code, i32::from(down)));
+        Some((code, events))
+    }
+}
+
+/// The LED a lock key toggles, or 0 for other keys.
+fn lock_led(code: u16) -> u8 {
+    match code as i32 {
+        input_linux::sys::KEY_SCROLLLOCK => LED_SCROLL_LOCK,
+        input_linux::sys::KEY_NUMLOCK => LED_NUM_LOCK,
+        input_linux::sys::KEY_CAPSLOCK => LED_CAPS_LOCK,
+        _ => 0,
+    }
+}
+
 fn make_event(type_: u16, code: u16, value: i32) -> input_linux::sys::input_event {
     let mut ev: input_linux::sys::input_event = unsafe { std::mem::zeroed() };
     ev.type_ = type_;
@@ -78,98 +182,108 @@ fn make_event(type_: u16, code: u16, value: i32) -> input_linux::sys::input_even
     ev
 }
 
-const ALL_KEYS: [Key; 85] = [
-    Key::Esc,
-    Key::Num1,
-    Key::Num2,
-    Key::Num3,
-    Key::Num4,
-    Key::Num5,
-    Key::Num6,
-    Key::Num7,
-    Key::Num8,
-    Key::Num9,
-    Key::Num0,
-    Key::Minus,
-    Key::Equal,
-    Key::Backspace,
-    Key::Tab,
-    Key::Q,
-    Key::W,
-    Key::E,
-    Key::R,
-    Key::T,
-    Key::Y,
-    Key::U,
-    Key::I,
-    Key::O,
-    Key::P,
-    Key::LeftBrace,
-    Key::RightBrace,
-    Key::Enter,
-    Key::LeftCtrl,
-    Key::A,
-    Key::S,
-    Key::D,
-    Key::F,
-    Key::G,
-    Key::H,
-    Key::J,
-    Key::K,
-    Key::L,
-    Key::Semicolon,
-    Key::Apostrophe,
-    Key::Grave,
-    Key::LeftShift,
-    Key::Backslash,
-    Key::Z,
-    Key::X,
-    Key::C,
-    Key::V,
-    Key::B,
-    Key::N,
-    Key::M,
-    Key::Comma,
-    Key::Dot,
-    Key::Slash,
-    Key::RightShift,
-    Key::KpAsterisk,
-    Key::LeftAlt,
-    Key::Space,
-    Key::CapsLock,
-    Key::F1,
-    Key::F2,
-    Key::F3,
-    Key::F4,
-    Key::F5,
-    Key::F6,
-    Key::F7,
-    Key::F8,
-    Key::F9,
-    Key::F10,
-    Key::NumLock,
-    Key::ScrollLock,
-    Key::Kp7,
-    Key::Kp8,
-    Key::Kp9,
-    Key::KpMinus,
-    Key::Kp4,
-    Key::Kp5,
-    Key::Kp6,
-    Key::KpPlus,
-    Key::Kp1,
-    Key::Kp2,
-    Key::Kp3,
-    Key::Kp0,
-    Key::KpDot,
-    Key::F11,
-    Key::F12,
+/// Key codes enabled on the device: the whole keyboard range below the
+/// button codes (BTN_MISC), which holds every key `keysym_to_linux_key` and
+/// `xt_to_linux_key` can produce. The kernel drops events for keys whose bit was never set.
+const KEYBOARD_KEYS: std::ops::Range<u16> = 1..input_linux::sys::BTN_MISC as u16;
+
+/// XF86 multimedia and browser keysyms (0x1008ffxx) and their keys.
+const XF86_KEYS: [(u32, Key); 23] = [
+    (0x1008ff02, Key::BrightnessUp),
+    (0x1008ff03, Key::BrightnessDown),
+    (0x1008ff11, Key::VolumeDown),
+    (0x1008ff12, Key::Mute),
+    (0x1008ff13, Key::VolumeUp),
+    (0x1008ff14, Key::PlayPause),
+    (0x1008ff15, Key::StopCD),
+    (0x1008ff16, Key::PreviousSong),
+    (0x1008ff17, Key::NextSong),
+    (0x1008ff18, Key::Homepage),
+    (0x1008ff19, Key::Mail),
+    (0x1008ff1b, Key::Search),
+    (0x1008ff1d, Key::Calc),
+    (0x1008ff26, Key::Back),
+    (0x1008ff27, Key::Forward),
+    (0x1008ff28, Key::Stop),
+    (0x1008ff29, Key::Refresh),
+    (0x1008ff2a, Key::Power),
+    (0x1008ff2c, Key::EjectCD),
+    (0x1008ff2f, Key::Sleep),
+    (0x1008ff30, Key::Bookmarks),
+    (0x1008ff31, Key::PauseCD),
+    (0x1008ffb2, Key::MicMute),
 ];
 
+/// Map an XT scancode as QEMU extended key events encode it (an 0xE0
+/// prefix sets the high bit) to a Linux KEY_* code. Codes up to 0x58 are
+/// the same in both; the table covers the rest of a PC keyboard.
+fn xt_to_linux_key(keycode: u32) -> Option<u16> {
+    use input_linux::sys::*;
+
+    let code: i32 = match keycode {
+        0x01..=0x53 | 0x55..=0x58 => keycode as i32,
+        0x54 => KEY_SYSRQ,
+        0x59 => KEY_KPEQUAL,
+        0x70 => KEY_KATAKANAHIRAGANA,
+        0x73 => KEY_RO,
+        0x79 => KEY_HENKAN,
+        0x7b => KEY_MUHENKAN,
+        0x7d => KEY_YEN,
+        0x7e => KEY_KPCOMMA,
+
+        // 0xE0-prefixed
+        0x90 => KEY_PREVIOUSSONG,
+        0x99 => KEY_NEXTSONG,
+        0x9c => KEY_KPENTER,
+        0x9d => KEY_RIGHTCTRL,
+        0xa0 => KEY_MUTE,
+        0xa1 => KEY_CALC,
+        0xa2 => KEY_PLAYPAUSE,
+        0xa4 => KEY_STOPCD,
+        0xae => KEY_VOLUMEDOWN,
+        0xb0 => KEY_VOLUMEUP,
+        0xb2 => KEY_HOMEPAGE,
+        0xb5 => KEY_KPSLASH,
+        0xb7 => KEY_SYSRQ,
+        0xb8 => KEY_RIGHTALT,
+        0xc6 => KEY_PAUSE,
+        0xc7 => KEY_HOME,
+        0xc8 => KEY_UP,
+        0xc9 => KEY_PAGEUP,
+        0xcb => KEY_LEFT,
+        0xcd => KEY_RIGHT,
+        0xcf => KEY_END,
+        0xd0 => KEY_DOWN,
+        0xd1 => KEY_PAGEDOWN,
+        0xd2 => KEY_INSERT,
+        0xd3 => KEY_DELETE,
+        0xdb => KEY_LEFTMETA,
+        0xdc => KEY_RIGHTMETA,
+        0xdd => KEY_COMPOSE,
+        0xde => KEY_POWER,
+        0xdf => KEY_SLEEP,
+        0xe3 => KEY_WAKEUP,
+        0xe5 => KEY_SEARCH,
+        0xe6 => KEY_BOOKMARKS,
+        0xe7 => KEY_REFRESH,
+        0xe9 => KEY_FORWARD,
+        0xea => KEY_BACK,
+        0xec => KEY_MAIL,
+
+        _ => return None,
+    };
+
+    Some(code as u16)
+}
+
 /// Map X11 keysym to Linux KEY_* code.
 fn keysym_to_linux_key(keysym: u32) -> Option<u16> {
     use input_linux::sys::*;
 
+    if let Some(&(_, key)) = XF86_KEYS.iter().find(|&&(sym, _)| sym == keysym) {
+        return Some(key as u16);
+    }
+
     let code: i32 = match keysym {
         // TTY function keys
         0xff08 => KEY_BACKSPACE,
@@ -209,6 +323,9 @@ fn keysym_to_linux_key(keysym: u32) -> Option<u16> {
         0xffe3 => KEY_LEFTCTRL,
         0xffe4 => KEY_RIGHTCTRL,
         0xffe5 => KEY_CAPSLOCK,
+        // Meta_L/Meta_R: sent for the Windows/Command key by some clients
+        0xffe7 => KEY_LEFTMETA,
+        0xffe8 => KEY_RIGHTMETA,
         0xffe9 => KEY_LEFTALT,
         0xffea => KEY_RIGHTALT,
         0xffeb => KEY_LEFTMETA,
@@ -347,3 +464,79 @@ fn keysym_to_linux_key(keysym: u32) -> Option<u16> {
 
     Some(code as u16)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_mapped_keysym_has_its_key_registered() {
+        let keysyms = (0x0020..=0x007e)
+            .chain(0xff00..=0xffff)
+            .chain(XF86_KEYS.map(|(sym, _)| sym));
+        for keysym in keysyms {
+            if let Some(code) = keysym_to_linux_key(keysym) {
+                assert!(
+                    KEYBOARD_KEYS.contains(&code),
+                    "keysym 0x{keysym:04x} maps to unregistered key {code}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn xt_scancodes_map_to_registered_keys() {
+        use input_linux::sys::*;
+
+        assert_eq!(xt_to_linux_key(0x1e), Some(KEY_A as u16));
+        assert_eq!(xt_to_linux_key(0x58), Some(KEY_F12 as u16));
+        // E0 1D and E0 48
+        assert_eq!(xt_to_linux_key(0x9d), Some(KEY_RIGHTCTRL as u16));
+        assert_eq!(xt_to_linux_key(0xc8), Some(KEY_UP as u16));
+        assert_eq!(xt_to_linux_key(0), None);
+        for keycode in 0..=0xff {
+            if let Some(code) = xt_to_linux_key(keycode) {
+                assert!(
+                    KEYBOARD_KEYS.contains(&code),
+                    "scancode 0x{keycode:02x} maps to unregistered key {code}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn modifier_chords_press_keys_in_order() {
+        use input_linux::sys::*;
+
+        let keymap = Keymap::default();
+        // Keysyms as a client sends them, each key pressed in turn
+        let press = |keysyms: &[u32]| -> Vec<(u16, i32)> {
+            keysyms
+                .iter()
+                .flat_map(|&keysym| key_events(&keymap, true, keysym).unwrap().1)
+                .map(|ev| (ev.code, ev.value))
+                .collect()
+        };
+        let down = |codes: &[i32]| -> Vec<(u16, i32)> {
+            codes.iter().map(|&code| (code as u16, 1)).collect()
+        };
+
+        // Control_L, Alt_L, Delete
+        assert_eq!(
+            press(&[0xffe3, 0xffe9, 0xffff]),
+            down(&[KEY_LEFTCTRL, KEY_LEFTALT, KEY_DELETE])
+        );
+        // Control_L, Alt_L, F2: VT switch
+        assert_eq!(
+            press(&[0xffe3, 0xffe9, 0xffbf]),
+            down(&[KEY_LEFTCTRL, KEY_LEFTALT, KEY_F2])
+        );
+        // Alt_L, Tab
+        assert_eq!(press(&[0xffe9, 0xff09]), down(&[KEY_LEFTALT, KEY_TAB]));
+        // Super_L and Super_R, also as the Meta keysyms some clients send
+        assert_eq!(
+            press(&[0xffeb, 0xffec, 0xffe7, 0xffe8]),
+            down(&[KEY_LEFTMETA, KEY_RIGHTMETA, KEY_LEFTMETA, KEY_RIGHTMETA])
+        );
+    }
+}
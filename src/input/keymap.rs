@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use input_linux::Key;
+
+/// Modifier keys a keymap entry may hold down around its key.
+const MODIFIERS: [(&str, Key); 5] = [
+    ("shift", Key::LeftShift),
+    ("ctrl", Key::LeftCtrl),
+    ("alt", Key::LeftAlt),
+    ("altgr", Key::RightAlt),
+    ("meta", Key::LeftMeta),
+];
+
+/// Linux key code for a keysym, plus the modifiers pressed with it.
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub code: u16,
+    pub modifiers: Vec<u16>,
+}
+
+/// User keysym → key code table loaded with `--keymap`.
+///
+/// One entry per line: `keysym = keycode[+modifier...]`. Keysyms and key
+/// codes are decimal or `0x` hex; modifiers are shift, ctrl, alt, altgr and
+/// meta. `#` starts a comment. For example, on a German layout:
+///
+/// ```text
+/// 0x0040 = 16+altgr   # @ is AltGr+Q
+/// 0x007a = 21         # z and y are swapped
+/// 0x0079 = 44
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct Keymap {
+    map: HashMap<u32, Mapping>,
+}
+
+impl Keymap {
+    /// Load and validate a keymap file. Every malformed line is reported.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("Cannot read keymap {}", path.display()))?;
+
+        let mut map = HashMap::new();
+        let mut errors = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            match parse_line(line) {
+                Ok((keysym, mapping)) => {
+                    if map.insert(keysym, mapping).is_some() {
+                        tracing::warn!(
+                            "{}:{}: keysym 0x{keysym:04x} mapped again, last entry wins",
+                            path.display(),
+                            n + 1
+                        );
+                    }
+                }
+                Err(e) => errors.push(format!("  line {}: {e}", n + 1)),
+            }
+        }
+        if !errors.is_empty() {
+            bail!("Invalid keymap {}:\n{}", path.display(), errors.join("\n"));
+        }
+
+        tracing::info!(
+            "Loaded {} keymap entries from {}",
+            map.len(),
+            path.display()
+        );
+        Ok(Self { map })
+    }
+
+    pub fn get(&self, keysym: u32) -> Option<&Mapping> {
+        self.map.get(&keysym)
+    }
+
+    /// Every key code the keymap can emit, for registering with uinput.
+    pub fn codes(&self) -> impl Iterator<Item = u16> + '_ {
+        self.map
+            .values()
+            .flat_map(|m| std::iter::once(m.code).chain(m.modifiers.iter().copied()))
+    }
+}
+
+fn parse_line(line: &str) -> Result<(u32, Mapping)> {
+    let Some((keysym, target)) = line.split_once('=') else {
+        bail!("expected `keysym = keycode[+modifiers]`");
+    };
+    let keysym = parse_number(keysym.trim()).context("bad keysym")?;
+
+    let mut parts = target.split('+').map(str::trim);
+    let code = parts.next().unwrap_or_default();
+    let code = parse_number(code)
+        .ok()
+        .and_then(|c| u16::try_from(c).ok())
+        .filter(|&c| Key::from_code(c).is_ok())
+        .with_context(|| format!("bad key code {code:?}"))?;
+
+    let modifiers = parts
+        .map(|name| {
+            MODIFIERS
+                .iter()
+                .find(|(m, _)| m.eq_ignore_ascii_case(name))
+                .map(|&(_, key)| key as u16)
+                .with_context(|| format!("unknown modifier {name:?}"))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok((keysym, Mapping { code, modifiers }))
+}
+
+/// Parse a decimal or `0x` hex number.
+fn parse_number(s: &str) -> Result<u32> {
+    let r = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    r.with_context(|| format!("invalid number {s:?}"))
+}
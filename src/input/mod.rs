@@ -1,2 +1,4 @@
 pub mod keyboard;
+pub mod keymap;
+pub mod recovery;
 pub mod touch;
@@ -0,0 +1,139 @@
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+/// Consecutive failed writes after which a device is taken to be gone.
+const FAILURES_BEFORE_RECREATE: u32 = 3;
+/// First wait between failed attempts to recreate a device; doubled after
+/// each further failure up to `RETRY_MAX`.
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+
+type CreateFn<T> = Box<dyn FnMut() -> Result<T> + Send>;
+
+/// A uinput device that is created again, the same way, once writes to it
+/// keep failing — as they do after udev or a suspend/resume cycle
+/// invalidates it. A device that couldn't be created at startup stays off.
+pub struct Recovering<T> {
+    what: &'static str,
+    create: CreateFn<T>,
+    device: Option<T>,
+    enabled: bool,
+    failures: u32,
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+impl<T> Recovering<T> {
+    /// Create the virtual `what` (e.g. "keyboard") with `create`.
+    pub fn new(what: &'static str, mut create: impl FnMut() -> Result<T> + Send + 'static) -> Self {
+        let device = match create() {
+            Ok(device) => Some(device),
+            Err(e) => {
+                tracing::warn!("Failed to create virtual {what}: {e:#}");
+                tracing::warn!("Input through the virtual {what} will be disabled");
+                None
+            }
+        };
+        Self {
+            what,
+            create: Box::new(create),
+            enabled: device.is_some(),
+            device,
+            failures: 0,
+            retry_at: Instant::now(),
+            backoff: RETRY_MIN,
+        }
+    }
+
+    /// The device, recreated first if it was dropped and the backoff has
+    /// passed. `None` while it is missing.
+    pub fn get(&mut self) -> Option<&mut T> {
+        if self.device.is_none() && self.enabled && Instant::now() >= self.retry_at {
+            match (self.create)() {
+                Ok(device) => {
+                    tracing::info!("Recreated virtual {}", self.what);
+                    self.device = Some(device);
+                    self.backoff = RETRY_MIN;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Cannot recreate virtual {}, retrying in {:?}: {e:#}",
+                        self.what,
+                        self.backoff
+                    );
+                    self.retry_at = Instant::now() + self.backoff;
+                    self.backoff = (self.backoff * 2).min(RETRY_MAX);
+                }
+            }
+        }
+        self.device.as_mut()
+    }
+
+    /// Check the result of a write to the device. After
+    /// `FAILURES_BEFORE_RECREATE` failures in a row the device is dropped,
+    /// to be recreated by the next `get`.
+    pub fn record<R>(&mut self, result: Result<R>) -> Option<R> {
+        match result {
+            Ok(r) => {
+                self.failures = 0;
+                Some(r)
+            }
+            Err(e) => {
+                tracing::warn!("Virtual {} event error: {e:#}", self.what);
+                self.failures += 1;
+                if self.failures >= FAILURES_BEFORE_RECREATE && self.device.take().is_some() {
+                    tracing::warn!("Virtual {} keeps failing, recreating it", self.what);
+                    self.failures = 0;
+                    self.retry_at = Instant::now();
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::anyhow;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn failing_device_is_recreated_with_backoff() {
+        // Creations 1 and 3 succeed, 2 fails
+        let created = Arc::new(AtomicU32::new(0));
+        let count = created.clone();
+        let mut device = Recovering::new("test", move || {
+            match count.fetch_add(1, Ordering::Relaxed) + 1 {
+                2 => Err(anyhow!("no uinput")),
+                n => Ok(n),
+            }
+        });
+        assert_eq!(device.get().copied(), Some(1));
+
+        // Two failures are tolerated, the third drops the device
+        for _ in 0..2 {
+            assert_eq!(device.record::<()>(Err(anyhow!("ENODEV"))), None);
+            assert!(device.get().is_some());
+        }
+        device.record::<()>(Err(anyhow!("ENODEV")));
+        // Recreating fails and isn't retried before the backoff is over
+        assert!(device.get().is_none());
+        assert!(device.get().is_none());
+        assert_eq!(created.load(Ordering::Relaxed), 2);
+
+        device.retry_at = Instant::now();
+        assert_eq!(device.get().copied(), Some(3));
+        assert_eq!(device.record(Ok(7)), Some(7));
+    }
+
+    #[test]
+    fn device_missing_at_startup_stays_off() {
+        let mut device = Recovering::<()>::new("test", || Err(anyhow!("no uinput")));
+        assert!(device.get().is_none());
+        device.retry_at = Instant::now();
+        assert!(device.get().is_none());
+    }
+}
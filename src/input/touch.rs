@@ -1,23 +1,62 @@
 use std::fs::OpenOptions;
 use std::io::Write;
+use std::time::{Duration, Instant};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use input_linux::{
     AbsoluteAxis, AbsoluteInfo, AbsoluteInfoSetup, EventKind, InputId, InputProperty, Key,
     UInputHandle,
 };
 
+/// Multitouch slots the device reports (ABS_MT_SLOT 0-9).
+pub const MAX_CONTACTS: usize = 10;
+
+/// How long a touch must be held still to count as a long press.
+const LONG_PRESS: Duration = Duration::from_millis(600);
+/// How far, in pixels, a long press may drift before it is a drag.
+const LONG_PRESS_SLOP: u16 = 8;
+
+/// One touch contact: its multitouch slot and position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Contact {
+    pub slot: u8,
+    pub x: u16,
+    pub y: u16,
+}
+
 /// Virtual touchscreen backed by uinput.
+///
+/// VNC pointer buttons map onto the single-touch model as follows:
+/// - left (bit 0): touch contact in slot 0 — press starts a touch at the
+///   pointer position, motion while held moves it, release lifts it
+/// - middle (bit 1) / right (bit 2): reported as BTN_MIDDLE / BTN_RIGHT key
+///   events on the same device, independent of touch contact
+/// - wheel (bits 3-6): ignored
+///
+/// Several contacts at once can be reported with `set_contacts`.
 pub struct VirtualTouchscreen {
     handle: UInputHandle<std::fs::File>,
+    /// Screen size the position axes cover.
+    width: u32,
+    height: u32,
     tracking_id: i32,
-    is_touching: bool,
-    last_x: u16,
-    last_y: u16,
+    /// Tracking ID and position of the contact in each slot.
+    slots: [Option<(i32, u16, u16)>; MAX_CONTACTS],
+    /// Last reported middle/right button state (button mask bits 1-2).
+    buttons: u8,
+    long_press: LongPress,
 }
 
 impl VirtualTouchscreen {
-    pub fn new(width: u32, height: u32) -> Result<Self> {
+    /// With `right_click_hold`, a touch held still for `LONG_PRESS` is a
+    /// right click instead.
+    pub fn new(
+        width: u32,
+        height: u32,
+        id: &InputId,
+        name: &str,
+        right_click_hold: bool,
+    ) -> Result<Self> {
         let file = OpenOptions::new()
             .read(true)
             .write(true)
@@ -33,6 +72,12 @@ impl VirtualTouchscreen {
         handle
             .set_keybit(Key::ButtonTouch)
             .context("set BTN_TOUCH")?;
+        handle
+            .set_keybit(Key::ButtonRight)
+            .context("set BTN_RIGHT")?;
+        handle
+            .set_keybit(Key::ButtonMiddle)
+            .context("set BTN_MIDDLE")?;
         handle
             .set_absbit(AbsoluteAxis::MultitouchSlot)
             .context("set ABS_MT_SLOT")?;
@@ -49,13 +94,6 @@ impl VirtualTouchscreen {
             .set_propbit(InputProperty::Direct)
             .context("set INPUT_PROP_DIRECT")?;
 
-        let id = InputId {
-            bustype: 0x06, // BUS_VIRTUAL
-            vendor: 0x1234,
-            product: 0x5678,
-            version: 1,
-        };
-
         let abs = [
             AbsoluteInfoSetup {
                 axis: AbsoluteAxis::MultitouchSlot,
@@ -104,84 +142,146 @@ impl VirtualTouchscreen {
         ];
 
         handle
-            .create(&id, b"kmsvnc-touch", 0, &abs)
+            .create(id, name.as_bytes(), 0, &abs)
             .context("create uinput touch device")?;
 
-        tracing::info!("Created virtual touchscreen ({}x{})", width, height);
+        tracing::info!("Created virtual touchscreen {name} ({}x{})", width, height);
 
         // Give udev time to create the device node
         std::thread::sleep(std::time::Duration::from_millis(100));
 
         Ok(Self {
             handle,
+            width,
+            height,
             tracking_id: 0,
-            is_touching: false,
-            last_x: 0,
-            last_y: 0,
+            slots: [None; MAX_CONTACTS],
+            buttons: 0,
+            long_press: LongPress::new(right_click_hold),
         })
     }
 
     /// Process a VNC PointerEvent.
-    /// button_mask bit 0 = left click = touch.
+    /// button_mask bit 0 = left click = touch, bit 1 = middle, bit 2 = right.
     pub fn handle_pointer(&mut self, button_mask: u8, x: u16, y: u16) -> Result<()> {
         let touching = (button_mask & 1) != 0;
 
-        if touching && !self.is_touching {
-            self.tracking_id = (self.tracking_id + 1) % 65536;
-            self.touch_down(x, y)?;
-            self.is_touching = true;
-        } else if touching && self.is_touching && (x != self.last_x || y != self.last_y) {
-            self.touch_move(x, y)?;
-        } else if !touching && self.is_touching {
-            self.touch_up()?;
-            self.is_touching = false;
+        let buttons = button_mask & 0b110;
+        if buttons != self.buttons {
+            self.update_buttons(buttons)?;
+            self.buttons = buttons;
         }
 
-        self.last_x = x;
-        self.last_y = y;
-        Ok(())
+        let gestures = self.long_press.pointer(touching, x, y, Instant::now());
+        self.apply(&gestures)
     }
 
-    fn write_events(&self, events: &[input_linux::sys::input_event]) -> Result<()> {
-        let bytes = unsafe {
-            std::slice::from_raw_parts(
-                events.as_ptr() as *const u8,
-                std::mem::size_of_val(events),
-            )
-        };
-        self.handle.as_inner().write_all(bytes).context("write events to uinput")?;
+    /// When a touch being held back will turn into a right click.
+    pub fn long_press_deadline(&self) -> Option<Instant> {
+        self.long_press.deadline()
+    }
+
+    /// Send the right click of a touch held until `long_press_deadline`.
+    pub fn expire_long_press(&mut self) -> Result<()> {
+        let gestures = self.long_press.expire(Instant::now());
+        self.apply(&gestures)
+    }
+
+    fn apply(&mut self, gestures: &[Gesture]) -> Result<()> {
+        for gesture in gestures {
+            match *gesture {
+                Gesture::Touch(Some((x, y))) => self.set_contacts(&[Contact { slot: 0, x, y }])?,
+                Gesture::Touch(None) => self.set_contacts(&[])?,
+                Gesture::RightClick => self.write_events(&[
+                    make_event(EV_KEY, BTN_RIGHT, 1),
+                    make_event(EV_SYN, SYN_REPORT, 0),
+                    make_event(EV_KEY, BTN_RIGHT, 0),
+                    make_event(EV_SYN, SYN_REPORT, 0),
+                ])?,
+            }
+        }
         Ok(())
     }
 
-    fn touch_down(&self, x: u16, y: u16) -> Result<()> {
-        let events = [
-            make_event(EV_ABS, ABS_MT_SLOT, 0),
-            make_event(EV_ABS, ABS_MT_TRACKING_ID, self.tracking_id),
-            make_event(EV_ABS, ABS_MT_POSITION_X, x as i32),
-            make_event(EV_ABS, ABS_MT_POSITION_Y, y as i32),
-            make_event(EV_KEY, BTN_TOUCH, 1),
-            make_event(EV_SYN, SYN_REPORT, 0),
-        ];
+    /// Report exactly `contacts` as touching: slots not listed are lifted,
+    /// new ones start a touch and the rest move, all in one frame.
+    /// Positions off the screen are moved onto its nearest edge.
+    pub fn set_contacts(&mut self, contacts: &[Contact]) -> Result<()> {
+        if let Some(c) = contacts.iter().find(|c| c.slot as usize >= MAX_CONTACTS) {
+            bail!("touch slot {} out of range", c.slot);
+        }
+        let contacts: Vec<Contact> = contacts
+            .iter()
+            .map(|&c| {
+                let (x, y) = clamp_to_screen(c.x, c.y, self.width, self.height);
+                Contact { x, y, ..c }
+            })
+            .collect();
+        let was_touching = self.slots.iter().any(Option::is_some);
+        let mut events = Vec::new();
+        for slot in 0..MAX_CONTACTS {
+            let contact = contacts.iter().find(|c| c.slot as usize == slot);
+            let select = make_event(EV_ABS, ABS_MT_SLOT, slot as i32);
+            match (contact, self.slots[slot]) {
+                (None, None) => {}
+                (None, Some(_)) => {
+                    events.extend([select, make_event(EV_ABS, ABS_MT_TRACKING_ID, -1)]);
+                    self.slots[slot] = None;
+                }
+                (Some(c), None) => {
+                    self.tracking_id = (self.tracking_id + 1) % 65536;
+                    events.extend([
+                        select,
+                        make_event(EV_ABS, ABS_MT_TRACKING_ID, self.tracking_id),
+                        make_event(EV_ABS, ABS_MT_POSITION_X, c.x as i32),
+                        make_event(EV_ABS, ABS_MT_POSITION_Y, c.y as i32),
+                    ]);
+                    self.slots[slot] = Some((self.tracking_id, c.x, c.y));
+                }
+                (Some(c), Some((id, x, y))) => {
+                    if (c.x, c.y) != (x, y) {
+                        events.extend([
+                            select,
+                            make_event(EV_ABS, ABS_MT_POSITION_X, c.x as i32),
+                            make_event(EV_ABS, ABS_MT_POSITION_Y, c.y as i32),
+                        ]);
+                        self.slots[slot] = Some((id, c.x, c.y));
+                    }
+                }
+            }
+        }
+        let touching = !contacts.is_empty();
+        if touching != was_touching {
+            events.push(make_event(EV_KEY, BTN_TOUCH, touching as i32));
+        }
+        if events.is_empty() {
+            return Ok(());
+        }
+        events.push(make_event(EV_SYN, SYN_REPORT, 0));
         self.write_events(&events)
     }
 
-    fn touch_move(&self, x: u16, y: u16) -> Result<()> {
-        let events = [
-            make_event(EV_ABS, ABS_MT_SLOT, 0),
-            make_event(EV_ABS, ABS_MT_POSITION_X, x as i32),
-            make_event(EV_ABS, ABS_MT_POSITION_Y, y as i32),
-            make_event(EV_SYN, SYN_REPORT, 0),
-        ];
-        self.write_events(&events)
+    fn write_events(&self, events: &[input_linux::sys::input_event]) -> Result<()> {
+        let bytes = unsafe {
+            std::slice::from_raw_parts(events.as_ptr() as *const u8, std::mem::size_of_val(events))
+        };
+        self.handle
+            .as_inner()
+            .write_all(bytes)
+            .context("write events to uinput")?;
+        Ok(())
     }
 
-    fn touch_up(&self) -> Result<()> {
-        let events = [
-            make_event(EV_ABS, ABS_MT_SLOT, 0),
-            make_event(EV_ABS, ABS_MT_TRACKING_ID, -1),
-            make_event(EV_KEY, BTN_TOUCH, 0),
-            make_event(EV_SYN, SYN_REPORT, 0),
-        ];
+    /// Emit BTN_MIDDLE / BTN_RIGHT for every button whose state changed.
+    fn update_buttons(&self, buttons: u8) -> Result<()> {
+        let mut events = Vec::with_capacity(3);
+        for (bit, code) in [(0b010, BTN_MIDDLE), (0b100, BTN_RIGHT)] {
+            if (buttons ^ self.buttons) & bit != 0 {
+                let pressed = buttons & bit != 0;
+                events.push(make_event(EV_KEY, code, pressed as i32));
+            }
+        }
+        events.push(make_event(EV_SYN, SYN_REPORT, 0));
         self.write_events(&events)
     }
 }
@@ -199,11 +299,20 @@ const EV_KEY: u16 = input_linux::sys::EV_KEY as u16;
 const EV_ABS: u16 = input_linux::sys::EV_ABS as u16;
 const SYN_REPORT: u16 = input_linux::sys::SYN_REPORT as u16;
 const BTN_TOUCH: u16 = input_linux::sys::BTN_TOUCH as u16;
+const BTN_RIGHT: u16 = input_linux::sys::BTN_RIGHT as u16;
+const BTN_MIDDLE: u16 = input_linux::sys::BTN_MIDDLE as u16;
 const ABS_MT_SLOT: u16 = input_linux::sys::ABS_MT_SLOT as u16;
 const ABS_MT_TRACKING_ID: u16 = input_linux::sys::ABS_MT_TRACKING_ID as u16;
 const ABS_MT_POSITION_X: u16 = input_linux::sys::ABS_MT_POSITION_X as u16;
 const ABS_MT_POSITION_Y: u16 = input_linux::sys::ABS_MT_POSITION_Y as u16;
 
+/// `(x, y)` moved onto a `width` x `height` screen. Clients can send
+/// positions past its edges, outside the range of the position axes.
+fn clamp_to_screen(x: u16, y: u16, width: u32, height: u32) -> (u16, u16) {
+    let clamp = |v: u16, size: u32| v.min(size.saturating_sub(1).min(u16::MAX.into()) as u16);
+    (clamp(x, width), clamp(y, height))
+}
+
 fn make_event(type_: u16, code: u16, value: i32) -> input_linux::sys::input_event {
     let mut ev: input_linux::sys::input_event = unsafe { std::mem::zeroed() };
     ev.type_ = type_;
@@ -211,3 +320,142 @@ fn make_event(type_: u16, code: u16, value: i32) -> input_linux::sys::input_even
     ev.value = value;
     ev
 }
+
+/// What a pointer event becomes on the touchscreen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Gesture {
+    /// Slot 0 touching at this position, or lifted.
+    Touch(Option<(u16, u16)>),
+    RightClick,
+}
+
+/// Long-press right-click emulation. While enabled, a new touch is held
+/// back until it moves more than `LONG_PRESS_SLOP` (then it is a drag from
+/// where it started), lifts (a tap) or stays put for `LONG_PRESS` (a right
+/// click, after which the rest of the press is ignored).
+struct LongPress {
+    enabled: bool,
+    /// Where and when the touch being held back started.
+    held: Option<(u16, u16, Instant)>,
+    /// A touch is passing through until it lifts.
+    touching: bool,
+    /// The current press already turned into a right click.
+    clicked: bool,
+}
+
+impl LongPress {
+    fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            held: None,
+            touching: false,
+            clicked: false,
+        }
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.held.map(|(_, _, started)| started + LONG_PRESS)
+    }
+
+    fn pointer(&mut self, touching: bool, x: u16, y: u16, now: Instant) -> Vec<Gesture> {
+        let position = touching.then_some((x, y));
+        if !self.enabled || self.touching {
+            self.touching = touching;
+            return vec![Gesture::Touch(position)];
+        }
+        if self.clicked {
+            self.clicked = touching;
+            return Vec::new();
+        }
+        let Some((hx, hy, started)) = self.held else {
+            if touching {
+                self.held = Some((x, y, now));
+            }
+            return Vec::new();
+        };
+        if touching && now >= started + LONG_PRESS {
+            return self.expire(now);
+        }
+        let moved = hx.abs_diff(x) > LONG_PRESS_SLOP || hy.abs_diff(y) > LONG_PRESS_SLOP;
+        if touching && !moved {
+            return Vec::new();
+        }
+        // A drag or a tap: replay the touch from where it started
+        self.held = None;
+        self.touching = touching;
+        vec![Gesture::Touch(Some((hx, hy))), Gesture::Touch(position)]
+    }
+
+    fn expire(&mut self, now: Instant) -> Vec<Gesture> {
+        match self.deadline() {
+            Some(deadline) if now >= deadline => {
+                self.held = None;
+                self.clicked = true;
+                vec![Gesture::RightClick]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_press_is_a_right_click_and_taps_and_drags_still_work() {
+        let t0 = Instant::now();
+        let ms = |ms| t0 + Duration::from_millis(ms);
+        let mut lp = LongPress::new(true);
+
+        // Held still: nothing until the deadline, then a right click, and
+        // the rest of the press is swallowed
+        assert_eq!(lp.pointer(true, 100, 100, t0), []);
+        assert_eq!(lp.pointer(true, 103, 98, ms(300)), []);
+        assert_eq!(lp.deadline(), Some(t0 + LONG_PRESS));
+        assert_eq!(lp.expire(ms(599)), []);
+        assert_eq!(lp.expire(ms(600)), [Gesture::RightClick]);
+        assert_eq!(lp.pointer(true, 150, 150, ms(700)), []);
+        assert_eq!(lp.pointer(false, 150, 150, ms(800)), []);
+
+        // A quick tap is replayed on release
+        assert_eq!(lp.pointer(true, 10, 20, ms(1000)), []);
+        assert_eq!(
+            lp.pointer(false, 10, 20, ms(1100)),
+            [Gesture::Touch(Some((10, 20))), Gesture::Touch(None)]
+        );
+
+        // Moving past the slop starts a drag from the original position
+        assert_eq!(lp.pointer(true, 10, 20, ms(2000)), []);
+        assert_eq!(
+            lp.pointer(true, 30, 20, ms(2100)),
+            [
+                Gesture::Touch(Some((10, 20))),
+                Gesture::Touch(Some((30, 20)))
+            ]
+        );
+        assert_eq!(lp.deadline(), None);
+        assert_eq!(
+            lp.pointer(true, 40, 20, ms(3000)),
+            [Gesture::Touch(Some((40, 20)))]
+        );
+        assert_eq!(lp.pointer(false, 40, 20, ms(3100)), [Gesture::Touch(None)]);
+    }
+
+    #[test]
+    fn positions_are_clamped_to_the_screen() {
+        assert_eq!(clamp_to_screen(10, 20, 1920, 1080), (10, 20));
+        assert_eq!(clamp_to_screen(1920, 1080, 1920, 1080), (1919, 1079));
+        assert_eq!(clamp_to_screen(u16::MAX, 500, 1920, 1080), (1919, 500));
+        assert_eq!(clamp_to_screen(5, 5, 0, 0), (0, 0));
+    }
+
+    #[test]
+    fn touches_pass_straight_through_when_disabled() {
+        let mut lp = LongPress::new(false);
+        let now = Instant::now();
+        assert_eq!(lp.pointer(true, 1, 2, now), [Gesture::Touch(Some((1, 2)))]);
+        assert_eq!(lp.deadline(), None);
+        assert_eq!(lp.pointer(false, 1, 2, now), [Gesture::Touch(None)]);
+    }
+}
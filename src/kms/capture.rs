@@ -1,14 +1,22 @@
-use std::ffi::c_void;
+use std::collections::hash_map::{Entry, HashMap};
+use std::ffi::{c_int, c_ulong, c_void};
 use std::fs;
-use std::os::fd::{AsFd, OwnedFd};
+use std::os::fd::{AsFd, AsRawFd, OwnedFd, RawFd};
 use std::ptr;
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
-use drm::control::{connector, crtc, framebuffer, Device as ControlDevice};
+use drm::control::{
+    self, connector, crtc, framebuffer, property, Device as ControlDevice, ResourceHandle,
+    ResourceHandles,
+};
 use drm_fourcc::{DrmFourcc, DrmModifier};
+use rustix::io::Errno;
 use rustix::mm::{self, MapFlags, ProtFlags};
 
 use super::card::Card;
+use super::edid::Edid;
+use super::info::CaptureInfo;
 use super::pixel_format;
 
 use crate::frame_diff::DirtyTiles;
@@ -20,6 +28,9 @@ fn exe_path() -> String {
         .unwrap_or_else(|| "<binary>".into())
 }
 
+/// Longest time the sampled-rows check may skip the full compare.
+const SAMPLE_FULL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
 /// Active output: connector -> encoder -> CRTC chain.
 pub struct ActiveOutput {
     pub connector_name: String,
@@ -27,10 +38,24 @@ pub struct ActiveOutput {
     pub width: u32,
     pub height: u32,
     pub fb_handle: framebuffer::Handle,
+    /// The attached monitor, if its EDID could be read.
+    pub monitor: Option<Edid>,
+}
+
+/// How cards are opened, and which outputs `probe_outputs` may select.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProbeOptions {
+    /// Also accept connectors that report disconnected/unknown, as long as
+    /// they drive a CRTC with a mode and framebuffer (virtual displays, vkms).
+    pub allow_disconnected: bool,
+    /// Capture this CRTC regardless of connector state.
+    pub force_crtc: Option<u32>,
+    /// Hold on to DRM master instead of releasing it when opening a card.
+    pub keep_master: bool,
 }
 
 /// Open the first DRI card that has connected outputs.
-pub fn open_card() -> Result<(Card, Vec<ActiveOutput>)> {
+pub fn open_card(opts: &ProbeOptions) -> Result<(Card, Vec<ActiveOutput>)> {
     let mut entries: Vec<_> = fs::read_dir("/dev/dri")?
         .filter_map(|e| e.ok())
         .filter(|e| {
@@ -41,10 +66,12 @@ pub fn open_card() -> Result<(Card, Vec<ActiveOutput>)> {
         .collect();
     entries.sort_by_key(|e| e.file_name());
 
+    // Outputs left out for a 0x0 mode, across all cards
+    let mut zero_sized = 0;
     for entry in &entries {
         let path = entry.path();
         let path_str = path.to_string_lossy();
-        let card = match Card::open(&path_str) {
+        let card = match Card::open(&path_str, opts.keep_master) {
             Ok(c) => c,
             Err(e) => {
                 tracing::debug!("Cannot open {path_str}: {e}");
@@ -52,16 +79,17 @@ pub fn open_card() -> Result<(Card, Vec<ActiveOutput>)> {
             }
         };
 
-        match probe_outputs(&card) {
-            Ok(outputs) if !outputs.is_empty() => {
+        match probe_outputs(&card, opts) {
+            Ok((outputs, _)) if !outputs.is_empty() => {
                 tracing::info!(
                     "KMS: using {path_str} with {} active output(s)",
                     outputs.len()
                 );
                 return Ok((card, outputs));
             }
-            Ok(_) => {
+            Ok((_, skipped)) => {
                 tracing::debug!("{path_str}: no active outputs");
+                zero_sized += skipped;
             }
             Err(e) => {
                 tracing::debug!("{path_str}: probe failed: {e}");
@@ -69,6 +97,13 @@ pub fn open_card() -> Result<(Card, Vec<ActiveOutput>)> {
         }
     }
 
+    if zero_sized > 0 {
+        bail!(
+            "No DRI card with usable outputs found: {zero_sized} active output(s) \
+             report a 0x0 mode, as happens during a modeset. Try again once the \
+             display is up"
+        );
+    }
     bail!(
         "No DRI card with active outputs found. \
          Ensure /dev/dri/card* exists and the process has CAP_SYS_ADMIN \
@@ -78,9 +113,17 @@ pub fn open_card() -> Result<(Card, Vec<ActiveOutput>)> {
 }
 
 /// Open a specific DRI card by path.
-pub fn open_card_path(path: &str) -> Result<(Card, Vec<ActiveOutput>)> {
-    let card = Card::open(path).with_context(|| format!("Cannot open {path}"))?;
-    let outputs = probe_outputs(&card)?;
+pub fn open_card_path(path: &str, opts: &ProbeOptions) -> Result<(Card, Vec<ActiveOutput>)> {
+    // Render nodes reject every KMS ioctl, so the scanout buffer can't be found
+    let node_name = path.rsplit('/').next().unwrap_or(path);
+    if node_name.starts_with("renderD") {
+        bail!("{path} is a render node; framebuffers can only be read from /dev/dri/card*");
+    }
+    let card = Card::open(path, opts.keep_master).with_context(|| format!("Cannot open {path}"))?;
+    let (outputs, zero_sized) = probe_outputs(&card, opts)?;
+    if outputs.is_empty() && zero_sized > 0 {
+        bail!("{path}: every active output reports a 0x0 mode (modeset in progress?)");
+    }
     if outputs.is_empty() {
         bail!("{path}: no active outputs found");
     }
@@ -88,13 +131,20 @@ pub fn open_card_path(path: &str) -> Result<(Card, Vec<ActiveOutput>)> {
     Ok((card, outputs))
 }
 
-fn probe_outputs(card: &Card) -> Result<Vec<ActiveOutput>> {
+/// Active outputs on `card`, and how many more were left out because their
+/// mode is 0x0, which can happen transiently during a modeset.
+fn probe_outputs(card: &Card, opts: &ProbeOptions) -> Result<(Vec<ActiveOutput>, usize)> {
     let res = card.resource_handles()?;
+    if let Some(id) = opts.force_crtc {
+        return probe_crtc(card, &res, id).map(|output| (vec![output], 0));
+    }
     let mut outputs = Vec::new();
+    let mut zero_sized = 0;
+    let mut names = PropertyNames::new();
 
     for &conn_h in res.connectors() {
         let conn = card.get_connector(conn_h, false)?;
-        if conn.state() != connector::State::Connected {
+        if conn.state() != connector::State::Connected && !opts.allow_disconnected {
             continue;
         }
 
@@ -107,34 +157,246 @@ fn probe_outputs(card: &Card) -> Result<Vec<ActiveOutput>> {
             Some(h) => h,
             None => continue,
         };
-        let crtc_info = card.get_crtc(crtc_h)?;
-        let mode = match crtc_info.mode() {
-            Some(m) => m,
-            None => continue,
-        };
-        let fb_h = match crtc_info.framebuffer() {
-            Some(h) => h,
-            None => continue,
+        let (Some((w, h)), Some(fb_h)) = scanout(card, crtc_h, &mut names)? else {
+            continue;
         };
 
-        let (w, h) = mode.size();
+        if w == 0 || h == 0 {
+            tracing::debug!("{conn}: mode is {w}x{h}, skipping");
+            zero_sized += 1;
+            continue;
+        }
         outputs.push(ActiveOutput {
             connector_name: format!("{conn}"),
             crtc_handle: crtc_h,
             width: w as u32,
             height: h as u32,
             fb_handle: fb_h,
+            monitor: read_edid(card, conn_h),
+        });
+    }
+
+    Ok((outputs, zero_sized))
+}
+
+/// Build an output for a specific CRTC, ignoring connector state. The name
+/// comes from a connector routed to the CRTC, if any.
+fn probe_crtc(card: &Card, res: &ResourceHandles, id: u32) -> Result<ActiveOutput> {
+    let crtc_h = *res
+        .crtcs()
+        .iter()
+        .find(|&&h| u32::from(h) == id)
+        .with_context(|| format!("CRTC {id} not found"))?;
+    let (mode, fb_h) = scanout(card, crtc_h, &mut PropertyNames::new())?;
+    let (w, h) = mode.with_context(|| format!("CRTC {id} has no mode set"))?;
+    let fb_h = fb_h.with_context(|| format!("CRTC {id} has no framebuffer"))?;
+
+    let conn = res
+        .connectors()
+        .iter()
+        .filter_map(|&h| card.get_connector(h, false).ok())
+        .find(|conn| {
+            conn.current_encoder()
+                .and_then(|enc_h| card.get_encoder(enc_h).ok())
+                .and_then(|enc| enc.crtc())
+                == Some(crtc_h)
         });
+    let connector_name = conn
+        .as_ref()
+        .map(|conn| format!("{conn}"))
+        .unwrap_or_else(|| format!("CRTC {id}"));
+
+    if w == 0 || h == 0 {
+        bail!("CRTC {id} mode is {w}x{h} (modeset in progress?)");
+    }
+    Ok(ActiveOutput {
+        connector_name,
+        crtc_handle: crtc_h,
+        width: w as u32,
+        height: h as u32,
+        fb_handle: fb_h,
+        monitor: conn.and_then(|conn| read_edid(card, conn.handle())),
+    })
+}
+
+/// Identity of the monitor on `conn`, from its EDID property.
+fn read_edid(card: &Card, conn: connector::Handle) -> Option<Edid> {
+    let read = || -> Result<Option<Edid>> {
+        let props = card.get_properties(conn)?;
+        for (&prop, &value) in props.iter() {
+            if card.get_property(prop)?.name().to_bytes() == b"EDID" {
+                if value == 0 {
+                    return Ok(None);
+                }
+                return Ok(Edid::parse(&card.get_property_blob(value)?));
+            }
+        }
+        Ok(None)
+    };
+    read().unwrap_or_else(|e| {
+        tracing::debug!("Cannot read EDID of connector {conn:?}: {e}");
+        None
+    })
+}
+
+/// Property names by handle; handles are stable for the device's life.
+type PropertyNames = HashMap<property::Handle, String>;
+
+/// Current property values of a DRM object, by name. Names not yet in
+/// `names` are looked up and kept there.
+fn named_properties<H: ResourceHandle>(
+    card: &Card,
+    handle: H,
+    names: &mut PropertyNames,
+) -> Result<HashMap<String, u64>> {
+    let values = card
+        .get_properties(handle)
+        .context("Failed to get properties")?;
+    let mut props = HashMap::new();
+    for (&prop, &value) in values.iter() {
+        let name = match names.entry(prop) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let info = card.get_property(prop).context("Failed to get property")?;
+                e.insert(info.name().to_string_lossy().into_owned())
+            }
+        };
+        props.insert(name.clone(), value);
     }
+    Ok(props)
+}
+
+/// A CRTC's mode size and primary framebuffer, each `None` if unset.
+type Scanout = (Option<(u16, u16)>, Option<framebuffer::Handle>);
 
-    Ok(outputs)
+/// Mode size and primary framebuffer of `crtc`.
+///
+/// With the atomic cap these are the committed atomic state: the CRTC's
+/// MODE_ID, and the FB_ID of the primary plane read together with the
+/// CRTC_ID binding it there, so a compositor's atomic commit is not seen
+/// half applied. Drivers without it get the legacy CRTC query.
+fn scanout(card: &Card, crtc: crtc::Handle, names: &mut PropertyNames) -> Result<Scanout> {
+    if card.atomic() {
+        match atomic_scanout(card, crtc, names) {
+            Ok(state) => return Ok(state),
+            Err(e) => tracing::debug!("Cannot read atomic state of {crtc:?}: {e:#}"),
+        }
+    }
+    let info = card.get_crtc(crtc).context("Failed to get CRTC")?;
+    Ok((info.mode().map(|mode| mode.size()), info.framebuffer()))
+}
+
+fn atomic_scanout(card: &Card, crtc: crtc::Handle, names: &mut PropertyNames) -> Result<Scanout> {
+    let props = named_properties(card, crtc, names)?;
+    let mode = match props.get("MODE_ID") {
+        None => bail!("CRTC has no MODE_ID property"),
+        Some(0) => None,
+        Some(&blob) => {
+            let blob = card
+                .get_property_blob(blob)
+                .context("Failed to read mode")?;
+            Some(mode_size(&blob).context("Truncated mode")?)
+        }
+    };
+    for plane in card.plane_handles().context("Failed to list planes")? {
+        let props = named_properties(card, plane, names)?;
+        if props.get("type") == Some(&DRM_PLANE_TYPE_PRIMARY)
+            && props.get("CRTC_ID") == Some(&u64::from(u32::from(crtc)))
+        {
+            return Ok((mode, fb_property(&props)));
+        }
+    }
+    Ok((mode, None))
+}
+
+/// The framebuffer a plane's properties say it scans out.
+fn fb_property(props: &HashMap<String, u64>) -> Option<framebuffer::Handle> {
+    props
+        .get("FB_ID")
+        .and_then(|&id| control::from_u32(id as u32))
+}
+
+/// `hdisplay` and `vdisplay` of a `struct drm_mode_modeinfo`.
+fn mode_size(blob: &[u8]) -> Option<(u16, u16)> {
+    let field = |at: usize| Some(u16::from_ne_bytes(blob.get(at..at + 2)?.try_into().ok()?));
+    Some((field(4)?, field(14)?))
 }
 
 // ---------------------------------------------------------------------------
 // Persistent DRM capturer with mmap cache
 // ---------------------------------------------------------------------------
 
-const MAX_CACHE_ENTRIES: usize = 4;
+/// Room for a triple-buffered primary plane plus a double-buffered overlay.
+const MAX_CACHE_ENTRIES: usize = 6;
+
+/// `DMA_BUF_IOCTL_SYNC`: `_IOW('b', 0, struct dma_buf_sync)`.
+const DMA_BUF_IOCTL_SYNC: c_ulong = 0x4008_6200;
+const DMA_BUF_SYNC_READ: u64 = 1 << 0;
+const DMA_BUF_SYNC_START: u64 = 0 << 2;
+const DMA_BUF_SYNC_END: u64 = 1 << 2;
+
+/// Kernel's `struct dma_buf_sync`.
+#[repr(C)]
+struct DmaBufSync {
+    flags: u64,
+}
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+}
+
+/// Values of a plane's "type" property.
+const DRM_PLANE_TYPE_OVERLAY: u64 = 0;
+const DRM_PLANE_TYPE_PRIMARY: u64 = 1;
+
+/// The part of a framebuffer a plane scans out, in whole pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SourceRect {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+impl SourceRect {
+    /// From the plane's SRC_* properties, which are 16.16 fixed point.
+    fn from_properties(props: &HashMap<String, u64>) -> Self {
+        let prop = |name: &str| (props.get(name).copied().unwrap_or(0) >> 16) as u32;
+        Self {
+            x: prop("SRC_X"),
+            y: prop("SRC_Y"),
+            width: prop("SRC_W"),
+            height: prop("SRC_H"),
+        }
+    }
+
+    /// The rectangle's bytes in a framebuffer mapping, from its first pixel
+    /// to its last; rows are still `pitch` apart.
+    fn bytes<'a>(&self, raw: &'a [u8], pitch: u32, format: DrmFourcc) -> Result<&'a [u8]> {
+        let bpp = pixel_format::bytes_per_pixel(format);
+        let start = (self.y * pitch + self.x * bpp) as usize;
+        let end = start + (self.height.saturating_sub(1) * pitch + self.width * bpp) as usize;
+        if self.width == 0 || self.height == 0 || end > raw.len() {
+            bail!(
+                "Plane source {}x{}+{}+{} outside its framebuffer",
+                self.width,
+                self.height,
+                self.x,
+                self.y
+            );
+        }
+        Ok(&raw[start..end])
+    }
+}
+
+/// An overlay plane on the captured CRTC, composited over the primary
+/// framebuffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Overlay {
+    fb: framebuffer::Handle,
+    src: SourceRect,
+    geometry: pixel_format::PlaneGeometry,
+}
 
 struct CachedBuffer {
     fb_key: u32,
@@ -143,38 +405,123 @@ struct CachedBuffer {
     size: usize,
     format: DrmFourcc,
     pitch: u32,
-    _prime_fd: Option<OwnedFd>,
+    prime_fd: Option<OwnedFd>,
 }
 
 pub struct Capturer {
     card: Card,
     crtc_handle: crtc::Handle,
     default_fb: framebuffer::Handle,
+    connector_name: String,
     width: u32,
     height: u32,
     use_fb2: Option<bool>,
     use_prime: Option<bool>,
+    /// Mapped buffers, least recently used first.
     cache: Vec<CachedBuffer>,
     last_fb_key: Option<u32>,
+    /// Modifier of the last framebuffer mapped via GET_FB2.
+    modifier: Option<DrmModifier>,
+    /// Whether the PRIME exporter accepts DMA_BUF_IOCTL_SYNC; cleared on the
+    /// first failure so unsupporting drivers aren't asked every frame.
+    dmabuf_sync: bool,
+    /// Scanlines hashed before an incremental compare (0 = disabled).
+    sample_rows: u32,
+    /// Signature of the sampled rows at the last full compare, and when
+    /// that compare ran.
+    last_sample: Option<(u64, Instant)>,
+    /// Tone-map 10-bit framebuffers from HDR10 instead of truncating them.
+    tonemap: bool,
+    /// Shortest time between two unforced captures, and when the last one
+    /// started.
+    min_interval: Duration,
+    last_read: Option<Instant>,
+    /// Times a read step failing with EINTR or EAGAIN is tried again.
+    read_retries: u32,
+    /// Overlay composited into the last capture.
+    last_overlay: Option<Overlay>,
+    /// The primary plane's source rectangle at the last capture, if it is
+    /// not simply the mode-sized top-left corner of its framebuffer.
+    last_primary_src: Option<SourceRect>,
+    /// That source rectangle converted to BGRA, when it is scaled.
+    source_buf: Vec<u8>,
+    /// The overlay's pixels, converted to BGRA.
+    overlay_buf: Vec<u8>,
+    /// Whole frame converted to BGRA, for diffing formats that aren't
+    /// direct-copy.
+    convert_buf: Vec<u8>,
+    prop_names: PropertyNames,
 }
 
+/// The primary plane's framebuffer and the part of it on screen.
+type PrimaryPlane = (framebuffer::Handle, SourceRect);
+
 // SAFETY: The mmap pointers in CachedBuffer are read-only and their backing
 // resources (prime fd or card fd) are kept alive by Capturer.
 unsafe impl Send for Capturer {}
 
 impl Capturer {
-    pub fn new(card: Card, output: &ActiveOutput) -> Self {
-        Self {
+    pub fn new(card: Card, output: &ActiveOutput) -> Result<Self> {
+        if output.width == 0 || output.height == 0 {
+            bail!(
+                "{}: cannot capture a {}x{} mode",
+                output.connector_name,
+                output.width,
+                output.height
+            );
+        }
+        Ok(Self {
             crtc_handle: output.crtc_handle,
             default_fb: output.fb_handle,
+            connector_name: output.connector_name.clone(),
             width: output.width,
             height: output.height,
             use_fb2: None,
             use_prime: None,
             cache: Vec::new(),
             last_fb_key: None,
+            modifier: None,
+            dmabuf_sync: true,
+            sample_rows: 0,
+            last_sample: None,
+            tonemap: false,
+            min_interval: Duration::ZERO,
+            last_read: None,
+            read_retries: 0,
+            last_overlay: None,
+            last_primary_src: None,
+            source_buf: Vec::new(),
+            overlay_buf: Vec::new(),
+            convert_buf: Vec::new(),
+            prop_names: PropertyNames::new(),
             card,
-        }
+        })
+    }
+
+    /// Skip the full incremental compare while `rows` evenly spaced
+    /// scanlines hash the same as at the last compare. Changes confined to
+    /// other rows are picked up within `SAMPLE_FULL_CHECK_INTERVAL`.
+    pub fn set_sample_rows(&mut self, rows: u32) {
+        self.sample_rows = rows;
+    }
+
+    /// Take 10-bit framebuffers as HDR10 and tone-map them to sRGB.
+    pub fn set_tonemap(&mut self, tonemap: bool) {
+        self.tonemap = tonemap;
+    }
+
+    /// Report unforced captures within `interval` of the previous one as
+    /// unchanged without reading the framebuffer, however the caller is
+    /// driven. Reads from uncached scanout memory can cost a whole core.
+    pub fn set_min_interval(&mut self, interval: Duration) {
+        self.min_interval = interval;
+    }
+
+    /// Retry mapping a framebuffer and syncing its dma-buf up to `retries`
+    /// times when they are interrupted (EINTR, EAGAIN), as some drivers do
+    /// under heavy GPU load, rather than dropping the frame.
+    pub fn set_read_retries(&mut self, retries: u32) {
+        self.read_retries = retries;
     }
 
     /// Capture a frame into a caller-provided buffer.
@@ -190,55 +537,281 @@ impl Capturer {
         force: bool,
         dirty_tiles: Option<&DirtyTiles>,
     ) -> Result<bool> {
-        let crtc_info = self
-            .card
-            .get_crtc(self.crtc_handle)
-            .context("Failed to get CRTC")?;
-        let fb_handle = crtc_info.framebuffer().unwrap_or(self.default_fb);
+        let now = Instant::now();
+        let too_soon = self
+            .last_read
+            .is_some_and(|at| now < at + self.min_interval);
+        if too_soon && !force {
+            return Ok(false);
+        }
+        self.last_read = Some(now);
+
+        let (primary, overlay) = self.find_planes().unwrap_or_else(|e| {
+            tracing::debug!("Cannot query planes: {e:#}");
+            (None, None)
+        });
+        // The primary plane's framebuffer and source rectangle come from one
+        // read of its state, so a flip can't fall between them
+        let (fb_handle, primary_src) = match primary {
+            Some((fb, src)) => (fb, Some(src)),
+            None => {
+                let crtc_info = self
+                    .card
+                    .get_crtc(self.crtc_handle)
+                    .context("Failed to get CRTC")?;
+                (crtc_info.framebuffer().unwrap_or(self.default_fb), None)
+            }
+        };
         let fb_key = u32::from(fb_handle);
 
-        // Skip capture if fb_handle hasn't changed (same page-flip buffer)
-        if !force && self.last_fb_key == Some(fb_key) {
+        // A source covering exactly the mode from the corner is the plain case
+        let full = SourceRect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        };
+        let primary_src = primary_src.filter(|src| *src != full);
+
+        // Skip capture if neither the primary nor the overlay framebuffer
+        // changed (same page-flip buffers), nor the part of it on screen
+        let overlay_changed = overlay != self.last_overlay;
+        let source_changed = primary_src != self.last_primary_src;
+        if !force && self.last_fb_key == Some(fb_key) && !overlay_changed && !source_changed {
             return Ok(false);
         }
         self.last_fb_key = Some(fb_key);
+        self.last_overlay = overlay;
+        self.last_primary_src = primary_src;
+        if let (true, Some(src)) = (source_changed, primary_src) {
+            tracing::info!(
+                "Primary plane shows {}x{}+{}+{} of its framebuffer, captured at {}x{}",
+                src.width,
+                src.height,
+                src.x,
+                src.y,
+                self.width,
+                self.height
+            );
+        }
+
+        // The overlay is converted before the primary plane is read, so the
+        // primary stays the most recently used cache entry (see `info`).
+        // One that cannot be read (e.g. a tiled video buffer) is left out.
+        let overlay = overlay.and_then(|overlay| match self.read_overlay(&overlay) {
+            Ok(per_pixel_alpha) => Some((overlay, per_pixel_alpha)),
+            Err(e) => {
+                tracing::debug!("Skipping overlay plane: {e:#}");
+                None
+            }
+        });
+
+        let mut attempt = 0;
+        let cached = loop {
+            match self.cache_buffer(fb_handle) {
+                Err(e) if is_interrupted(&e) && attempt < self.read_retries => {
+                    attempt += 1;
+                    tracing::debug!("Mapping framebuffer interrupted ({e:#}), retry {attempt}");
+                }
+                result => break result?,
+            }
+        };
+        let entry = self.cache.last().expect("buffer was just cached");
+        let raw = unsafe { std::slice::from_raw_parts(entry.ptr.cast::<u8>(), entry.size) };
+        let (format, pitch) = (entry.format, entry.pitch);
+        let prime_fd = entry.prime_fd.as_ref().map(|fd| fd.as_raw_fd());
+        // Only the primary plane's source rectangle is on screen, scaled to
+        // the mode if it differs in size
+        let (raw, scaled_from) = match primary_src.map(|src| (src, src.bytes(raw, pitch, format))) {
+            Some((src, Ok(bytes))) => {
+                let scaled = (src.width, src.height) != (self.width, self.height);
+                (bytes, scaled.then_some(src))
+            }
+            Some((_, Err(e))) => {
+                tracing::debug!("Capturing the whole primary framebuffer: {e:#}");
+                (raw, None)
+            }
+            None => (raw, None),
+        };
+        self.dmabuf_sync(prime_fd, DMA_BUF_SYNC_START);
+        let result = if let Some(src) = scaled_from {
+            self.convert_scaled(dst, raw, format, pitch, src, dirty_tiles)
+        } else if cached {
+            // With an overlay, or one just gone, or a moved source
+            // rectangle, the sampled rows say nothing about the frame
+            let force = force || overlay.is_some() || overlay_changed || source_changed;
+            self.convert_or_incremental(dst, raw, format, pitch, force, dirty_tiles)
+        } else {
+            self.convert_full(dst, raw, format, pitch, dirty_tiles)
+        };
+        self.dmabuf_sync(prime_fd, DMA_BUF_SYNC_END);
+        let changed = result?;
 
+        let Some((overlay, per_pixel_alpha)) = overlay else {
+            return Ok(changed);
+        };
+        // The primary compare sees the previous overlay pixels as changes,
+        // so the covered tiles were copied afresh and get drawn over again
+        let drawn = pixel_format::blend_plane(
+            dst,
+            self.width,
+            self.height,
+            &self.overlay_buf,
+            overlay.src.width,
+            overlay.src.height,
+            &overlay.geometry,
+            per_pixel_alpha,
+        );
+        if let (Some(rect), Some(dt)) = (drawn, dirty_tiles) {
+            dt.mark_rect(rect);
+        }
+        Ok(changed || drawn.is_some())
+    }
+
+    /// Make `fb_handle`'s buffer the most recently used cache entry, mapping
+    /// it on a cache miss. Returns whether it was already mapped.
+    fn cache_buffer(&mut self, fb_handle: framebuffer::Handle) -> Result<bool> {
         // Get the current GEM handle (the true identity of the buffer)
         let current_gem = self.get_gem_handle(fb_handle)?;
 
         // Cache lookup by GEM handle — supports double/triple buffering where
         // the same fb_handle maps to rotating GEM objects.
         if let Some(idx) = self.cache.iter().position(|e| e.gem_handle == current_gem) {
+            // Move to the back: with double/triple buffering the entry
+            // inserted first is often the one in use, so eviction must go
+            // by last use, not by insertion order
+            let mut entry = self.cache.remove(idx);
             // Update fb_key in case it changed (fb_handle recycling detection)
-            self.cache[idx].fb_key = fb_key;
-            let entry = &self.cache[idx];
-            let raw =
-                unsafe { std::slice::from_raw_parts(entry.ptr.cast::<u8>(), entry.size) };
-            return self.convert_or_incremental(dst, raw, entry.format, entry.pitch, dirty_tiles);
+            entry.fb_key = u32::from(fb_handle);
+            self.cache.push(entry);
+            return Ok(true);
         }
 
         // Cache miss — map the buffer
         let entry = self.map_buffer(fb_handle)?;
-        let raw = unsafe { std::slice::from_raw_parts(entry.ptr.cast::<u8>(), entry.size) };
-        let result = self.convert_full(dst, raw, entry.format, entry.pitch, dirty_tiles);
 
-        // Evict oldest entry if cache is full
+        // Evict the least recently used entry if cache is full
         if self.cache.len() >= MAX_CACHE_ENTRIES {
             let evicted = self.cache.remove(0);
             self.evict_entry(evicted);
         }
         self.cache.push(entry);
+        Ok(false)
+    }
+
+    /// The framebuffer and source rectangle of the primary plane on our
+    /// CRTC and the first overlay plane scanning out on it, if any. Needs
+    /// the atomic client cap, without which plane state is not visible.
+    fn find_planes(&mut self) -> Result<(Option<PrimaryPlane>, Option<Overlay>)> {
+        if !self.card.atomic() {
+            return Ok((None, None));
+        }
+        let crtc_id = u64::from(u32::from(self.crtc_handle));
+        let (mut primary, mut overlay) = (None, None);
+        for plane in self.card.plane_handles().context("Failed to list planes")? {
+            // Binding, framebuffer and position all from one query
+            let props = named_properties(&self.card, plane, &mut self.prop_names)?;
+            if props.get("CRTC_ID") != Some(&crtc_id) {
+                continue;
+            }
+            let Some(fb) = fb_property(&props) else {
+                continue;
+            };
+            match props.get("type") {
+                Some(&DRM_PLANE_TYPE_PRIMARY) => {
+                    primary = Some((fb, SourceRect::from_properties(&props)));
+                }
+                Some(&DRM_PLANE_TYPE_OVERLAY) if overlay.is_none() => {
+                    let prop = |name: &str| props.get(name).copied().unwrap_or(0);
+                    overlay = Some(Overlay {
+                        fb,
+                        src: SourceRect::from_properties(&props),
+                        // CRTC_X/Y are signed
+                        geometry: pixel_format::PlaneGeometry {
+                            x: prop("CRTC_X") as i32,
+                            y: prop("CRTC_Y") as i32,
+                            width: prop("CRTC_W") as u32,
+                            height: prop("CRTC_H") as u32,
+                            // Drivers without the property blend planes opaquely
+                            alpha: props.get("alpha").map_or(u16::MAX, |&a| a as u16),
+                        },
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok((primary, overlay))
+    }
+
+    /// Convert the overlay's source rectangle to BGRA in `overlay_buf`.
+    /// Returns whether its pixels carry alpha.
+    fn read_overlay(&mut self, overlay: &Overlay) -> Result<bool> {
+        self.cache_buffer(overlay.fb)?;
+        let entry = self.cache.last().expect("buffer was just cached");
+        let raw = unsafe { std::slice::from_raw_parts(entry.ptr.cast::<u8>(), entry.size) };
+        let (format, pitch) = (entry.format, entry.pitch);
+        let prime_fd = entry.prime_fd.as_ref().map(|fd| fd.as_raw_fd());
+        let src = overlay.src.bytes(raw, pitch, format)?;
+
+        self.dmabuf_sync(prime_fd, DMA_BUF_SYNC_START);
+        let result = to_bgra(
+            self.tonemap,
+            &mut self.overlay_buf,
+            src,
+            overlay.src.width,
+            overlay.src.height,
+            pitch,
+            format,
+        );
+        self.dmabuf_sync(prime_fd, DMA_BUF_SYNC_END);
+        result?;
+        Ok(format == DrmFourcc::Argb8888)
+    }
 
-        result
+    /// Bracket CPU reads of a PRIME buffer with DMA_BUF_IOCTL_SYNC so the
+    /// exporter can make the pixels coherent (non-coherent caches on ARM
+    /// SoCs otherwise show stale or torn frames). Dumb-buffer mappings
+    /// (`fd` is `None`) need no sync.
+    fn dmabuf_sync(&mut self, fd: Option<RawFd>, phase: u64) {
+        let Some(fd) = fd else { return };
+        if !self.dmabuf_sync {
+            return;
+        }
+        let sync = DmaBufSync {
+            flags: DMA_BUF_SYNC_READ | phase,
+        };
+        let mut attempt = 0;
+        while unsafe { ioctl(fd, DMA_BUF_IOCTL_SYNC, &sync) } < 0 {
+            let err = std::io::Error::last_os_error();
+            let interrupted = matches!(
+                err.kind(),
+                std::io::ErrorKind::Interrupted | std::io::ErrorKind::WouldBlock
+            );
+            if !interrupted {
+                tracing::debug!("DMA_BUF_IOCTL_SYNC not supported ({err}), reading without it");
+                self.dmabuf_sync = false;
+                return;
+            }
+            if attempt == self.read_retries {
+                // The driver does support it; only this frame goes unsynced
+                tracing::debug!("DMA_BUF_IOCTL_SYNC still interrupted ({err}), reading anyway");
+                return;
+            }
+            attempt += 1;
+            tracing::debug!("DMA_BUF_IOCTL_SYNC interrupted ({err}), retry {attempt}");
+        }
     }
 
-    /// Try incremental copy if possible, otherwise fall back to full copy.
+    /// Incremental copy into a warm `dst` if `dirty_tiles` is given,
+    /// otherwise a full copy. Direct-copy formats are compared straight
+    /// from the mapping; others are converted in full, then diffed.
     fn convert_or_incremental(
-        &self,
+        &mut self,
         dst: &mut Vec<u8>,
         raw: &[u8],
         format: DrmFourcc,
         pitch: u32,
+        force: bool,
         dirty_tiles: Option<&DirtyTiles>,
     ) -> Result<bool> {
         let expected_size = (self.width * self.height * 4) as usize;
@@ -246,17 +819,81 @@ impl Capturer {
         // Incremental path: direct-copy format + warm buffer + dirty_tiles available
         if let Some(dt) = dirty_tiles {
             if pixel_format::is_direct_copy(format) && dst.len() == expected_size {
+                if self.sample_rows > 0 {
+                    let sig = pixel_format::sample_rows_signature(
+                        raw,
+                        self.width,
+                        self.height,
+                        pitch,
+                        self.sample_rows,
+                    );
+                    let now = Instant::now();
+                    if let Some((last, at)) = self.last_sample {
+                        if !force && last == sig && now - at < SAMPLE_FULL_CHECK_INTERVAL {
+                            return Ok(false);
+                        }
+                    }
+                    self.last_sample = Some((sig, now));
+                }
                 let changed = pixel_format::copy_rows_incremental(
                     dst, raw, self.width, self.height, pitch, dt,
                 );
                 return Ok(changed);
             }
+            if dst.len() == expected_size {
+                let (w, h) = (self.width, self.height);
+                let bgra = &mut self.convert_buf;
+                to_bgra(self.tonemap, bgra, raw, w, h, pitch, format)?;
+                let changed = pixel_format::copy_rows_incremental(dst, bgra, w, h, w * 4, dt);
+                return Ok(changed);
+            }
         }
 
         // Full copy fallback
         self.convert_full(dst, raw, format, pitch, dirty_tiles)
     }
 
+    /// Convert a primary plane source rectangle of `src`'s size (`raw`
+    /// starts at its first pixel) and scale it to the mode, as the display
+    /// does. Diffed against a warm `dst` when `dirty_tiles` is given.
+    fn convert_scaled(
+        &mut self,
+        dst: &mut Vec<u8>,
+        raw: &[u8],
+        format: DrmFourcc,
+        pitch: u32,
+        src: SourceRect,
+        dirty_tiles: Option<&DirtyTiles>,
+    ) -> Result<bool> {
+        let (w, h) = (self.width, self.height);
+        let source = &mut self.source_buf;
+        to_bgra(
+            self.tonemap,
+            source,
+            raw,
+            src.width,
+            src.height,
+            pitch,
+            format,
+        )?;
+        let bgra = &mut self.convert_buf;
+        pixel_format::scale_into(bgra, source, src.width, src.height, w, h);
+        match dirty_tiles {
+            Some(dt) if dst.len() == bgra.len() => {
+                let changed = pixel_format::copy_rows_incremental(dst, bgra, w, h, w * 4, dt);
+                Ok(changed)
+            }
+            _ => {
+                dst.clear();
+                dst.extend_from_slice(bgra);
+                if let Some(dt) = dirty_tiles {
+                    dt.set_all();
+                }
+                Ok(true)
+            }
+        }
+    }
+
     /// Full pixel format conversion. Marks all tiles dirty.
     fn convert_full(
         &self,
@@ -266,8 +903,15 @@ impl Capturer {
         pitch: u32,
         dirty_tiles: Option<&DirtyTiles>,
     ) -> Result<bool> {
-        pixel_format::convert_to_bgra_into(dst, raw, self.width, self.height, pitch, format)
-            .map_err(|e| anyhow::anyhow!(e))?;
+        to_bgra(
+            self.tonemap,
+            dst,
+            raw,
+            self.width,
+            self.height,
+            pitch,
+            format,
+        )?;
         if let Some(dt) = dirty_tiles {
             dt.set_all();
         }
@@ -284,6 +928,34 @@ impl Capturer {
         }
     }
 
+    /// Describe the capture path. Reflects the most recently used
+    /// framebuffer, so call it after the first capture.
+    pub fn info(&self) -> CaptureInfo {
+        let format = self
+            .cache
+            .last()
+            .map(|e| e.format)
+            .unwrap_or(DrmFourcc::Xrgb8888);
+        CaptureInfo {
+            backend: "drm",
+            device: self.card.path().to_string(),
+            output: Some(self.connector_name.clone()),
+            width: self.width,
+            height: self.height,
+            format,
+            modifier: self.modifier,
+            fb_query: self
+                .use_fb2
+                .map(|fb2| if fb2 { "GET_FB2" } else { "GET_FB" }),
+            mapping: if self.use_prime == Some(true) {
+                "prime"
+            } else {
+                "dumb"
+            },
+            incremental: pixel_format::is_direct_copy(format),
+        }
+    }
+
     /// Get the current GEM handle for a framebuffer, to detect fb_handle recycling.
     fn get_gem_handle(&self, fb_handle: framebuffer::Handle) -> Result<drm::buffer::Handle> {
         match self.use_fb2 {
@@ -315,7 +987,12 @@ impl Capturer {
                     return Ok(entry);
                 }
                 Err(e) => {
-                    if self.use_fb2 == Some(true) {
+                    // GET_FB would map the tiled buffer as if it were linear,
+                    // or guess a format the buffer isn't in
+                    if self.use_fb2 == Some(true)
+                        || e.is::<TiledFramebuffer>()
+                        || e.is::<UnsupportedFormat>()
+                    {
                         return Err(e);
                     }
                     tracing::debug!("GET_FB2 failed ({e}), trying GET_FB");
@@ -337,10 +1014,7 @@ impl Capturer {
 
         if let Some(modifier) = info.modifier() {
             if modifier != DrmModifier::Linear {
-                bail!(
-                    "Framebuffer has non-linear modifier ({modifier:?}); \
-                     tiled buffers cannot be read via mmap"
-                );
+                return Err(TiledFramebuffer { modifier }.into());
             }
         }
 
@@ -351,6 +1025,10 @@ impl Capturer {
             "FB2: format={format:?}, pitch={pitch}, modifier={:?}",
             info.modifier()
         );
+        self.modifier = info.modifier();
+        if !pixel_format::SUPPORTED_FORMATS.contains(&format) {
+            return Err(UnsupportedFormat { format }.into());
+        }
 
         self.map_gem_cached(fb_handle, gem_handle, pitch, format)
     }
@@ -449,7 +1127,7 @@ impl Capturer {
             size,
             format,
             pitch,
-            _prime_fd: Some(prime_fd),
+            prime_fd: Some(prime_fd),
         })
     }
 
@@ -484,10 +1162,23 @@ impl Capturer {
             size,
             format,
             pitch,
-            _prime_fd: None,
+            prime_fd: None,
         })
     }
 
+    /// Unmap every cached buffer and forget the last framebuffer, so the
+    /// next capture maps the scanout buffer afresh. Recovers from a mapping
+    /// that went stale, e.g. when a mode change replaced the buffer.
+    pub fn flush_cache(&mut self) {
+        for entry in std::mem::take(&mut self.cache) {
+            self.evict_entry(entry);
+        }
+        self.last_fb_key = None;
+        self.last_sample = None;
+        self.last_overlay = None;
+        self.last_primary_src = None;
+    }
+
     fn evict_entry(&self, entry: CachedBuffer) {
         unsafe {
             let _ = mm::munmap(entry.ptr, entry.size);
@@ -498,11 +1189,114 @@ impl Capturer {
 
 impl Drop for Capturer {
     fn drop(&mut self) {
-        for entry in self.cache.drain(..) {
-            unsafe {
-                let _ = mm::munmap(entry.ptr, entry.size);
-            }
-            let _ = self.card.close_buffer(entry.gem_handle);
+        self.flush_cache();
+    }
+}
+
+/// Convert to BGRA, tone-mapping 10-bit formats from HDR10 with `tonemap`.
+fn to_bgra(
+    tonemap: bool,
+    dst: &mut Vec<u8>,
+    raw: &[u8],
+    width: u32,
+    height: u32,
+    pitch: u32,
+    format: DrmFourcc,
+) -> Result<()> {
+    if tonemap && pixel_format::is_10bit(format) {
+        pixel_format::convert_hdr10_to_bgra_into(dst, raw, width, height, pitch, format)
+    } else {
+        pixel_format::convert_to_bgra_into(dst, raw, width, height, pitch, format, false)
+    }
+    .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// The scanout buffer uses a tiled (non-linear) layout, which mmap exposes
+/// as-is: its pixels can't be read without detiling.
+#[derive(Debug)]
+pub struct TiledFramebuffer {
+    pub modifier: DrmModifier,
+}
+
+impl std::fmt::Display for TiledFramebuffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Framebuffer has non-linear modifier {:?} ({:#x}); tiled buffers \
+             cannot be read via mmap. Make the compositor scan out linear \
+             buffers (e.g. KWIN_DRM_NO_MODIFIERS=1 for KDE, \
+             WLR_DRM_NO_MODIFIERS=1 for wlroots) or try another --device",
+            self.modifier,
+            u64::from(self.modifier)
+        )
+    }
+}
+
+impl std::error::Error for TiledFramebuffer {}
+
+/// The tiled scanout buffer behind a capture error, if that is its cause.
+pub fn tiled_framebuffer(err: &anyhow::Error) -> Option<&TiledFramebuffer> {
+    err.chain().find_map(|cause| cause.downcast_ref())
+}
+
+/// The scanout buffer is in a pixel format kmsvnc has no conversion for.
+#[derive(Debug)]
+pub struct UnsupportedFormat {
+    pub format: DrmFourcc,
+}
+
+impl std::fmt::Display for UnsupportedFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Framebuffer format {:?} is not supported. kmsvnc supports",
+            self.format
+        )?;
+        for (i, format) in pixel_format::SUPPORTED_FORMATS.iter().enumerate() {
+            write!(f, "{}{format}", if i == 0 { " " } else { ", " })?;
         }
+        write!(
+            f,
+            "; make the compositor scan out one of those or try another --device"
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedFormat {}
+
+/// The unsupported scanout format behind a capture error, if that is its
+/// cause.
+pub fn unsupported_format(err: &anyhow::Error) -> Option<&UnsupportedFormat> {
+    err.chain().find_map(|cause| cause.downcast_ref())
+}
+
+/// Whether a capture error is an interrupted system call (EINTR, EAGAIN)
+/// that is worth repeating at once.
+fn is_interrupted(err: &anyhow::Error) -> bool {
+    let interrupted = [Errno::INTR, Errno::AGAIN].map(Errno::raw_os_error);
+    err.chain().any(|cause| {
+        let code = match cause.downcast_ref::<std::io::Error>() {
+            Some(e) => e.raw_os_error(),
+            None => cause.downcast_ref::<Errno>().map(|e| e.raw_os_error()),
+        };
+        code.is_some_and(|c| interrupted.contains(&c))
+    })
+}
+
+/// Whether a capture error may clear up on retry. A framebuffer replaced
+/// mid-capture (mode change, page flip) fails with e.g. ENOENT or EINVAL
+/// and the next buffer reads fine; losing access to the device (EACCES,
+/// EPERM, ENODEV) does not go away, and neither does a tiled scanout buffer.
+pub fn is_transient_error(err: &anyhow::Error) -> bool {
+    if tiled_framebuffer(err).is_some() {
+        return false;
     }
+    let fatal = [Errno::ACCESS, Errno::PERM, Errno::NODEV].map(Errno::raw_os_error);
+    !err.chain().any(|cause| {
+        let code = match cause.downcast_ref::<std::io::Error>() {
+            Some(e) => e.raw_os_error(),
+            None => cause.downcast_ref::<Errno>().map(|e| e.raw_os_error()),
+        };
+        code.is_some_and(|c| fatal.contains(&c))
+    })
 }
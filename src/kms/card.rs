@@ -2,13 +2,19 @@ use std::fs::{File, OpenOptions};
 use std::os::fd::{AsFd, BorrowedFd};
 
 use drm::control::Device as ControlDevice;
-use drm::Device;
+use drm::{ClientCapability, Device};
 
-pub struct Card(File);
+pub struct Card {
+    file: File,
+    path: String,
+    /// Whether the atomic client cap was granted, exposing every plane and
+    /// the committed atomic state of CRTCs and planes as properties.
+    atomic: bool,
+}
 
 impl AsFd for Card {
     fn as_fd(&self) -> BorrowedFd<'_> {
-        self.0.as_fd()
+        self.file.as_fd()
     }
 }
 
@@ -16,12 +22,35 @@ impl Device for Card {}
 impl ControlDevice for Card {}
 
 impl Card {
-    pub fn open(path: &str) -> std::io::Result<Self> {
+    /// Open the card at `path`. DRM master is released unless `keep_master`,
+    /// for sessions where kmsvnc is the only DRM user.
+    pub fn open(path: &str, keep_master: bool) -> std::io::Result<Self> {
         let file = OpenOptions::new().read(true).write(true).open(path)?;
-        let card = Card(file);
+        let mut card = Card {
+            file,
+            path: path.to_string(),
+            atomic: false,
+        };
         // Release DRM master so other apps (e.g. EGLFS) can acquire it.
         // kmsvnc only reads framebuffers and doesn't need master privileges.
-        let _ = card.release_master_lock();
+        if !keep_master {
+            let _ = card.release_master_lock();
+        }
+        // Drivers without atomic modesetting refuse these; the legacy CRTC
+        // queries work either way
+        let _ = card.set_client_capability(ClientCapability::UniversalPlanes, true);
+        card.atomic = card
+            .set_client_capability(ClientCapability::Atomic, true)
+            .is_ok();
         Ok(card)
     }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Whether CRTC and plane state can be read through atomic properties.
+    pub fn atomic(&self) -> bool {
+        self.atomic
+    }
 }
@@ -0,0 +1,191 @@
+use std::fmt;
+use std::str::FromStr;
+
+const HEADER: [u8; 8] = [0x00, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0x00];
+/// Offsets of the four 18-byte display descriptors in the base block.
+const DESCRIPTORS: [usize; 4] = [54, 72, 90, 108];
+const TAG_SERIAL: u8 = 0xFF;
+const TAG_NAME: u8 = 0xFC;
+
+/// Monitor identity from a connector's EDID base block.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edid {
+    /// Three-letter PNP manufacturer ID, e.g. "DEL".
+    pub manufacturer: String,
+    pub product: u16,
+    /// Numeric serial number; 0 when the monitor leaves it unset.
+    pub serial: u32,
+    /// Serial number string descriptor, which most monitors use instead.
+    pub serial_text: Option<String>,
+    /// Monitor name descriptor, e.g. "DELL U2720Q".
+    pub name: Option<String>,
+}
+
+impl Edid {
+    /// Parse the base block of an EDID blob. `None` if it isn't one.
+    pub fn parse(blob: &[u8]) -> Option<Self> {
+        if blob.len() < 128 || blob[..8] != HEADER {
+            return None;
+        }
+        let id = u16::from_be_bytes([blob[8], blob[9]]);
+        let manufacturer = [10, 5, 0]
+            .iter()
+            .map(|shift| match (id >> shift) & 0x1F {
+                c @ 1..=26 => Some((b'A' + c as u8 - 1) as char),
+                _ => None,
+            })
+            .collect::<Option<String>>()?;
+
+        let text = |tag: u8| {
+            DESCRIPTORS.iter().find_map(|&off| {
+                let d = &blob[off..off + 18];
+                (d[..3] == [0, 0, 0] && d[3] == tag).then(|| {
+                    let s = &d[5..];
+                    let end = s.iter().position(|&b| b == b'\n').unwrap_or(s.len());
+                    String::from_utf8_lossy(&s[..end]).trim().to_string()
+                })
+            })
+        };
+        Some(Self {
+            manufacturer,
+            product: u16::from_le_bytes([blob[10], blob[11]]),
+            serial: u32::from_le_bytes([blob[12], blob[13], blob[14], blob[15]]),
+            serial_text: text(TAG_SERIAL).filter(|s| !s.is_empty()),
+            name: text(TAG_NAME).filter(|s| !s.is_empty()),
+        })
+    }
+}
+
+impl fmt::Display for Edid {
+    /// E.g. "DEL DELL U2720Q serial 7XK2M33", in the form `--monitor` takes
+    /// the manufacturer and serial.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.manufacturer)?;
+        if let Some(ref name) = self.name {
+            write!(f, " {name}")?;
+        }
+        match (&self.serial_text, self.serial) {
+            (Some(serial), _) => write!(f, " serial {serial}"),
+            (None, 0) => Ok(()),
+            (None, serial) => write!(f, " serial {serial}"),
+        }
+    }
+}
+
+/// Which monitor to capture (`--monitor`): an EDID manufacturer ID and,
+/// optionally, serial number, as `DEL` or `DEL:7XK2M33`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MonitorId {
+    manufacturer: String,
+    serial: Option<String>,
+}
+
+impl MonitorId {
+    /// Whether `edid` is this monitor. The serial is compared with the
+    /// serial string, or else the numeric serial in decimal.
+    pub fn matches(&self, edid: &Edid) -> bool {
+        self.manufacturer == edid.manufacturer
+            && self.serial.as_ref().is_none_or(|serial| {
+                edid.serial_text.as_ref() == Some(serial)
+                    || (edid.serial != 0 && *serial == edid.serial.to_string())
+            })
+    }
+}
+
+impl fmt::Display for MonitorId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.manufacturer)?;
+        if let Some(ref serial) = self.serial {
+            write!(f, ":{serial}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for MonitorId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (manufacturer, serial) = match s.split_once(':') {
+            Some((m, serial)) => (m, Some(serial)),
+            None => (s, None),
+        };
+        if manufacturer.len() != 3 || !manufacturer.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(format!(
+                "invalid manufacturer {manufacturer:?}: expected a three-letter EDID ID such as DEL"
+            ));
+        }
+        if serial == Some("") {
+            return Err("empty serial number after ':'".to_string());
+        }
+        Ok(Self {
+            manufacturer: manufacturer.to_ascii_uppercase(),
+            serial: serial.map(String::from),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// EDID base block for "DEL" product 0xA0F6, numeric serial 0x4C453031,
+    /// with name and (optionally) serial string descriptors.
+    fn edid(serial_text: Option<&str>) -> Vec<u8> {
+        let mut blob = vec![0u8; 128];
+        blob[..8].copy_from_slice(&HEADER);
+        blob[8..10].copy_from_slice(&[0x10, 0xAC]);
+        blob[10..12].copy_from_slice(&0xA0F6u16.to_le_bytes());
+        blob[12..16].copy_from_slice(&0x4C45_3031u32.to_le_bytes());
+        let mut descriptor = |off: usize, tag: u8, text: &str| {
+            blob[off + 3] = tag;
+            let field = &mut blob[off + 5..off + 18];
+            field.fill(b' ');
+            field[..text.len()].copy_from_slice(text.as_bytes());
+            if text.len() < 13 {
+                field[text.len()] = b'\n';
+            }
+        };
+        descriptor(72, TAG_NAME, "DELL U2720Q");
+        if let Some(serial) = serial_text {
+            descriptor(90, TAG_SERIAL, serial);
+        }
+        blob
+    }
+
+    #[test]
+    fn parses_identity() {
+        let parsed = Edid::parse(&edid(Some("7XK2M33"))).unwrap();
+        assert_eq!(
+            parsed,
+            Edid {
+                manufacturer: "DEL".into(),
+                product: 0xA0F6,
+                serial: 0x4C45_3031,
+                serial_text: Some("7XK2M33".into()),
+                name: Some("DELL U2720Q".into()),
+            }
+        );
+        assert_eq!(parsed.to_string(), "DEL DELL U2720Q serial 7XK2M33");
+        assert_eq!(Edid::parse(&[0; 128]), None);
+        assert_eq!(Edid::parse(&HEADER), None);
+    }
+
+    #[test]
+    fn matches_manufacturer_and_serial() {
+        let with_text = Edid::parse(&edid(Some("7XK2M33"))).unwrap();
+        let numeric = Edid::parse(&edid(None)).unwrap();
+        let id = |s: &str| s.parse::<MonitorId>().unwrap();
+
+        assert!(id("del").matches(&with_text));
+        assert!(id("DEL:7XK2M33").matches(&with_text));
+        assert!(!id("DEL:7XK2M34").matches(&with_text));
+        assert!(id("DEL:1279602737").matches(&numeric));
+        assert!(!id("DEL:7XK2M33").matches(&numeric));
+        assert!(!id("SAM").matches(&with_text));
+
+        assert!("DELL".parse::<MonitorId>().is_err());
+        assert!("DEL:".parse::<MonitorId>().is_err());
+        assert_eq!(id("del:ab").to_string(), "DEL:ab");
+    }
+}
@@ -9,6 +9,8 @@ use rustix::mm::{self, MapFlags, ProtFlags};
 
 use super::pixel_format;
 
+use crate::frame_diff::DirtyTiles;
+
 const FBIOGET_VSCREENINFO: c_ulong = 0x4600;
 const FBIOGET_FSCREENINFO: c_ulong = 0x4602;
 
@@ -92,6 +94,9 @@ pub struct FbdevCapture {
     format: DrmFourcc,
     mmap_ptr: *mut c_void,
     mmap_size: usize,
+    /// Whole frame converted to BGRA, for diffing formats that aren't
+    /// direct-copy.
+    convert_buf: Vec<u8>,
 }
 
 // The mmap pointer is read-only and the mapped region does not change.
@@ -139,6 +144,8 @@ impl FbdevCapture {
             (32, 16, 8, 0, 8) => DrmFourcc::Argb8888,
             (32, 0, 8, 16, 0) => DrmFourcc::Xbgr8888,
             (32, 0, 8, 16, 8) => DrmFourcc::Abgr8888,
+            (24, 16, 8, 0, _) => DrmFourcc::Rgb888,
+            (24, 0, 8, 16, _) => DrmFourcc::Bgr888,
             (16, 11, 5, 0, _) => DrmFourcc::Rgb565,
             (bpp, r, g, b, a) => {
                 bail!(
@@ -179,6 +186,7 @@ impl FbdevCapture {
             format,
             mmap_ptr,
             mmap_size,
+            convert_buf: Vec::new(),
         })
     }
 
@@ -190,12 +198,63 @@ impl FbdevCapture {
         self.height
     }
 
-    pub fn capture_frame_into(&self, dst: &mut Vec<u8>) -> Result<()> {
-        let bpp = match self.format {
-            DrmFourcc::Rgb565 => 2u32,
-            _ => 4u32,
+    pub fn format(&self) -> DrmFourcc {
+        self.format
+    }
+
+    /// Capture into `dst`. If it holds an earlier frame and `dirty_tiles` is
+    /// given, only the tiles that changed are copied and marked; otherwise
+    /// the whole frame is converted and every tile marked. Returns whether
+    /// `dst` changed.
+    pub fn capture_into(
+        &mut self,
+        dst: &mut Vec<u8>,
+        dirty_tiles: Option<&DirtyTiles>,
+    ) -> Result<bool> {
+        let (w, h) = (self.width, self.height);
+        let Some(dt) = dirty_tiles.filter(|_| dst.len() == (w * h * 4) as usize) else {
+            self.capture_frame_into(dst)?;
+            if let Some(dt) = dirty_tiles {
+                dt.set_all();
+            }
+            return Ok(true);
         };
 
+        if pixel_format::is_direct_copy(self.format) {
+            let raw = self.mapped_frame()?;
+            let changed = pixel_format::copy_rows_incremental(dst, raw, w, h, self.stride, dt);
+            return Ok(changed);
+        }
+        let mut bgra = std::mem::take(&mut self.convert_buf);
+        let converted = self.mapped_frame().and_then(|raw| {
+            pixel_format::convert_to_bgra_into(
+                &mut bgra,
+                raw,
+                w,
+                h,
+                self.stride,
+                self.format,
+                false,
+            )
+            .map_err(|e| anyhow::anyhow!(e))
+        });
+        let changed =
+            converted.map(|()| pixel_format::copy_rows_incremental(dst, &bgra, w, h, w * 4, dt));
+        self.convert_buf = bgra;
+        changed
+    }
+
+    pub fn capture_frame_into(&self, dst: &mut Vec<u8>) -> Result<()> {
+        let raw = self.mapped_frame()?;
+        let (w, h) = (self.width, self.height);
+        pixel_format::convert_to_bgra_into(dst, raw, w, h, self.stride, self.format, false)
+            .map_err(|e| anyhow::anyhow!(e))
+    }
+
+    /// The visible frame within the mapping.
+    fn mapped_frame(&self) -> Result<&[u8]> {
+        let bpp = pixel_format::bytes_per_pixel(self.format);
+
         // Compute start offset from xoffset/yoffset
         let start = (self.yoffset as usize) * (self.stride as usize)
             + (self.xoffset as usize) * (bpp as usize);
@@ -210,13 +269,10 @@ impl FbdevCapture {
             );
         }
 
-        let raw = unsafe {
+        Ok(unsafe {
             let base = (self.mmap_ptr as *const u8).add(start);
             std::slice::from_raw_parts(base, needed)
-        };
-
-        pixel_format::convert_to_bgra_into(dst, raw, self.width, self.height, self.stride, self.format)
-            .map_err(|e| anyhow::anyhow!(e))
+        })
     }
 
     pub fn capture_frame(&self) -> Result<Vec<u8>> {
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use drm_fourcc::{DrmFourcc, DrmModifier};
+
+/// Summary of the capture path chosen at startup, for logs and
+/// `--print-capture-info`.
+#[derive(Debug, Clone)]
+pub struct CaptureInfo {
+    /// "drm" or "fbdev"
+    pub backend: &'static str,
+    /// Device node the frames are read from.
+    pub device: String,
+    /// DRM connector name (DRM only).
+    pub output: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub format: DrmFourcc,
+    /// Framebuffer modifier as reported by GET_FB2 (DRM only).
+    pub modifier: Option<DrmModifier>,
+    /// Framebuffer query ioctl: "GET_FB2" or "GET_FB" (DRM only).
+    pub fb_query: Option<&'static str>,
+    /// How the framebuffer is mapped: "prime", "dumb" or "fbdev".
+    pub mapping: &'static str,
+    /// Whether changed tiles are found by comparing the mapping directly.
+    /// Other formats are converted in full each capture, then diffed.
+    pub incremental: bool,
+}
+
+impl CaptureInfo {
+    /// Expand the `{hostname}`, `{output}`, `{width}` and `{height}` tokens
+    /// of a `--name` template.
+    pub fn desktop_name(&self, template: &str, hostname: &str) -> String {
+        template
+            .replace("{hostname}", hostname)
+            .replace("{output}", self.output.as_deref().unwrap_or(&self.device))
+            .replace("{width}", &self.width.to_string())
+            .replace("{height}", &self.height.to_string())
+    }
+
+    /// Emit the summary as a single structured log line.
+    pub fn log(&self) {
+        tracing::info!(
+            backend = self.backend,
+            device = %self.device,
+            output = self.output.as_deref().unwrap_or("-"),
+            width = self.width,
+            height = self.height,
+            format = ?self.format,
+            modifier = ?self.modifier,
+            fb_query = self.fb_query.unwrap_or("-"),
+            mapping = self.mapping,
+            incremental = self.incremental,
+            "Capture ready"
+        );
+    }
+}
+
+impl fmt::Display for CaptureInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "backend:     {}", self.backend)?;
+        writeln!(f, "device:      {}", self.device)?;
+        if let Some(ref output) = self.output {
+            writeln!(f, "output:      {output}")?;
+        }
+        writeln!(f, "resolution:  {}x{}", self.width, self.height)?;
+        writeln!(f, "format:      {:?}", self.format)?;
+        if let Some(modifier) = self.modifier {
+            writeln!(f, "modifier:    {modifier:?}")?;
+        }
+        if let Some(fb_query) = self.fb_query {
+            writeln!(f, "fb query:    {fb_query}")?;
+        }
+        writeln!(f, "mapping:     {}", self.mapping)?;
+        write!(
+            f,
+            "incremental: {}",
+            if self.incremental { "yes" } else { "no" }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn desktop_name_expands_tokens() {
+        let info = CaptureInfo {
+            backend: "drm",
+            device: "/dev/dri/card0".to_string(),
+            output: Some("HDMI-A-1".to_string()),
+            width: 1920,
+            height: 1080,
+            format: DrmFourcc::Xrgb8888,
+            modifier: None,
+            fb_query: Some("GET_FB2"),
+            mapping: "prime",
+            incremental: true,
+        };
+        assert_eq!(
+            info.desktop_name("{hostname} {output} ({width}x{height})", "kiosk"),
+            "kiosk HDMI-A-1 (1920x1080)"
+        );
+        assert_eq!(info.desktop_name("kmsvnc", "kiosk"), "kmsvnc");
+    }
+}
@@ -1,4 +1,6 @@
 pub mod capture;
 pub mod card;
+pub mod edid;
 pub mod fbdev;
+pub mod info;
 pub mod pixel_format;
@@ -1,6 +1,24 @@
+use std::hash::{DefaultHasher, Hasher};
+use std::sync::OnceLock;
+
 use drm_fourcc::DrmFourcc;
 
-use crate::frame_diff::{DirtyTiles, TILE_SIZE};
+use crate::frame_diff::{DirtyRect, DirtyTiles};
+
+/// Framebuffer formats `convert_to_bgra_into` can read (`--list-formats`).
+pub const SUPPORTED_FORMATS: &[DrmFourcc] = &[
+    DrmFourcc::Xrgb8888,
+    DrmFourcc::Argb8888,
+    DrmFourcc::Xbgr8888,
+    DrmFourcc::Abgr8888,
+    DrmFourcc::Rgb888,
+    DrmFourcc::Bgr888,
+    DrmFourcc::Rgb565,
+    DrmFourcc::Xrgb2101010,
+    DrmFourcc::Argb2101010,
+    DrmFourcc::Xbgr2101010,
+    DrmFourcc::Abgr2101010,
+];
 
 /// Returns true if format is direct-copy (mmap bytes == BGRA output bytes).
 pub fn is_direct_copy(format: DrmFourcc) -> bool {
@@ -23,17 +41,18 @@ pub fn copy_rows_incremental(
     dirty_tiles: &DirtyTiles,
 ) -> bool {
     let row_bytes = (width * 4) as usize;
-    let tiles_x = width.div_ceil(TILE_SIZE) as usize;
+    let tile_size = dirty_tiles.tile_size();
+    let tiles_x = width.div_ceil(tile_size) as usize;
     let mut any_dirty = false;
 
     for y in 0..height {
         let src_row = (y * pitch) as usize;
         let dst_row = y as usize * row_bytes;
-        let ty = (y / TILE_SIZE) as usize;
+        let ty = (y / tile_size) as usize;
 
         for tx in 0..tiles_x {
-            let x0 = tx * TILE_SIZE as usize * 4;
-            let tw = (TILE_SIZE.min(width - tx as u32 * TILE_SIZE) * 4) as usize;
+            let x0 = tx * tile_size as usize * 4;
+            let tw = (tile_size.min(width - tx as u32 * tile_size) * 4) as usize;
 
             if dst[dst_row + x0..dst_row + x0 + tw]
                 != src[src_row + x0..src_row + x0 + tw]
@@ -48,8 +67,128 @@ pub fn copy_rows_incremental(
     any_dirty
 }
 
+/// Hash `rows` evenly spaced scanlines of `src`, a cheap signature for
+/// noticing that a frame probably hasn't changed without reading all of it.
+pub fn sample_rows_signature(src: &[u8], width: u32, height: u32, pitch: u32, rows: u32) -> u64 {
+    let row_bytes = (width * 4) as usize;
+    let rows = rows.min(height);
+    let mut hasher = DefaultHasher::new();
+    for i in 0..rows {
+        // Centre each sample in its band so the first and last rows (often
+        // static panels) don't dominate
+        let y = (2 * i + 1) * height / (2 * rows);
+        let off = (y * pitch) as usize;
+        hasher.write(&src[off..off + row_bytes]);
+    }
+    hasher.finish()
+}
+
+/// Bytes per pixel of a supported framebuffer format.
+pub fn bytes_per_pixel(format: DrmFourcc) -> u32 {
+    match format {
+        DrmFourcc::Rgb565 => 2,
+        DrmFourcc::Rgb888 | DrmFourcc::Bgr888 => 3,
+        _ => 4,
+    }
+}
+
+/// Where and how a plane is placed on the CRTC, for `blend_plane`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlaneGeometry {
+    /// Top-left corner on the CRTC; may be off screen.
+    pub x: i32,
+    pub y: i32,
+    /// Size on the CRTC; the source is scaled to it.
+    pub width: u32,
+    pub height: u32,
+    /// Plane-wide opacity, 0..=0xffff.
+    pub alpha: u16,
+}
+
+/// Blend a BGRA plane of `src_width` x `src_height` onto the BGRA `frame`
+/// (`width` x `height`) as KMS does by default: the source is premultiplied,
+/// so `out = plane_alpha * src + (1 - plane_alpha * src_alpha) * dst`.
+/// Scaling is nearest-neighbour. Without `per_pixel_alpha` the source's
+/// fourth byte is padding and every pixel is opaque.
+///
+/// Returns the part of the frame that was drawn to, if any.
+#[allow(clippy::too_many_arguments)]
+pub fn blend_plane(
+    frame: &mut [u8],
+    width: u32,
+    height: u32,
+    src: &[u8],
+    src_width: u32,
+    src_height: u32,
+    geometry: &PlaneGeometry,
+    per_pixel_alpha: bool,
+) -> Option<DirtyRect> {
+    let x0 = geometry.x.clamp(0, width as i32) as u32;
+    let y0 = geometry.y.clamp(0, height as i32) as u32;
+    let x1 = (geometry.x as i64 + geometry.width as i64).clamp(0, width as i64) as u32;
+    let y1 = (geometry.y as i64 + geometry.height as i64).clamp(0, height as i64) as u32;
+    if x0 >= x1 || y0 >= y1 || src_width == 0 || src_height == 0 {
+        return None;
+    }
+
+    // Source pixel for a frame coordinate along one axis
+    let scale = |pos: u32, origin: i32, size: u32, src_size: u32| {
+        ((pos as i64 - origin as i64) * src_size as i64 / size as i64) as u32
+    };
+    let plane_alpha = (geometry.alpha >> 8) as u32;
+    for y in y0..y1 {
+        let sy = scale(y, geometry.y, geometry.height, src_height);
+        let src_row = (sy * src_width) as usize * 4;
+        let dst_row = (y * width) as usize * 4;
+        for x in x0..x1 {
+            let sx = scale(x, geometry.x, geometry.width, src_width);
+            let s = &src[src_row + sx as usize * 4..][..4];
+            let d = &mut frame[dst_row + x as usize * 4..][..4];
+            let src_alpha = if per_pixel_alpha { s[3] as u32 } else { 255 };
+            let keep = 255 * 255 - plane_alpha * src_alpha;
+            for c in 0..3 {
+                let v = (plane_alpha * s[c] as u32 * 255 + keep * d[c] as u32) / (255 * 255);
+                d[c] = v.min(255) as u8;
+            }
+        }
+    }
+
+    Some(DirtyRect {
+        x: x0 as u16,
+        y: y0 as u16,
+        width: (x1 - x0) as u16,
+        height: (y1 - y0) as u16,
+    })
+}
+
+/// Scale a BGRA image of `src_width` x `src_height` to `width` x `height`
+/// into `dst` (cleared and resized as needed), nearest-neighbour.
+pub fn scale_into(
+    dst: &mut Vec<u8>,
+    src: &[u8],
+    src_width: u32,
+    src_height: u32,
+    width: u32,
+    height: u32,
+) {
+    dst.clear();
+    dst.reserve(width as usize * height as usize * 4);
+    for y in 0..height as u64 {
+        let sy = (y * src_height as u64 / height as u64) as usize;
+        let src_row = &src[sy * src_width as usize * 4..][..src_width as usize * 4];
+        for x in 0..width as u64 {
+            let sx = (x * src_width as u64 / width as u64) as usize;
+            dst.extend_from_slice(&src_row[sx * 4..][..4]);
+        }
+    }
+}
+
 /// Convert raw framebuffer pixels to BGRA8888 format into a caller-provided buffer.
 /// The buffer is cleared and resized as needed.
+///
+/// Converted pixels are opaque unless `preserve_alpha` is set, in which case
+/// formats with an alpha channel keep it (VNC itself has none). Direct-copy
+/// formats pass their fourth byte through either way.
 pub fn convert_to_bgra_into(
     dst: &mut Vec<u8>,
     src: &[u8],
@@ -57,17 +196,70 @@ pub fn convert_to_bgra_into(
     height: u32,
     pitch: u32,
     format: DrmFourcc,
+    preserve_alpha: bool,
 ) -> Result<(), String> {
     match format {
         DrmFourcc::Xrgb8888 | DrmFourcc::Argb8888 => copy_rows_into(dst, src, width, height, pitch),
-        DrmFourcc::Xbgr8888 => convert_xbgr8888_into(dst, src, width, height, pitch),
-        DrmFourcc::Abgr8888 => convert_abgr8888_into(dst, src, width, height, pitch),
+        DrmFourcc::Xbgr8888 => convert_abgr8888_into(dst, src, width, height, pitch, false),
+        DrmFourcc::Abgr8888 => {
+            convert_abgr8888_into(dst, src, width, height, pitch, preserve_alpha)
+        }
         DrmFourcc::Rgb565 => convert_rgb565_into(dst, src, width, height, pitch),
+        DrmFourcc::Rgb888 => convert_888_into(dst, src, width, height, pitch, false),
+        DrmFourcc::Bgr888 => convert_888_into(dst, src, width, height, pitch, true),
+        DrmFourcc::Xrgb2101010 | DrmFourcc::Argb2101010 => {
+            let alpha = preserve_alpha && format == DrmFourcc::Argb2101010;
+            convert_2101010_into(dst, src, width, height, pitch, false, alpha, truncate_10bit)
+        }
+        DrmFourcc::Xbgr2101010 | DrmFourcc::Abgr2101010 => {
+            let alpha = preserve_alpha && format == DrmFourcc::Abgr2101010;
+            convert_2101010_into(dst, src, width, height, pitch, true, alpha, truncate_10bit)
+        }
         other => return Err(format!("Unsupported pixel format: {other:?}")),
     }
     Ok(())
 }
 
+/// Whether `format` has 10 bits per colour channel.
+pub fn is_10bit(format: DrmFourcc) -> bool {
+    matches!(
+        format,
+        DrmFourcc::Xrgb2101010
+            | DrmFourcc::Argb2101010
+            | DrmFourcc::Xbgr2101010
+            | DrmFourcc::Abgr2101010
+    )
+}
+
+/// Like `convert_to_bgra_into` for a 10-bit format, but taking the pixels as
+/// HDR10 (SMPTE ST 2084 "PQ" transfer, BT.2020 primaries) and tone-mapping
+/// them to sRGB rather than truncating the code values.
+pub fn convert_hdr10_to_bgra_into(
+    dst: &mut Vec<u8>,
+    src: &[u8],
+    width: u32,
+    height: u32,
+    pitch: u32,
+    format: DrmFourcc,
+) -> Result<(), String> {
+    let luts = hdr10_luts();
+    let pixel = |rgb| tonemap_hdr10(rgb, luts);
+    match format {
+        DrmFourcc::Xrgb2101010 | DrmFourcc::Argb2101010 => {
+            convert_2101010_into(dst, src, width, height, pitch, false, false, pixel)
+        }
+        DrmFourcc::Xbgr2101010 | DrmFourcc::Abgr2101010 => {
+            convert_2101010_into(dst, src, width, height, pitch, true, false, pixel)
+        }
+        other => {
+            return Err(format!(
+                "Cannot tone-map {other:?}, it is not a 10-bit format"
+            ))
+        }
+    }
+    Ok(())
+}
+
 /// Row-copy for formats whose memory layout matches VNC's BGRX byte order.
 fn copy_rows_into(dst: &mut Vec<u8>, src: &[u8], width: u32, height: u32, pitch: u32) {
     let row_bytes = (width * 4) as usize;
@@ -84,27 +276,16 @@ fn copy_rows_into(dst: &mut Vec<u8>, src: &[u8], width: u32, height: u32, pitch:
     }
 }
 
-/// XBGR8888: memory layout [R, G, B, X] per pixel (little-endian u32 = 0xXXBBGGRR)
-/// Output BGRA: [B, G, R, 0xFF]
-fn convert_xbgr8888_into(dst: &mut Vec<u8>, src: &[u8], width: u32, height: u32, pitch: u32) {
-    let total = (width * height * 4) as usize;
-    dst.clear();
-    dst.reserve(total);
-    for y in 0..height {
-        let row = &src[(y * pitch) as usize..];
-        for x in 0..width as usize {
-            let off = x * 4;
-            dst.push(row[off + 2]); // B
-            dst.push(row[off + 1]); // G
-            dst.push(row[off]);     // R
-            dst.push(0xFF);         // A
-        }
-    }
-}
-
-/// ABGR8888: memory layout [R, G, B, A] per pixel (little-endian u32 = 0xAABBGGRR)
-/// Output BGRA: [B, G, R, 0xFF] (force opaque -- VNC has no alpha channel)
-fn convert_abgr8888_into(dst: &mut Vec<u8>, src: &[u8], width: u32, height: u32, pitch: u32) {
+/// ABGR8888 / XBGR8888: memory layout [R, G, B, A|X] per pixel (little-endian u32 = 0xAABBGGRR)
+/// Output BGRA: [B, G, R, A] with `alpha`, else [B, G, R, 0xFF]
+fn convert_abgr8888_into(
+    dst: &mut Vec<u8>,
+    src: &[u8],
+    width: u32,
+    height: u32,
+    pitch: u32,
+    alpha: bool,
+) {
     let total = (width * height * 4) as usize;
     dst.clear();
     dst.reserve(total);
@@ -112,10 +293,11 @@ fn convert_abgr8888_into(dst: &mut Vec<u8>, src: &[u8], width: u32, height: u32,
         let row = &src[(y * pitch) as usize..];
         for x in 0..width as usize {
             let off = x * 4;
+            let a = if alpha { row[off + 3] } else { 0xFF };
             dst.push(row[off + 2]); // B
             dst.push(row[off + 1]); // G
             dst.push(row[off]);     // R
-            dst.push(0xFF);         // A (force opaque)
+            dst.push(a);            // A
         }
     }
 }
@@ -143,3 +325,273 @@ fn convert_rgb565_into(dst: &mut Vec<u8>, src: &[u8], width: u32, height: u32, p
         }
     }
 }
+
+/// RGB888: memory layout [B, G, R] per pixel (little-endian 24-bit 0xRRGGBB);
+/// BGR888 (`bgr`): [R, G, B]. Packed, no padding between pixels.
+/// Output BGRA: [B, G, R, 0xFF]
+fn convert_888_into(dst: &mut Vec<u8>, src: &[u8], width: u32, height: u32, pitch: u32, bgr: bool) {
+    dst.clear();
+    dst.reserve((width * height * 4) as usize);
+    for y in 0..height {
+        let row = &src[(y * pitch) as usize..][..width as usize * 3];
+        for px in row.chunks_exact(3) {
+            let (b, r) = if bgr { (px[2], px[0]) } else { (px[0], px[2]) };
+            dst.extend_from_slice(&[b, px[1], r, 0xFF]);
+        }
+    }
+}
+
+/// 2101010 formats: little-endian u32 with 10-bit channels at bits 20-29,
+/// 10-19 and 0-9 — red first, or blue first with `bgr`. The top two bits
+/// are padding or alpha, which replaces the A byte with `alpha`. `pixel`
+/// turns the [R, G, B] codes into BGRA.
+#[allow(clippy::too_many_arguments)]
+fn convert_2101010_into(
+    dst: &mut Vec<u8>,
+    src: &[u8],
+    width: u32,
+    height: u32,
+    pitch: u32,
+    bgr: bool,
+    alpha: bool,
+    pixel: impl Fn([u16; 3]) -> [u8; 4],
+) {
+    dst.clear();
+    dst.reserve((width * height * 4) as usize);
+    for y in 0..height {
+        let row = &src[(y * pitch) as usize..][..width as usize * 4];
+        for px in row.chunks_exact(4) {
+            let v = u32::from_le_bytes([px[0], px[1], px[2], px[3]]);
+            let (hi, mid, lo) = (
+                (v >> 20) as u16 & 0x3FF,
+                (v >> 10) as u16 & 0x3FF,
+                v as u16 & 0x3FF,
+            );
+            let rgb = if bgr { [lo, mid, hi] } else { [hi, mid, lo] };
+            let mut out = pixel(rgb);
+            if alpha {
+                out[3] = (v >> 30) as u8 * 0x55;
+            }
+            dst.extend_from_slice(&out);
+        }
+    }
+}
+
+/// BGRA pixel from 10-bit [R, G, B] codes, keeping the top 8 bits.
+fn truncate_10bit([r, g, b]: [u16; 3]) -> [u8; 4] {
+    [(b >> 2) as u8, (g >> 2) as u8, (r >> 2) as u8, 0xFF]
+}
+
+/// Luminance of SDR reference white in HDR10 content (ITU-R BT.2408).
+const HDR_REFERENCE_WHITE_NITS: f32 = 203.0;
+/// Relative luminance up to which the tone curve leaves content untouched;
+/// brighter values are compressed into what is left below 1.0.
+const TONEMAP_KNEE: f32 = 0.8;
+/// Steps of the sRGB encoding table over linear 0.0..=1.0.
+const SRGB_LUT_STEPS: usize = 4096;
+
+/// Lookup tables for `tonemap_hdr10`.
+struct Hdr10Luts {
+    /// PQ code value to linear light relative to reference white.
+    pq_to_linear: Vec<f32>,
+    /// Linear light in `SRGB_LUT_STEPS` steps to sRGB-encoded 8 bits.
+    linear_to_srgb: Vec<u8>,
+}
+
+fn hdr10_luts() -> &'static Hdr10Luts {
+    static LUTS: OnceLock<Hdr10Luts> = OnceLock::new();
+    LUTS.get_or_init(|| {
+        // SMPTE ST 2084 EOTF constants
+        let (m1, m2) = (2610.0 / 16384.0, 2523.0 / 4096.0 * 128.0);
+        let (c1, c2, c3) = (
+            3424.0 / 4096.0,
+            2413.0 / 4096.0 * 32.0,
+            2392.0 / 4096.0 * 32.0,
+        );
+        let pq_to_linear = (0..1024)
+            .map(|code| {
+                let e = (code as f64 / 1023.0).powf(1.0 / m2);
+                let nits = 10000.0 * ((e - c1).max(0.0) / (c2 - c3 * e)).powf(1.0 / m1);
+                (nits / HDR_REFERENCE_WHITE_NITS as f64) as f32
+            })
+            .collect();
+        let linear_to_srgb = (0..=SRGB_LUT_STEPS)
+            .map(|i| {
+                let l = i as f64 / SRGB_LUT_STEPS as f64;
+                let v = if l <= 0.003_130_8 {
+                    12.92 * l
+                } else {
+                    1.055 * l.powf(1.0 / 2.4) - 0.055
+                };
+                (v * 255.0).round() as u8
+            })
+            .collect();
+        Hdr10Luts {
+            pq_to_linear,
+            linear_to_srgb,
+        }
+    })
+}
+
+/// Tone-map one HDR10 pixel to sRGB: decode PQ, convert BT.2020 to BT.709
+/// primaries, then compress highlights above `TONEMAP_KNEE` with a
+/// Reinhard shoulder applied to the brightest channel, keeping the hue.
+fn tonemap_hdr10([r, g, b]: [u16; 3], luts: &Hdr10Luts) -> [u8; 4] {
+    let lin = |code: u16| luts.pq_to_linear[code as usize];
+    let (r, g, b) = (lin(r), lin(g), lin(b));
+    let rgb = [
+        1.6605 * r - 0.5876 * g - 0.0728 * b,
+        -0.1246 * r + 1.1329 * g - 0.0083 * b,
+        -0.0182 * r - 0.1006 * g + 1.1187 * b,
+    ]
+    .map(|c| c.max(0.0));
+
+    let peak = rgb[0].max(rgb[1]).max(rgb[2]);
+    let scale = if peak > TONEMAP_KNEE {
+        let over = (peak - TONEMAP_KNEE) / (1.0 - TONEMAP_KNEE);
+        (TONEMAP_KNEE + (1.0 - TONEMAP_KNEE) * over / (1.0 + over)) / peak
+    } else {
+        1.0
+    };
+    let encode = |c: f32| {
+        let i = ((c * scale).min(1.0) * SRGB_LUT_STEPS as f32).round() as usize;
+        luts.linear_to_srgb[i]
+    };
+    [encode(rgb[2]), encode(rgb[1]), encode(rgb[0]), 0xFF]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_plane_clips_scales_and_blends() {
+        let mut frame = vec![100u8; 4 * 4 * 4];
+        // One premultiplied pixel at half opacity, stretched to 2x4 with its
+        // left column and bottom half off screen
+        let src = [50, 60, 70, 128];
+        let geometry = PlaneGeometry {
+            x: -1,
+            y: 2,
+            width: 2,
+            height: 4,
+            alpha: u16::MAX,
+        };
+        let drawn = blend_plane(&mut frame, 4, 4, &src, 1, 1, &geometry, true);
+        assert_eq!(
+            drawn,
+            Some(DirtyRect {
+                x: 0,
+                y: 2,
+                width: 1,
+                height: 2,
+            })
+        );
+        for y in 0..4 {
+            for x in 0..4 {
+                let px = &frame[(y * 4 + x) * 4..][..3];
+                if x == 0 && y >= 2 {
+                    // src + (1 - 128/255) * dst
+                    assert_eq!(px, [99, 109, 119], "({x}, {y})");
+                } else {
+                    assert_eq!(px, [100, 100, 100], "({x}, {y})");
+                }
+            }
+        }
+
+        // Opaque formats ignore the padding byte
+        let drawn = blend_plane(&mut frame, 4, 4, &[1, 2, 3, 0], 1, 1, &geometry, false);
+        assert!(drawn.is_some());
+        assert_eq!(&frame[2 * 16..][..3], [1, 2, 3]);
+    }
+    #[test]
+    fn scale_into_stretches_and_shrinks() {
+        // 2x1 → 4x2: each pixel doubled both ways
+        let src = [1, 1, 1, 1, 2, 2, 2, 2];
+        let mut dst = Vec::new();
+        scale_into(&mut dst, &src, 2, 1, 4, 2);
+        let row = [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2];
+        assert_eq!(dst, [row, row].concat());
+
+        // And back, keeping every other pixel
+        let wide = dst.clone();
+        scale_into(&mut dst, &wide, 4, 2, 2, 1);
+        assert_eq!(dst, src);
+    }
+    #[test]
+    fn packed_24bit_formats_expand_to_bgra() {
+        // Two pixels on a row padded to 8 bytes; R = 0x11, G = 0x22, B = 0x33
+        let src = [0x33, 0x22, 0x11, 0xAA, 0xBB, 0xCC, 0, 0];
+        let mut dst = Vec::new();
+        convert_to_bgra_into(&mut dst, &src, 2, 1, 8, DrmFourcc::Rgb888, false).unwrap();
+        assert_eq!(dst, [0x33, 0x22, 0x11, 0xFF, 0xAA, 0xBB, 0xCC, 0xFF]);
+        convert_to_bgra_into(&mut dst, &src, 2, 1, 8, DrmFourcc::Bgr888, false).unwrap();
+        assert_eq!(dst, [0x11, 0x22, 0x33, 0xFF, 0xCC, 0xBB, 0xAA, 0xFF]);
+        assert_eq!(bytes_per_pixel(DrmFourcc::Bgr888), 3);
+    }
+
+    #[test]
+    fn alpha_is_opaque_unless_preserved() {
+        let mut dst = Vec::new();
+        // ABGR8888 [R, G, B, A] with A = 0x40
+        let px = [0x10, 0x20, 0x30, 0x40];
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Abgr8888, false).unwrap();
+        assert_eq!(dst, [0x30, 0x20, 0x10, 0xFF]);
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Abgr8888, true).unwrap();
+        assert_eq!(dst, [0x30, 0x20, 0x10, 0x40]);
+        // No alpha channel to preserve in XBGR8888
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Xbgr8888, true).unwrap();
+        assert_eq!(dst, [0x30, 0x20, 0x10, 0xFF]);
+
+        // 2-bit alpha of 1 scales to 0x55
+        let px = (1u32 << 30).to_le_bytes();
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Argb2101010, true).unwrap();
+        assert_eq!(dst, [0, 0, 0, 0x55]);
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Argb2101010, false).unwrap();
+        assert_eq!(dst, [0, 0, 0, 0xFF]);
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Xrgb2101010, true).unwrap();
+        assert_eq!(dst, [0, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn ten_bit_formats_truncate_or_tonemap() {
+        // XRGB2101010 pixel with R = 0x3FF, G = 0x200, B = 0x004
+        let px = (0x3FFu32 << 20 | 0x200 << 10 | 0x004).to_le_bytes();
+        let mut dst = Vec::new();
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Xrgb2101010, false).unwrap();
+        assert_eq!(dst, [0x01, 0x80, 0xFF, 0xFF]);
+        convert_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Xbgr2101010, false).unwrap();
+        assert_eq!(dst, [0xFF, 0x80, 0x01, 0xFF]);
+
+        // Neutral greys stay neutral and get brighter with the code value;
+        // black stays black and only the PQ peak reaches full white
+        let grey = |code: u32| {
+            let px = (code << 20 | code << 10 | code).to_le_bytes();
+            let mut dst = Vec::new();
+            convert_hdr10_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Xrgb2101010).unwrap();
+            assert!(dst[0] == dst[1] && dst[1] == dst[2], "{code}: {dst:?}");
+            dst[0]
+        };
+        assert_eq!(grey(0), 0);
+        // 203 nits, reference white, is in the shoulder
+        let white = grey(594);
+        assert!((230..255).contains(&white), "{white}");
+        assert_eq!(grey(1023), 255);
+        let levels: Vec<u8> = (0..1024).step_by(64).map(grey).collect();
+        assert!(levels.windows(2).all(|w| w[0] <= w[1]), "{levels:?}");
+
+        assert!(convert_hdr10_to_bgra_into(&mut dst, &px, 1, 1, 4, DrmFourcc::Xrgb8888).is_err());
+    }
+
+    #[test]
+    fn every_listed_format_converts() {
+        let src = [0u8; 4];
+        let mut dst = Vec::new();
+        for &format in SUPPORTED_FORMATS {
+            let pitch = bytes_per_pixel(format);
+            convert_to_bgra_into(&mut dst, &src, 1, 1, pitch, format, false)
+                .unwrap_or_else(|e| panic!("{format}: {e}"));
+        }
+        assert!(convert_to_bgra_into(&mut dst, &src, 1, 1, 4, DrmFourcc::Yuyv, false).is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! KMS/DRM-based VNC server, as a library for embedding it in other daemons.
+//!
+//! [`Server`] ties a capture source, the virtual input devices and the VNC
+//! listeners together, the same way the `kmsvnc` binary does:
+//!
+//! ```no_run
+//! use kmsvnc::config::Backend;
+//! use kmsvnc::Server;
+//!
+//! # async fn example() -> anyhow::Result<()> {
+//! Server::new()
+//!     .backend(Backend::Drm)
+//!     .listen("127.0.0.1", 5901)
+//!     .password("secret")
+//!     .run()
+//!     .await
+//! # }
+//! ```
+//!
+//! Frames can come from the embedder instead of a capture device with
+//! [`Server::capture`], and client input can be taken with [`Server::input`].
+
+mod acl;
+mod adjust;
+mod audit;
+pub mod config;
+mod control;
+mod daemon;
+mod frame_diff;
+mod frame_hub;
+mod input;
+mod kms;
+mod placeholder;
+mod png;
+mod record;
+mod server;
+mod test_pattern;
+mod vnc;
+mod zlib;
+mod zstd;
+
+pub use acl::Cidr;
+pub use daemon::{daemonize, Daemon, PidFile};
+pub use frame_diff::{DirtyRect, DirtyTiles};
+pub use kms::edid::MonitorId;
+pub use server::{CaptureFn, Server};
+pub use vnc::server::InputEvent;
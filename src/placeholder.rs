@@ -0,0 +1,106 @@
+use crate::frame_diff::DirtyTiles;
+
+/// Resolution of the placeholder frame.
+pub const WIDTH: u32 = 1024;
+pub const HEIGHT: u32 = 768;
+
+const MESSAGE: &str = "WAITING FOR DISPLAY";
+const BACKGROUND: [u8; 4] = [0x20, 0x20, 0x20, 0xFF];
+const TEXT_COLOUR: [u8; 4] = [0xC0, 0xC0, 0xC0, 0xFF];
+/// Size of a font pixel on screen.
+const SCALE: u32 = 4;
+/// Glyph cell, including one column of spacing.
+const GLYPH_WIDTH: u32 = 6;
+const GLYPH_HEIGHT: u32 = 7;
+
+/// Frame served by `--wait-for-display` while no capture device is usable:
+/// `MESSAGE` in the middle of a dark screen.
+pub struct Placeholder {
+    frame: Vec<u8>,
+}
+
+impl Placeholder {
+    pub fn new(width: u32, height: u32) -> Self {
+        let mut frame = BACKGROUND.repeat(width as usize * height as usize);
+        let text_width = MESSAGE.len() as u32 * GLYPH_WIDTH * SCALE;
+        let left = width.saturating_sub(text_width) / 2;
+        let top = height.saturating_sub(GLYPH_HEIGHT * SCALE) / 2;
+        for (i, c) in MESSAGE.chars().enumerate() {
+            let glyph_left = left + i as u32 * GLYPH_WIDTH * SCALE;
+            for (row, bits) in glyph(c).iter().enumerate() {
+                for col in 0..5 {
+                    if bits & (0x10 >> col) == 0 {
+                        continue;
+                    }
+                    for y in 0..SCALE {
+                        for x in 0..SCALE {
+                            let px = glyph_left + col * SCALE + x;
+                            let py = top + row as u32 * SCALE + y;
+                            if px < width && py < height {
+                                let off = ((py * width + px) * 4) as usize;
+                                frame[off..off + 4].copy_from_slice(&TEXT_COLOUR);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        Self { frame }
+    }
+
+    /// Write the frame into `dst`. It never changes, so a buffer that
+    /// already holds it is left alone. Returns whether `dst` changed.
+    pub fn next_frame(&self, dst: &mut Vec<u8>, dirty_tiles: Option<&DirtyTiles>) -> bool {
+        if *dst == self.frame {
+            return false;
+        }
+        dst.clear();
+        dst.extend_from_slice(&self.frame);
+        if let Some(dt) = dirty_tiles {
+            dt.set_all();
+        }
+        true
+    }
+}
+
+/// 5x7 bitmap of an upper-case letter of `MESSAGE`, one row per byte with
+/// the leftmost pixel in bit 4. Anything else is blank.
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'D' => [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'N' => [0x11, 0x19, 0x15, 0x13, 0x11, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'Y' => [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04],
+        _ => [0; 7],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_is_drawn_once() {
+        let placeholder = Placeholder::new(WIDTH, HEIGHT);
+        let tiles = DirtyTiles::new(WIDTH, HEIGHT, 64);
+        let mut frame = Vec::new();
+        assert!(placeholder.next_frame(&mut frame, Some(&tiles)));
+        assert_eq!(frame.len(), (WIDTH * HEIGHT * 4) as usize);
+        assert_eq!(tiles.mask_to_rects(&tiles.drain()), tiles.all_rects());
+        // The message is there, and nothing happens until something else
+        // overwrites the buffer
+        assert!(frame.chunks_exact(4).any(|p| p == TEXT_COLOUR));
+        assert!(!placeholder.next_frame(&mut frame, Some(&tiles)));
+        assert!(tiles.mask_to_rects(&tiles.drain()).is_empty());
+    }
+}
@@ -0,0 +1,55 @@
+use crate::zlib::ZlibStream;
+
+/// Encode a BGRA frame as an 8-bit RGBA PNG.
+pub fn encode_bgra(width: u32, height: u32, bgra: &[u8]) -> Vec<u8> {
+    let row_bytes = width as usize * 4;
+
+    // Scanlines: filter type 0 (None) + RGBA pixels
+    let mut raw = Vec::with_capacity((row_bytes + 1) * height as usize);
+    for row in bgra.chunks_exact(row_bytes).take(height as usize) {
+        raw.push(0);
+        for px in row.chunks_exact(4) {
+            raw.extend_from_slice(&[px[2], px[1], px[0], 0xFF]);
+        }
+    }
+    let mut idat = Vec::new();
+    let mut z = ZlibStream::new();
+    z.compress(&raw, &mut idat);
+    z.finish(&mut idat);
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]); // 8-bit, RGBA, deflate, no filter, no interlace
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &idat);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-32 (ISO 3309 polynomial, as used by PNG).
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
@@ -0,0 +1,121 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+use crate::frame_hub::FrameHub;
+
+/// First bytes of a raw recording.
+const RAW_MAGIC: &[u8; 8] = b"KMSVNCR1";
+
+/// Destination of `--record`: a raw `.bgra` file, or an `ffmpeg` child
+/// encoding into whatever format the path's extension asks for.
+pub enum Recorder {
+    Raw(BufWriter<File>),
+    Ffmpeg { child: Child, stdin: ChildStdin },
+}
+
+impl Recorder {
+    /// Start a recording of `width` x `height` frames at `fps` to `path`.
+    pub fn create(path: &str, width: u32, height: u32, fps: u32) -> Result<Self> {
+        if path.ends_with(".bgra") {
+            let file = File::create(path).with_context(|| format!("Cannot create {path}"))?;
+            let mut out = BufWriter::with_capacity(1 << 20, file);
+            write_raw_header(&mut out, width, height, fps)
+                .with_context(|| format!("write {path}"))?;
+            return Ok(Self::Raw(out));
+        }
+        let mut child = Command::new("ffmpeg")
+            .args(["-loglevel", "error", "-y", "-f", "rawvideo"])
+            .args(["-pixel_format", "bgra"])
+            .args(["-video_size", &format!("{width}x{height}")])
+            .args(["-framerate", &fps.to_string()])
+            .args(["-i", "-", path])
+            .stdin(Stdio::piped())
+            .spawn()
+            .context("Cannot start ffmpeg for --record (use a .bgra path to record raw)")?;
+        let stdin = child.stdin.take().expect("stdin is piped");
+        Ok(Self::Ffmpeg { child, stdin })
+    }
+
+    fn write_frame(&mut self, bgra: &[u8]) -> std::io::Result<()> {
+        match self {
+            Self::Raw(out) => out.write_all(bgra),
+            Self::Ffmpeg { stdin, .. } => stdin.write_all(bgra),
+        }
+    }
+
+    /// Flush a raw file, or close ffmpeg's input and wait for it to finish
+    /// the file.
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Raw(mut out) => out.flush().context("flush recording"),
+            Self::Ffmpeg { mut child, stdin } => {
+                drop(stdin);
+                let status = child.wait().context("wait for ffmpeg")?;
+                anyhow::ensure!(status.success(), "ffmpeg failed: {status}");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Header of a raw recording: magic, then width, height and frame rate as
+/// little-endian u32. Frames of width * height * 4 BGRA bytes follow.
+fn write_raw_header(
+    out: &mut impl Write,
+    width: u32,
+    height: u32,
+    fps: u32,
+) -> std::io::Result<()> {
+    out.write_all(RAW_MAGIC)?;
+    for value in [width, height, fps] {
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// Write the hub's current frame to `recorder` `fps` times a second until
+/// `shutdown` is set, asking for a capture before each one. An unchanged
+/// screen repeats the last frame, so the recording plays at a constant rate.
+pub fn record_loop(
+    mut recorder: Recorder,
+    hub: &FrameHub,
+    capture_req_tx: std_mpsc::Sender<()>,
+    shutdown: &AtomicBool,
+    fps: u32,
+) {
+    let interval = Duration::from_secs(1) / fps.max(1);
+    let mut next = Instant::now();
+    while !shutdown.load(Ordering::Relaxed) {
+        let _ = capture_req_tx.send(());
+        next += interval;
+        std::thread::sleep(next.saturating_duration_since(Instant::now()));
+        if let Err(e) = recorder.write_frame(&hub.current().data) {
+            tracing::error!("Recording stopped: {e}");
+            break;
+        }
+    }
+    if let Err(e) = recorder.finish() {
+        tracing::warn!("Recording may be incomplete: {e:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_recording_starts_with_its_geometry() {
+        let mut out = Vec::new();
+        write_raw_header(&mut out, 1920, 1080, 30).unwrap();
+        assert_eq!(&out[..8], RAW_MAGIC);
+        assert_eq!(&out[8..12], &1920u32.to_le_bytes());
+        assert_eq!(&out[12..16], &1080u32.to_le_bytes());
+        assert_eq!(&out[16..], &30u32.to_le_bytes());
+    }
+}
@@ -0,0 +1,1773 @@
+use std::fs;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc as std_mpsc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use drm_fourcc::DrmFourcc;
+use input_linux::InputId;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+
+use crate::adjust::Lut;
+use crate::audit::{AuditLog, AuditSession};
+use crate::config::{Backend, CapturePolicy, Config, InputLog, KeyboardMode, SecurityType};
+use crate::control::{Clients, Control};
+use crate::frame_diff::DirtyTiles;
+use crate::frame_hub::{Frame, FrameHub};
+use crate::input;
+use crate::input::recovery::Recovering;
+use crate::kms::capture::{self, ProbeOptions};
+use crate::kms::card::Card;
+use crate::kms::edid::MonitorId;
+use crate::kms::fbdev::FbdevCapture;
+use crate::kms::info::CaptureInfo;
+use crate::kms::pixel_format;
+use crate::placeholder;
+use crate::png;
+use crate::record::{self, Recorder};
+use crate::test_pattern;
+use crate::vnc;
+use crate::vnc::repeater::RepeaterId;
+use crate::vnc::server::{self, FullRefreshThreshold, InputEvent};
+
+/// A kmsvnc server, configured with the builder methods and started with
+/// [`Server::run`] or [`Server::run_until`].
+///
+/// Unset options take the command-line defaults: auto-detected capture
+/// device, port 5900 on all interfaces, no password.
+pub struct Server {
+    config: Config,
+    /// Custom capture source: width, height and capture function.
+    source: Option<(u32, u32, CaptureFn)>,
+    /// Receives client input instead of the uinput devices.
+    input_sink: Option<mpsc::Sender<InputEvent>>,
+    /// Told once the server is up.
+    ready: Option<oneshot::Sender<()>>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    /// Server with the command-line defaults.
+    pub fn new() -> Self {
+        Self::from_config(Config::parse_from(["kmsvnc"]))
+    }
+
+    /// Server with every option taken from `config`, e.g. parsed command-line
+    /// arguments.
+    pub fn from_config(config: Config) -> Self {
+        Self {
+            config,
+            source: None,
+            input_sink: None,
+            ready: None,
+        }
+    }
+
+    /// Capture backend to use; see `--backend`.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    /// DRM or fbdev device to capture from instead of auto-detecting one.
+    pub fn device(mut self, path: impl Into<String>) -> Self {
+        self.config.device = Some(path.into());
+        self
+    }
+
+    /// Capture frames with `capture_fn` instead of a capture device. Frames
+    /// are `width` x `height` BGRA; the first one is taken by `run`.
+    pub fn capture(mut self, width: u32, height: u32, capture_fn: CaptureFn) -> Self {
+        self.source = Some((width, height, capture_fn));
+        self
+    }
+
+    /// Address to listen on, replacing the default of all interfaces.
+    pub fn listen(mut self, host: impl Into<String>, port: u16) -> Self {
+        self.config.listen = vec![host.into()];
+        self.config.port = port;
+        self
+    }
+
+    /// Require VNC Authentication (and allow ARD) with `password`.
+    pub fn password(mut self, password: impl Into<String>) -> Self {
+        self.config.password = Some(password.into());
+        self
+    }
+
+    /// Security types to offer, in order of preference; see `--security-types`.
+    pub fn security_types(mut self, types: &[SecurityType]) -> Self {
+        self.config.security_types = types.to_vec();
+        self
+    }
+
+    /// Send client input events to `sink` instead of creating virtual
+    /// keyboard and touchscreen devices.
+    pub fn input(mut self, sink: mpsc::Sender<InputEvent>) -> Self {
+        self.input_sink = Some(sink);
+        self
+    }
+
+    /// Send on `ready` once the capture source is set up and every listener
+    /// is bound, e.g. to report a successful start.
+    pub fn on_ready(mut self, ready: oneshot::Sender<()>) -> Self {
+        self.ready = Some(ready);
+        self
+    }
+
+    /// Serve clients until Ctrl+C or SIGTERM (how init scripts stop a
+    /// `--daemonize`d server). SIGUSR1 rings the bell on every client.
+    pub async fn run(self) -> Result<()> {
+        let mut term = signal(SignalKind::terminate()).context("install SIGTERM handler")?;
+        let stop = async move {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = term.recv() => {}
+            }
+            tracing::info!("Shutting down...");
+        };
+        serve(self, stop, true).await
+    }
+
+    /// Serve clients until `until` completes. No signal handlers are
+    /// installed.
+    pub async fn run_until(self, until: impl Future<Output = ()>) -> Result<()> {
+        serve(self, until, false).await
+    }
+}
+
+/// A boxed capture function: writes one BGRA frame into the provided buffer.
+/// Returns `true` if a new frame was captured, `false` if unchanged.
+/// The first argument asks for a capture even if nothing seems to have changed.
+///
+/// `dirty_tiles` is provided for incremental tile-level capture: the buffer
+/// may hold any earlier frame, and every tile that now differs from it must
+/// be marked (`DirtyTiles::set_all` when in doubt).
+pub type CaptureFn = Box<dyn FnMut(bool, &mut Vec<u8>, Option<&DirtyTiles>) -> Result<bool> + Send>;
+
+/// Try to set up DRM capture for a specific card path.
+fn try_drm_capture(
+    path: &str,
+    opts: &ProbeOptions,
+    monitor: Option<&MonitorId>,
+    sample_rows: u32,
+    tonemap: bool,
+    min_interval: Duration,
+    retries: u32,
+) -> Result<(CaptureInfo, Vec<u8>, CaptureFn)> {
+    let (card, outputs) = capture::open_card_path(path, opts)?;
+    let output = select_output(&outputs, monitor);
+    start_drm_capture(card, output, sample_rows, tonemap, min_interval, retries)
+}
+
+/// The output whose monitor is `monitor`, or else the first one.
+fn select_output<'a>(
+    outputs: &'a [capture::ActiveOutput],
+    monitor: Option<&MonitorId>,
+) -> &'a capture::ActiveOutput {
+    let Some(monitor) = monitor else {
+        return &outputs[0];
+    };
+    if let Some(output) = outputs
+        .iter()
+        .find(|o| o.monitor.as_ref().is_some_and(|edid| monitor.matches(edid)))
+    {
+        return output;
+    }
+    let found: Vec<String> = outputs
+        .iter()
+        .map(|o| match o.monitor {
+            Some(ref edid) => format!("{}: {edid}", o.connector_name),
+            None => format!("{}: no EDID", o.connector_name),
+        })
+        .collect();
+    tracing::warn!(
+        "No output has monitor {monitor} (found {}), using {}",
+        found.join(", "),
+        outputs[0].connector_name
+    );
+    &outputs[0]
+}
+
+/// Start capturing from a DRM output, taking the first frame.
+fn start_drm_capture(
+    card: Card,
+    output: &capture::ActiveOutput,
+    sample_rows: u32,
+    tonemap: bool,
+    min_interval: Duration,
+    retries: u32,
+) -> Result<(CaptureInfo, Vec<u8>, CaptureFn)> {
+    let monitor = output
+        .monitor
+        .as_ref()
+        .map(|edid| format!(", monitor {edid}"))
+        .unwrap_or_default();
+    tracing::info!(
+        "Output: {} ({}x{}){monitor}",
+        output.connector_name,
+        output.width,
+        output.height
+    );
+    let mut capturer = capture::Capturer::new(card, output)?;
+    capturer.set_sample_rows(sample_rows);
+    capturer.set_tonemap(tonemap);
+    capturer.set_min_interval(min_interval);
+    capturer.set_read_retries(retries);
+    let initial_data = capturer
+        .capture(true)?
+        .expect("first capture must produce a frame");
+    let info = capturer.info();
+    let capture_fn: CaptureFn = Box::new(move |force, dst, dt| {
+        let result = capturer.capture_into(dst, force, dt);
+        if result.is_err() {
+            // Remap the scanout buffer on the next attempt
+            capturer.flush_cache();
+        }
+        result
+    });
+    Ok((info, initial_data, capture_fn))
+}
+
+/// Try to set up fbdev capture for a specific device path.
+fn try_fbdev_capture(path: &str) -> Result<(CaptureInfo, Vec<u8>, CaptureFn)> {
+    let mut fbdev = FbdevCapture::open(path)?;
+    let info = CaptureInfo {
+        backend: "fbdev",
+        device: path.to_string(),
+        output: None,
+        width: fbdev.width(),
+        height: fbdev.height(),
+        format: fbdev.format(),
+        modifier: None,
+        fb_query: None,
+        mapping: "fbdev",
+        incremental: pixel_format::is_direct_copy(fbdev.format()),
+    };
+    let initial_data = fbdev.capture_frame()?;
+    let capture_fn: CaptureFn = Box::new(move |_force, dst, dt| fbdev.capture_into(dst, dt));
+    Ok((info, initial_data, capture_fn))
+}
+
+/// Set up the synthetic test pattern source.
+fn start_test_pattern() -> (CaptureInfo, Vec<u8>, CaptureFn) {
+    let (width, height) = (test_pattern::WIDTH, test_pattern::HEIGHT);
+    let info = CaptureInfo {
+        backend: "test-pattern",
+        device: "-".to_string(),
+        output: None,
+        width,
+        height,
+        format: DrmFourcc::Xrgb8888,
+        modifier: None,
+        fb_query: None,
+        mapping: "synthetic",
+        incremental: true,
+    };
+    let mut pattern = test_pattern::TestPattern::new(width, height);
+    let mut initial_data = Vec::new();
+    pattern.next_frame(&mut initial_data, None);
+    let capture_fn: CaptureFn = Box::new(move |_force, dst, dt| Ok(pattern.next_frame(dst, dt)));
+    (info, initial_data, capture_fn)
+}
+
+/// Set up the `--wait-for-display` placeholder source.
+fn start_placeholder() -> (CaptureInfo, Vec<u8>, CaptureFn) {
+    let (width, height) = (placeholder::WIDTH, placeholder::HEIGHT);
+    let info = CaptureInfo {
+        backend: "placeholder",
+        device: "-".to_string(),
+        output: None,
+        width,
+        height,
+        format: DrmFourcc::Xrgb8888,
+        modifier: None,
+        fb_query: None,
+        mapping: "synthetic",
+        incremental: true,
+    };
+    let placeholder = placeholder::Placeholder::new(width, height);
+    let mut initial_data = Vec::new();
+    placeholder.next_frame(&mut initial_data, None);
+    let capture_fn: CaptureFn =
+        Box::new(move |_force, dst, dt| Ok(placeholder.next_frame(dst, dt)));
+    (info, initial_data, capture_fn)
+}
+
+/// Set up a capture function given to [`Server::capture`], taking the first
+/// frame.
+fn start_custom_capture(
+    width: u32,
+    height: u32,
+    mut capture_fn: CaptureFn,
+) -> Result<(CaptureInfo, Vec<u8>, CaptureFn)> {
+    let info = CaptureInfo {
+        backend: "custom",
+        device: "-".to_string(),
+        output: None,
+        width,
+        height,
+        format: DrmFourcc::Xrgb8888,
+        modifier: None,
+        fb_query: None,
+        mapping: "custom",
+        incremental: true,
+    };
+    let mut initial_data = Vec::new();
+    capture_fn(true, &mut initial_data, None).context("capture the first frame")?;
+    if initial_data.len() != width as usize * height as usize * 4 {
+        bail!(
+            "Custom capture returned {} bytes for a {width}x{height} frame",
+            initial_data.len()
+        );
+    }
+    Ok((info, initial_data, capture_fn))
+}
+
+/// Set up capture with fallback chain: DRM (PRIME/dumb) -> fbdev.
+/// `--backend` restricts the chain to one backend, with no fallback.
+fn setup_capture(config: &Config) -> Result<(CaptureInfo, Vec<u8>, CaptureFn)> {
+    if config.backend == Backend::TestPattern {
+        return Ok(start_test_pattern());
+    }
+
+    let opts = ProbeOptions {
+        allow_disconnected: config.allow_disconnected,
+        force_crtc: config.force_crtc,
+        keep_master: config.keep_master,
+    };
+    let monitor = config.monitor.as_ref();
+    // By default reads run at most at twice --fps, so polled captures
+    // arriving a little early are not skipped
+    let min_interval = match config.min_capture_interval {
+        Some(ms) => Duration::from_millis(ms),
+        None => Duration::from_secs(1) / config.fps.max(1) / 2,
+    };
+    let try_drm = |path: &str| {
+        let (sample_rows, tonemap) = (config.sample_rows, config.tonemap);
+        let retries = config.capture_retries;
+        try_drm_capture(
+            path,
+            &opts,
+            monitor,
+            sample_rows,
+            tonemap,
+            min_interval,
+            retries,
+        )
+    };
+
+    if let Some(ref path) = config.device {
+        match config.backend {
+            Backend::Drm => {
+                return try_drm(path).with_context(|| format!("Cannot use {path} as DRM device"));
+            }
+            Backend::Fbdev => {
+                return try_fbdev_capture(path)
+                    .with_context(|| format!("Cannot use {path} as fbdev device"));
+            }
+            Backend::Auto | Backend::TestPattern => {}
+        }
+        // User specified a device — try as DRM first, then as fbdev
+        match try_drm(path) {
+            Ok(result) => return Ok(result),
+            Err(drm_err) => {
+                tracing::debug!("DRM capture failed for {path}: {drm_err}");
+                match try_fbdev_capture(path) {
+                    Ok(result) => return Ok(result),
+                    Err(fb_err) => {
+                        bail!("Cannot use {path} as DRM ({drm_err:#}) or fbdev ({fb_err:#})");
+                    }
+                }
+            }
+        }
+    }
+
+    // Auto-detect: try all DRM cards first. A tiled scanout buffer or one in
+    // an unsupported format is worth reporting even when fbdev takes over,
+    // and is the error if it can't.
+    let mut scanout_err = None;
+    if config.backend != Backend::Fbdev {
+        match capture::open_card(&opts) {
+            Ok((card, outputs)) => {
+                let output = select_output(&outputs, monitor);
+                let started = start_drm_capture(
+                    card,
+                    output,
+                    config.sample_rows,
+                    config.tonemap,
+                    min_interval,
+                    config.capture_retries,
+                );
+                match started {
+                    Err(e)
+                        if config.backend == Backend::Auto
+                            && (capture::tiled_framebuffer(&e).is_some()
+                                || capture::unsupported_format(&e).is_some()) =>
+                    {
+                        tracing::warn!("DRM capture unavailable, trying fbdev: {e:#}");
+                        scanout_err = Some(e);
+                    }
+                    result => return result,
+                }
+            }
+            Err(drm_err) if config.backend == Backend::Drm => {
+                return Err(drm_err.context("DRM backend forced with --backend drm"));
+            }
+            Err(drm_err) => {
+                tracing::debug!("DRM auto-detect failed: {drm_err}");
+            }
+        }
+    }
+
+    // Fall back to fbdev
+    let mut fb_entries: Vec<_> = fs::read_dir("/dev")
+        .ok()
+        .into_iter()
+        .flatten()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.starts_with("fb")))
+        .collect();
+    fb_entries.sort_by_key(|e| e.file_name());
+
+    for entry in &fb_entries {
+        let path = entry.path();
+        let path_str = path.to_string_lossy();
+        match try_fbdev_capture(&path_str) {
+            Ok(result) => return Ok(result),
+            Err(e) => {
+                tracing::debug!("fbdev {path_str} failed: {e}");
+            }
+        }
+    }
+
+    let exe = std::env::current_exe()
+        .map(|p| p.display().to_string())
+        .unwrap_or_else(|_| "<binary>".into());
+    if config.backend == Backend::Fbdev {
+        bail!("No usable fbdev device found (--backend fbdev). Tried all /dev/fb*");
+    }
+    if let Some(e) = scanout_err {
+        return Err(e.context("No usable capture device found (no fbdev fallback either)"));
+    }
+    bail!(
+        "No usable capture device found. Tried all /dev/dri/card* (DRM) \
+         and /dev/fb* (fbdev). Ensure a display is active and the process \
+         has CAP_SYS_ADMIN (try: sudo setcap cap_sys_admin+ep {exe})"
+    )
+}
+
+/// Run the server until `until` completes. The body of both [`Server::run`]
+/// and [`Server::run_until`]; `bell_on_usr1` installs the SIGUSR1 handler.
+async fn serve(server: Server, until: impl Future<Output = ()>, bell_on_usr1: bool) -> Result<()> {
+    let Server {
+        config,
+        source,
+        input_sink,
+        mut ready,
+    } = server;
+    let mut until = std::pin::pin!(until);
+
+    if config.list_formats {
+        for format in pixel_format::SUPPORTED_FORMATS {
+            println!("{format}");
+        }
+        return Ok(());
+    }
+
+    let input_enabled = !config.view_only && !config.no_input;
+
+    // Load the keymap before touching any device so a bad file fails fast
+    let keymap = match config.keymap {
+        Some(ref path) if input_enabled => input::keymap::Keymap::load(path)?,
+        _ => input::keymap::Keymap::default(),
+    };
+    // Likewise an unusable --security-types / --password combination
+    let security = Arc::new(server::Security::new(
+        config.password.clone(),
+        &config.security_types,
+    )?);
+    // And an audit log that can't be written
+    let audit_log = match config.audit_log {
+        Some(ref path) => Some(Arc::new(AuditLog::open(path)?)),
+        None => None,
+    };
+
+    let custom_source = source.is_some();
+    let mut capture = match source {
+        Some((width, height, capture_fn)) => Some(start_custom_capture(width, height, capture_fn)?),
+        None => None,
+    };
+    // Each session serves one capture source; a display turning up behind
+    // the --wait-for-display placeholder starts the next
+    loop {
+        let session = Session {
+            config: &config,
+            custom_source,
+            input_sink: input_sink.clone(),
+            keymap: keymap.clone(),
+            security: security.clone(),
+            audit_log: audit_log.clone(),
+            bell_on_usr1,
+            ready: &mut ready,
+        };
+        match serve_session(session, capture.take(), until.as_mut()).await? {
+            Some(display) => capture = Some(display),
+            None => return Ok(()),
+        }
+    }
+}
+
+/// What every session of a [`serve`] call shares.
+struct Session<'a> {
+    config: &'a Config,
+    custom_source: bool,
+    input_sink: Option<mpsc::Sender<InputEvent>>,
+    keymap: input::keymap::Keymap,
+    security: Arc<server::Security>,
+    audit_log: Option<Arc<AuditLog>>,
+    bell_on_usr1: bool,
+    /// Told when the first session is serving.
+    ready: &'a mut Option<oneshot::Sender<()>>,
+}
+
+/// How often `--wait-for-display` probes for a capture device.
+const DISPLAY_PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Serve clients from `capture`, or else from a freshly set up capture
+/// device, until `until` completes. Returns the capture of a display that
+/// appeared while the placeholder was served; clients have been told to
+/// reconnect and the caller serves it next.
+async fn serve_session(
+    session: Session<'_>,
+    capture: Option<(CaptureInfo, Vec<u8>, CaptureFn)>,
+    mut until: std::pin::Pin<&mut impl Future<Output = ()>>,
+) -> Result<Option<(CaptureInfo, Vec<u8>, CaptureFn)>> {
+    let Session {
+        config,
+        custom_source,
+        input_sink,
+        keymap,
+        security,
+        audit_log,
+        bell_on_usr1,
+        ready,
+    } = session;
+    let input_enabled = !config.view_only && !config.no_input;
+
+    let one_shot = config.print_capture_info || config.screenshot.is_some();
+    let mut display_probe = None;
+    let (capture_info, mut initial_data, mut capture_fn) = match capture {
+        Some(capture) => capture,
+        None => match setup_capture(config) {
+            Err(e) if config.wait_for_display && !one_shot => {
+                tracing::warn!("{e:#}");
+                tracing::info!(
+                    "Serving a placeholder until a display appears (--wait-for-display)"
+                );
+                display_probe = Some(tokio::spawn(probe_display(config.clone())));
+                start_placeholder()
+            }
+            result => result?,
+        },
+    };
+    let (width, height) = (capture_info.width, capture_info.height);
+
+    if config.print_capture_info {
+        println!("{capture_info}");
+        return Ok(None);
+    }
+    capture_info.log();
+
+    // Gamma and brightness are applied once per captured frame, for every
+    // client alike
+    let lut = Lut::new(config.gamma, config.brightness);
+    if let Some(ref lut) = lut {
+        lut.apply_in_place(&mut initial_data);
+    }
+
+    if let Some(ref path) = config.screenshot {
+        write_screenshot(path, width, height, &initial_data)?;
+        return Ok(None);
+    }
+
+    // Dirty tiles set by the capturer, drained once per captured frame
+    let dirty_tiles = Arc::new(DirtyTiles::new(width, height, config.tile_size));
+
+    // Frame hub: latest frame + its shared encoding, fanned out to all clients
+    let hub = Arc::new(FrameHub::new(width, height, config.tile_size, initial_data));
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    hub.set_desktop_name(capture_info.desktop_name(&config.name, hostname.trim()));
+
+    // Capture request channel: VNC clients signal when they need a frame
+    let (capture_req_tx, capture_req_rx) = std_mpsc::channel::<()>();
+
+    // Input event channel. In view-only mode the receiver is dropped right
+    // away, so events from clients are discarded at the channel.
+    let (input_tx, mut input_rx) = mpsc::channel::<InputEvent>(256);
+
+    // Shutdown flag for the capture loop
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_capture = shutdown.clone();
+
+    if config.capture_watchdog > 0 && !custom_source && display_probe.is_none() {
+        let timeout = Duration::from_secs(config.capture_watchdog);
+        let reopen_config = config.clone();
+        let reopen: ReopenFn = Box::new(move || setup_capture(&reopen_config));
+        capture_fn = with_watchdog(capture_fn, timeout, (width, height), reopen);
+    }
+    if let Some(lut) = lut {
+        capture_fn = with_adjustment(capture_fn, lut);
+    }
+
+    let fps = config.fps;
+    let capture_policy = config.capture_mode;
+    let pace = config.pace;
+    let full_refresh = config.full_refresh_threshold;
+    let hub_capture = hub.clone();
+
+    // Spawn capture loop (on-demand, driven by client requests)
+    let capture_handle = tokio::task::spawn_blocking(move || {
+        capture_loop(
+            capture_fn,
+            hub_capture,
+            capture_req_rx,
+            shutdown_capture,
+            fps,
+            capture_policy,
+            pace,
+            dirty_tiles,
+            full_refresh,
+        )
+    });
+
+    // Recorder, fed from the hub at --fps until shutdown
+    let record_handle = match config.record {
+        Some(ref path) => {
+            let recorder = Recorder::create(path, width, height, fps)?;
+            tracing::info!("Recording to {path}");
+            let hub = hub.clone();
+            let capture_req_tx = capture_req_tx.clone();
+            let shutdown = shutdown.clone();
+            Some(tokio::task::spawn_blocking(move || {
+                record::record_loop(recorder, &hub, capture_req_tx, &shutdown, fps)
+            }))
+        }
+        None => None,
+    };
+
+    // Spawn input handler
+    let mut keymap_reload = None;
+    let input_handle = if !input_enabled {
+        if config.view_only {
+            tracing::info!("View-only mode: input forwarding disabled");
+        }
+        drop(input_rx);
+        None
+    } else if let Some(sink) = input_sink {
+        // Client input goes to the embedder instead of uinput devices
+        Some(tokio::spawn(async move {
+            while let Some(event) = input_rx.recv().await {
+                if sink.send(event).await.is_err() {
+                    break;
+                }
+            }
+        }))
+    } else {
+        let input_name = config.input_name.clone();
+        let (vendor, product) = (config.input_vendor, config.input_product);
+        let hub_input = hub.clone();
+        let log_input = config.log_input;
+        let right_click_hold = config.emulate_right_click_hold;
+        let (keymap_tx, keymap_rx) = mpsc::channel(1);
+        keymap_reload = config.keymap.clone().map(|path| (path, keymap_tx));
+        Some(tokio::spawn(async move {
+            input_loop(
+                &mut input_rx,
+                &hub_input,
+                &input_name,
+                vendor,
+                product,
+                keymap,
+                keymap_rx,
+                log_input,
+                right_click_hold,
+            )
+            .await
+        }))
+    };
+
+    let clients = Arc::new(Clients::default());
+    let _control_socket = match config.control_socket {
+        Some(ref path) => {
+            let control = Control {
+                hub: hub.clone(),
+                clients: clients.clone(),
+                started: Instant::now(),
+                keymap: keymap_reload,
+            };
+            Some(control.listen(path).context("Cannot open control socket")?)
+        }
+        None => None,
+    };
+
+    let handshake_timeout = Duration::from_secs(config.handshake_timeout);
+    let options = Arc::new(server::ClientOptions {
+        width: width as u16,
+        height: height as u16,
+        security,
+        defer_update: Duration::from_millis(config.defer_update),
+        capture_interval: Duration::from_secs(1) / config.fps.max(1),
+        min_update_interval: match config.max_client_fps {
+            0 => Duration::ZERO,
+            fps => Duration::from_secs(1) / fps,
+        },
+        coalesce: Duration::from_millis(config.coalesce_ms),
+        max_kbps: config.max_client_kbps,
+        band_height: config.band_height,
+        full_refresh,
+        depth: config.depth,
+        scancodes: config.keyboard_mode == KeyboardMode::Scancode,
+    });
+
+    // Listeners and the like, stopped with the session so the next one can
+    // bind the same ports
+    let mut tasks = Vec::new();
+
+    // Bind every listen address; accepted connections from all listeners
+    // arrive on one channel, tagged with whether they speak WebSocket
+    let (conn_tx, mut conn_rx) = mpsc::channel::<Connection>(16);
+    for host in &config.listen {
+        let ports = std::iter::once((config.port, false))
+            .chain(config.websocket_port.map(|port| (port, true)));
+        for (port, websocket) in ports {
+            let addr = listen_addr(host, port);
+            let listener = TcpListener::bind(&addr)
+                .await
+                .with_context(|| format!("Failed to bind to {addr}"))?;
+            if websocket {
+                tracing::info!("WebSocket listening on {addr}");
+            } else {
+                tracing::info!("VNC server listening on {addr}");
+            }
+            let conn_tx = conn_tx.clone();
+            tasks.push(tokio::spawn(async move {
+                loop {
+                    let accepted = listener.accept().await;
+                    let failed = accepted.is_err();
+                    if conn_tx.send((accepted, websocket, None)).await.is_err() || failed {
+                        break;
+                    }
+                }
+            }));
+        }
+    }
+    if let (Some(addr), Some(id)) = (&config.repeater, &config.repeater_id) {
+        tasks.push(tokio::spawn(repeater_loop(
+            addr.clone(),
+            id.clone(),
+            conn_tx.clone(),
+        )));
+    }
+    drop(conn_tx);
+
+    // SIGUSR1 rings the bell on every connected client
+    if bell_on_usr1 {
+        let hub_bell = hub.clone();
+        let mut usr1 = signal(SignalKind::user_defined1()).context("install SIGUSR1 handler")?;
+        tasks.push(tokio::spawn(async move {
+            while usr1.recv().await.is_some() {
+                tracing::info!("SIGUSR1: ringing bell");
+                hub_bell.ring_bell();
+            }
+        }));
+    }
+
+    if let Some(ready) = ready.take() {
+        let _ = ready.send(());
+    }
+
+    let mut display = None;
+    loop {
+        let (stream, peer, websocket, repeater) = tokio::select! {
+            conn = conn_rx.recv() => {
+                let Some((accepted, websocket, repeater)) = conn else { break };
+                let (stream, peer) = accepted?;
+                (stream, peer, websocket, repeater)
+            }
+            found = async { display_probe.as_mut().unwrap().await }, if display_probe.is_some() => {
+                display = Some(found.context("display probe")?);
+                break;
+            }
+            _ = &mut until => break,
+        };
+        let peer_str = peer.to_string();
+        let mut audit = AuditSession::new(audit_log.clone(), &peer_str, websocket);
+        // The repeater was dialled on purpose; its address says nothing
+        // about the viewer behind it
+        let allowed = repeater.is_some() || config.allow.iter().any(|n| n.contains(peer.ip()));
+        if !config.allow.is_empty() && !allowed {
+            tracing::warn!("Rejected connection from {peer}: not in --allow list");
+            audit.close("not in --allow list");
+            continue;
+        }
+        tracing::info!(
+            "VNC client connected: {peer}{}",
+            match (websocket, &repeater) {
+                (true, _) => " (WebSocket)",
+                (false, Some(_)) => " (repeater)",
+                (false, None) => "",
+            }
+        );
+        let hub = hub.clone();
+        let capture_req_tx = capture_req_tx.clone();
+        let input_tx = input_tx.clone();
+        let options = options.clone();
+        let clients = clients.clone();
+        let client = tokio::spawn(async move {
+            let _registered = clients.register(&peer_str, websocket);
+            // The repeater holds its connection idle until a viewer turns
+            // up, so it is exempt; it is one connection at a time anyway
+            let handshake_deadline = (!handshake_timeout.is_zero() && repeater.is_none())
+                .then(|| tokio::time::Instant::now() + handshake_timeout);
+            // Dropped when the session ends, so the repeater is dialled again
+            let _repeater = repeater;
+            let result = if websocket {
+                // The HTTP upgrade counts towards the handshake
+                let upgrade = vnc::websocket::accept(stream);
+                let upgraded = match handshake_deadline {
+                    Some(deadline) => tokio::time::timeout_at(deadline, upgrade)
+                        .await
+                        .unwrap_or_else(|_| {
+                            Err(anyhow::anyhow!("handshake timed out in WebSocket upgrade"))
+                        }),
+                    None => upgrade.await,
+                };
+                match upgraded {
+                    Ok(ws) => {
+                        server::handle_client(
+                            ws,
+                            &mut audit,
+                            hub,
+                            capture_req_tx,
+                            input_tx,
+                            &options,
+                            handshake_deadline,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                }
+            } else {
+                server::handle_client(
+                    stream,
+                    &mut audit,
+                    hub,
+                    capture_req_tx,
+                    input_tx,
+                    &options,
+                    handshake_deadline,
+                )
+                .await
+            };
+            // Covers sessions that ended before handle_client got to it
+            audit.close(&server::end_reason(&result));
+            match result {
+                Ok(()) => {}
+                Err(e) if server::is_disconnect(&e) => {
+                    tracing::info!("Client {peer} disconnected: {e:#}");
+                }
+                Err(e) => tracing::warn!("Client {peer} dropped: {e:#}"),
+            }
+        });
+        if config.once {
+            // Serve only this client, then shut down
+            tokio::select! {
+                _ = client => {
+                    tracing::info!("Client {peer} finished, exiting (--once)");
+                }
+                _ = &mut until => {}
+            }
+            break;
+        }
+    }
+
+    // Signal capture loop to stop and wait for it
+    shutdown.store(true, Ordering::Relaxed);
+    drop(input_tx);
+    if let Some(handle) = input_handle {
+        handle.abort();
+    }
+    if let Some(probe) = display_probe {
+        probe.abort();
+    }
+    for task in tasks {
+        task.abort();
+        let _ = task.await;
+    }
+    if display.is_some() {
+        tracing::info!("Display found, disconnecting clients to serve it");
+        hub.disconnect_all();
+    }
+    let _ = capture_handle.await;
+    if let Some(handle) = record_handle {
+        let _ = handle.await;
+    }
+
+    Ok(display)
+}
+
+/// Set capture up every `DISPLAY_PROBE_INTERVAL` until it works.
+async fn probe_display(config: Config) -> (CaptureInfo, Vec<u8>, CaptureFn) {
+    loop {
+        tokio::time::sleep(DISPLAY_PROBE_INTERVAL).await;
+        let config = config.clone();
+        match tokio::task::spawn_blocking(move || setup_capture(&config)).await {
+            Ok(Ok(capture)) => return capture,
+            Ok(Err(e)) => tracing::debug!("Still no display: {e:#}"),
+            Err(e) => tracing::warn!("Display probe failed: {e}"),
+        }
+    }
+}
+
+/// A connection for the accept loop: accepted or dialled, whether it
+/// speaks WebSocket, and for a repeater connection, what to drop once its
+/// session is over.
+type Connection = (
+    std::io::Result<(TcpStream, std::net::SocketAddr)>,
+    bool,
+    Option<oneshot::Sender<()>>,
+);
+
+/// First wait before dialling the repeater again after a failure; doubled
+/// after each further failure up to `REPEATER_RETRY_MAX`.
+const REPEATER_RETRY_MIN: Duration = Duration::from_secs(1);
+const REPEATER_RETRY_MAX: Duration = Duration::from_secs(60);
+
+/// Keep a connection registered with the repeater at `addr`, handing each
+/// to the accept loop and dialling the next once its session has ended.
+async fn repeater_loop(addr: String, id: RepeaterId, conn_tx: mpsc::Sender<Connection>) {
+    let mut backoff = REPEATER_RETRY_MIN;
+    loop {
+        match vnc::repeater::connect(&addr, &id).await {
+            Ok(conn) => {
+                tracing::info!("Registered with repeater {addr} as ID {id}");
+                backoff = REPEATER_RETRY_MIN;
+                let (done_tx, done_rx) = oneshot::channel();
+                if conn_tx
+                    .send((Ok(conn), false, Some(done_tx)))
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+                let _ = done_rx.await;
+                // A repeater that drops us at once isn't dialled in a loop
+                tokio::time::sleep(REPEATER_RETRY_MIN).await;
+            }
+            Err(e) => {
+                tracing::warn!("{e:#}, retrying in {backoff:?}");
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(REPEATER_RETRY_MAX);
+            }
+        }
+    }
+}
+
+/// `host:port`, bracketing bare IPv6 addresses.
+fn listen_addr(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{host}]:{port}")
+    } else {
+        format!("{host}:{port}")
+    }
+}
+
+/// Write a captured BGRA frame as PNG to `path` (`-` for stdout). A `.bgra`
+/// path gets the raw frame instead, for exact comparisons in tests.
+fn write_screenshot(path: &str, width: u32, height: u32, bgra: &[u8]) -> Result<()> {
+    let data = if path.ends_with(".bgra") {
+        bgra.to_vec()
+    } else {
+        png::encode_bgra(width, height, bgra)
+    };
+    if path == "-" {
+        use std::io::Write;
+        std::io::stdout()
+            .lock()
+            .write_all(&data)
+            .context("write screenshot to stdout")?;
+    } else {
+        fs::write(path, &data).with_context(|| format!("write screenshot to {path}"))?;
+        tracing::info!("Screenshot saved to {path} ({width}x{height})");
+    }
+    Ok(())
+}
+
+/// Sets capture up again from scratch, for the watchdog.
+type ReopenFn = Box<dyn FnMut() -> Result<(CaptureInfo, Vec<u8>, CaptureFn)> + Send>;
+
+/// Wrap `capture_fn` so that once captures have been failing for `timeout`,
+/// the capture device is probed again and the capturer rebuilt: a stale
+/// framebuffer after suspend/resume or a GPU reset otherwise leaves
+/// clients frozen for good. Retried every `timeout` while failures go on.
+fn with_watchdog(
+    mut capture_fn: CaptureFn,
+    timeout: Duration,
+    size: (u32, u32),
+    mut reopen: ReopenFn,
+) -> CaptureFn {
+    let mut failing_since: Option<Instant> = None;
+    Box::new(move |force, dst, dirty_tiles| {
+        let result = capture_fn(force, dst, dirty_tiles);
+        if result.is_ok() {
+            failing_since = None;
+            return result;
+        }
+        let since = *failing_since.get_or_insert_with(Instant::now);
+        if since.elapsed() < timeout {
+            return result;
+        }
+        tracing::warn!("No frame captured for {timeout:?}, probing the capture device again");
+        failing_since = Some(Instant::now());
+        match reopen() {
+            Ok((info, _, new_fn)) if (info.width, info.height) == size => {
+                info.log();
+                // Dropping the old capturer releases its cached buffers
+                capture_fn = new_fn;
+            }
+            Ok((info, ..)) => tracing::error!(
+                "Capture came back at {}x{} instead of {}x{}; restart kmsvnc to follow it",
+                info.width,
+                info.height,
+                size.0,
+                size.1
+            ),
+            Err(e) => tracing::warn!("Capture device still unusable: {e:#}"),
+        }
+        result
+    })
+}
+
+/// Wrap `capture_fn` to pass every frame through `lut`. Frames are captured
+/// into a buffer of their own, so incremental capture keeps comparing
+/// unadjusted pixels, and the whole frame is adjusted into the caller's
+/// buffer whenever it changed.
+fn with_adjustment(mut capture_fn: CaptureFn, lut: Lut) -> CaptureFn {
+    let mut raw = Vec::new();
+    Box::new(move |force, dst, dirty_tiles| {
+        let changed = capture_fn(force, &mut raw, dirty_tiles)?;
+        if changed || dst.len() != raw.len() {
+            lut.apply(&raw, dst);
+        }
+        Ok(changed)
+    })
+}
+
+/// Adaptive capture mode: switches between on-demand and polling based on request frequency.
+enum CaptureMode {
+    /// Wait for explicit capture requests; always force-capture to ensure fresh frames.
+    OnDemand,
+    /// Actively poll at the given interval; skip unchanged frames to save CPU.
+    Polling { interval: Duration },
+}
+
+#[allow(clippy::too_many_arguments)]
+fn capture_loop(
+    mut capture_fn: CaptureFn,
+    hub: Arc<FrameHub>,
+    capture_req_rx: std_mpsc::Receiver<()>,
+    shutdown: Arc<AtomicBool>,
+    fps: u32,
+    policy: CapturePolicy,
+    pace: bool,
+    dirty_tiles: Arc<DirtyTiles>,
+    full_refresh: FullRefreshThreshold,
+) {
+    let poll_interval = Duration::from_millis(1000 / fps.max(1) as u64);
+    let mut mode = match policy {
+        CapturePolicy::Polling => CaptureMode::Polling {
+            interval: poll_interval,
+        },
+        CapturePolicy::Adaptive | CapturePolicy::OnDemand => CaptureMode::OnDemand,
+    };
+    // Only adaptive mode switches on the client request rate
+    let adaptive = policy == CapturePolicy::Adaptive;
+    let mut last_request_time: Option<Instant> = None;
+    let mut fast_request_count = 0u32;
+
+    // Buffer pool: try to reuse the frame from the previous Arc
+    let mut reuse: Option<Frame> = None;
+
+    // Idle backoff: reduce capture rate when screen content is unchanged.
+    // Consecutive unchanged captures increase idle_streak; any change resets it.
+    let mut idle_streak = 0u32;
+
+    let mut failures = CaptureFailures::default();
+    let mut rate = CaptureRate::new(Instant::now());
+
+    // With `pace`, when the next polled capture is due. Client requests
+    // wake the loop early, so waiting a full interval from each wake-up
+    // would push the capture back.
+    let mut next_tick: Option<Instant> = None;
+
+    loop {
+        let interval = match mode {
+            CaptureMode::OnDemand => Duration::from_millis(100),
+            CaptureMode::Polling { .. } if policy == CapturePolicy::Polling => poll_interval,
+            CaptureMode::Polling { interval } => {
+                // Exponential backoff when idle: double interval every 5 unchanged
+                // captures, up to 4x the base interval.
+                let shift = (idle_streak / 5).min(2);
+                interval * (1 << shift)
+            }
+        };
+        let timeout = match mode {
+            CaptureMode::Polling { .. } if pace => {
+                let tick = *next_tick.get_or_insert_with(|| Instant::now() + interval);
+                tick.saturating_duration_since(Instant::now())
+            }
+            _ => {
+                next_tick = None;
+                interval
+            }
+        };
+
+        match capture_req_rx.recv_timeout(timeout) {
+            Ok(()) => {
+                // Check request interval to detect high-frequency clients
+                let now = Instant::now();
+                if let Some(last) = last_request_time.filter(|_| adaptive) {
+                    if now.duration_since(last) < Duration::from_millis(100) {
+                        fast_request_count += 1;
+                        if fast_request_count >= 3 {
+                            if matches!(mode, CaptureMode::OnDemand) {
+                                tracing::debug!("Switching to polling mode ({}fps)", fps);
+                            }
+                            mode = CaptureMode::Polling {
+                                interval: poll_interval,
+                            };
+                        }
+                    } else {
+                        fast_request_count = 0;
+                    }
+                }
+                last_request_time = Some(now);
+
+                // Drain any additional queued requests (coalesce)
+                while capture_req_rx.try_recv().is_ok() {}
+
+                match mode {
+                    CaptureMode::OnDemand => {
+                        // On-demand: capture immediately on each client request
+                        let result = do_capture(
+                            &mut capture_fn,
+                            &hub,
+                            false,
+                            &mut reuse,
+                            &dirty_tiles,
+                            full_refresh,
+                        );
+                        rate.record(&result);
+                        failures.check(result);
+                    }
+                    CaptureMode::Polling { .. } => {
+                        // Polling: timer drives captures — don't capture here.
+                        // The VNC server will get the response on the next timer tick.
+                        // This prevents double-captures (timer + request) which
+                        // effectively doubled the capture rate.
+                    }
+                }
+            }
+            Err(std_mpsc::RecvTimeoutError::Timeout) => {
+                if shutdown.load(Ordering::Relaxed) {
+                    tracing::debug!("Capture loop shutting down");
+                    break;
+                }
+                if let Some(tick) = next_tick {
+                    // Skip ticks missed by a slow capture instead of
+                    // catching up in a burst
+                    let now = Instant::now();
+                    next_tick = Some((tick + interval).max(now));
+                }
+                match mode {
+                    CaptureMode::Polling { .. } if policy == CapturePolicy::Polling => {
+                        // Pinned polling: capture every tick, requested or not
+                        let result = do_capture(
+                            &mut capture_fn,
+                            &hub,
+                            false,
+                            &mut reuse,
+                            &dirty_tiles,
+                            full_refresh,
+                        );
+                        rate.record(&result);
+                        failures.check(result);
+                    }
+                    CaptureMode::Polling { .. } => {
+                        // Check if we should switch back to on-demand
+                        if let Some(last) = last_request_time {
+                            if Instant::now().duration_since(last) > Duration::from_millis(500) {
+                                tracing::debug!("Switching to on-demand mode");
+                                mode = CaptureMode::OnDemand;
+                                fast_request_count = 0;
+                                idle_streak = 0;
+                            } else {
+                                // Timer-driven capture with idle backoff
+                                let result = do_capture(
+                                    &mut capture_fn,
+                                    &hub,
+                                    false,
+                                    &mut reuse,
+                                    &dirty_tiles,
+                                    full_refresh,
+                                );
+                                rate.record(&result);
+                                if failures.check(result) {
+                                    idle_streak = 0;
+                                } else {
+                                    idle_streak = idle_streak.saturating_add(1);
+                                }
+                            }
+                        }
+                    }
+                    CaptureMode::OnDemand => {}
+                }
+            }
+            Err(std_mpsc::RecvTimeoutError::Disconnected) => {
+                tracing::debug!("Capture request channel closed");
+                break;
+            }
+        }
+        rate.tick(Instant::now(), fps, &hub);
+    }
+}
+
+/// How long the capture rate is measured over before it is reported.
+const CAPTURE_RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Counts captures to report the frame rate actually achieved, which
+/// capture cost and client demand keep below `--fps`.
+struct CaptureRate {
+    started: Instant,
+    captures: u32,
+    changed: u32,
+}
+
+impl CaptureRate {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            captures: 0,
+            changed: 0,
+        }
+    }
+
+    fn record(&mut self, result: &Result<bool>) {
+        if let Ok(changed) = result {
+            self.captures += 1;
+            self.changed += u32::from(*changed);
+        }
+    }
+
+    /// Once a window is over, log its rates, hand the capture rate to the
+    /// hub for the control socket and start the next.
+    fn tick(&mut self, now: Instant, fps: u32, hub: &FrameHub) {
+        let elapsed = now.duration_since(self.started);
+        if elapsed < CAPTURE_RATE_WINDOW {
+            return;
+        }
+        let per_sec = |n: u32| f64::from(n) / elapsed.as_secs_f64();
+        tracing::debug!(
+            "Capture rate: {:.1} fps, {:.1} with changes (--fps {fps})",
+            per_sec(self.captures),
+            per_sec(self.changed)
+        );
+        hub.set_capture_fps(per_sec(self.captures));
+        *self = Self::new(now);
+    }
+}
+
+/// Consecutive failed captures after which the failure is logged as an
+/// error: clients have been looking at a frozen screen for a while.
+const PERSISTENT_CAPTURE_FAILURES: u32 = 30;
+
+/// Tracks consecutive capture failures so a one-off glitch stays a warning
+/// and a capture path that keeps failing is reported once, loudly.
+#[derive(Default)]
+struct CaptureFailures {
+    count: u32,
+    /// A tiled scanout buffer was reported; it comes and goes with what the
+    /// compositor shows, so it is only warned about once per run.
+    tiled_reported: bool,
+}
+
+impl CaptureFailures {
+    /// Log a failed capture, or the recovery after failures. Returns whether
+    /// the frame changed.
+    fn check(&mut self, result: Result<bool>) -> bool {
+        match result {
+            Ok(changed) => {
+                if self.count > 0 {
+                    tracing::info!("Capture recovered after {} failed attempts", self.count);
+                    self.count = 0;
+                }
+                changed
+            }
+            Err(e) => {
+                self.count += 1;
+                let tiled = capture::tiled_framebuffer(&e);
+                if let Some(tiled) = tiled.filter(|_| !self.tiled_reported) {
+                    tracing::warn!("Capture blocked, clients see a frozen screen: {tiled}");
+                    self.tiled_reported = true;
+                } else if tiled.is_some() {
+                    tracing::debug!("Capture failed again ({}): {e:#}", self.count);
+                } else if !capture::is_transient_error(&e) {
+                    if self.count == 1 {
+                        tracing::error!("Capture failed and cannot recover by retrying: {e:#}");
+                    }
+                } else if self.count == 1 {
+                    tracing::warn!("Capture failed: {e:#}");
+                } else if self.count == PERSISTENT_CAPTURE_FAILURES {
+                    tracing::error!(
+                        "Capture has failed {} times in a row, clients see a frozen screen: {e:#}",
+                        self.count
+                    );
+                } else {
+                    tracing::debug!("Capture failed again ({}): {e:#}", self.count);
+                }
+                false
+            }
+        }
+    }
+}
+
+/// Perform a capture and publish the result if a new frame was obtained.
+/// Returns `true` if the frame content actually changed. A transient
+/// failure is retried once, as a forced capture of a freshly mapped buffer.
+fn do_capture(
+    capture_fn: &mut CaptureFn,
+    hub: &FrameHub,
+    force: bool,
+    reuse: &mut Option<Frame>,
+    dirty_tiles: &DirtyTiles,
+    full_refresh: FullRefreshThreshold,
+) -> Result<bool> {
+    // Try to reclaim the frame from the previous Arc (if refcount == 1).
+    // Otherwise allocate full-size buffers once so neither grows while filled.
+    let mut frame = reuse.take().unwrap_or_else(|| {
+        let frame_bytes = hub.width() as usize * hub.height() as usize * 4;
+        Frame {
+            data: Vec::with_capacity(frame_bytes),
+            dirty: dirty_tiles.empty_mask(),
+            // Pixels plus headroom for the message and rect headers
+            encoded: Vec::with_capacity(frame_bytes + 4096),
+            captured_at: Instant::now(),
+        }
+    });
+
+    // A reclaimed buffer holds the frame before the published one, with the
+    // tiles that differ from it in `dirty`. Bring it up to date first: the
+    // capturer diffs against it, and may leave it untouched and report no
+    // change, which must mean the published frame is still current.
+    if frame.dirty.iter().any(|&w| w != 0) {
+        let published = hub.current();
+        if published.data.len() == frame.data.len() {
+            let stride = hub.width() as usize * 4;
+            for rect in dirty_tiles.mask_to_rects(&frame.dirty) {
+                let x = rect.x as usize * 4;
+                for y in rect.y as usize..(rect.y + rect.height) as usize {
+                    let row = y * stride + x..y * stride + x + rect.width as usize * 4;
+                    frame.data[row.clone()].copy_from_slice(&published.data[row]);
+                }
+            }
+        } else {
+            frame.data.clone_from(&published.data);
+        }
+        frame.dirty.fill(0);
+    }
+
+    let result = match capture_fn(force, &mut frame.data, Some(dirty_tiles)) {
+        Err(e) if capture::is_transient_error(&e) => {
+            tracing::debug!("Capture failed ({e:#}), retrying");
+            capture_fn(true, &mut frame.data, Some(dirty_tiles))
+        }
+        r => r,
+    };
+    match result {
+        Ok(true) => {
+            frame.captured_at = Instant::now();
+            frame.dirty = dirty_tiles.drain();
+            // Capturers may mark more than changed; trim the mask to the
+            // tiles that really differ from the published frame
+            let published = hub.current();
+            if published.data.len() == frame.data.len() {
+                let stride = hub.width() as usize * 4;
+                dirty_tiles.retain_changed(&mut frame.dirty, &frame.data, &published.data, stride);
+            }
+            drop(published);
+
+            frame.encoded.clear();
+            if hub.has_clients() {
+                let rects = dirty_tiles.mask_to_rects(&frame.dirty);
+                server::encode_shared_update(
+                    &mut frame.encoded,
+                    &frame.data,
+                    hub.width(),
+                    &rects,
+                    full_refresh,
+                );
+            }
+
+            let mask = frame.dirty.clone();
+            let old_arc = hub.publish(frame);
+            // Try to reclaim the old frame for next capture
+            if let Ok(mut old_frame) = Arc::try_unwrap(old_arc) {
+                old_frame.dirty = mask;
+                *reuse = Some(old_frame);
+            }
+            Ok(true)
+        }
+        Ok(false) => {
+            // Frame unchanged — notify VNC server to unblock changed().await
+            // (no dirty tiles set, so server sends empty FramebufferUpdate)
+            hub.notify_unchanged();
+            *reuse = Some(frame);
+            Ok(false)
+        }
+        Err(e) => {
+            // Answer waiting clients with the frame they already have
+            // rather than leaving their requests hanging
+            hub.notify_unchanged();
+            // Keep buffer for next attempt
+            *reuse = Some(frame);
+            Err(e)
+        }
+    }
+}
+
+/// How long pointer motion may be held back to coalesce it with newer motion.
+const POINTER_COALESCE_WINDOW: Duration = Duration::from_millis(8);
+
+/// A reloaded keymap for the input loop, and where to report whether the
+/// keyboard took it.
+pub(crate) type KeymapReload = (input::keymap::Keymap, oneshot::Sender<Result<()>>);
+
+#[allow(clippy::too_many_arguments)]
+async fn input_loop(
+    input_rx: &mut mpsc::Receiver<InputEvent>,
+    hub: &FrameHub,
+    name: &str,
+    vendor: u16,
+    product: u16,
+    keymap: input::keymap::Keymap,
+    mut keymap_rx: mpsc::Receiver<KeymapReload>,
+    log_input: Option<InputLog>,
+    right_click_hold: bool,
+) {
+    let input_id = |product| InputId {
+        bustype: 0x06, // BUS_VIRTUAL
+        vendor,
+        product,
+        version: 1,
+    };
+
+    // Devices that stop accepting events are recreated with the same name,
+    // ID and key/abs bits; the keyboard also with the latest keymap
+    let touch_name = format!("{name}-touch");
+    let touch_id = input_id(product);
+    let (width, height) = (hub.width(), hub.height());
+    let mut touch = Recovering::new("touchscreen", move || {
+        input::touch::VirtualTouchscreen::new(
+            width,
+            height,
+            &touch_id,
+            &touch_name,
+            right_click_hold,
+        )
+    });
+
+    let keyboard_name = format!("{name}-keyboard");
+    let keyboard_id = input_id(product.wrapping_add(1));
+    let keymap = Arc::new(std::sync::Mutex::new(keymap));
+    let current_keymap = keymap.clone();
+    let mut keyboard = Recovering::new("keyboard", move || {
+        let keymap = current_keymap.lock().unwrap().clone();
+        input::keyboard::VirtualKeyboard::new(&keyboard_id, &keyboard_name, keymap)
+    });
+
+    // Pointer motion is coalesced: a motion event is held for up to
+    // POINTER_COALESCE_WINDOW and replaced by any newer motion with the same
+    // button state. Button changes and key events flush it first and are
+    // forwarded immediately, so only intermediate positions are dropped.
+    let mut pending: Option<(u8, u16, u16)> = None;
+    let mut deadline = tokio::time::Instant::now();
+    let mut last_mask = 0u8;
+
+    loop {
+        let long_press = touch.get().and_then(|t| t.long_press_deadline());
+        let event = tokio::select! {
+            event = input_rx.recv() => event,
+            _ = tokio::time::sleep_until(deadline), if pending.is_some() => {
+                if let Some((mask, x, y)) = pending.take() {
+                    forward_pointer(&mut touch, mask, x, y);
+                }
+                continue;
+            }
+            _ = sleep_until_std(long_press), if long_press.is_some() => {
+                if let Some(t) = touch.get() {
+                    let result = t.expire_long_press();
+                    touch.record(result);
+                }
+                continue;
+            }
+            Some((new_keymap, reply)) = keymap_rx.recv() => {
+                let result = match keyboard.get() {
+                    Some(k) => k.set_keymap(new_keymap.clone()),
+                    None => Err(anyhow::anyhow!("no virtual keyboard")),
+                };
+                if result.is_ok() {
+                    *keymap.lock().unwrap() = new_keymap;
+                }
+                let _ = reply.send(result);
+                continue;
+            }
+        };
+        let Some(event) = event else {
+            break;
+        };
+
+        match event {
+            InputEvent::Pointer { button_mask, x, y } => {
+                if button_mask == last_mask {
+                    if pending.is_none() {
+                        deadline = tokio::time::Instant::now() + POINTER_COALESCE_WINDOW;
+                    }
+                    pending = Some((button_mask, x, y));
+                } else {
+                    if let Some((mask, px, py)) = pending.take() {
+                        forward_pointer(&mut touch, mask, px, py);
+                    }
+                    if let Some(mode) = log_input {
+                        tracing::info!("{}", pointer_log_line(mode, last_mask, button_mask, x, y));
+                    }
+                    forward_pointer(&mut touch, button_mask, x, y);
+                    last_mask = button_mask;
+                }
+            }
+            InputEvent::Key { down, keysym } => {
+                if let Some((mask, x, y)) = pending.take() {
+                    forward_pointer(&mut touch, mask, x, y);
+                }
+                let mut code = None;
+                if let Some(k) = keyboard.get() {
+                    let result = k.handle_key(down, keysym);
+                    let leds = k.led_state();
+                    code = keyboard.record(result).flatten();
+                    hub.set_led_state(leds);
+                }
+                if let Some(mode) = log_input {
+                    tracing::info!("{}", key_log_line(mode, down, keysym, code));
+                }
+            }
+            InputEvent::ExtendedKey {
+                down,
+                keysym,
+                keycode,
+            } => {
+                if let Some((mask, x, y)) = pending.take() {
+                    forward_pointer(&mut touch, mask, x, y);
+                }
+                let mut code = None;
+                if let Some(k) = keyboard.get() {
+                    let result = k.handle_scancode(down, keysym, keycode);
+                    let leds = k.led_state();
+                    code = keyboard.record(result).flatten();
+                    hub.set_led_state(leds);
+                }
+                if let Some(mode) = log_input {
+                    tracing::info!("{}", key_log_line(mode, down, keysym, code));
+                }
+            }
+        }
+    }
+
+    if let Some((mask, x, y)) = pending.take() {
+        forward_pointer(&mut touch, mask, x, y);
+    }
+}
+
+/// `--log-input` line for a key event that resolved to key `code`. Redacted
+/// lines leave out which key it was.
+fn key_log_line(mode: InputLog, down: bool, keysym: u32, code: Option<u16>) -> String {
+    let action = if down { "down" } else { "up" };
+    match (mode, code) {
+        (InputLog::Redacted, _) => format!("Input: key event ({action})"),
+        (InputLog::Full, Some(code)) => {
+            format!("Input: key {action} keysym 0x{keysym:04x} -> key code {code}")
+        }
+        (InputLog::Full, None) => format!("Input: key {action} keysym 0x{keysym:04x} (unmapped)"),
+    }
+}
+
+/// `--log-input` line for a change of pointer buttons from `from` to `to`
+/// at (`x`, `y`). Redacted lines leave out where it was, which would give
+/// away taps on an on-screen keyboard.
+fn pointer_log_line(mode: InputLog, from: u8, to: u8, x: u16, y: u16) -> String {
+    let line = format!("Input: pointer buttons {from:#04x} -> {to:#04x}");
+    match mode {
+        InputLog::Redacted => line,
+        InputLog::Full => format!("{line} at ({x}, {y})"),
+    }
+}
+
+/// Sleep until `at`, or forever without one.
+async fn sleep_until_std(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn forward_pointer(
+    touch: &mut Recovering<input::touch::VirtualTouchscreen>,
+    button_mask: u8,
+    x: u16,
+    y: u16,
+) {
+    if let Some(t) = touch.get() {
+        let result = t.handle_pointer(button_mask, x, y);
+        touch.record(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Capture function filling every frame with one grey.
+    fn grey(width: u32, height: u32) -> CaptureFn {
+        Box::new(move |_force, dst, dirty_tiles| {
+            dst.clear();
+            dst.resize(width as usize * height as usize * 4, 0x80);
+            if let Some(dt) = dirty_tiles {
+                dt.set_all();
+            }
+            Ok(true)
+        })
+    }
+
+    #[test]
+    fn redacted_key_log_leaves_out_the_key() {
+        let line = key_log_line(InputLog::Redacted, true, 0x61, Some(30));
+        assert_eq!(line, "Input: key event (down)");
+        let line = key_log_line(InputLog::Full, false, 0x61, Some(30));
+        assert_eq!(line, "Input: key up keysym 0x0061 -> key code 30");
+    }
+
+    #[test]
+    fn redacted_pointer_log_leaves_out_the_position() {
+        let line = pointer_log_line(InputLog::Redacted, 0, 1, 120, 45);
+        assert_eq!(line, "Input: pointer buttons 0x00 -> 0x01");
+        let line = pointer_log_line(InputLog::Full, 1, 0, 120, 45);
+        assert_eq!(line, "Input: pointer buttons 0x01 -> 0x00 at (120, 45)");
+    }
+
+    #[test]
+    fn unchanged_capture_keeps_the_published_frame() {
+        let (a, b) = (vec![0x11; 32 * 32 * 4], vec![0x22; 32 * 32 * 4]);
+        let hub = FrameHub::new(32, 32, 16, a.clone());
+        let dirty_tiles = DirtyTiles::new(32, 32, 16);
+        // Like `Capturer`: an unchanged source, or one whose sampled rows
+        // match `dst`, is reported without touching `dst`. The screen shows
+        // B for a while, then A again for good.
+        let mut screens = vec![b.clone(), b.clone(), b.clone(), a.clone()].into_iter();
+        let mut shown = a.clone();
+        let mut last_written = Vec::new();
+        let mut capture_fn: CaptureFn = Box::new(move |_force, dst, dt| {
+            shown = screens.next().unwrap_or(shown.clone());
+            if shown == last_written || shown == *dst {
+                return Ok(false);
+            }
+            dst.clone_from(&shown);
+            last_written.clone_from(&shown);
+            dt.unwrap().set_all();
+            Ok(true)
+        });
+        let mut reuse = None;
+        let mut published = Vec::new();
+        for _ in 0..7 {
+            do_capture(
+                &mut capture_fn,
+                &hub,
+                false,
+                &mut reuse,
+                &dirty_tiles,
+                FullRefreshThreshold::Off,
+            )
+            .unwrap();
+            published.push(hub.current().data[0]);
+        }
+        assert_eq!(published, [0x22, 0x22, 0x22, 0x11, 0x11, 0x11, 0x11]);
+    }
+
+    #[test]
+    fn shared_update_merges_scattered_tiles() {
+        // What in-sync raw clients in the default format are sent: three of
+        // the four 16x16 tiles change, which is past 50% of their bounds
+        let hub = FrameHub::new(32, 32, 16, vec![0; 32 * 32 * 4]);
+        let dirty_tiles = DirtyTiles::new(32, 32, 16);
+        let (_frame_rx, _client_tiles) = hub.subscribe();
+        let mut capture_fn: CaptureFn = Box::new(|_force, dst, dt| {
+            dst.resize(32 * 32 * 4, 0);
+            for (x, y) in [(0, 0), (16, 0), (16, 16)] {
+                dst[(y * 32 + x) * 4] = 0xff;
+                dt.unwrap().set(y / 16 * 2 + x / 16);
+            }
+            Ok(true)
+        });
+        let mut reuse = None;
+        let half = "50%".parse().unwrap();
+        do_capture(&mut capture_fn, &hub, false, &mut reuse, &dirty_tiles, half).unwrap();
+        let encoded = &hub.current().encoded;
+        // One Raw rect of the whole 32x32 bounding box
+        assert_eq!(
+            encoded[..16],
+            [0, 0, 0, 1, 0, 0, 0, 0, 0, 32, 0, 32, 0, 0, 0, 0]
+        );
+        assert_eq!(encoded.len(), 16 + 32 * 32 * 4);
+    }
+
+    #[tokio::test]
+    async fn custom_source_serves_until_stopped() {
+        let (input_tx, _input_rx) = mpsc::channel(1);
+        Server::new()
+            .capture(64, 32, grey(64, 32))
+            .listen("127.0.0.1", 0)
+            .input(input_tx)
+            .run_until(async {})
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn custom_source_of_the_wrong_size_is_rejected() {
+        let err = Server::new()
+            .capture(64, 32, grey(32, 32))
+            .listen("127.0.0.1", 0)
+            .run_until(async {})
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("4096 bytes for a 64x32"), "{err}");
+    }
+}
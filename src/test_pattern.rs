@@ -0,0 +1,190 @@
+use crate::frame_diff::{DirtyRect, DirtyTiles};
+use crate::kms::pixel_format::copy_rows_incremental;
+
+/// Resolution of the synthetic frames.
+pub const WIDTH: u32 = 1024;
+pub const HEIGHT: u32 = 768;
+
+/// Edge length of the bouncing box in pixels.
+const BOX_SIZE: u32 = 64;
+/// Distance the box moves per frame, along each axis.
+const BOX_STEP: u32 = 8;
+const BOX_COLOUR: [u8; 4] = [0xF0, 0xF0, 0xF0, 0xFF];
+
+/// Synthetic capture source for `--backend test-pattern`: a static gradient
+/// with a box bouncing diagonally across it, one step per frame.
+///
+/// Only the tiles under the box's previous and new positions change from
+/// one frame to the next, so the dirty regions are predictable.
+pub struct TestPattern {
+    width: u32,
+    height: u32,
+    /// The current frame (BGRA), empty before the first one is drawn.
+    frame: Vec<u8>,
+    /// Top-left corner of the box.
+    x: u32,
+    y: u32,
+    /// Direction of travel (true = right / down).
+    right: bool,
+    down: bool,
+}
+
+impl TestPattern {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            frame: Vec::new(),
+            x: 0,
+            y: 0,
+            right: true,
+            down: true,
+        }
+    }
+
+    /// Render the next frame into `dst`. As with DRM capture, `dst` may hold
+    /// any earlier frame: only the tiles that differ from it are copied and
+    /// marked dirty. Returns whether `dst` changed.
+    pub fn next_frame(&mut self, dst: &mut Vec<u8>, dirty_tiles: Option<&DirtyTiles>) -> bool {
+        let mut frame = std::mem::take(&mut self.frame);
+        if frame.is_empty() {
+            frame.resize((self.width * self.height * 4) as usize, 0);
+            self.fill(&mut frame, self.frame_rect(), |x, y| self.gradient(x, y));
+        } else {
+            self.fill(&mut frame, self.box_rect(), |x, y| self.gradient(x, y));
+            self.advance();
+        }
+        self.fill(&mut frame, self.box_rect(), |_, _| BOX_COLOUR);
+        self.frame = frame;
+
+        match dirty_tiles {
+            Some(dt) if dst.len() == self.frame.len() => copy_rows_incremental(
+                dst,
+                &self.frame,
+                self.width,
+                self.height,
+                self.width * 4,
+                dt,
+            ),
+            _ => {
+                dst.clear();
+                dst.extend_from_slice(&self.frame);
+                if let Some(dt) = dirty_tiles {
+                    dt.set_all();
+                }
+                true
+            }
+        }
+    }
+
+    /// Move the box one step, bouncing off the frame edges.
+    fn advance(&mut self) {
+        let step = |pos: u32, forward: &mut bool, max: u32| {
+            if *forward && pos + BOX_STEP > max {
+                *forward = false;
+            } else if !*forward && pos < BOX_STEP {
+                *forward = true;
+            }
+            if *forward {
+                (pos + BOX_STEP).min(max)
+            } else {
+                pos.saturating_sub(BOX_STEP)
+            }
+        };
+        self.x = step(self.x, &mut self.right, self.width.saturating_sub(BOX_SIZE));
+        self.y = step(self.y, &mut self.down, self.height.saturating_sub(BOX_SIZE));
+    }
+
+    /// Background BGRA pixel: blue across, green down.
+    fn gradient(&self, x: u32, y: u32) -> [u8; 4] {
+        [
+            (x * 255 / self.width) as u8,
+            (y * 255 / self.height) as u8,
+            0x40,
+            0xFF,
+        ]
+    }
+
+    fn frame_rect(&self) -> DirtyRect {
+        DirtyRect {
+            x: 0,
+            y: 0,
+            width: self.width as u16,
+            height: self.height as u16,
+        }
+    }
+
+    fn box_rect(&self) -> DirtyRect {
+        DirtyRect {
+            x: self.x as u16,
+            y: self.y as u16,
+            width: BOX_SIZE.min(self.width) as u16,
+            height: BOX_SIZE.min(self.height) as u16,
+        }
+    }
+
+    fn fill(&self, dst: &mut [u8], rect: DirtyRect, pixel: impl Fn(u32, u32) -> [u8; 4]) {
+        for y in rect.y as u32..(rect.y + rect.height) as u32 {
+            for x in rect.x as u32..(rect.x + rect.width) as u32 {
+                let off = ((y * self.width + x) * 4) as usize;
+                dst[off..off + 4].copy_from_slice(&pixel(x, y));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frames_change_only_tiles_under_the_box() {
+        let (w, h) = (256, 128);
+        let mut pattern = TestPattern::new(w, h);
+        let tiles = DirtyTiles::new(w, h, 16);
+        let mut frame = Vec::new();
+        assert!(pattern.next_frame(&mut frame, Some(&tiles)));
+        assert_eq!(tiles.mask_to_rects(&tiles.drain()), tiles.all_rects());
+
+        // Drive the box into the bottom edge (at y = 64) and back
+        for _ in 0..10 {
+            let prev = frame.clone();
+            let old = pattern.box_rect();
+            assert!(pattern.next_frame(&mut frame, Some(&tiles)));
+            let new = pattern.box_rect();
+            assert_eq!(new.x.abs_diff(old.x), BOX_STEP as u16);
+            assert_eq!(new.y.abs_diff(old.y), BOX_STEP as u16);
+
+            // Exactly the tiles with changed pixels are dirty, and every
+            // changed pixel is under the old or the new box
+            let expected = DirtyTiles::new(w, h, 16);
+            for y in 0..h {
+                for x in 0..w {
+                    let off = ((y * w + x) * 4) as usize;
+                    if frame[off..off + 4] != prev[off..off + 4] {
+                        let pixel = DirtyRect {
+                            x: x as u16,
+                            y: y as u16,
+                            width: 1,
+                            height: 1,
+                        };
+                        assert!(old.intersect(&pixel).or(new.intersect(&pixel)).is_some());
+                        expected.mark_rect(pixel);
+                    }
+                }
+            }
+            assert_eq!(
+                tiles.mask_to_rects(&tiles.drain()),
+                expected.mask_to_rects(&expected.drain())
+            );
+        }
+        assert!(!pattern.down, "box should have bounced off the bottom");
+
+        // A buffer holding an older frame is brought up to date too
+        let mut stale = frame.clone();
+        pattern.next_frame(&mut frame, None);
+        pattern.next_frame(&mut frame, None);
+        assert!(pattern.next_frame(&mut stale, Some(&tiles)));
+        assert_eq!(stale, pattern.frame);
+    }
+}
@@ -0,0 +1,546 @@
+use anyhow::{Context, Result};
+use rand::Rng;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Diffie-Hellman generator sent to the client.
+const DH_GENERATOR: u16 = 2;
+
+/// 1024-bit MODP prime from RFC 2409 (Oakley group 2), big-endian.
+const DH_PRIME: [u8; 128] = [
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2, 0x21, 0x68, 0xC2, 0x34,
+    0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1, 0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74,
+    0x02, 0x0B, 0xBE, 0xA6, 0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D, 0xF2, 0x5F, 0x14, 0x37,
+    0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45, 0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6,
+    0xF4, 0x4C, 0x42, 0xE9, 0xA6, 0x37, 0xED, 0x6B, 0x0B, 0xFF, 0x5C, 0xB6, 0xF4, 0x06, 0xB7, 0xED,
+    0xEE, 0x38, 0x6B, 0xFB, 0x5A, 0x89, 0x9F, 0xA5, 0xAE, 0x9F, 0x24, 0x11, 0x7C, 0x4B, 0x1F, 0xE6,
+    0x49, 0x28, 0x66, 0x51, 0xEC, 0xE6, 0x53, 0x81, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+/// Size of the encrypted credentials block: 64-byte username + 64-byte password.
+const CREDENTIALS_LEN: usize = 128;
+
+/// Perform Apple Remote Desktop authentication (Type 30).
+/// Returns Ok(true) if the password matched, Ok(false) otherwise.
+///
+/// The server sends DH parameters and its public key; the client replies with
+/// its public key and the username/password block AES-128-ECB encrypted under
+/// MD5(shared secret). There are no user accounts, so the username is ignored.
+pub async fn perform_ard_auth(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    password: &str,
+) -> Result<bool> {
+    let key_len = DH_PRIME.len();
+    let private_key: [u8; 32] = rand::rng().random();
+    let public_key = mod_pow(&[DH_GENERATOR as u8], &private_key, &DH_PRIME);
+
+    let mut msg = Vec::with_capacity(4 + 2 * key_len);
+    msg.extend_from_slice(&DH_GENERATOR.to_be_bytes());
+    msg.extend_from_slice(&(key_len as u16).to_be_bytes());
+    msg.extend_from_slice(&DH_PRIME);
+    msg.extend_from_slice(&public_key);
+    stream
+        .write_all(&msg)
+        .await
+        .context("send ARD auth parameters")?;
+
+    let mut response = vec![0u8; CREDENTIALS_LEN + key_len];
+    stream
+        .read_exact(&mut response)
+        .await
+        .context("read ARD auth response")?;
+    let (ciphertext, client_public) = response.split_at(CREDENTIALS_LEN);
+
+    let shared = mod_pow(client_public, &private_key, &DH_PRIME);
+    let aes_key = md5(&shared);
+    let round_keys = aes128_expand_key(&aes_key);
+
+    let mut credentials = [0u8; CREDENTIALS_LEN];
+    credentials.copy_from_slice(ciphertext);
+    for block in credentials.chunks_exact_mut(16) {
+        aes128_decrypt_block(&round_keys, block.try_into().unwrap());
+    }
+
+    let Some(expected) = credentials_field(password.as_bytes()) else {
+        return Ok(false);
+    };
+    let client_password = credentials_field(&credentials[64..]).unwrap();
+    Ok(constant_time_eq(&client_password, &expected))
+}
+
+/// `bytes` up to the first NUL, zero-padded to a 64-byte credentials field;
+/// `None` if that doesn't fit. The client NUL-terminates both fields and may
+/// put anything after the NUL.
+fn credentials_field(bytes: &[u8]) -> Option<[u8; 64]> {
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    let mut field = [0u8; 64];
+    field.get_mut(..len)?.copy_from_slice(&bytes[..len]);
+    Some(field)
+}
+
+/// Compare secrets without stopping at the first difference, so the time
+/// taken doesn't tell a client how much of its guess was right.
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let diff = a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y));
+    a.len() == b.len() && std::hint::black_box(diff) == 0
+}
+
+// ---------------------------------------------------------------------------
+// Big-integer modular exponentiation (Montgomery, 32-bit limbs)
+// ---------------------------------------------------------------------------
+
+/// Compute `base^exp mod modulus` for big-endian byte strings.
+/// `modulus` must be odd. The result is big-endian, padded to `modulus.len()`.
+fn mod_pow(base: &[u8], exp: &[u8], modulus: &[u8]) -> Vec<u8> {
+    let n = limbs_from_be(modulus);
+    let len = n.len();
+    let n_inv = mont_inverse(n[0]);
+
+    // R^2 mod n, by doubling 1 for 2 * 32 * len steps
+    let mut r2 = vec![0u32; len];
+    r2[0] = 1;
+    for _ in 0..64 * len {
+        let carry = shl1(&mut r2);
+        if carry || !less_than(&r2, &n) {
+            sub_in_place(&mut r2, &n);
+        }
+    }
+
+    let mut b = limbs_from_be(base);
+    b.resize(len.max(b.len()), 0);
+    let b = reduce(&b, &n);
+    let b = mont_mul(&b, &r2, &n, n_inv);
+
+    let mut one = vec![0u32; len];
+    one[0] = 1;
+    let mut acc = mont_mul(&one, &r2, &n, n_inv);
+
+    for &byte in exp {
+        for bit in (0..8).rev() {
+            acc = mont_mul(&acc, &acc, &n, n_inv);
+            if byte & (1 << bit) != 0 {
+                acc = mont_mul(&acc, &b, &n, n_inv);
+            }
+        }
+    }
+    let result = mont_mul(&acc, &one, &n, n_inv);
+    limbs_to_be(&result, modulus.len())
+}
+
+fn limbs_from_be(bytes: &[u8]) -> Vec<u32> {
+    let mut limbs = vec![0u32; bytes.len().div_ceil(4).max(1)];
+    for (i, &b) in bytes.iter().rev().enumerate() {
+        limbs[i / 4] |= (b as u32) << (8 * (i % 4));
+    }
+    limbs
+}
+
+fn limbs_to_be(limbs: &[u32], len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    for (i, byte) in out.iter_mut().rev().enumerate() {
+        *byte = limbs.get(i / 4).map_or(0, |l| (l >> (8 * (i % 4))) as u8);
+    }
+    out
+}
+
+/// `-n0^-1 mod 2^32` via Newton iteration.
+fn mont_inverse(n0: u32) -> u32 {
+    let mut inv = 1u32;
+    for _ in 0..5 {
+        inv = inv.wrapping_mul(2u32.wrapping_sub(n0.wrapping_mul(inv)));
+    }
+    inv.wrapping_neg()
+}
+
+/// Montgomery product `a * b * R^-1 mod n` (CIOS).
+fn mont_mul(a: &[u32], b: &[u32], n: &[u32], n_inv: u32) -> Vec<u32> {
+    let len = n.len();
+    let mut t = vec![0u32; len + 2];
+    for &ai in a.iter().take(len) {
+        let mut carry = 0u64;
+        for j in 0..len {
+            let v = t[j] as u64 + ai as u64 * b[j] as u64 + carry;
+            t[j] = v as u32;
+            carry = v >> 32;
+        }
+        let v = t[len] as u64 + carry;
+        t[len] = v as u32;
+        t[len + 1] = (v >> 32) as u32;
+
+        let m = t[0].wrapping_mul(n_inv);
+        let v = t[0] as u64 + m as u64 * n[0] as u64;
+        let mut carry = v >> 32;
+        for j in 1..len {
+            let v = t[j] as u64 + m as u64 * n[j] as u64 + carry;
+            t[j - 1] = v as u32;
+            carry = v >> 32;
+        }
+        let v = t[len] as u64 + carry;
+        t[len - 1] = v as u32;
+        t[len] = t[len + 1] + (v >> 32) as u32;
+    }
+    let overflow = t[len] != 0;
+    t.truncate(len);
+    if overflow || !less_than(&t, n) {
+        sub_in_place(&mut t, n);
+    }
+    t
+}
+
+/// Reduce an arbitrary-length value modulo `n` by shift-and-subtract.
+fn reduce(a: &[u32], n: &[u32]) -> Vec<u32> {
+    let mut r = vec![0u32; n.len()];
+    for &limb in a.iter().rev() {
+        for bit in (0..32).rev() {
+            let carry = shl1(&mut r);
+            r[0] |= (limb >> bit) & 1;
+            if carry || !less_than(&r, n) {
+                sub_in_place(&mut r, n);
+            }
+        }
+    }
+    r
+}
+
+fn shl1(a: &mut [u32]) -> bool {
+    let mut carry = 0u32;
+    for limb in a.iter_mut() {
+        let next = *limb >> 31;
+        *limb = (*limb << 1) | carry;
+        carry = next;
+    }
+    carry != 0
+}
+
+fn less_than(a: &[u32], b: &[u32]) -> bool {
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x < y;
+        }
+    }
+    false
+}
+
+fn sub_in_place(a: &mut [u32], b: &[u32]) {
+    let mut borrow = 0i64;
+    for (x, &y) in a.iter_mut().zip(b) {
+        let v = *x as i64 - y as i64 - borrow;
+        *x = v as u32;
+        borrow = (v < 0) as i64;
+    }
+}
+
+// ---------------------------------------------------------------------------
+// MD5 (RFC 1321)
+// ---------------------------------------------------------------------------
+
+fn md5(data: &[u8]) -> [u8; 16] {
+    const S: [u32; 64] = [
+        7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 7, 12, 17, 22, 5, 9, 14, 20, 5, 9, 14, 20, 5,
+        9, 14, 20, 5, 9, 14, 20, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 4, 11, 16, 23, 6, 10,
+        15, 21, 6, 10, 15, 21, 6, 10, 15, 21, 6, 10, 15, 21,
+    ];
+    let k: [u32; 64] =
+        std::array::from_fn(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32);
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_le_bytes());
+
+    let mut h: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for chunk in msg.chunks_exact(64) {
+        let m: [u32; 16] = std::array::from_fn(|i| {
+            u32::from_le_bytes(chunk[i * 4..i * 4 + 4].try_into().unwrap())
+        });
+        let [mut a, mut b, mut c, mut d] = h;
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let f = f.wrapping_add(a).wrapping_add(k[i]).wrapping_add(m[g]);
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(f.rotate_left(S[i]));
+        }
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+    }
+
+    let mut out = [0u8; 16];
+    for (i, word) in h.iter().enumerate() {
+        out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    out
+}
+
+// ---------------------------------------------------------------------------
+// AES-128 decryption (FIPS 197)
+// ---------------------------------------------------------------------------
+
+/// Multiply in GF(2^8) with the AES polynomial.
+fn gmul(mut a: u8, mut b: u8) -> u8 {
+    let mut p = 0u8;
+    while b != 0 {
+        if b & 1 != 0 {
+            p ^= a;
+        }
+        let hi = a & 0x80;
+        a <<= 1;
+        if hi != 0 {
+            a ^= 0x1b;
+        }
+        b >>= 1;
+    }
+    p
+}
+
+/// AES S-box and its inverse, derived from the GF(2^8) inverse + affine map.
+fn sboxes() -> ([u8; 256], [u8; 256]) {
+    let mut sbox = [0u8; 256];
+    let mut inv = [0u8; 256];
+    for x in 0..=255u8 {
+        // x^254 = x^-1 in GF(2^8) (0 maps to 0)
+        let mut xi = 1u8;
+        for _ in 0..254 {
+            xi = gmul(xi, x);
+        }
+        let s = xi
+            ^ xi.rotate_left(1)
+            ^ xi.rotate_left(2)
+            ^ xi.rotate_left(3)
+            ^ xi.rotate_left(4)
+            ^ 0x63;
+        sbox[x as usize] = s;
+        inv[s as usize] = x;
+    }
+    (sbox, inv)
+}
+
+struct Aes128Keys {
+    round_keys: [[u8; 16]; 11],
+    inv_sbox: [u8; 256],
+}
+
+fn aes128_expand_key(key: &[u8; 16]) -> Aes128Keys {
+    let (sbox, inv_sbox) = sboxes();
+    let mut w = [[0u8; 4]; 44];
+    for (i, word) in w.iter_mut().take(4).enumerate() {
+        word.copy_from_slice(&key[i * 4..i * 4 + 4]);
+    }
+    let mut rcon = 1u8;
+    for i in 4..44 {
+        let mut t = w[i - 1];
+        if i % 4 == 0 {
+            t.rotate_left(1);
+            for b in &mut t {
+                *b = sbox[*b as usize];
+            }
+            t[0] ^= rcon;
+            rcon = gmul(rcon, 2);
+        }
+        for j in 0..4 {
+            w[i][j] = w[i - 4][j] ^ t[j];
+        }
+    }
+    let round_keys = std::array::from_fn(|r| {
+        let mut k = [0u8; 16];
+        for c in 0..4 {
+            k[c * 4..c * 4 + 4].copy_from_slice(&w[r * 4 + c]);
+        }
+        k
+    });
+    Aes128Keys {
+        round_keys,
+        inv_sbox,
+    }
+}
+
+fn aes128_decrypt_block(keys: &Aes128Keys, block: &mut [u8; 16]) {
+    let add_round_key = |s: &mut [u8; 16], r: usize| {
+        for (b, k) in s.iter_mut().zip(&keys.round_keys[r]) {
+            *b ^= k;
+        }
+    };
+    // Inverse ShiftRows + inverse SubBytes (state is column-major: s[r + 4c])
+    let inv_shift_sub = |s: &mut [u8; 16]| {
+        let old = *s;
+        for r in 0..4 {
+            for c in 0..4 {
+                s[r + 4 * c] = keys.inv_sbox[old[r + 4 * ((c + 4 - r) % 4)] as usize];
+            }
+        }
+    };
+
+    add_round_key(block, 10);
+    for round in (1..10).rev() {
+        inv_shift_sub(block);
+        add_round_key(block, round);
+        for col in block.chunks_exact_mut(4) {
+            let [a, b, c, d] = [col[0], col[1], col[2], col[3]];
+            col[0] = gmul(a, 14) ^ gmul(b, 11) ^ gmul(c, 13) ^ gmul(d, 9);
+            col[1] = gmul(a, 9) ^ gmul(b, 14) ^ gmul(c, 11) ^ gmul(d, 13);
+            col[2] = gmul(a, 13) ^ gmul(b, 9) ^ gmul(c, 14) ^ gmul(d, 11);
+            col[3] = gmul(a, 11) ^ gmul(b, 13) ^ gmul(c, 9) ^ gmul(d, 14);
+        }
+    }
+    inv_shift_sub(block);
+    add_round_key(block, 0);
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn hex(bytes: &[u8]) -> String {
+        bytes.iter().map(|b| format!("{b:02x}")).collect()
+    }
+
+    /// AES-128 encryption of one block, the client's half of the cipher.
+    fn aes128_encrypt_block(keys: &Aes128Keys, block: &mut [u8; 16]) {
+        let (sbox, _) = sboxes();
+        let add_round_key = |s: &mut [u8; 16], r: usize| {
+            for (b, k) in s.iter_mut().zip(&keys.round_keys[r]) {
+                *b ^= k;
+            }
+        };
+        let sub_shift = |s: &mut [u8; 16]| {
+            let old = *s;
+            for r in 0..4 {
+                for c in 0..4 {
+                    s[r + 4 * c] = sbox[old[r + 4 * ((c + r) % 4)] as usize];
+                }
+            }
+        };
+
+        add_round_key(block, 0);
+        for round in 1..10 {
+            sub_shift(block);
+            for col in block.chunks_exact_mut(4) {
+                let [a, b, c, d] = [col[0], col[1], col[2], col[3]];
+                col[0] = gmul(a, 2) ^ gmul(b, 3) ^ c ^ d;
+                col[1] = a ^ gmul(b, 2) ^ gmul(c, 3) ^ d;
+                col[2] = a ^ b ^ gmul(c, 2) ^ gmul(d, 3);
+                col[3] = gmul(a, 3) ^ b ^ c ^ gmul(d, 2);
+            }
+            add_round_key(block, round);
+        }
+        sub_shift(block);
+        add_round_key(block, 10);
+    }
+
+    /// What an ARD client answers to the server's `params` (generator, key
+    /// length, prime and public key): the encrypted credentials block, then
+    /// its own public key. The fields are padded with junk after their NUL,
+    /// as some clients do.
+    pub(crate) fn client_response(params: &[u8], username: &str, password: &str) -> Vec<u8> {
+        let key_len = u16::from_be_bytes([params[2], params[3]]) as usize;
+        let prime = &params[4..4 + key_len];
+        let server_public = &params[4 + key_len..4 + 2 * key_len];
+
+        let private_key: [u8; 32] = rand::rng().random();
+        let public_key = mod_pow(&params[..2], &private_key, prime);
+        let shared = mod_pow(server_public, &private_key, prime);
+        let keys = aes128_expand_key(&md5(&shared));
+
+        let mut credentials = [0xa5u8; CREDENTIALS_LEN];
+        for (field, value) in credentials.chunks_exact_mut(64).zip([username, password]) {
+            field[..value.len()].copy_from_slice(value.as_bytes());
+            field[value.len()] = 0;
+        }
+        for block in credentials.chunks_exact_mut(16) {
+            aes128_encrypt_block(&keys, block.try_into().unwrap());
+        }
+        [&credentials[..], &public_key].concat()
+    }
+
+    #[test]
+    fn md5_rfc1321_test_suite() {
+        let vectors: [(&str, &str); 7] = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("a", "0cc175b9c0f1b6a831c399e269772661"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                "c3fcd3d76192e4007dfb496cca67e13b",
+            ),
+            (
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "d174ab98d277d9f5a5611c2c9f419d9f",
+            ),
+            (
+                "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ];
+        for (input, digest) in vectors {
+            assert_eq!(hex(&md5(input.as_bytes())), digest, "MD5({input:?})");
+        }
+    }
+
+    #[test]
+    fn aes128_fips197_vector() {
+        // FIPS-197 appendix C.1
+        let key: [u8; 16] = std::array::from_fn(|i| i as u8);
+        let plaintext: [u8; 16] = std::array::from_fn(|i| (i as u8) * 0x11);
+        let keys = aes128_expand_key(&key);
+
+        let mut block = plaintext;
+        aes128_encrypt_block(&keys, &mut block);
+        assert_eq!(hex(&block), "69c4e0d86a7b0430d8cdb78070b4c55a");
+        aes128_decrypt_block(&keys, &mut block);
+        assert_eq!(block, plaintext);
+    }
+
+    #[test]
+    fn mod_pow_known_answers() {
+        // 4^13 mod 497 = 445, padded to the modulus length
+        assert_eq!(mod_pow(&[4], &[13], &[0x01, 0xf1]), [0x01, 0xbd]);
+        // Fermat: 2^(p-1) = 1 mod p for the DH prime
+        let mut exp = DH_PRIME;
+        exp[127] -= 1;
+        let one = mod_pow(&[2], &exp, &DH_PRIME);
+        assert_eq!(one[..127], [0; 127]);
+        assert_eq!(one[127], 1);
+        // Both sides of the exchange arrive at the same secret
+        let (a, b) = ([0x3c; 32], [0xc3; 32]);
+        let (ga, gb) = (mod_pow(&[2], &a, &DH_PRIME), mod_pow(&[2], &b, &DH_PRIME));
+        assert_eq!(mod_pow(&gb, &a, &DH_PRIME), mod_pow(&ga, &b, &DH_PRIME));
+    }
+
+    #[test]
+    fn credentials_compare_whole_fields() {
+        let field = |s: &[u8]| credentials_field(s).unwrap();
+        assert!(constant_time_eq(&field(b"secret\0junk"), &field(b"secret")));
+        assert!(!constant_time_eq(&field(b"secre"), &field(b"secret")));
+        assert!(!constant_time_eq(&field(b"secrets"), &field(b"secret")));
+        assert!(!constant_time_eq(b"secret", b"secret!"));
+        // A password with no room for the NUL can't be entered
+        assert!(credentials_field(&[b'x'; 65]).is_none());
+    }
+
+    #[tokio::test]
+    async fn ard_handshake_over_duplex() {
+        for (password, accepted) in [("secret", true), ("secre", false), ("wrong!", false)] {
+            let (mut client, mut server) = tokio::io::duplex(4096);
+            let server = tokio::spawn(async move { perform_ard_auth(&mut server, "secret").await });
+
+            let mut params = vec![0u8; 4 + 2 * DH_PRIME.len()];
+            client.read_exact(&mut params).await.unwrap();
+            assert_eq!(params[..4], [0, 2, 0, 128]);
+            assert_eq!(params[4..132], DH_PRIME);
+            let response = client_response(&params, "anyone", password);
+            client.write_all(&response).await.unwrap();
+            assert_eq!(server.await.unwrap().unwrap(), accepted, "{password:?}");
+        }
+    }
+}
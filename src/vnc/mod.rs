@@ -1 +1,7 @@
+pub mod ard;
+pub mod repeater;
+pub mod rre;
 pub mod server;
+pub mod websocket;
+pub mod zrle;
+pub mod zstd;
@@ -0,0 +1,97 @@
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use anyhow::{Context, Result};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+
+/// Size of the ID message an UltraVNC repeater reads from a server.
+const ID_MESSAGE_LEN: usize = 250;
+/// Longest ID that fits the message after "ID:" and a terminating NUL.
+const MAX_ID_LEN: usize = ID_MESSAGE_LEN - 4;
+
+/// The ID a server registers under with a repeater (`--repeater-id`);
+/// viewers connect to the repeater with the same ID.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RepeaterId(String);
+
+impl RepeaterId {
+    /// "ID:<id>", NUL-padded to the fixed message size.
+    fn message(&self) -> [u8; ID_MESSAGE_LEN] {
+        let mut msg = [0u8; ID_MESSAGE_LEN];
+        let text = format!("ID:{}", self.0);
+        msg[..text.len()].copy_from_slice(text.as_bytes());
+        msg
+    }
+}
+
+impl fmt::Display for RepeaterId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for RepeaterId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err("empty repeater ID".to_string());
+        }
+        if s.len() > MAX_ID_LEN {
+            return Err(format!("repeater ID longer than {MAX_ID_LEN} bytes"));
+        }
+        if s.contains('\0') {
+            return Err("repeater ID contains a NUL byte".to_string());
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// Connect to the repeater at `addr` (`host:port`) and register as the
+/// server for `id`. The repeater holds the connection until a viewer asks
+/// for the same ID and then bridges the two, so the RFB handshake runs
+/// over it as over an accepted connection.
+pub async fn connect(addr: &str, id: &RepeaterId) -> Result<(TcpStream, SocketAddr)> {
+    let mut stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Cannot connect to repeater {addr}"))?;
+    let peer = stream.peer_addr().context("repeater address")?;
+    stream
+        .write_all(&id.message())
+        .await
+        .context("send repeater ID")?;
+    Ok((stream, peer))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn ids_are_validated() {
+        assert!("1234".parse::<RepeaterId>().is_ok());
+        assert!("".parse::<RepeaterId>().is_err());
+        assert!("a\0b".parse::<RepeaterId>().is_err());
+        assert!("x".repeat(MAX_ID_LEN).parse::<RepeaterId>().is_ok());
+        assert!("x".repeat(MAX_ID_LEN + 1).parse::<RepeaterId>().is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_sends_padded_id() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let id = "1234".parse().unwrap();
+        let (_stream, peer) = connect(&addr.to_string(), &id).await.unwrap();
+        assert_eq!(peer, addr);
+
+        let (mut repeater, _) = listener.accept().await.unwrap();
+        let mut msg = [0u8; ID_MESSAGE_LEN];
+        repeater.read_exact(&mut msg).await.unwrap();
+        assert_eq!(&msg[..8], b"ID:1234\0");
+        assert!(msg[8..].iter().all(|&b| b == 0));
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use crate::frame_diff::DirtyRect;
+
+/// RRE encoding number.
+pub const ENC_RRE: i32 = 2;
+
+/// Append `rect` as an RRE rectangle (header included) if that is smaller
+/// than Raw. Returns `false`, leaving `out` untouched, when it isn't.
+///
+/// The most frequent colour becomes the background; every other colour is
+/// covered by subrectangles built from horizontal runs, extended downwards
+/// while the next row repeats the same run. `put_pixel` appends one BGRA
+/// pixel in the client's format, `bytes_pp` bytes long.
+pub fn encode_rect(
+    out: &mut Vec<u8>,
+    frame: &[u8],
+    stride: usize,
+    rect: &DirtyRect,
+    bytes_pp: usize,
+    put_pixel: impl Fn(&mut Vec<u8>, [u8; 4]),
+) -> bool {
+    let (w, h) = (rect.width as usize, rect.height as usize);
+    let pixel_at = |x: usize, y: usize| {
+        let off = (rect.y as usize + y) * stride + (rect.x as usize + x) * 4;
+        // Compare colour only: the fourth byte is padding
+        u32::from_le_bytes([frame[off], frame[off + 1], frame[off + 2], 0])
+    };
+
+    let mut counts: HashMap<u32, usize> = HashMap::new();
+    for y in 0..h {
+        for x in 0..w {
+            *counts.entry(pixel_at(x, y)).or_default() += 1;
+        }
+    }
+    let Some((&bg, _)) = counts.iter().max_by_key(|(_, &n)| n) else {
+        return false;
+    };
+
+    // Give up as soon as the subrectangles outgrow the Raw payload
+    let max_subrects = (w * h * bytes_pp).saturating_sub(4 + bytes_pp) / (bytes_pp + 8);
+
+    // Finished subrects, and the runs of the previous row still growing down
+    let mut subrects: Vec<(u32, u16, u16, u16, u16)> = Vec::new();
+    let mut open: Vec<(u32, u16, u16, u16, u16)> = Vec::new();
+    for y in 0..h {
+        let mut row_runs = Vec::new();
+        let mut x = 0;
+        while x < w {
+            let color = pixel_at(x, y);
+            let start = x;
+            while x < w && pixel_at(x, y) == color {
+                x += 1;
+            }
+            if color != bg {
+                row_runs.push((color, start as u16, (x - start) as u16));
+            }
+        }
+
+        let mut still_open = Vec::with_capacity(row_runs.len());
+        for (color, rx, rw) in row_runs {
+            match open
+                .iter()
+                .position(|&(c, ox, _, ow, _)| c == color && ox == rx && ow == rw)
+            {
+                Some(i) => {
+                    let mut sr = open.swap_remove(i);
+                    sr.4 += 1;
+                    still_open.push(sr);
+                }
+                None => still_open.push((color, rx, y as u16, rw, 1)),
+            }
+        }
+        // Runs not continued in this row are complete
+        subrects.append(&mut open);
+        open = still_open;
+        if subrects.len() + open.len() > max_subrects {
+            return false;
+        }
+    }
+    subrects.append(&mut open);
+
+    out.extend_from_slice(&rect.x.to_be_bytes());
+    out.extend_from_slice(&rect.y.to_be_bytes());
+    out.extend_from_slice(&rect.width.to_be_bytes());
+    out.extend_from_slice(&rect.height.to_be_bytes());
+    out.extend_from_slice(&ENC_RRE.to_be_bytes());
+    out.extend_from_slice(&(subrects.len() as u32).to_be_bytes());
+    put_pixel(out, bgra(bg));
+    for (color, x, y, w, h) in subrects {
+        put_pixel(out, bgra(color));
+        for v in [x, y, w, h] {
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+    true
+}
+
+fn bgra(color: u32) -> [u8; 4] {
+    let [b, g, r, _] = color.to_le_bytes();
+    [b, g, r, 0xFF]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put_raw(out: &mut Vec<u8>, px: [u8; 4]) {
+        out.extend_from_slice(&px);
+    }
+
+    #[test]
+    fn solid_rect_is_background_only() {
+        let (w, h) = (16usize, 8usize);
+        let frame = [0x10, 0x20, 0x30, 0x00].repeat(w * h);
+        let rect = DirtyRect {
+            x: 0,
+            y: 0,
+            width: w as u16,
+            height: h as u16,
+        };
+
+        let mut out = Vec::new();
+        assert!(encode_rect(&mut out, &frame, w * 4, &rect, 4, put_raw));
+
+        let mut expected = vec![0, 0, 0, 0, 0, 16, 0, 8]; // x, y, width, height
+        expected.extend_from_slice(&ENC_RRE.to_be_bytes());
+        expected.extend_from_slice(&0u32.to_be_bytes()); // no subrectangles
+        expected.extend_from_slice(&[0x10, 0x20, 0x30, 0xFF]); // background
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn noisy_rect_falls_back_to_raw() {
+        let (w, h) = (8usize, 8usize);
+        let frame: Vec<u8> = (0..w * h).flat_map(|i| [i as u8, 0, 0, 0]).collect();
+        let rect = DirtyRect {
+            x: 0,
+            y: 0,
+            width: w as u16,
+            height: h as u16,
+        };
+
+        let mut out = Vec::new();
+        assert!(!encode_rect(&mut out, &frame, w * 4, &rect, 4, put_raw));
+        assert!(out.is_empty());
+    }
+}
@@ -1,20 +1,479 @@
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
 
 use anyhow::{bail, Context, Result};
 use cipher::{BlockEncrypt, KeyInit};
 use des::Des;
 use rand::Rng;
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
-use tokio::net::TcpStream;
-use tokio::sync::{mpsc, watch};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::sync::{broadcast, mpsc, watch};
 
-use crate::frame_diff::{self, DirtyTiles};
+use crate::audit::AuditSession;
+use crate::config::SecurityType;
+use crate::frame_diff::DirtyRect;
+use crate::frame_hub::FrameHub;
+
+use super::ard;
+use super::rre::{self, ENC_RRE};
+use super::zrle::{ZrleEncoder, ENC_ZRLE};
+use super::zstd::{ZstdEncoder, ENC_ZSTD};
 
 /// Input event forwarded from VNC client to the input subsystem.
 #[derive(Debug, Clone)]
 pub enum InputEvent {
-    Pointer { button_mask: u8, x: u16, y: u16 },
-    Key { down: bool, keysym: u32 },
+    Pointer {
+        button_mask: u8,
+        x: u16,
+        y: u16,
+    },
+    Key {
+        down: bool,
+        keysym: u32,
+    },
+    /// QEMU extended key event: `keycode` is the XT scancode of the
+    /// physical key, with an 0xE0 prefix folded into the high bit.
+    ExtendedKey {
+        down: bool,
+        keysym: u32,
+        keycode: u32,
+    },
+}
+
+/// Keys one client holds down, released for it when it disconnects: a
+/// modifier whose up event never arrived would stick otherwise. Keys held
+/// through other clients stay down.
+#[derive(Default)]
+struct HeldKeys {
+    keysyms: HashSet<u32>,
+    /// Keysym of each held extended key event, by scancode.
+    scancodes: HashMap<u32, u32>,
+}
+
+impl HeldKeys {
+    fn record(&mut self, event: &InputEvent) {
+        match *event {
+            InputEvent::Key { down, keysym } if down => {
+                self.keysyms.insert(keysym);
+            }
+            InputEvent::Key { keysym, .. } => {
+                self.keysyms.remove(&keysym);
+            }
+            InputEvent::ExtendedKey {
+                down,
+                keysym,
+                keycode,
+            } if down => {
+                self.scancodes.insert(keycode, keysym);
+            }
+            InputEvent::ExtendedKey { keycode, .. } => {
+                self.scancodes.remove(&keycode);
+            }
+            InputEvent::Pointer { .. } => {}
+        }
+    }
+
+    /// Up events for every key still held, which are then forgotten.
+    fn releases(&mut self) -> Vec<InputEvent> {
+        let keys = self.keysyms.drain().map(|keysym| InputEvent::Key {
+            down: false,
+            keysym,
+        });
+        let scancodes = self
+            .scancodes
+            .drain()
+            .map(|(keycode, keysym)| InputEvent::ExtendedKey {
+                down: false,
+                keysym,
+                keycode,
+            });
+        keys.chain(scancodes).collect()
+    }
+}
+
+/// Raw encoding number.
+const ENC_RAW: i32 = 0;
+
+/// Pseudo-encoding: client supports the ContinuousUpdates extension.
+const ENC_CONTINUOUS_UPDATES: i32 = -313;
+
+/// Pseudo-encoding: client supports the Fence extension.
+const ENC_FENCE: i32 = -312;
+/// Pseudo-encoding: client accepts updates terminated by a LastRect marker.
+const ENC_LAST_RECT: i32 = -224;
+/// Pseudo-encoding: client shows the server's keyboard lock LEDs.
+const ENC_LED_STATE: i32 = -261;
+/// Pseudo-encoding: client accepts a new desktop name mid-session.
+const ENC_DESKTOP_NAME: i32 = -307;
+/// Pseudo-encoding: client takes the screen layout and answers to
+/// SetDesktopSize as ExtendedDesktopSize rectangles.
+const ENC_EXTENDED_DESKTOP_SIZE: i32 = -308;
+/// Pseudo-encoding: client can send QEMU extended key events.
+const ENC_QEMU_EXTENDED_KEY: i32 = -258;
+
+/// How long the client's socket may accept no part of a message before the
+/// client is considered stuck and disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Server message: Bell.
+const MSG_BELL: u8 = 2;
+/// Server message: SetColourMapEntries.
+const MSG_SET_COLOUR_MAP_ENTRIES: u8 = 1;
+/// Server message: EndOfContinuousUpdates.
+const MSG_END_OF_CONTINUOUS_UPDATES: u8 = 150;
+/// Server/client message: Fence.
+const MSG_FENCE: u8 = 248;
+/// Client message: SetDesktopSize.
+const MSG_SET_DESKTOP_SIZE: u8 = 251;
+/// Client message: QEMU client message, subtype 0 being an extended key event.
+const MSG_QEMU: u8 = 255;
+
+/// ExtendedDesktopSize status: the server does not allow resizing.
+const RESIZE_PROHIBITED: u16 = 1;
+
+/// Fence flag: process all prior messages before handling the fence.
+const FENCE_BLOCK_BEFORE: u32 = 1 << 0;
+/// Fence flag: don't process later messages until the fence is handled.
+const FENCE_BLOCK_AFTER: u32 = 1 << 1;
+/// Fence flag: this is a request the peer must answer.
+const FENCE_REQUEST: u32 = 1 << 31;
+
+/// When an incremental update is sent as one rect, the bounding box of its
+/// changed rects, instead of the rects themselves
+/// (`--full-refresh-threshold`). Changes scattered over an area cost a rect
+/// header each and encode worse than a single large rect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FullRefreshThreshold {
+    Off,
+    /// Once the rects cover more than this fraction of their bounding box.
+    Coverage(f32),
+    /// Once there are more than this many rects.
+    Rects(usize),
+}
+
+impl FullRefreshThreshold {
+    /// The bounding box of `rects` if they are better sent as that.
+    fn merged(self, rects: &[DirtyRect]) -> Option<DirtyRect> {
+        let (first, rest) = rects.split_first()?;
+        let bounds = rest.iter().fold(*first, |acc, r| acc.union(r));
+        let merge = match self {
+            Self::Off => false,
+            Self::Coverage(fraction) => {
+                let covered: u64 = rects.iter().map(|r| r.width as u64 * r.height as u64).sum();
+                covered as f32 > fraction * (bounds.width as u64 * bounds.height as u64) as f32
+            }
+            Self::Rects(max) => rects.len() > max,
+        };
+        (merge && rects.len() > 1).then_some(bounds)
+    }
+}
+
+impl std::str::FromStr for FullRefreshThreshold {
+    type Err = String;
+
+    /// `N%` for coverage, a plain number for a rect count, 0 for off.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(percent) = s.strip_suffix('%') {
+            return match percent.parse::<f32>() {
+                Ok(p) if p > 0.0 && p <= 100.0 => Ok(Self::Coverage(p / 100.0)),
+                _ => Err(format!("coverage must be between 0% and 100%, not {s:?}")),
+            };
+        }
+        match s.parse() {
+            Ok(0) => Ok(Self::Off),
+            Ok(n) => Ok(Self::Rects(n)),
+            Err(_) => Err(format!(
+                "expected a percentage (50%) or a rect count, not {s:?}"
+            )),
+        }
+    }
+}
+
+/// Encodings and pseudo-encodings advertised by the client in SetEncodings.
+#[derive(Clone, Debug, Default)]
+struct ClientEncodings {
+    /// First encoding in the client's list that we implement (Raw if none).
+    preferred: i32,
+    continuous_updates: bool,
+    fence: bool,
+    last_rect: bool,
+    led_state: bool,
+    desktop_name: bool,
+    extended_desktop_size: bool,
+    qemu_extended_key: bool,
+}
+
+impl ClientEncodings {
+    fn from_list(encodings: &[i32]) -> Self {
+        Self {
+            preferred: encodings
+                .iter()
+                .copied()
+                .find(|e| [ENC_ZSTD, ENC_ZRLE, ENC_RRE, ENC_RAW].contains(e))
+                .unwrap_or(ENC_RAW),
+            continuous_updates: encodings.contains(&ENC_CONTINUOUS_UPDATES),
+            fence: encodings.contains(&ENC_FENCE),
+            last_rect: encodings.contains(&ENC_LAST_RECT),
+            led_state: encodings.contains(&ENC_LED_STATE),
+            desktop_name: encodings.contains(&ENC_DESKTOP_NAME),
+            extended_desktop_size: encodings.contains(&ENC_EXTENDED_DESKTOP_SIZE),
+            qemu_extended_key: encodings.contains(&ENC_QEMU_EXTENDED_KEY),
+        }
+    }
+
+    /// Whether Raw is the best encoding the client accepts, so the shared
+    /// Raw update can be forwarded as is.
+    fn raw_only(&self) -> bool {
+        self.preferred == ENC_RAW
+    }
+}
+
+/// A client's compression streams, each created when the client first
+/// selects its encoding and kept for the connection.
+#[derive(Default)]
+struct Streams {
+    zrle: Option<ZrleEncoder>,
+    zstd: Option<ZstdEncoder>,
+}
+
+impl Streams {
+    /// Create the stream `encoding` needs, if it needs one.
+    fn prepare(&mut self, encoding: i32) {
+        match encoding {
+            ENC_ZRLE => {
+                self.zrle.get_or_insert_with(ZrleEncoder::new);
+            }
+            ENC_ZSTD => {
+                self.zstd.get_or_insert_with(ZstdEncoder::new);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Non-input client messages the writer loop has to act on.
+enum ClientControl {
+    SetEncodings(ClientEncodings),
+    EnableContinuousUpdates { enable: bool, region: DirtyRect },
+    Fence { flags: u32, payload: Vec<u8> },
+    SetDesktopSize { width: u16, height: u16 },
+}
+
+/// Build a Fence message.
+fn fence_message(flags: u32, payload: &[u8]) -> Vec<u8> {
+    let mut msg = Vec::with_capacity(9 + payload.len());
+    msg.extend_from_slice(&[MSG_FENCE, 0, 0, 0]);
+    msg.extend_from_slice(&flags.to_be_bytes());
+    msg.push(payload.len() as u8);
+    msg.extend_from_slice(payload);
+    msg
+}
+
+/// Build a FramebufferUpdate carrying only a LED State pseudo-rectangle.
+fn led_state_message(leds: u8) -> Vec<u8> {
+    let mut msg = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+    msg.extend_from_slice(&ENC_LED_STATE.to_be_bytes());
+    msg.push(leds);
+    msg
+}
+
+/// Build a FramebufferUpdate carrying only a DesktopName pseudo-rectangle.
+fn desktop_name_message(name: &str) -> Vec<u8> {
+    let mut msg = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+    msg.extend_from_slice(&ENC_DESKTOP_NAME.to_be_bytes());
+    msg.extend_from_slice(&(name.len() as u32).to_be_bytes());
+    msg.extend_from_slice(name.as_bytes());
+    msg
+}
+
+/// Build a FramebufferUpdate carrying only an ExtendedDesktopSize
+/// pseudo-rectangle: the `width` x `height` framebuffer as a single screen.
+/// `reason` is 0 for a server announcement or 1 to answer this client's
+/// SetDesktopSize, with `status` the outcome.
+fn extended_desktop_size_message(reason: u16, status: u16, width: u16, height: u16) -> Vec<u8> {
+    let mut msg = vec![0, 0, 0, 1];
+    for value in [reason, status, width, height] {
+        msg.extend_from_slice(&value.to_be_bytes());
+    }
+    msg.extend_from_slice(&ENC_EXTENDED_DESKTOP_SIZE.to_be_bytes());
+    msg.extend_from_slice(&[1, 0, 0, 0]); // number of screens, padding
+    msg.extend_from_slice(&[0; 8]); // screen ID 0 at x 0, y 0
+    msg.extend_from_slice(&width.to_be_bytes());
+    msg.extend_from_slice(&height.to_be_bytes());
+    msg.extend_from_slice(&[0; 4]); // flags
+    msg
+}
+
+/// Build a FramebufferUpdate carrying only a QEMU Extended Key Event
+/// pseudo-rectangle, telling the client it may send extended key events.
+fn qemu_extended_key_message() -> Vec<u8> {
+    let mut msg = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+    msg.extend_from_slice(&ENC_QEMU_EXTENDED_KEY.to_be_bytes());
+    msg
+}
+
+/// Write half wrapper that counts the bytes accepted by the socket.
+struct CountingWriter<W> {
+    inner: W,
+    bytes: u64,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for CountingWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.bytes += n as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Write and flush one message, giving up once no part of it has been
+/// accepted for `WRITE_TIMEOUT`.
+///
+/// A client that stops reading eventually fills the socket buffer; without the
+/// bound its writer would wait forever while holding on to the current frame.
+/// The bound is on progress, not on the whole message, so a large update
+/// still reaches a slow but healthy link.
+async fn send(writer: &mut (impl AsyncWrite + Unpin), msg: &[u8], what: &str) -> Result<()> {
+    send_within(writer, msg, what, WRITE_TIMEOUT).await
+}
+
+/// `send` with the progress bound given as `timeout`.
+async fn send_within(
+    writer: &mut (impl AsyncWrite + Unpin),
+    msg: &[u8],
+    what: &str,
+    timeout: Duration,
+) -> Result<()> {
+    let stalled = || anyhow::anyhow!("slow client: no part of {what} accepted within {timeout:?}");
+    let mut rest = msg;
+    while !rest.is_empty() {
+        let written = tokio::time::timeout(timeout, writer.write(rest))
+            .await
+            .map_err(|_| stalled())?
+            .with_context(|| format!("write {what}"))?;
+        if written == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero))
+                .with_context(|| format!("write {what}"));
+        }
+        rest = &rest[written..];
+    }
+    tokio::time::timeout(timeout, writer.flush())
+        .await
+        .map_err(|_| stalled())?
+        .with_context(|| format!("write {what}"))
+}
+
+/// Whether a session error only means the client went away (connection
+/// closed mid-message, reset or aborted) rather than that it broke the
+/// protocol or stopped reading.
+pub fn is_disconnect(err: &anyhow::Error) -> bool {
+    use std::io::ErrorKind;
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .any(|e| {
+            matches!(
+                e.kind(),
+                ErrorKind::UnexpectedEof
+                    | ErrorKind::ConnectionReset
+                    | ErrorKind::ConnectionAborted
+                    | ErrorKind::BrokenPipe
+            )
+        })
+}
+
+/// Why a client session ended, for logs and the audit record.
+pub fn end_reason(result: &Result<()>) -> String {
+    match result {
+        Ok(()) => "client closed".to_string(),
+        Err(e) if is_disconnect(e) => "connection lost".to_string(),
+        Err(e) => format!("{e:#}"),
+    }
+}
+
+/// Age of frames when they reach a client's socket, from capture to flush.
+/// Tells capture-side latency apart from network-side latency.
+#[derive(Default)]
+struct FrameAge {
+    /// Exponential moving average in milliseconds.
+    avg_ms: f64,
+    samples: u64,
+}
+
+impl FrameAge {
+    /// Weight of the newest sample in the moving average.
+    const SMOOTHING: f64 = 0.1;
+
+    fn record(&mut self, peer: &str, captured_at: Instant) {
+        let age_ms = captured_at.elapsed().as_secs_f64() * 1000.0;
+        tracing::debug!(peer, age_ms, "Frame flushed");
+        self.avg_ms = if self.samples == 0 {
+            age_ms
+        } else {
+            self.avg_ms + (age_ms - self.avg_ms) * Self::SMOOTHING
+        };
+        self.samples += 1;
+    }
+}
+
+/// Token bucket holding a client to `--max-client-kbps`. Bytes written
+/// take tokens, which refill at the cap up to one second's worth; while
+/// the bucket is in debt further updates wait.
+struct Bandwidth {
+    /// Refill rate in bytes per second.
+    rate: f64,
+    tokens: f64,
+    /// The connection's byte count when last accounted for.
+    sent: u64,
+    last: tokio::time::Instant,
+}
+
+impl Bandwidth {
+    /// A bucket for `kbps` kilobits per second, or `None` for no cap.
+    fn new(kbps: u32) -> Option<Self> {
+        let rate = f64::from(kbps) * 1000.0 / 8.0;
+        (kbps > 0).then(|| Self {
+            rate,
+            tokens: rate,
+            sent: 0,
+            last: tokio::time::Instant::now(),
+        })
+    }
+
+    /// Charge the bytes written since the last call, given the
+    /// connection's total `sent`, and return when the next write may go
+    /// out.
+    fn ready_at(&mut self, sent: u64, now: tokio::time::Instant) -> tokio::time::Instant {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.tokens -= sent.saturating_sub(self.sent) as f64;
+        self.last = now;
+        self.sent = sent;
+        if self.tokens >= 0.0 {
+            now
+        } else {
+            now + Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
 }
 
 /// Client-negotiated pixel format.
@@ -28,12 +487,16 @@ struct ClientPixelFormat {
     red_shift: u8,
     green_shift: u8,
     blue_shift: u8,
+    /// 8bpp colour-mapped: pixels index the fixed 3-3-2 colour map.
+    colour_map: bool,
 }
 
 impl ClientPixelFormat {
-    /// Our server's default: 32bpp LE, red=16, green=8, blue=0 (BGRX byte order).
-    fn server_default() -> Self {
-        Self {
+    /// Our server's default for `--depth`: 32bpp LE, red=16, green=8,
+    /// blue=0 (BGRX byte order); the same packed into 3 bytes for 24; or
+    /// RGB565 for 16.
+    fn server_default(depth: u8) -> Self {
+        let bgrx = Self {
             bpp: 32,
             big_endian: false,
             red_max: 255,
@@ -42,13 +505,61 @@ impl ClientPixelFormat {
             red_shift: 16,
             green_shift: 8,
             blue_shift: 0,
+            colour_map: false,
+        };
+        match depth {
+            16 => Self {
+                bpp: 16,
+                red_max: 31,
+                green_max: 63,
+                blue_max: 31,
+                red_shift: 11,
+                green_shift: 5,
+                ..bgrx
+            },
+            24 => Self { bpp: 24, ..bgrx },
+            _ => bgrx,
         }
     }
 
-    fn from_bytes(buf: &[u8]) -> Self {
-        Self {
+    /// PIXEL_FORMAT of a true-colour format, as sent in ServerInit.
+    fn to_bytes(&self) -> [u8; 16] {
+        let depth = [self.red_max, self.green_max, self.blue_max]
+            .iter()
+            .map(|max| max.count_ones() as u8)
+            .sum();
+        let mut buf = [0u8; 16];
+        buf[..4].copy_from_slice(&[self.bpp, depth, self.big_endian as u8, 1]);
+        buf[4..6].copy_from_slice(&self.red_max.to_be_bytes());
+        buf[6..8].copy_from_slice(&self.green_max.to_be_bytes());
+        buf[8..10].copy_from_slice(&self.blue_max.to_be_bytes());
+        buf[10..13].copy_from_slice(&[self.red_shift, self.green_shift, self.blue_shift]);
+        buf
+    }
+
+    /// Parse and validate a PIXEL_FORMAT. Colour-mapped formats other than
+    /// 8bpp and shifts outside a 32-bit pixel are rejected.
+    ///
+    /// An 8bpp colour-mapped request is served with the fixed 3-3-2 colour
+    /// map, so its pixels are the same as 8bpp true-colour with red in bits
+    /// 7-5, green in 4-2 and blue in 1-0.
+    fn from_bytes(buf: &[u8]) -> Result<Self> {
+        if buf[3] == 0 && buf[0] == 8 {
+            return Ok(Self {
+                bpp: 8,
+                big_endian: false,
+                red_max: 7,
+                green_max: 7,
+                blue_max: 3,
+                red_shift: 5,
+                green_shift: 2,
+                blue_shift: 0,
+                colour_map: true,
+            });
+        }
+        let pf = Self {
             bpp: buf[0],
-            // buf[1] = depth, buf[3] = true-colour (we assume true-colour)
+            // buf[1] = depth
             big_endian: buf[2] != 0,
             red_max: u16::from_be_bytes([buf[4], buf[5]]),
             green_max: u16::from_be_bytes([buf[6], buf[7]]),
@@ -56,6 +567,47 @@ impl ClientPixelFormat {
             red_shift: buf[10],
             green_shift: buf[11],
             blue_shift: buf[12],
+            colour_map: false,
+        };
+        // 24bpp isn't in the spec, but some clients ask for it
+        if !matches!(pf.bpp, 8 | 16 | 24 | 32) {
+            bail!("Unsupported pixel format: {}bpp", pf.bpp);
+        }
+        if buf[3] == 0 {
+            bail!(
+                "Unsupported pixel format: {}bpp colour-mapped (only 8bpp is supported)",
+                pf.bpp
+            );
+        }
+        if let Some(shift) = [pf.red_shift, pf.green_shift, pf.blue_shift]
+            .into_iter()
+            .find(|&s| s >= 32)
+        {
+            bail!("Invalid pixel format: colour shift {shift} out of range");
+        }
+        Ok(pf)
+    }
+
+    /// ZRLE sends 32bpp pixels whose colour bits fit in three bytes as
+    /// 3-byte CPIXELs. Returns the index of the dropped byte within the
+    /// pixel as sent, or `None` if CPIXELs are full pixels.
+    fn cpixel_skip(&self) -> Option<usize> {
+        if self.bpp != 32 {
+            return None;
+        }
+        let mask = [
+            (self.red_max, self.red_shift),
+            (self.green_max, self.green_shift),
+            (self.blue_max, self.blue_shift),
+        ]
+        .iter()
+        .fold(0u64, |m, &(max, shift)| m | (max as u64) << shift);
+        let msb_unused = mask & !0x00FF_FFFF == 0;
+        let lsb_unused = mask & !0xFFFF_FF00 == 0;
+        match (msb_unused, lsb_unused, self.big_endian) {
+            (true, _, false) | (false, true, true) => Some(3),
+            (true, _, true) | (false, true, false) => Some(0),
+            _ => None,
         }
     }
 
@@ -71,13 +623,12 @@ impl ClientPixelFormat {
     }
 }
 
-/// Convert one row of BGRA pixel data to the client's requested pixel format.
-/// Reuses `out` buffer to avoid per-row allocation.
+/// Convert a run of BGRA pixels (one or more whole rows) to the client's
+/// requested pixel format, appending the result to `out`. The caller
+/// reserves space up front.
 fn convert_row_into(bgra_row: &[u8], pf: &ClientPixelFormat, out: &mut Vec<u8>) {
     let bytes_pp = (pf.bpp / 8) as usize;
     let num_pixels = bgra_row.len() / 4;
-    out.clear();
-    out.reserve(num_pixels * bytes_pp);
 
     for i in 0..num_pixels {
         let off = i * 4;
@@ -122,28 +673,274 @@ fn convert_row_into(bgra_row: &[u8], pf: &ClientPixelFormat, out: &mut Vec<u8>)
                 out.push(pixel as u8);
             }
             _ => {
-                out.extend_from_slice(&{ pixel }.to_le_bytes()[..bytes_pp]);
+                // 24bpp: the low bytes of the pixel value, in the client's order
+                if pf.big_endian {
+                    out.extend_from_slice(&pixel.to_be_bytes()[4 - bytes_pp..]);
+                } else {
+                    out.extend_from_slice(&pixel.to_le_bytes()[..bytes_pp]);
+                }
+            }
+        }
+    }
+}
+
+/// SetColourMapEntries message installing the fixed 3-3-2 colour map used
+/// for 8bpp colour-mapped clients.
+fn colour_map_message() -> Vec<u8> {
+    let mut msg = Vec::with_capacity(6 + 256 * 6);
+    msg.extend_from_slice(&[MSG_SET_COLOUR_MAP_ENTRIES, 0]);
+    msg.extend_from_slice(&0u16.to_be_bytes()); // first-colour
+    msg.extend_from_slice(&256u16.to_be_bytes()); // number-of-colours
+    for i in 0..=255u32 {
+        let scale = |v: u32, max: u32| (v * 65535 / max) as u16;
+        for c in [scale(i >> 5, 7), scale((i >> 2) & 7, 7), scale(i & 3, 3)] {
+            msg.extend_from_slice(&c.to_be_bytes());
+        }
+    }
+    msg
+}
+
+/// Append a FramebufferUpdate message for `rects` to `out`.
+/// `pf` is the client's pixel format; `None` means the server default.
+/// Rects use the client's preferred encoding: ZRLE or zstd (given the
+/// client's stream for it), RRE where it beats Raw, and Raw otherwise.
+/// With `last_rect`, the header carries no rect count (0xFFFF) and the
+/// update is terminated by a LastRect pseudo-rectangle instead.
+fn encode_update(
+    out: &mut Vec<u8>,
+    frame: &[u8],
+    stride: usize,
+    rects: &[DirtyRect],
+    pf: Option<&ClientPixelFormat>,
+    encodings: &ClientEncodings,
+    streams: &mut Streams,
+) {
+    encode_update_header(out, rects.len(), encodings);
+    encode_rects(out, frame, stride, rects, pf, encodings, streams);
+    if encodings.last_rect {
+        encode_last_rect(out);
+    }
+}
+
+/// Append a FramebufferUpdate header announcing `num_rects` rects, or an
+/// open-ended count if the client takes a LastRect marker.
+fn encode_update_header(out: &mut Vec<u8>, num_rects: usize, encodings: &ClientEncodings) {
+    let num_rects = if encodings.last_rect {
+        0xFFFF
+    } else {
+        num_rects as u16
+    };
+    out.extend_from_slice(&[0, 0]); // type + padding
+    out.extend_from_slice(&num_rects.to_be_bytes());
+}
+
+/// Append the LastRect pseudo-rectangle that ends an open-ended update.
+fn encode_last_rect(out: &mut Vec<u8>) {
+    out.extend_from_slice(&[0; 8]); // x, y, width, height
+    out.extend_from_slice(&ENC_LAST_RECT.to_be_bytes());
+}
+
+/// Append the encoded `rects` of a FramebufferUpdate to `out`; see
+/// `encode_update`.
+fn encode_rects(
+    out: &mut Vec<u8>,
+    frame: &[u8],
+    stride: usize,
+    rects: &[DirtyRect],
+    pf: Option<&ClientPixelFormat>,
+    encodings: &ClientEncodings,
+    streams: &mut Streams,
+) {
+    // Reserve the whole message once instead of growing row by row
+    let bytes_pp = pf.map_or(4, |pf| (pf.bpp / 8) as usize);
+    let pixels: usize = rects
+        .iter()
+        .map(|r| r.width as usize * r.height as usize)
+        .sum();
+    out.reserve(rects.len() * 12 + pixels * bytes_pp + 12);
+
+    let put_pixel = |out: &mut Vec<u8>, px: [u8; 4]| match pf {
+        Some(pf) => convert_row_into(&px, pf, out),
+        None => out.extend_from_slice(&px),
+    };
+    let cpixel_skip = pf.map_or(Some(3), |pf| pf.cpixel_skip());
+    let put_cpixel = |out: &mut Vec<u8>, px: [u8; 4]| {
+        let start = out.len();
+        put_pixel(out, px);
+        if let Some(i) = cpixel_skip {
+            out.remove(start + i);
+        }
+    };
+    let cpixel_len = bytes_pp - usize::from(cpixel_skip.is_some());
+
+    for rect in rects {
+        let put_raw = |out: &mut Vec<u8>| put_raw_pixels(out, frame, stride, rect, pf);
+        let encoded = match (encodings.preferred, &mut streams.zrle, &mut streams.zstd) {
+            (ENC_ZRLE, Some(zrle), _) => {
+                zrle.encode_rect(out, frame, stride, rect, cpixel_len, put_cpixel);
+                true
+            }
+            (ENC_ZSTD, _, Some(zstd)) => {
+                zstd.encode_rect(out, rect, put_raw);
+                true
             }
+            (ENC_RRE, ..) => rre::encode_rect(out, frame, stride, rect, bytes_pp, put_pixel),
+            _ => false,
+        };
+        if encoded {
+            continue;
+        }
+
+        out.extend_from_slice(&rect.x.to_be_bytes());
+        out.extend_from_slice(&rect.y.to_be_bytes());
+        out.extend_from_slice(&rect.width.to_be_bytes());
+        out.extend_from_slice(&rect.height.to_be_bytes());
+        out.extend_from_slice(&ENC_RAW.to_be_bytes());
+        put_raw(out);
+    }
+}
+
+/// Append the pixels of `rect` as Raw sends them, in `pf` or the server
+/// default format.
+fn put_raw_pixels(
+    out: &mut Vec<u8>,
+    frame: &[u8],
+    stride: usize,
+    rect: &DirtyRect,
+    pf: Option<&ClientPixelFormat>,
+) {
+    // Copy pixel data straight from the frame buffer. A full-width rect
+    // is one contiguous run of rows, copied or converted in one go.
+    let put_rows = |out: &mut Vec<u8>, bgra: &[u8]| match pf {
+        Some(pf) => convert_row_into(bgra, pf, out),
+        None => out.extend_from_slice(bgra),
+    };
+    let row_bytes = rect.width as usize * 4;
+    if rect.x == 0 && row_bytes == stride {
+        let start = rect.y as usize * stride;
+        put_rows(out, &frame[start..start + rect.height as usize * stride]);
+    } else {
+        for row in rect.y..rect.y + rect.height {
+            let start = row as usize * stride + rect.x as usize * 4;
+            put_rows(out, &frame[start..start + row_bytes]);
         }
     }
 }
 
-/// Server-side pixel format: 32bpp, depth 24, little-endian,
-/// true-color, blue at bits 0-7, green at 8-15, red at 16-23.
-/// This matches BGRA byte order in memory.
-const PIXEL_FORMAT: [u8; 16] = [
-    32, // bits-per-pixel
-    24, // depth
-    0,  // big-endian-flag (little-endian)
-    1,  // true-colour-flag
-    0, 255, // red-max (255)
-    0, 255, // green-max (255)
-    0, 255, // blue-max (255)
-    16,  // red-shift
-    8,   // green-shift
-    0,   // blue-shift
-    0, 0, 0, // padding
-];
+/// Full-width rects of `band_height` rows (the last one shorter) covering
+/// a `width` x `height` frame.
+fn frame_bands(width: u16, height: u16, band_height: u16) -> Vec<DirtyRect> {
+    (0..height)
+        .step_by(band_height as usize)
+        .map(|y| DirtyRect {
+            x: 0,
+            y,
+            width,
+            height: band_height.min(height - y),
+        })
+        .collect()
+}
+
+/// Encode the shared FramebufferUpdate for a freshly captured frame, in the
+/// server's default pixel format, merging `rects` past `full_refresh` as a
+/// client's own update would. Done once per frame by the capture thread so
+/// in-sync default-format clients can forward it without re-encoding.
+pub fn encode_shared_update(
+    out: &mut Vec<u8>,
+    frame: &[u8],
+    width: u32,
+    rects: &[DirtyRect],
+    full_refresh: FullRefreshThreshold,
+) {
+    out.clear();
+    let raw = ClientEncodings::default();
+    let mut streams = Streams::default();
+    let merged = full_refresh.merged(rects);
+    let rects = match &merged {
+        Some(bounds) => std::slice::from_ref(bounds),
+        None => rects,
+    };
+    encode_update(
+        out,
+        frame,
+        width as usize * 4,
+        rects,
+        None,
+        &raw,
+        &mut streams,
+    );
+}
+
+/// Security type: None.
+const SEC_NONE: u8 = 1;
+/// Security type: VNC Authentication (DES challenge-response).
+const SEC_VNC_AUTH: u8 = 2;
+/// Security type: Apple Remote Desktop (DH + AES-128).
+const SEC_ARD: u8 = 30;
+/// Security type: Tight (capability lists around VNC Authentication or None).
+const SEC_TIGHT: u8 = 16;
+
+/// Name of a security type in audit records, as `--security-types` spells it.
+fn security_type_name(code: u8) -> &'static str {
+    match code {
+        SEC_NONE => "none",
+        SEC_VNC_AUTH => "vnc",
+        SEC_ARD => "ard",
+        SEC_TIGHT => "tight",
+        _ => "unknown",
+    }
+}
+
+/// A client failed authentication (as opposed to the handshake breaking off).
+#[derive(Debug)]
+pub struct AuthFailed;
+
+impl std::fmt::Display for AuthFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("VNC authentication failed")
+    }
+}
+
+impl std::error::Error for AuthFailed {}
+
+/// Authentication settings shared by all client connections.
+pub struct Security {
+    password: Option<String>,
+    /// Security types offered in RFB 3.7+, in order of preference.
+    offered: Vec<u8>,
+}
+
+impl Security {
+    /// Offer the `allowed` types that fit `password`: VNC Authentication and
+    /// ARD need one, None is only offered without one. Tight wraps whichever
+    /// of the two applies, so it fits either way.
+    pub fn new(password: Option<String>, allowed: &[SecurityType]) -> Result<Self> {
+        let mut offered: Vec<u8> = Vec::new();
+        for ty in allowed {
+            let code = match (ty, password.is_some()) {
+                (SecurityType::None, false) => SEC_NONE,
+                (SecurityType::Vnc, true) => SEC_VNC_AUTH,
+                (SecurityType::Ard, true) => SEC_ARD,
+                (SecurityType::Tight, _) => SEC_TIGHT,
+                _ => continue,
+            };
+            if !offered.contains(&code) {
+                offered.push(code);
+            }
+        }
+        if offered.is_empty() {
+            match password {
+                Some(_) => {
+                    bail!("--security-types must include vnc, ard or tight when --password is set")
+                }
+                None => {
+                    bail!("--security-types must include none or tight unless --password is set")
+                }
+            }
+        }
+        Ok(Self { password, offered })
+    }
+}
 
 /// Compute the VNC DES response for a given password and 16-byte challenge.
 ///
@@ -175,7 +972,10 @@ fn vnc_des_auth(password: &str, challenge: &[u8; 16]) -> [u8; 16] {
 
 /// Perform VNC Authentication (Type 2) challenge-response.
 /// Returns Ok(true) if auth succeeded, Ok(false) if failed.
-async fn perform_vnc_auth(stream: &mut TcpStream, password: &str) -> Result<bool> {
+async fn perform_vnc_auth(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    password: &str,
+) -> Result<bool> {
     let challenge: [u8; 16] = rand::rng().random();
 
     stream
@@ -190,311 +990,810 @@ async fn perform_vnc_auth(stream: &mut TcpStream, password: &str) -> Result<bool
         .context("read VNC auth response")?;
 
     let expected = vnc_des_auth(password, &challenge);
-    Ok(response == expected)
+    Ok(ard::constant_time_eq(&response, &expected))
 }
 
-/// Handle a single VNC client connection.
-pub async fn handle_client(
-    mut stream: TcpStream,
-    width: u16,
-    height: u16,
-    mut frame_rx: watch::Receiver<Arc<Vec<u8>>>,
-    capture_req_tx: std::sync::mpsc::Sender<()>,
-    input_tx: mpsc::Sender<InputEvent>,
+/// Tight capability: code, 4-byte vendor and 8-byte signature.
+fn tight_capability(code: u32, vendor: &[u8; 4], signature: &[u8; 8]) -> [u8; 16] {
+    let mut cap = [0u8; 16];
+    cap[..4].copy_from_slice(&code.to_be_bytes());
+    cap[4..8].copy_from_slice(vendor);
+    cap[8..].copy_from_slice(signature);
+    cap
+}
+
+/// Run the Tight security type (16) once the client has selected it: offer
+/// "no tunnel" as the only tunnel, then a single auth capability (VNC
+/// Authentication with a password, None without) and run it as usual.
+async fn tight_security(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
     password: Option<&str>,
-    dirty_tiles: Arc<DirtyTiles>,
+    rfb_minor: u16,
+    peer: &str,
 ) -> Result<()> {
-    // === RFB Handshake ===
-
+    let mut tunnels = 1u32.to_be_bytes().to_vec();
+    tunnels.extend_from_slice(&tight_capability(0, b"TGHT", b"NOTUNNEL"));
     stream
-        .write_all(b"RFB 003.008\n")
+        .write_all(&tunnels)
         .await
-        .context("send protocol version")?;
-
-    let mut ver_buf = [0u8; 12];
+        .context("send Tight tunnel capabilities")?;
+    let mut choice = [0u8; 4];
     stream
-        .read_exact(&mut ver_buf)
+        .read_exact(&mut choice)
         .await
-        .context("read client version")?;
+        .context("read Tight tunnel selection")?;
+    if u32::from_be_bytes(choice) != 0 {
+        bail!(
+            "Client selected unsupported Tight tunnel {}",
+            u32::from_be_bytes(choice)
+        );
+    }
 
-    // Parse client version to determine the RFB minor version.
-    // Format: "RFB 003.MMM\n"
-    let rfb_minor = std::str::from_utf8(&ver_buf)
-        .ok()
-        .and_then(|s| s.get(8..11))
-        .and_then(|m| m.parse::<u16>().ok())
-        .unwrap_or(8);
-    tracing::info!("Client requested RFB 003.{:03}", rfb_minor);
+    let auth = match password {
+        Some(_) => tight_capability(SEC_VNC_AUTH as u32, b"STDV", b"VNCAUTH_"),
+        None => tight_capability(SEC_NONE as u32, b"STDV", b"NOAUTH__"),
+    };
+    let mut auths = 1u32.to_be_bytes().to_vec();
+    auths.extend_from_slice(&auth);
+    stream
+        .write_all(&auths)
+        .await
+        .context("send Tight auth capabilities")?;
+    stream
+        .read_exact(&mut choice)
+        .await
+        .context("read Tight auth selection")?;
+    if choice != auth[..4] {
+        bail!(
+            "Client selected unsupported Tight auth type {}",
+            u32::from_be_bytes(choice)
+        );
+    }
 
-    match rfb_minor {
-        // RFB 3.3 (and older): server dictates security type as u32, no SecurityResult.
-        0..=6 => {
-            if let Some(pw) = password {
-                // Type 2: VNC Authentication
-                stream
-                    .write_all(&2u32.to_be_bytes())
-                    .await
-                    .context("send security type 2 (3.3)")?;
-                if !perform_vnc_auth(&mut stream, pw).await? {
-                    // RFB 3.3: no SecurityResult, just close the connection
-                    bail!("VNC authentication failed");
-                }
-            } else {
-                stream
-                    .write_all(&1u32.to_be_bytes())
-                    .await
-                    .context("send security type (3.3)")?;
-            }
-        }
-        // RFB 3.7: security type list + client selection, but no SecurityResult.
-        7 => {
-            if let Some(pw) = password {
-                stream
-                    .write_all(&[1, 2])
-                    .await
-                    .context("send security types (3.7)")?;
+    match password {
+        Some(pw) => authenticate(stream, pw, SEC_VNC_AUTH, rfb_minor, peer).await,
+        None if rfb_minor >= 8 => stream
+            .write_all(&0u32.to_be_bytes())
+            .await
+            .context("send security result"),
+        None => Ok(()),
+    }
+}
 
-                let mut sec_type = [0u8; 1];
-                stream
-                    .read_exact(&mut sec_type)
-                    .await
-                    .context("read security type selection (3.7)")?;
-                if sec_type[0] != 2 {
-                    bail!("Client selected unsupported security type {}", sec_type[0]);
-                }
-                if !perform_vnc_auth(&mut stream, pw).await? {
-                    bail!("VNC authentication failed");
-                }
-            } else {
-                stream
-                    .write_all(&[1, 1])
-                    .await
-                    .context("send security types (3.7)")?;
+/// Run the selected password-based security type and send the SecurityResult.
+///
+/// Every RFB version sends the SecurityResult word after authentication;
+/// only 3.8+ follows a failure with a reason string. Failures are logged with
+/// the peer address and returned as an error so the connection is closed.
+async fn authenticate(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    password: &str,
+    sec_type: u8,
+    rfb_minor: u16,
+    peer: &str,
+) -> Result<()> {
+    let ok = match sec_type {
+        SEC_ARD => ard::perform_ard_auth(stream, password).await?,
+        _ => perform_vnc_auth(stream, password).await?,
+    };
+    if ok {
+        // SecurityResult: OK
+        stream
+            .write_all(&0u32.to_be_bytes())
+            .await
+            .context("send security result")?;
+        return Ok(());
+    }
 
-                let mut sec_type = [0u8; 1];
-                stream
-                    .read_exact(&mut sec_type)
-                    .await
-                    .context("read security type selection (3.7)")?;
-                if sec_type[0] != 1 {
-                    bail!("Client selected unsupported security type {}", sec_type[0]);
-                }
-            }
-        }
-        // RFB 3.8+: security type list + client selection + SecurityResult.
-        _ => {
-            if let Some(pw) = password {
-                stream
-                    .write_all(&[1, 2])
-                    .await
-                    .context("send security types")?;
+    let reason = "Authentication failed";
+    tracing::warn!("VNC authentication failed for {peer} (RFB 003.{rfb_minor:03}): {reason}");
 
-                let mut sec_type = [0u8; 1];
-                stream
-                    .read_exact(&mut sec_type)
-                    .await
-                    .context("read security type selection")?;
-                if sec_type[0] != 2 {
-                    bail!("Client selected unsupported security type {}", sec_type[0]);
-                }
+    // SecurityResult: Failed. The connection is closed regardless, so write
+    // errors are ignored.
+    stream.write_all(&1u32.to_be_bytes()).await.ok();
+    if rfb_minor >= 8 {
+        stream
+            .write_all(&(reason.len() as u32).to_be_bytes())
+            .await
+            .ok();
+        stream.write_all(reason.as_bytes()).await.ok();
+    }
+    stream.flush().await.ok();
+    Err(AuthFailed.into())
+}
 
-                if perform_vnc_auth(&mut stream, pw).await? {
-                    // SecurityResult: OK
+/// Agree on a security type with the client and run it, recording the type
+/// in `audit`. Returns whether it was Tight.
+async fn negotiate_security(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    security: &Security,
+    rfb_minor: u16,
+    peer: &str,
+    audit: &mut AuditSession,
+) -> Result<bool> {
+    let password = security.password.as_deref();
+    match rfb_minor {
+        // RFB 3.3 (and older): server dictates security type as u32. Only
+        // None and VNC Authentication exist there.
+        0..=6 => {
+            let sec_type = security
+                .offered
+                .iter()
+                .copied()
+                .find(|t| [SEC_NONE, SEC_VNC_AUTH].contains(t));
+            match (sec_type, password) {
+                (Some(SEC_VNC_AUTH), Some(pw)) => {
+                    audit.auth_type = Some(security_type_name(SEC_VNC_AUTH));
                     stream
-                        .write_all(&0u32.to_be_bytes())
+                        .write_all(&2u32.to_be_bytes())
                         .await
-                        .context("send security result")?;
-                } else {
-                    // SecurityResult: Failed
+                        .context("send security type 2 (3.3)")?;
+                    authenticate(stream, pw, SEC_VNC_AUTH, rfb_minor, peer).await?;
+                }
+                (Some(_), _) => {
+                    audit.auth_type = Some(security_type_name(SEC_NONE));
                     stream
                         .write_all(&1u32.to_be_bytes())
                         .await
-                        .context("send security result (failed)")?;
-                    let reason = b"Authentication failed";
-                    stream
-                        .write_all(&(reason.len() as u32).to_be_bytes())
-                        .await
-                        .ok();
-                    stream.write_all(reason).await.ok();
-                    bail!("VNC authentication failed");
+                        .context("send security type (3.3)")?;
                 }
-            } else {
-                stream
-                    .write_all(&[1, 1])
-                    .await
-                    .context("send security types")?;
-
-                let mut sec_type = [0u8; 1];
-                stream
-                    .read_exact(&mut sec_type)
-                    .await
-                    .context("read security type selection")?;
-                if sec_type[0] != 1 {
-                    bail!("Client selected unsupported security type {}", sec_type[0]);
+                (None, _) => {
+                    // Type 0 (invalid) followed by the reason
+                    let reason = b"No security type for RFB 3.3 is enabled";
+                    let mut msg = 0u32.to_be_bytes().to_vec();
+                    msg.extend_from_slice(&(reason.len() as u32).to_be_bytes());
+                    msg.extend_from_slice(reason);
+                    stream.write_all(&msg).await.ok();
+                    bail!("RFB 3.3 client refused: no security type for it is enabled");
                 }
+            }
+            Ok(false)
+        }
+        // RFB 3.7+: security type list + client selection.
+        _ => {
+            let offered = &security.offered;
+            let mut types = vec![offered.len() as u8];
+            types.extend_from_slice(offered);
+            stream
+                .write_all(&types)
+                .await
+                .context("send security types")?;
+
+            let mut sec_type = [0u8; 1];
+            stream
+                .read_exact(&mut sec_type)
+                .await
+                .context("read security type selection")?;
+            if !offered.contains(&sec_type[0]) {
+                bail!("Client selected unsupported security type {}", sec_type[0]);
+            }
+            audit.auth_type = Some(security_type_name(sec_type[0]));
 
-                // SecurityResult: OK
+            if sec_type[0] == SEC_TIGHT {
+                tight_security(stream, password, rfb_minor, peer).await?;
+            } else if let Some(pw) = password {
+                authenticate(stream, pw, sec_type[0], rfb_minor, peer).await?;
+            } else if rfb_minor >= 8 {
+                // SecurityResult: OK (3.7 sends none for security type None)
                 stream
                     .write_all(&0u32.to_be_bytes())
                     .await
                     .context("send security result")?;
             }
+            Ok(sec_type[0] == SEC_TIGHT)
         }
     }
+}
 
-    // ClientInit
-    let mut client_init = [0u8; 1];
-    stream
-        .read_exact(&mut client_init)
-        .await
-        .context("read ClientInit")?;
-
-    // ServerInit
-    let name = b"kmsvnc";
-    let mut server_init = Vec::with_capacity(24 + name.len());
-    server_init.extend_from_slice(&width.to_be_bytes());
-    server_init.extend_from_slice(&height.to_be_bytes());
-    server_init.extend_from_slice(&PIXEL_FORMAT);
-    server_init.extend_from_slice(&(name.len() as u32).to_be_bytes());
-    server_init.extend_from_slice(name);
-    stream
-        .write_all(&server_init)
-        .await
-        .context("send ServerInit")?;
+/// Per-server settings for client connections, built once and shared by
+/// every client.
+pub struct ClientOptions {
+    /// Framebuffer size announced in ServerInit.
+    pub width: u16,
+    pub height: u16,
+    pub security: Arc<Security>,
+    /// Wait before answering an update request (`--defer-update`).
+    pub defer_update: Duration,
+    /// Time between captures at `--fps`; how often a held request asks for
+    /// another one.
+    pub capture_interval: Duration,
+    /// Least time between two updates to one client.
+    pub min_update_interval: Duration,
+    /// Wait for changes that follow the first before sending an update.
+    pub coalesce: Duration,
+    /// Bandwidth cap per client in kbit/s; 0 = no cap.
+    pub max_kbps: u32,
+    /// Rows per band of full-frame updates; 0 = one rect.
+    pub band_height: u16,
+    /// When an incremental update is sent as one merged rect instead.
+    pub full_refresh: FullRefreshThreshold,
+    /// Bits per pixel for clients that keep the server's pixel format (16,
+    /// 24 or 32).
+    pub depth: u8,
+    /// Ask clients that support QEMU extended key events to send them.
+    pub scancodes: bool,
+}
+
+/// Handle a single VNC client connection over any byte stream (TCP or the
+/// WebSocket adapter), with the settings in `options`. A client that
+/// hasn't finished the handshake by `handshake_deadline` (`None` = no
+/// limit) is dropped. The handshake and the end of the session are
+/// recorded in `audit`, whose peer identifies the client in log messages.
+pub async fn handle_client(
+    mut stream: impl AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    audit: &mut AuditSession,
+    hub: Arc<FrameHub>,
+    capture_req_tx: std::sync::mpsc::Sender<()>,
+    input_tx: mpsc::Sender<InputEvent>,
+    options: &ClientOptions,
+    handshake_deadline: Option<tokio::time::Instant>,
+) -> Result<()> {
+    let ClientOptions {
+        width,
+        height,
+        ref security,
+        defer_update,
+        capture_interval,
+        min_update_interval,
+        coalesce,
+        max_kbps,
+        band_height,
+        full_refresh,
+        depth,
+        scancodes,
+    } = *options;
+    let peer = audit.peer().to_string();
+    let peer = peer.as_str();
+
+    // === RFB Handshake ===
+
+    // Later renames reach clients that support DesktopName
+    let mut name_rx = hub.subscribe_desktop_name();
+    let server_pf = ClientPixelFormat::server_default(depth);
+
+    // Phase the handshake is in, for the log when it stalls
+    let mut phase = "protocol version";
+    let handshake = async {
+        stream
+            .write_all(b"RFB 003.008\n")
+            .await
+            .context("send protocol version")?;
+
+        let mut ver_buf = [0u8; 12];
+        stream
+            .read_exact(&mut ver_buf)
+            .await
+            .context("read client version")?;
+
+        // Parse client version to determine the RFB minor version.
+        // Format: "RFB 003.MMM\n"
+        let rfb_minor = std::str::from_utf8(&ver_buf)
+            .ok()
+            .and_then(|s| s.get(8..11))
+            .and_then(|m| m.parse::<u16>().ok())
+            .unwrap_or(8);
+        tracing::info!("Client requested RFB 003.{:03}", rfb_minor);
+        audit.rfb_minor = Some(rfb_minor);
+
+        // Whether the Tight security type was negotiated, which extends ServerInit
+        phase = "security negotiation";
+        let negotiated = negotiate_security(&mut stream, security, rfb_minor, peer, audit).await;
+        audit.authenticated(match &negotiated {
+            Ok(_) => "ok",
+            Err(e) if e.is::<AuthFailed>() => "failed",
+            Err(_) => "error",
+        });
+        let tight = negotiated?;
+
+        // ClientInit
+        phase = "ClientInit";
+        let mut client_init = [0u8; 1];
+        stream
+            .read_exact(&mut client_init)
+            .await
+            .context("read ClientInit")?;
+
+        // ServerInit
+        phase = "ServerInit";
+        let name = name_rx.borrow_and_update().clone();
+        let mut server_init = Vec::with_capacity(24 + name.len());
+        server_init.extend_from_slice(&width.to_be_bytes());
+        server_init.extend_from_slice(&height.to_be_bytes());
+        server_init.extend_from_slice(&server_pf.to_bytes());
+        server_init.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        server_init.extend_from_slice(name.as_bytes());
+        if tight {
+            // Interaction capabilities: no server message, client message or
+            // encoding capabilities listed (three counts and padding)
+            server_init.extend_from_slice(&[0u8; 8]);
+        }
+        stream
+            .write_all(&server_init)
+            .await
+            .context("send ServerInit")
+    };
+    match handshake_deadline {
+        None => handshake.await?,
+        Some(deadline) => match tokio::time::timeout_at(deadline, handshake).await {
+            Ok(result) => result?,
+            Err(_) => bail!("handshake timed out in {phase}"),
+        },
+    }
 
     tracing::info!("VNC handshake complete ({}x{})", width, height);
 
     // === Message loop ===
 
-    let (reader, writer) = stream.into_split();
+    // Session stats, logged on disconnect
+    let started = Instant::now();
+    let mut frames_sent = 0u64;
+    let mut frame_age = FrameAge::default();
+    let input_events = Arc::new(AtomicU64::new(0));
+    let held_keys = Arc::new(std::sync::Mutex::new(HeldKeys::default()));
+
+    let (reader, writer) = tokio::io::split(stream);
+    let writer = CountingWriter {
+        inner: writer,
+        bytes: 0,
+    };
     let mut writer = BufWriter::with_capacity(65536, writer);
     let (update_req_tx, mut update_req_rx) = mpsc::channel::<bool>(4);
-    let (pf_tx, pf_rx) = watch::channel(ClientPixelFormat::server_default());
+    let (control_tx, mut control_rx) = mpsc::channel::<ClientControl>(4);
+    let (pf_tx, mut pf_rx) = watch::channel(server_pf);
 
-    let reader_handle = tokio::spawn(async move {
-        let r = read_client_messages(reader, update_req_tx, input_tx, pf_tx).await;
+    let reader_input_events = input_events.clone();
+    let reader_input_tx = input_tx.clone();
+    let reader_held_keys = held_keys.clone();
+    let mut reader_handle = tokio::spawn(async move {
+        let r = read_client_messages(
+            reader,
+            update_req_tx,
+            control_tx,
+            reader_input_tx,
+            pf_tx,
+            &reader_input_events,
+            &reader_held_keys,
+        )
+        .await;
         if let Err(e) = &r {
             tracing::debug!("Client reader ended: {e}");
         }
         r
     });
 
+    let (mut frame_rx, client_tiles) = hub.subscribe();
+    let mut bell_rx = hub.subscribe_bell();
+    let mut disconnect_rx = hub.subscribe_disconnect();
+    let mut led_rx = hub.subscribe_led();
     let stride = width as usize * 4;
+    let screen = DirtyRect {
+        x: 0,
+        y: 0,
+        width,
+        height,
+    };
 
-    // Reusable buffer for pixel format conversion
-    let mut convert_buf = Vec::new();
+    // Reusable buffer for updates this client has to encode itself
+    let mut update_buf = Vec::new();
+    // ZRLE and zstd streams, created on first use and kept for the connection
+    let mut streams = Streams::default();
 
     let writer_loop = async {
+        let mut encodings = ClientEncodings::default();
+        // Region being pushed while ContinuousUpdates is enabled
+        let mut continuous: Option<DirtyRect> = None;
+        // Flow control: after each continuous push we send a fence request
+        // and hold further pushes until the client answers it.
+        let mut awaiting_fence = false;
+        let mut fence_due = false;
+        // An incremental request with nothing to send yet, answered with an
+        // empty update at this deadline unless something changes first
+        let mut deferred: Option<tokio::time::Instant> = None;
+        // When a held request next asks for a capture. Not on every wakeup:
+        // an on-demand capture that finds nothing wakes us straight away.
+        let mut recapture: Option<tokio::time::Instant> = None;
+        // When the last update went out, for --max-client-fps
+        let mut last_update: Option<tokio::time::Instant> = None;
+        let mut bandwidth = Bandwidth::new(max_kbps);
+
         loop {
-            let incremental = match update_req_rx.recv().await {
-                Some(v) => v,
-                None => return Ok::<(), anyhow::Error>(()),
+            if fence_due {
+                fence_due = false;
+                awaiting_fence = true;
+                send(
+                    &mut writer,
+                    &fence_message(FENCE_REQUEST | FENCE_BLOCK_BEFORE, &[]),
+                    "Fence",
+                )
+                .await?;
+            }
+
+            // Control messages are polled first so e.g. SetEncodings takes
+            // effect before an update request the client sent after it.
+            // Each branch yields `Some(incremental)` when an update should be
+            // sent now, or `None` when there is nothing to send.
+            let step = tokio::select! {
+                biased;
+                control = control_rx.recv() => {
+                    match control {
+                        Some(ClientControl::SetEncodings(new)) => {
+                            if new.continuous_updates && !encodings.continuous_updates {
+                                // Tells the client we support ContinuousUpdates
+                                send(
+                                    &mut writer,
+                                    &[MSG_END_OF_CONTINUOUS_UPDATES],
+                                    "EndOfContinuousUpdates",
+                                )
+                                .await?;
+                            }
+                            if new.led_state && !encodings.led_state {
+                                let leds = *led_rx.borrow_and_update();
+                                send(&mut writer, &led_state_message(leds), "LED state").await?;
+                            }
+                            if scancodes && new.qemu_extended_key && !encodings.qemu_extended_key {
+                                send(
+                                    &mut writer,
+                                    &qemu_extended_key_message(),
+                                    "QEMU Extended Key Event",
+                                )
+                                .await?;
+                            }
+                            if new.extended_desktop_size && !encodings.extended_desktop_size {
+                                let msg = extended_desktop_size_message(0, 0, width, height);
+                                send(&mut writer, &msg, "ExtendedDesktopSize").await?;
+                            }
+                            if new.fence && !encodings.fence {
+                                // Tells the client we support fences
+                                send(
+                                    &mut writer,
+                                    &fence_message(FENCE_REQUEST, &[]),
+                                    "Fence",
+                                )
+                                .await?;
+                            }
+                            encodings = new;
+                            None
+                        }
+                        Some(ClientControl::EnableContinuousUpdates { enable, region }) => {
+                            if !encodings.continuous_updates {
+                                tracing::debug!("Ignoring EnableContinuousUpdates without -313");
+                            } else if enable {
+                                tracing::debug!("Continuous updates enabled for {region:?}");
+                                continuous = Some(region);
+                                let _ = capture_req_tx.send(());
+                            } else if continuous.take().is_some() {
+                                send(
+                                    &mut writer,
+                                    &[MSG_END_OF_CONTINUOUS_UPDATES],
+                                    "EndOfContinuousUpdates",
+                                )
+                                .await?;
+                            }
+                            None
+                        }
+                        Some(ClientControl::Fence { flags, payload }) => {
+                            if flags & FENCE_REQUEST != 0 {
+                                // Everything before this point has been written
+                                // and nothing after it has, so BlockBefore and
+                                // BlockAfter hold; other flags are cleared.
+                                let reply = flags & (FENCE_BLOCK_BEFORE | FENCE_BLOCK_AFTER);
+                                send(&mut writer, &fence_message(reply, &payload), "Fence").await?;
+                                None
+                            } else if awaiting_fence {
+                                // The client caught up: push what accumulated
+                                awaiting_fence = false;
+                                continuous.map(|_| true)
+                            } else {
+                                None
+                            }
+                        }
+                        Some(ClientControl::SetDesktopSize { width: w, height: h }) => {
+                            // The framebuffer follows the display, which
+                            // stays at the size it was set to
+                            tracing::info!("Client asked for a {w}x{h} desktop, refused");
+                            if encodings.extended_desktop_size {
+                                let msg = extended_desktop_size_message(
+                                    1,
+                                    RESIZE_PROHIBITED,
+                                    width,
+                                    height,
+                                );
+                                send(&mut writer, &msg, "ExtendedDesktopSize").await?;
+                            }
+                            None
+                        }
+                        None => return Ok::<(), anyhow::Error>(()),
+                    }
+                }
+                bell = bell_rx.recv() => {
+                    // Lagged only means several rings collapse into one
+                    if let Err(broadcast::error::RecvError::Closed) = bell {
+                        return Ok(());
+                    }
+                    send(&mut writer, &[MSG_BELL], "Bell").await?;
+                    None
+                }
+                target = disconnect_rx.recv() => {
+                    match target {
+                        Ok(Some(target)) if target == peer => bail!("disconnected by operator"),
+                        Ok(None) => bail!("server restarting"),
+                        Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                        _ => None,
+                    }
+                }
+                r = led_rx.changed(), if encodings.led_state => {
+                    if r.is_err() {
+                        return Ok(());
+                    }
+                    let leds = *led_rx.borrow_and_update();
+                    send(&mut writer, &led_state_message(leds), "LED state").await?;
+                    None
+                }
+                r = name_rx.changed(), if encodings.desktop_name => {
+                    if r.is_err() {
+                        return Ok(());
+                    }
+                    let msg = desktop_name_message(&name_rx.borrow_and_update());
+                    send(&mut writer, &msg, "DesktopName").await?;
+                    None
+                }
+                req = update_req_rx.recv() => {
+                    let Some(incremental) = req else {
+                        return Ok(());
+                    };
+                    if incremental && continuous.is_some() {
+                        // Already covered by continuous updates
+                        None
+                    } else {
+                        if incremental {
+                            // Request a capture and wait for a new frame
+                            let _ = capture_req_tx.send(());
+                            if frame_rx.changed().await.is_err() {
+                                return Ok(());
+                            }
+                        }
+                        Some(incremental)
+                    }
+                }
+                r = frame_rx.changed(),
+                    if (continuous.is_some() && !awaiting_fence) || deferred.is_some() =>
+                {
+                    if r.is_err() {
+                        return Ok(());
+                    }
+                    if continuous.is_some() {
+                        // Keep frames coming for the next push
+                        let _ = capture_req_tx.send(());
+                    }
+                    Some(true)
+                }
+                _ = tokio::time::sleep_until(deferred.unwrap_or_else(tokio::time::Instant::now)),
+                    if deferred.is_some() =>
+                {
+                    deferred = None;
+                    send(&mut writer, &[0, 0, 0, 0], "empty fb").await?;
+                    None
+                }
+                _ = tokio::time::sleep_until(recapture.unwrap_or_else(tokio::time::Instant::now)),
+                    if deferred.is_some() && recapture.is_some() =>
+                {
+                    recapture = None;
+                    let _ = capture_req_tx.send(());
+                    None
+                }
+            };
+            let Some(incremental) = step else {
+                continue;
             };
 
-            if incremental {
-                // Request a capture and wait for a new frame
+            // Coalescing window: changes following the one that woke us
+            // land in the client's tiles and go out in the same update
+            if incremental && !coalesce.is_zero() {
                 let _ = capture_req_tx.send(());
-                if frame_rx.changed().await.is_err() {
-                    return Ok(());
-                }
+                tokio::time::sleep(coalesce).await;
+            }
+
+            // Rate limit: changes captured while waiting out the interval
+            // accumulate in the client's tiles and go into this update
+            if let Some(last) = last_update.filter(|_| !min_update_interval.is_zero()) {
+                tokio::time::sleep_until(last + min_update_interval).await;
+            }
+
+            // Bandwidth cap: likewise, changes made while the client is over
+            // it are merged into the update that follows
+            if let Some(bandwidth) = &mut bandwidth {
+                let now = tokio::time::Instant::now();
+                tokio::time::sleep_until(bandwidth.ready_at(writer.get_ref().bytes, now)).await;
             }
 
             // Drain queued requests (coalesce)
             while update_req_rx.try_recv().is_ok() {}
 
-            let frame = frame_rx.borrow_and_update().clone();
+            let (mask, frame) = hub.snapshot(&mut frame_rx, &client_tiles);
+
+            // Snapshot the client pixel format for this whole update: a
+            // SetPixelFormat arriving while it is encoded applies from the
+            // next update on. A newly selected colour-mapped format needs its
+            // colour map before the first update using it.
+            let pf_changed = pf_rx.has_changed().unwrap_or(false);
+            let pf = pf_rx.borrow_and_update().clone();
+            if pf_changed && pf.colour_map {
+                send(&mut writer, &colour_map_message(), "SetColourMapEntries").await?;
+            }
+            let need_convert = !pf.matches_server_default();
 
             let rects = if incremental {
-                // Drain accumulated dirty tiles set by the capture thread
-                let rects = dirty_tiles.drain_to_rects();
+                let mut rects = client_tiles.mask_to_rects(&mask);
+                if let Some(region) = continuous {
+                    rects.retain_mut(|r| match r.intersect(&region) {
+                        Some(clipped) => {
+                            *r = clipped;
+                            true
+                        }
+                        None => false,
+                    });
+                }
                 if rects.is_empty() {
+                    if continuous.is_some() {
+                        // Continuous updates only push real changes
+                        continue;
+                    }
+                    let now = tokio::time::Instant::now();
+                    let deadline = *deferred.get_or_insert(now + defer_update);
+                    if now < deadline {
+                        // Hold the request and keep frames coming, one per
+                        // capture interval, until something changes or the
+                        // deadline passes
+                        recapture = Some(now + capture_interval);
+                        continue;
+                    }
                     // Nothing changed — send empty FramebufferUpdate (0 rects)
                     // to satisfy the client's request per RFB protocol
-                    writer.write_all(&[0, 0, 0, 0]).await.context("write empty fb")?;
-                    writer.flush().await.ok();
+                    deferred = None;
+                    send(&mut writer, &[0, 0, 0, 0], "empty fb").await?;
+                    continue;
+                }
+                deferred = None;
+                let whole_screen = continuous.is_none_or(|r| r == screen);
+                let in_sync = mask == frame.dirty && !frame.encoded.is_empty();
+                fence_due = continuous.is_some() && encodings.fence;
+                if !need_convert && whole_screen && in_sync && encodings.raw_only() {
+                    // In sync with the capture thread: forward the shared encoding
+                    send(&mut writer, &frame.encoded, "shared update").await?;
+                    last_update = Some(tokio::time::Instant::now());
+                    frames_sent += 1;
+                    frame_age.record(peer, frame.captured_at);
                     continue;
                 }
-                rects
+                // Changes scattered over most of an area go as one rect
+                match full_refresh.merged(&rects) {
+                    Some(bounds) => vec![bounds],
+                    None => rects,
+                }
+            } else if band_height > 0 {
+                // Non-incremental, banded: full-width bands written below
+                // one at a time. It answers any held request too.
+                deferred = None;
+                frame_bands(width, height, band_height)
             } else {
-                // Non-incremental: full frame
-                dirty_tiles.drain_to_rects(); // clear any stale bits
-                vec![frame_diff::DirtyRect {
-                    x: 0,
-                    y: 0,
-                    width,
-                    height,
-                }]
+                // Non-incremental: full frame, split into per-tile-row bands
+                // so each rect stays bounded in size. It answers any held
+                // request too.
+                deferred = None;
+                client_tiles.all_rects()
             };
 
-            // Get current client pixel format
-            let pf = pf_rx.borrow().clone();
-            let need_convert = !pf.matches_server_default();
-
-            // Build FramebufferUpdate
-            let num_rects = rects.len() as u16;
-            let mut hdr = [0u8; 4];
-            hdr[0] = 0; // type
-            hdr[2..4].copy_from_slice(&num_rects.to_be_bytes());
-            writer.write_all(&hdr).await.context("write fb header")?;
-
-            for rect in &rects {
-                let mut rhdr = [0u8; 12];
-                rhdr[0..2].copy_from_slice(&rect.x.to_be_bytes());
-                rhdr[2..4].copy_from_slice(&rect.y.to_be_bytes());
-                rhdr[4..6].copy_from_slice(&rect.width.to_be_bytes());
-                rhdr[6..8].copy_from_slice(&rect.height.to_be_bytes());
-                rhdr[8..12].copy_from_slice(&0i32.to_be_bytes()); // Raw encoding
-                writer.write_all(&rhdr).await.context("write rect header")?;
-
-                // Write tile data directly from frame buffer, row by row
-                for row in rect.y..rect.y + rect.height {
-                    let start = row as usize * stride + rect.x as usize * 4;
-                    let end = start + rect.width as usize * 4;
-                    let bgra_row = &frame[start..end];
-
-                    if need_convert {
-                        convert_row_into(bgra_row, &pf, &mut convert_buf);
-                        writer
-                            .write_all(&convert_buf)
-                            .await
-                            .context("write rect data")?;
-                    } else {
-                        writer
-                            .write_all(bgra_row)
-                            .await
-                            .context("write rect data")?;
+            update_buf.clear();
+            let pf = need_convert.then_some(&pf);
+            streams.prepare(encodings.preferred);
+            if !incremental && band_height > 0 {
+                // Encode and write band by band, letting the runtime serve
+                // other clients in between rather than stalling on one
+                // multi-megabyte message
+                encode_update_header(&mut update_buf, rects.len(), &encodings);
+                for band in &rects {
+                    if let Some(bandwidth) = &mut bandwidth {
+                        let now = tokio::time::Instant::now();
+                        let ready = bandwidth.ready_at(writer.get_ref().bytes, now);
+                        tokio::time::sleep_until(ready).await;
                     }
+                    let band = std::slice::from_ref(band);
+                    encode_rects(
+                        &mut update_buf,
+                        &frame.data,
+                        stride,
+                        band,
+                        pf,
+                        &encodings,
+                        &mut streams,
+                    );
+                    send(&mut writer, &update_buf, "fb update band").await?;
+                    update_buf.clear();
+                    tokio::task::yield_now().await;
+                }
+                if encodings.last_rect {
+                    encode_last_rect(&mut update_buf);
+                    send(&mut writer, &update_buf, "last rect").await?;
                 }
+            } else {
+                encode_update(
+                    &mut update_buf,
+                    &frame.data,
+                    stride,
+                    &rects,
+                    pf,
+                    &encodings,
+                    &mut streams,
+                );
+                send(&mut writer, &update_buf, "fb update").await?;
             }
-
-            writer.flush().await.ok();
+            last_update = Some(tokio::time::Instant::now());
+            frames_sent += 1;
+            frame_age.record(peer, frame.captured_at);
         }
     };
 
-    tokio::select! {
-        r = writer_loop => {
-            r?;
-        }
-        r = reader_handle => {
-            r??;
-        }
+    let joined = |r: Result<Result<()>, tokio::task::JoinError>| r.map_err(anyhow::Error::from)?;
+    let (mut result, mut reader_done) = tokio::select! {
+        r = writer_loop => (r, false),
+        r = &mut reader_handle => (joined(r), true),
+    };
+    // The writer stops quietly once the reader hangs up; the reader's own
+    // result says why the session ended
+    if !reader_done && result.is_ok() && update_req_rx.is_closed() {
+        result = joined((&mut reader_handle).await);
+        reader_done = true;
     }
 
-    Ok(())
+    // Stop the reader before releasing keys so no event of this client can
+    // arrive after the release
+    if !reader_done {
+        reader_handle.abort();
+        let _ = reader_handle.await;
+    }
+    let releases = held_keys.lock().unwrap().releases();
+    if !releases.is_empty() {
+        tracing::debug!("Releasing {} held keys", releases.len());
+    }
+    for event in releases {
+        let _ = input_tx.send(event).await;
+    }
+
+    let reason = end_reason(&result);
+    audit.close(&reason);
+    tracing::info!(
+        peer,
+        reason,
+        frames_sent,
+        bytes_written = writer.get_ref().bytes,
+        input_events = input_events.load(Ordering::Relaxed),
+        avg_frame_age_ms = frame_age.avg_ms.round(),
+        duration_secs = started.elapsed().as_secs(),
+        "Client session ended"
+    );
+    result
 }
 
 async fn read_client_messages(
-    mut reader: tokio::net::tcp::OwnedReadHalf,
+    mut reader: impl AsyncRead + Unpin,
     update_req_tx: mpsc::Sender<bool>,
+    control_tx: mpsc::Sender<ClientControl>,
     input_tx: mpsc::Sender<InputEvent>,
     pf_tx: watch::Sender<ClientPixelFormat>,
+    input_events: &AtomicU64,
+    held_keys: &std::sync::Mutex<HeldKeys>,
 ) -> Result<()> {
     loop {
         let mut msg_type = [0u8; 1];
-        reader
-            .read_exact(&mut msg_type)
-            .await
-            .context("read message type")?;
+        match reader.read_exact(&mut msg_type).await {
+            Ok(_) => {}
+            // Closed between two messages: the client simply went away
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                tracing::debug!("Client closed the connection");
+                return Ok(());
+            }
+            Err(e) => return Err(e).context("read message type"),
+        }
 
         match msg_type[0] {
             // SetPixelFormat
@@ -504,7 +1803,7 @@ async fn read_client_messages(
                     .read_exact(&mut buf)
                     .await
                     .context("read SetPixelFormat")?;
-                let pf = ClientPixelFormat::from_bytes(&buf[3..19]);
+                let pf = ClientPixelFormat::from_bytes(&buf[3..19])?;
                 tracing::info!(
                     "Client SetPixelFormat: {}bpp {}, r_shift={} g_shift={} b_shift={}, \
                      r_max={} g_max={} b_max={}",
@@ -532,6 +1831,15 @@ async fn read_client_messages(
                     .read_exact(&mut enc_buf)
                     .await
                     .context("read SetEncodings body")?;
+                let list: Vec<i32> = enc_buf
+                    .chunks_exact(4)
+                    .map(|c| i32::from_be_bytes([c[0], c[1], c[2], c[3]]))
+                    .collect();
+                tracing::debug!("Client SetEncodings: {list:?}");
+                let encodings = ClientEncodings::from_list(&list);
+                let _ = control_tx
+                    .send(ClientControl::SetEncodings(encodings))
+                    .await;
             }
             // FramebufferUpdateRequest
             3 => {
@@ -549,7 +1857,11 @@ async fn read_client_messages(
                 reader.read_exact(&mut buf).await.context("read KeyEvent")?;
                 let down = buf[0] != 0;
                 let keysym = u32::from_be_bytes([buf[3], buf[4], buf[5], buf[6]]);
-                let _ = input_tx.send(InputEvent::Key { down, keysym }).await;
+                // Recorded first: the reader may be stopped at any await
+                let event = InputEvent::Key { down, keysym };
+                held_keys.lock().unwrap().record(&event);
+                let _ = input_tx.send(event).await;
+                input_events.fetch_add(1, Ordering::Relaxed);
             }
             // PointerEvent
             5 => {
@@ -564,6 +1876,7 @@ async fn read_client_messages(
                 let _ = input_tx
                     .send(InputEvent::Pointer { button_mask, x, y })
                     .await;
+                input_events.fetch_add(1, Ordering::Relaxed);
             }
             // ClientCutText
             6 => {
@@ -579,9 +1892,1040 @@ async fn read_client_messages(
                     .await
                     .context("read ClientCutText body")?;
             }
+            // EnableContinuousUpdates
+            150 => {
+                let mut buf = [0u8; 9];
+                reader
+                    .read_exact(&mut buf)
+                    .await
+                    .context("read EnableContinuousUpdates")?;
+                let enable = buf[0] != 0;
+                let region = DirtyRect {
+                    x: u16::from_be_bytes([buf[1], buf[2]]),
+                    y: u16::from_be_bytes([buf[3], buf[4]]),
+                    width: u16::from_be_bytes([buf[5], buf[6]]),
+                    height: u16::from_be_bytes([buf[7], buf[8]]),
+                };
+                let _ = control_tx
+                    .send(ClientControl::EnableContinuousUpdates { enable, region })
+                    .await;
+            }
+            // ClientFence
+            248 => {
+                let mut buf = [0u8; 8]; // 3 padding + 4 flags + 1 length
+                reader
+                    .read_exact(&mut buf)
+                    .await
+                    .context("read ClientFence header")?;
+                let flags = u32::from_be_bytes([buf[3], buf[4], buf[5], buf[6]]);
+                let len = buf[7] as usize;
+                if len > 64 {
+                    bail!("ClientFence payload too long ({len} bytes)");
+                }
+                let mut payload = vec![0u8; len];
+                reader
+                    .read_exact(&mut payload)
+                    .await
+                    .context("read ClientFence payload")?;
+                let _ = control_tx
+                    .send(ClientControl::Fence { flags, payload })
+                    .await;
+            }
+            // SetDesktopSize
+            MSG_SET_DESKTOP_SIZE => {
+                let mut buf = [0u8; 7]; // 1 padding + 2 width + 2 height + 1 screens + 1 padding
+                reader
+                    .read_exact(&mut buf)
+                    .await
+                    .context("read SetDesktopSize header")?;
+                let width = u16::from_be_bytes([buf[1], buf[2]]);
+                let height = u16::from_be_bytes([buf[3], buf[4]]);
+                let mut screens = vec![0u8; buf[5] as usize * 16];
+                reader
+                    .read_exact(&mut screens)
+                    .await
+                    .context("read SetDesktopSize screens")?;
+                let _ = control_tx
+                    .send(ClientControl::SetDesktopSize { width, height })
+                    .await;
+            }
+            // QEMU client message
+            MSG_QEMU => {
+                let mut subtype = [0u8; 1];
+                reader
+                    .read_exact(&mut subtype)
+                    .await
+                    .context("read QEMU message subtype")?;
+                if subtype[0] != 0 {
+                    bail!("Unknown QEMU client message subtype: {}", subtype[0]);
+                }
+                let mut buf = [0u8; 10]; // 2 down-flag + 4 keysym + 4 keycode
+                reader
+                    .read_exact(&mut buf)
+                    .await
+                    .context("read QEMU extended key event")?;
+                let down = u16::from_be_bytes([buf[0], buf[1]]) != 0;
+                let keysym = u32::from_be_bytes([buf[2], buf[3], buf[4], buf[5]]);
+                let keycode = u32::from_be_bytes([buf[6], buf[7], buf[8], buf[9]]);
+                let event = InputEvent::ExtendedKey {
+                    down,
+                    keysym,
+                    keycode,
+                };
+                held_keys.lock().unwrap().record(&event);
+                let _ = input_tx.send(event).await;
+                input_events.fetch_add(1, Ordering::Relaxed);
+            }
             other => {
                 bail!("Unknown client message type: {other}");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::ValueEnum;
+    use tokio::io::DuplexStream;
+    use tokio::task::JoinHandle;
+
+    const WIDTH: u16 = 32;
+    const HEIGHT: u16 = 32;
+    /// Tile size of the test hub: full-screen updates are two 16-row bands.
+    const TILE_SIZE: u32 = 16;
+    /// Every pixel of the test frame (BGRA): r=0x11, g=0x22, b=0x33.
+    const PIXEL: [u8; 4] = [0x33, 0x22, 0x11, 0x00];
+    /// Handshake timeout of the test server.
+    const HANDSHAKE_TIMEOUT: Duration = Duration::from_millis(500);
+
+    /// Default server pixel format: 32bpp, depth 24, little-endian,
+    /// true-color, blue at bits 0-7, green at 8-15, red at 16-23.
+    /// This matches BGRA byte order in memory.
+    const PIXEL_FORMAT: [u8; 16] = [
+        32, // bits-per-pixel
+        24, // depth
+        0,  // big-endian-flag (little-endian)
+        1,  // true-colour-flag
+        0, 255, // red-max (255)
+        0, 255, // green-max (255)
+        0, 255, // blue-max (255)
+        16,  // red-shift
+        8,   // green-shift
+        0,   // blue-shift
+        0, 0, 0, // padding
+    ];
+
+    /// Run `handle_client` on one end of an in-memory pipe and return the
+    /// other end for the test to play the client.
+    fn start_server(password: Option<&'static str>) -> (DuplexStream, JoinHandle<Result<()>>) {
+        start_server_on(test_hub(), password)
+    }
+
+    /// Hub holding a uniform `PIXEL` frame.
+    fn test_hub() -> Arc<FrameHub> {
+        let frame = PIXEL.repeat(WIDTH as usize * HEIGHT as usize);
+        Arc::new(FrameHub::new(WIDTH as u32, HEIGHT as u32, TILE_SIZE, frame))
+    }
+
+    fn start_server_on(
+        hub: Arc<FrameHub>,
+        password: Option<&'static str>,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let all = SecurityType::value_variants();
+        let security = Security::new(password.map(String::from), all).unwrap();
+        start_server_with(hub, security, 0, 32)
+    }
+
+    fn start_server_with(
+        hub: Arc<FrameHub>,
+        security: Security,
+        band_height: u16,
+        depth: u8,
+    ) -> (DuplexStream, JoinHandle<Result<()>>) {
+        let (client, _input_rx, handle) = spawn_server(hub, security, band_height, depth, false);
+        (client, handle)
+    }
+
+    /// `start_server_with`, also handing out the client input the server
+    /// forwards.
+    fn spawn_server(
+        hub: Arc<FrameHub>,
+        security: Security,
+        band_height: u16,
+        depth: u8,
+        scancodes: bool,
+    ) -> (
+        DuplexStream,
+        mpsc::Receiver<InputEvent>,
+        JoinHandle<Result<()>>,
+    ) {
+        let options = ClientOptions {
+            width: WIDTH,
+            height: HEIGHT,
+            security: Arc::new(security),
+            defer_update: Duration::ZERO,
+            capture_interval: Duration::from_millis(33),
+            min_update_interval: Duration::ZERO,
+            coalesce: Duration::ZERO,
+            max_kbps: 0,
+            band_height,
+            full_refresh: FullRefreshThreshold::Off,
+            depth,
+            scancodes,
+        };
+        let (client, input_rx, _capture_req_rx, handle) = spawn_server_with_options(hub, options);
+        (client, input_rx, handle)
+    }
+
+    /// `spawn_server` with any `options`, also handing out the capture
+    /// requests the server makes.
+    fn spawn_server_with_options(
+        hub: Arc<FrameHub>,
+        options: ClientOptions,
+    ) -> (
+        DuplexStream,
+        mpsc::Receiver<InputEvent>,
+        std::sync::mpsc::Receiver<()>,
+        JoinHandle<Result<()>>,
+    ) {
+        let (client, server) = tokio::io::duplex(65536);
+        let (capture_req_tx, capture_req_rx) = std::sync::mpsc::channel();
+        let (input_tx, input_rx) = mpsc::channel(16);
+        let handle = tokio::spawn(async move {
+            handle_client(
+                server,
+                &mut AuditSession::new(None, "test", false),
+                hub,
+                capture_req_tx,
+                input_tx,
+                &options,
+                Some(tokio::time::Instant::now() + HANDSHAKE_TIMEOUT),
+            )
+            .await
+        });
+        (client, input_rx, capture_req_rx, handle)
+    }
+
+    async fn read_bytes<const N: usize>(client: &mut DuplexStream) -> [u8; N] {
+        let mut buf = [0u8; N];
+        client.read_exact(&mut buf).await.unwrap();
+        buf
+    }
+
+    async fn read_u32(client: &mut DuplexStream) -> u32 {
+        u32::from_be_bytes(read_bytes(client).await)
+    }
+
+    /// Exchange protocol versions, answering with `version`.
+    async fn exchange_version(client: &mut DuplexStream, version: &[u8; 12]) {
+        assert_eq!(&read_bytes::<12>(client).await, b"RFB 003.008\n");
+        client.write_all(version).await.unwrap();
+    }
+
+    /// Send ClientInit and check the ServerInit that follows.
+    async fn client_init(client: &mut DuplexStream) {
+        client.write_all(&[1]).await.unwrap();
+        let init: [u8; 24] = read_bytes(client).await;
+        assert_eq!(&init[0..4], &[0, WIDTH as u8, 0, HEIGHT as u8]);
+        assert_eq!(&init[4..20], &PIXEL_FORMAT);
+        assert_eq!(u32::from_be_bytes(init[20..24].try_into().unwrap()), 6);
+        assert_eq!(&read_bytes::<6>(client).await, b"kmsvnc");
+    }
+
+    #[tokio::test]
+    async fn full_frame_goes_out_in_bands() {
+        let all = SecurityType::value_variants();
+        let security = Security::new(None, all).unwrap();
+        let (mut client, _server) = start_server_with(test_hub(), security, 12, 32);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        let mut set_encodings = vec![2, 0, 0, 2];
+        set_encodings.extend_from_slice(&ENC_RAW.to_be_bytes());
+        set_encodings.extend_from_slice(&ENC_LAST_RECT.to_be_bytes());
+        client.write_all(&set_encodings).await.unwrap();
+        let full_request = [3, 0, 0, 0, 0, 0, 0, 32, 0, 32];
+        client.write_all(&full_request).await.unwrap();
+
+        // Bands of 12, 12 and 8 rows, then LastRect
+        assert_eq!(read_bytes::<4>(&mut client).await, [0, 0, 0xFF, 0xFF]);
+        for (y, height) in [(0u16, 12u16), (12, 12), (24, 8)] {
+            let rect: [u8; 12] = read_bytes(&mut client).await;
+            let mut expected = [0u8; 12];
+            expected[2..4].copy_from_slice(&y.to_be_bytes());
+            expected[4..6].copy_from_slice(&WIDTH.to_be_bytes());
+            expected[6..8].copy_from_slice(&height.to_be_bytes());
+            assert_eq!(rect, expected);
+            let mut pixels = vec![0u8; WIDTH as usize * height as usize * 4];
+            client.read_exact(&mut pixels).await.unwrap();
+            assert!(pixels.chunks(4).all(|p| p == PIXEL));
+        }
+        let last: [u8; 12] = read_bytes(&mut client).await;
+        assert_eq!(&last[8..], &ENC_LAST_RECT.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn zstd_rects_share_one_frame() {
+        let (mut client, _server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        let mut set_encodings = vec![2, 0, 0, 2];
+        set_encodings.extend_from_slice(&ENC_ZSTD.to_be_bytes());
+        set_encodings.extend_from_slice(&ENC_RAW.to_be_bytes());
+        client.write_all(&set_encodings).await.unwrap();
+        let full_request = [3, 0, 0, 0, 0, 0, 0, 32, 0, 32];
+        client.write_all(&full_request).await.unwrap();
+
+        // Only the first rect starts the frame; the second is mostly a
+        // match into the first
+        assert_eq!(read_bytes::<4>(&mut client).await, [0, 0, 0, 2]);
+        let mut sizes = Vec::new();
+        for _ in 0..2 {
+            let rect: [u8; 12] = read_bytes(&mut client).await;
+            assert_eq!(&rect[8..], &ENC_ZSTD.to_be_bytes());
+            let mut data = vec![0u8; read_u32(&mut client).await as usize];
+            client.read_exact(&mut data).await.unwrap();
+            sizes.push(data.len());
+            assert_eq!(
+                sizes.len() == 1,
+                data.starts_with(&[0x28, 0xB5, 0x2F, 0xFD])
+            );
+        }
+        assert!(sizes[1] < 32, "{sizes:?}");
+    }
+
+    #[tokio::test]
+    async fn depth_16_serves_rgb565_by_default() {
+        let all = SecurityType::value_variants();
+        let security = Security::new(None, all).unwrap();
+        let (mut client, _server) = start_server_with(test_hub(), security, 0, 16);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client.write_all(&[1]).await.unwrap();
+        let init: [u8; 24] = read_bytes(&mut client).await;
+        assert_eq!(
+            &init[4..20],
+            &[16, 16, 0, 1, 0, 31, 0, 63, 0, 31, 11, 5, 0, 0, 0, 0]
+        );
+        read_bytes::<6>(&mut client).await;
+
+        let full_request = [3, 0, 0, 0, 0, 0, 0, 32, 0, 32];
+        client.write_all(&full_request).await.unwrap();
+        assert_eq!(read_bytes::<4>(&mut client).await, [0, 0, 0, 2]);
+        for _ in 0..2 {
+            let rect: [u8; 12] = read_bytes(&mut client).await;
+            assert_eq!(&rect[8..], &ENC_RAW.to_be_bytes());
+            // r=0x11, g=0x22, b=0x33 scaled to 5-6-5 bits: 2, 8, 6
+            let mut pixels = vec![0u8; WIDTH as usize * TILE_SIZE as usize * 2];
+            client.read_exact(&mut pixels).await.unwrap();
+            assert!(pixels.chunks(2).all(|p| p == [0x06, 0x11]));
+        }
+    }
+
+    #[test]
+    fn bandwidth_cap_delays_after_a_burst() {
+        // 80 kbit/s is 10000 bytes per second, a second's worth banked
+        let mut bandwidth = Bandwidth::new(80).unwrap();
+        let start = bandwidth.last;
+        assert_eq!(bandwidth.ready_at(10_000, start), start);
+        // 5000 bytes over: half a second to pay off
+        let ready = bandwidth.ready_at(15_000, start);
+        assert_eq!(ready - start, Duration::from_millis(500));
+        // Idle time refills, but no more than a second's worth
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bandwidth.ready_at(15_000, later), later);
+        assert_eq!(bandwidth.ready_at(25_000, later), later);
+        assert!(bandwidth.ready_at(25_001, later) > later);
+        assert!(Bandwidth::new(0).is_none());
+    }
+
+    #[tokio::test]
+    async fn slow_reader_gets_the_whole_message() {
+        let timeout = Duration::from_millis(100);
+        let msg = vec![7u8; 16 * 1024];
+        // 1 KiB every 20 ms takes well over the timeout, but keeps moving
+        let (mut writer, mut reader) = tokio::io::duplex(1024);
+        let slow = tokio::spawn(async move {
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                match reader.read(&mut buf).await.unwrap() {
+                    0 => return received,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+        });
+        send_within(&mut writer, &msg, "update", timeout)
+            .await
+            .unwrap();
+        drop(writer);
+        assert_eq!(slow.await.unwrap(), msg);
+
+        // A reader that stops is given up on
+        let (mut writer, _reader) = tokio::io::duplex(1024);
+        let err = send_within(&mut writer, &msg, "update", timeout)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("slow client"), "{err:#}");
+    }
+
+    #[test]
+    fn full_refresh_threshold_by_coverage_or_count() {
+        let tile = |x| DirtyRect {
+            x,
+            y: 0,
+            width: 10,
+            height: 100,
+        };
+        // Every other column of a 110-wide area: six of eleven columns
+        let six: Vec<_> = (0..6).map(|i| tile(i * 20)).collect();
+        let bounds = DirtyRect {
+            x: 0,
+            y: 0,
+            width: 110,
+            height: 100,
+        };
+
+        let half: FullRefreshThreshold = "50%".parse().unwrap();
+        assert_eq!(half.merged(&six), Some(bounds));
+        // Tiles further apart cover less of their bounding box
+        assert_eq!(half.merged(&[tile(0), tile(40), tile(80)]), None);
+        assert_eq!(half.merged(&[tile(0), tile(100)]), None);
+
+        let five: FullRefreshThreshold = "5".parse().unwrap();
+        assert_eq!(five.merged(&six), Some(bounds));
+        assert_eq!(five.merged(&six[..5]), None);
+
+        assert_eq!("0".parse(), Ok(FullRefreshThreshold::Off));
+        assert_eq!(FullRefreshThreshold::Off.merged(&six), None);
+        assert!("150%".parse::<FullRefreshThreshold>().is_err());
+        assert!("half".parse::<FullRefreshThreshold>().is_err());
+    }
+
+    #[test]
+    fn server_pixel_formats_round_trip() {
+        assert_eq!(
+            ClientPixelFormat::server_default(32).to_bytes(),
+            PIXEL_FORMAT
+        );
+        for depth in [16, 24, 32] {
+            let pf = ClientPixelFormat::server_default(depth);
+            let parsed = ClientPixelFormat::from_bytes(&pf.to_bytes()).unwrap();
+            assert_eq!(parsed.to_bytes(), pf.to_bytes());
+            assert_eq!(parsed.bpp, depth);
+            assert_eq!(parsed.matches_server_default(), depth == 32);
+        }
+    }
+
+    #[tokio::test]
+    async fn led_state_sent_on_negotiation_and_change() {
+        let hub = test_hub();
+        let (mut client, _server) = start_server_on(hub.clone(), None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        let mut set_encodings = vec![2, 0, 0, 1];
+        set_encodings.extend_from_slice(&ENC_LED_STATE.to_be_bytes());
+        client.write_all(&set_encodings).await.unwrap();
+
+        let expected = |leds| {
+            let mut msg = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+            msg.extend_from_slice(&(-261i32).to_be_bytes());
+            msg.push(leds);
+            msg
+        };
+        assert_eq!(read_bytes::<17>(&mut client).await.to_vec(), expected(0));
+        hub.set_led_state(4); // Caps Lock
+        assert_eq!(read_bytes::<17>(&mut client).await.to_vec(), expected(4));
+    }
+
+    #[tokio::test]
+    async fn set_desktop_size_is_refused() {
+        let (mut client, _server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        let mut set_encodings = vec![2, 0, 0, 1];
+        set_encodings.extend_from_slice(&ENC_EXTENDED_DESKTOP_SIZE.to_be_bytes());
+        client.write_all(&set_encodings).await.unwrap();
+        let announced: [u8; 36] = read_bytes(&mut client).await;
+        assert_eq!(
+            announced.to_vec(),
+            extended_desktop_size_message(0, 0, WIDTH, HEIGHT)
+        );
+
+        // 64x64 as one screen
+        let mut resize = vec![MSG_SET_DESKTOP_SIZE, 0, 0, 64, 0, 64, 1, 0];
+        resize.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 64, 0, 64, 0, 0, 0, 0]);
+        client.write_all(&resize).await.unwrap();
+        let reply: [u8; 36] = read_bytes(&mut client).await;
+        assert_eq!(&reply[4..8], &[0, 1, 0, RESIZE_PROHIBITED as u8]);
+        assert_eq!(&reply[8..12], &[0, WIDTH as u8, 0, HEIGHT as u8]);
+        assert_eq!(&reply[12..16], &ENC_EXTENDED_DESKTOP_SIZE.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn scancode_mode_takes_qemu_extended_key_events() {
+        let all = SecurityType::value_variants();
+        let security = Security::new(None, all).unwrap();
+        let (mut client, mut input_rx, _server) = spawn_server(test_hub(), security, 0, 32, true);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        let mut set_encodings = vec![2, 0, 0, 1];
+        set_encodings.extend_from_slice(&ENC_QEMU_EXTENDED_KEY.to_be_bytes());
+        client.write_all(&set_encodings).await.unwrap();
+        let ack: [u8; 16] = read_bytes(&mut client).await;
+        assert_eq!(&ack[..4], &[0, 0, 0, 1]);
+        assert_eq!(&ack[12..], &ENC_QEMU_EXTENDED_KEY.to_be_bytes());
+
+        // Right Ctrl down: keysym 0xffe4, scancode E0 1D
+        let mut key = vec![MSG_QEMU, 0, 0, 1];
+        key.extend_from_slice(&0xffe4u32.to_be_bytes());
+        key.extend_from_slice(&0x9du32.to_be_bytes());
+        client.write_all(&key).await.unwrap();
+        match input_rx.recv().await.unwrap() {
+            InputEvent::ExtendedKey {
+                down,
+                keysym,
+                keycode,
+            } => assert_eq!((down, keysym, keycode), (true, 0xffe4, 0x9d)),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn held_request_asks_for_captures_at_the_capture_interval() {
+        let hub = test_hub();
+        let all = SecurityType::value_variants();
+        let options = ClientOptions {
+            width: WIDTH,
+            height: HEIGHT,
+            security: Arc::new(Security::new(None, all).unwrap()),
+            defer_update: Duration::from_millis(300),
+            capture_interval: Duration::from_millis(50),
+            min_update_interval: Duration::ZERO,
+            coalesce: Duration::ZERO,
+            max_kbps: 0,
+            band_height: 0,
+            full_refresh: FullRefreshThreshold::Off,
+            depth: 32,
+            scancodes: false,
+        };
+        let (mut client, _input_rx, capture_req_rx, server) =
+            spawn_server_with_options(hub.clone(), options);
+        // An on-demand capture that finds nothing new, answered at once
+        let captures = std::thread::spawn(move || {
+            let mut count = 0;
+            while capture_req_rx.recv().is_ok() {
+                count += 1;
+                hub.notify_unchanged();
+            }
+            count
+        });
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        // Take the initial frame, then hold an incremental request
+        client
+            .write_all(&[3, 0, 0, 0, 0, 0, 0, WIDTH as u8, 0, HEIGHT as u8])
+            .await
+            .unwrap();
+        let header: [u8; 4] = read_bytes(&mut client).await;
+        for _ in 0..u16::from_be_bytes([header[2], header[3]]) {
+            let rect: [u8; 12] = read_bytes(&mut client).await;
+            let (w, h) = (
+                u16::from_be_bytes([rect[4], rect[5]]),
+                u16::from_be_bytes([rect[6], rect[7]]),
+            );
+            let mut pixels = vec![0u8; w as usize * h as usize * 4];
+            client.read_exact(&mut pixels).await.unwrap();
+        }
+        client
+            .write_all(&[3, 1, 0, 0, 0, 0, 0, WIDTH as u8, 0, HEIGHT as u8])
+            .await
+            .unwrap();
+        assert_eq!(read_bytes::<4>(&mut client).await, [0, 0, 0, 0]);
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        // About one per 50 ms over the 300 ms it was held, not a storm
+        let count = captures.join().unwrap();
+        assert!((2..=12).contains(&count), "{count} capture requests");
+    }
+
+    #[tokio::test]
+    async fn disconnect_releases_the_clients_held_keys() {
+        let all = SecurityType::value_variants();
+        let security = Security::new(None, all).unwrap();
+        let (mut client, mut input_rx, server) = spawn_server(test_hub(), security, 0, 32, true);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        // Right Ctrl held by scancode; "a" pressed and released, "b" held
+        let mut keys = vec![MSG_QEMU, 0, 0, 1];
+        keys.extend_from_slice(&0xffe4u32.to_be_bytes());
+        keys.extend_from_slice(&0x9du32.to_be_bytes());
+        for (down, keysym) in [(1, 0x61u32), (0, 0x61), (1, 0x62)] {
+            keys.extend_from_slice(&[4, down, 0, 0]);
+            keys.extend_from_slice(&keysym.to_be_bytes());
+        }
+        client.write_all(&keys).await.unwrap();
+        for _ in 0..4 {
+            input_rx.recv().await.unwrap();
+        }
+        drop(client);
+        server.await.unwrap().unwrap();
+
+        let mut released = Vec::new();
+        while let Some(event) = input_rx.recv().await {
+            released.push(format!("{event:?}"));
+        }
+        released.sort();
+        assert_eq!(
+            released,
+            [
+                "ExtendedKey { down: false, keysym: 65508, keycode: 157 }",
+                "Key { down: false, keysym: 98 }",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn desktop_name_in_server_init_and_on_rename() {
+        let hub = test_hub();
+        hub.set_desktop_name("panel".into());
+        let (mut client, _server) = start_server_on(hub.clone(), None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client.write_all(&[1]).await.unwrap();
+        let init: [u8; 24] = read_bytes(&mut client).await;
+        assert_eq!(u32::from_be_bytes(init[20..24].try_into().unwrap()), 5);
+        assert_eq!(&read_bytes::<5>(&mut client).await, b"panel");
+
+        let mut set_encodings = vec![2, 0, 0, 1];
+        set_encodings.extend_from_slice(&ENC_DESKTOP_NAME.to_be_bytes());
+        client.write_all(&set_encodings).await.unwrap();
+        hub.set_desktop_name("panel 2".into());
+
+        let mut expected = vec![0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0];
+        expected.extend_from_slice(&(-307i32).to_be_bytes());
+        expected.extend_from_slice(&7u32.to_be_bytes());
+        expected.extend_from_slice(b"panel 2");
+        assert_eq!(read_bytes::<27>(&mut client).await.to_vec(), expected);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pixel_format_change_never_splits_an_update() {
+        let (mut client, _server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        client_init(&mut client).await;
+
+        // RGB565 little-endian
+        let mut rgb565 = PIXEL_FORMAT;
+        rgb565[..2].copy_from_slice(&[16, 16]);
+        rgb565[4..10].copy_from_slice(&[0, 31, 0, 63, 0, 31]);
+        rgb565[10..13].copy_from_slice(&[11, 5, 0]);
+        // PIXEL scaled to 5-6-5: (2 << 11) | (8 << 5) | 6
+        let pixel_565 = 0x1106u16.to_le_bytes();
+
+        // Each update must be entirely in one format: the one before or
+        // the one after the change, never a mix across its rects
+        let request = [3, 0, 0, 0, 0, 0, 0, WIDTH as u8, 0, HEIGHT as u8];
+        let mut msgs = request.to_vec();
+        msgs.extend_from_slice(&[0, 0, 0, 0]);
+        msgs.extend_from_slice(&rgb565);
+        client.write_all(&msgs).await.unwrap();
+        let first = read_update_pixel(&mut client, pixel_565).await;
+        assert!(first == PIXEL || first == pixel_565, "{first:?}");
+
+        client.write_all(&request).await.unwrap();
+        assert_eq!(read_update_pixel(&mut client, pixel_565).await, pixel_565);
+    }
+
+    /// Read a Raw FramebufferUpdate of the uniform test frame and return its
+    /// pixel, checking that every rect encodes it the same way.
+    async fn read_update_pixel(client: &mut DuplexStream, pixel_565: [u8; 2]) -> Vec<u8> {
+        let header: [u8; 4] = read_bytes(client).await;
+        let rects = u16::from_be_bytes([header[2], header[3]]);
+        assert_eq!(rects, 2);
+
+        let mut pixels = Vec::new();
+        for _ in 0..rects {
+            let rect: [u8; 12] = read_bytes(client).await;
+            assert_eq!(&rect[8..], &ENC_RAW.to_be_bytes());
+            let w = u16::from_be_bytes([rect[4], rect[5]]) as usize;
+            let h = u16::from_be_bytes([rect[6], rect[7]]) as usize;
+            // The first two bytes tell the two formats apart
+            let head: [u8; 2] = read_bytes(client).await;
+            let mut pixel = head.to_vec();
+            if head != pixel_565 {
+                pixel.extend_from_slice(&read_bytes::<2>(client).await);
+            }
+            let mut rest = vec![0u8; (w * h - 1) * pixel.len()];
+            client.read_exact(&mut rest).await.unwrap();
+            assert!(rest.chunks(pixel.len()).all(|p| p == pixel));
+            pixels.push(pixel);
+        }
+        assert!(
+            pixels.iter().all(|p| *p == pixels[0]),
+            "update mixes pixel formats: {pixels:?}"
+        );
+        pixels.swap_remove(0)
+    }
+
+    fn pixel_format_24bpp(big_endian: bool) -> ClientPixelFormat {
+        ClientPixelFormat {
+            bpp: 24,
+            big_endian,
+            ..ClientPixelFormat::server_default(32)
+        }
+    }
+
+    #[test]
+    fn convert_24bpp_honours_endianness() {
+        // BGRA for r=0x10, g=0x20, b=0x30: pixel value 0x102030
+        let bgra = [0x30, 0x20, 0x10, 0xFF];
+
+        let mut out = Vec::new();
+        convert_row_into(&bgra, &pixel_format_24bpp(true), &mut out);
+        assert_eq!(out, [0x10, 0x20, 0x30]);
+
+        out.clear();
+        convert_row_into(&bgra, &pixel_format_24bpp(false), &mut out);
+        assert_eq!(out, [0x30, 0x20, 0x10]);
+    }
+
+    #[test]
+    fn pixel_format_rejects_out_of_range_shift() {
+        let mut buf = PIXEL_FORMAT;
+        buf[10] = 40; // red-shift
+        let err = ClientPixelFormat::from_bytes(&buf).unwrap_err();
+        assert!(err.to_string().contains("shift 40"), "{err}");
+    }
+
+    #[test]
+    fn pixel_format_rejects_wide_colour_map() {
+        let mut buf = PIXEL_FORMAT;
+        buf[0] = 16; // bits-per-pixel
+        buf[3] = 0; // true-colour-flag
+        let err = ClientPixelFormat::from_bytes(&buf).unwrap_err();
+        assert!(err.to_string().contains("colour-mapped"), "{err}");
+    }
+
+    #[test]
+    fn colour_mapped_8bpp_uses_332_palette() {
+        let mut buf = PIXEL_FORMAT;
+        buf[0] = 8; // bits-per-pixel
+        buf[3] = 0; // true-colour-flag
+        let pf = ClientPixelFormat::from_bytes(&buf).unwrap();
+        assert!(pf.colour_map);
+
+        // Pure red, green, blue and white (BGRA) → RRRGGGBB indices
+        let bgra = [0, 0, 255, 0, 0, 255, 0, 0, 255, 0, 0, 0, 255, 255, 255, 0];
+        let mut out = Vec::new();
+        convert_row_into(&bgra, &pf, &mut out);
+        assert_eq!(out, [0xE0, 0x1C, 0x03, 0xFF]);
+
+        // The colour map entry for each index is the colour it stands for
+        let msg = colour_map_message();
+        assert_eq!(&msg[..6], &[1, 0, 0, 0, 1, 0]);
+        let entry = |i: usize| &msg[6 + i * 6..12 + i * 6];
+        assert_eq!(entry(0xE0), &[0xFF, 0xFF, 0, 0, 0, 0]);
+        assert_eq!(entry(0x03), &[0, 0, 0, 0, 0xFF, 0xFF]);
+        assert_eq!(entry(0xFF), &[0xFF; 6]);
+    }
+
+    #[test]
+    fn pixel_format_accepts_server_default() {
+        let pf = ClientPixelFormat::from_bytes(&PIXEL_FORMAT).unwrap();
+        assert!(pf.matches_server_default());
+    }
+
+    #[test]
+    fn vnc_des_auth_known_answer() {
+        let challenge: [u8; 16] = std::array::from_fn(|i| i as u8);
+        assert_eq!(
+            vnc_des_auth("password", &challenge),
+            [
+                0xb8, 0x66, 0x92, 0x41, 0x25, 0xc8, 0xee, 0xbb, 0x9d, 0xeb, 0xc1, 0xdb, 0x61, 0xc5,
+                0x38, 0xe2
+            ]
+        );
+        // Only the first 8 characters are part of the key
+        assert_eq!(
+            vnc_des_auth("password-and-more", &challenge),
+            vnc_des_auth("password", &challenge)
+        );
+    }
+
+    #[test]
+    fn vnc_des_auth_pads_short_passwords() {
+        // Cross-checked with `openssl enc -des-ecb` on the bit-reversed,
+        // zero-padded key. Both blocks are encrypted independently (ECB).
+        let block = [0x96, 0x13, 0xa2, 0x3f, 0x1d, 0xca, 0xc5, 0x41];
+        let mut expected = [0u8; 16];
+        expected[..8].copy_from_slice(&block);
+        expected[8..].copy_from_slice(&block);
+        assert_eq!(vnc_des_auth("secret", &[0; 16]), expected);
+
+        let block = [0x35, 0x55, 0x50, 0xb2, 0x15, 0x0e, 0x24, 0x51];
+        expected[..8].copy_from_slice(&block);
+        expected[8..].copy_from_slice(&block);
+        assert_eq!(vnc_des_auth("", &[0xff; 16]), expected);
+    }
+
+    #[tokio::test]
+    async fn rfb_33_server_picks_security_none() {
+        let (mut client, _server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.003\n").await;
+        assert_eq!(read_u32(&mut client).await, SEC_NONE as u32);
+        client_init(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn rfb_33_vnc_auth() {
+        let (mut client, _server) = start_server(Some("secret"));
+        exchange_version(&mut client, b"RFB 003.003\n").await;
+        assert_eq!(read_u32(&mut client).await, SEC_VNC_AUTH as u32);
+        let challenge = read_bytes(&mut client).await;
+        let response = vnc_des_auth("secret", &challenge);
+        client.write_all(&response).await.unwrap();
+        assert_eq!(read_u32(&mut client).await, 0);
+        client_init(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn rfb_37_none_has_no_security_result() {
+        let (mut client, _server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.007\n").await;
+        assert_eq!(read_bytes::<3>(&mut client).await, [2, SEC_NONE, SEC_TIGHT]);
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        // ServerInit follows directly
+        client_init(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn rfb_37_failed_auth_has_no_reason() {
+        let (mut client, server) = start_server(Some("secret"));
+        exchange_version(&mut client, b"RFB 003.007\n").await;
+        assert_eq!(
+            read_bytes::<4>(&mut client).await,
+            [3, SEC_VNC_AUTH, SEC_ARD, SEC_TIGHT]
+        );
+        client.write_all(&[SEC_VNC_AUTH]).await.unwrap();
+        let challenge = read_bytes(&mut client).await;
+        let response = vnc_des_auth("wrong", &challenge);
+        client.write_all(&response).await.unwrap();
+        assert_eq!(read_u32(&mut client).await, 1);
+
+        assert!(server.await.unwrap().is_err());
+        let mut rest = Vec::new();
+        client.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty(), "unexpected bytes after SecurityResult");
+    }
+
+    #[tokio::test]
+    async fn rfb_38_none_sends_security_result() {
+        let (mut client, _server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        assert_eq!(read_bytes::<3>(&mut client).await, [2, SEC_NONE, SEC_TIGHT]);
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        assert_eq!(read_u32(&mut client).await, 0);
+        client_init(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn stalled_handshake_is_dropped() {
+        let (mut client, server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_NONE]).await.unwrap();
+        read_u32(&mut client).await;
+        // No ClientInit
+        let err = server.await.unwrap().unwrap_err();
+        assert!(format!("{err:#}").contains("in ClientInit"), "{err:#}");
+    }
+
+    #[tokio::test]
+    async fn clean_close_is_told_apart_from_bad_messages() {
+        // Closed between messages, in the middle of a FramebufferUpdateRequest,
+        // and after an unknown message type
+        for input in [&[][..], &[3, 0], &[99]] {
+            let (mut client, server) = start_server(None);
+            exchange_version(&mut client, b"RFB 003.008\n").await;
+            read_bytes::<3>(&mut client).await;
+            client.write_all(&[SEC_NONE]).await.unwrap();
+            read_u32(&mut client).await;
+            client_init(&mut client).await;
+            client.write_all(input).await.unwrap();
+            drop(client);
+
+            let result = server.await.unwrap();
+            match input {
+                [] => assert!(result.is_ok()),
+                [3, ..] => assert!(is_disconnect(&result.unwrap_err())),
+                _ => assert!(!is_disconnect(&result.unwrap_err())),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn rfb_38_vnc_auth() {
+        let (mut client, _server) = start_server(Some("secret"));
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        assert_eq!(
+            read_bytes::<4>(&mut client).await,
+            [3, SEC_VNC_AUTH, SEC_ARD, SEC_TIGHT]
+        );
+        client.write_all(&[SEC_VNC_AUTH]).await.unwrap();
+        let challenge = read_bytes(&mut client).await;
+        let response = vnc_des_auth("secret", &challenge);
+        client.write_all(&response).await.unwrap();
+        assert_eq!(read_u32(&mut client).await, 0);
+        client_init(&mut client).await;
+    }
+
+    #[tokio::test]
+    async fn rfb_38_failed_auth_sends_reason() {
+        let (mut client, server) = start_server(Some("secret"));
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<4>(&mut client).await;
+        client.write_all(&[SEC_VNC_AUTH]).await.unwrap();
+        let challenge = read_bytes(&mut client).await;
+        let response = vnc_des_auth("wrong", &challenge);
+        client.write_all(&response).await.unwrap();
+
+        assert_eq!(read_u32(&mut client).await, 1);
+        let len = read_u32(&mut client).await as usize;
+        let mut reason = vec![0u8; len];
+        client.read_exact(&mut reason).await.unwrap();
+        assert_eq!(reason, b"Authentication failed");
+        // Told apart from a broken handshake in the audit log
+        assert!(server.await.unwrap().unwrap_err().is::<AuthFailed>());
+    }
+
+    #[tokio::test]
+    async fn tight_security_wraps_vnc_auth_or_none() {
+        for password in [Some("secret"), None] {
+            let (mut client, _server) = start_server(password);
+            exchange_version(&mut client, b"RFB 003.008\n").await;
+            let count = read_bytes::<1>(&mut client).await[0] as usize;
+            let mut types = vec![0u8; count];
+            client.read_exact(&mut types).await.unwrap();
+            assert_eq!(types.last(), Some(&SEC_TIGHT));
+            client.write_all(&[SEC_TIGHT]).await.unwrap();
+
+            // One tunnel, "no tunnel", which the client picks
+            assert_eq!(read_u32(&mut client).await, 1);
+            let tunnel: [u8; 16] = read_bytes(&mut client).await;
+            assert_eq!(tunnel, tight_capability(0, b"TGHT", b"NOTUNNEL"));
+            client.write_all(&0u32.to_be_bytes()).await.unwrap();
+
+            // One auth type, matching the password
+            assert_eq!(read_u32(&mut client).await, 1);
+            let auth: [u8; 16] = read_bytes(&mut client).await;
+            client.write_all(&auth[..4]).await.unwrap();
+            if password.is_some() {
+                assert_eq!(&auth[4..], b"STDVVNCAUTH_");
+                let challenge = read_bytes(&mut client).await;
+                let response = vnc_des_auth("secret", &challenge);
+                client.write_all(&response).await.unwrap();
+            } else {
+                assert_eq!(&auth[4..], b"STDVNOAUTH__");
+            }
+            assert_eq!(read_u32(&mut client).await, 0);
+
+            // ServerInit carries empty interaction capability lists
+            client_init(&mut client).await;
+            assert_eq!(read_bytes::<8>(&mut client).await, [0; 8]);
+        }
+    }
+
+    #[tokio::test]
+    async fn security_types_restrict_the_offer() {
+        let ard_only = || Security::new(Some("secret".into()), &[SecurityType::Ard]).unwrap();
+
+        // RFB 3.8: only ARD is listed, and DES is refused
+        let (mut client, server) = start_server_with(test_hub(), ard_only(), 0, 32);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        assert_eq!(read_bytes::<2>(&mut client).await, [1, SEC_ARD]);
+        client.write_all(&[SEC_VNC_AUTH]).await.unwrap();
+        assert!(server.await.unwrap().is_err());
+
+        // RFB 3.3 has no ARD: the client is refused with a reason
+        let (mut client, server) = start_server_with(test_hub(), ard_only(), 0, 32);
+        exchange_version(&mut client, b"RFB 003.003\n").await;
+        assert_eq!(read_u32(&mut client).await, 0);
+        let len = read_u32(&mut client).await as usize;
+        let mut reason = vec![0u8; len];
+        client.read_exact(&mut reason).await.unwrap();
+        assert!(server.await.unwrap().is_err());
+
+        // Nothing left to offer
+        assert!(Security::new(None, &[SecurityType::Vnc]).is_err());
+        assert!(Security::new(Some("secret".into()), &[SecurityType::None]).is_err());
+    }
+
+    #[tokio::test]
+    async fn ard_authentication() {
+        for (password, result) in [("secret", 0), ("guess", 1)] {
+            let security = Security::new(Some("secret".into()), &[SecurityType::Ard]).unwrap();
+            let (mut client, server) = start_server_with(test_hub(), security, 0, 32);
+            exchange_version(&mut client, b"RFB 003.008\n").await;
+            assert_eq!(read_bytes::<2>(&mut client).await, [1, SEC_ARD]);
+            client.write_all(&[SEC_ARD]).await.unwrap();
+
+            let mut params = vec![0u8; 4 + 2 * 128];
+            client.read_exact(&mut params).await.unwrap();
+            let response = ard::tests::client_response(&params, "user", password);
+            client.write_all(&response).await.unwrap();
+            assert_eq!(read_u32(&mut client).await, result, "{password:?}");
+            if result == 0 {
+                client_init(&mut client).await;
+            } else {
+                assert!(server.await.unwrap().is_err());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn unoffered_security_type_is_rejected() {
+        let (mut client, server) = start_server(None);
+        exchange_version(&mut client, b"RFB 003.008\n").await;
+        read_bytes::<3>(&mut client).await;
+        client.write_all(&[SEC_VNC_AUTH]).await.unwrap();
+        assert!(server.await.unwrap().is_err());
+    }
+}
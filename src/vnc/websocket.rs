@@ -0,0 +1,448 @@
+use anyhow::{bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+/// GUID appended to the client key when computing Sec-WebSocket-Accept.
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Upper bound for the HTTP upgrade request.
+const MAX_REQUEST_LEN: usize = 8192;
+
+/// Upper bound for a single client frame; RFB client messages are small.
+const MAX_FRAME_LEN: u64 = 1 << 20;
+
+/// Buffer size of the in-memory pipe between the RFB session and the pumps.
+const PIPE_SIZE: usize = 65536;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Perform the WebSocket upgrade handshake on `stream` and return a byte
+/// stream carrying the raw RFB protocol.
+///
+/// Two tasks translate between WebSocket binary frames on the socket and the
+/// returned pipe, so `handle_client` runs unchanged over it. The pumps end
+/// when either side closes.
+pub async fn accept(mut stream: TcpStream) -> Result<DuplexStream> {
+    let (request, leftover) = read_request(&mut stream).await?;
+    let response = upgrade_response(&request)?;
+    stream
+        .write_all(response.as_bytes())
+        .await
+        .context("send WebSocket upgrade response")?;
+
+    let (rfb_side, ws_side) = tokio::io::duplex(PIPE_SIZE);
+    let (pipe_rx, pipe_tx) = tokio::io::split(ws_side);
+    let (sock_rx, sock_tx) = stream.into_split();
+    // Control frames (pong, close) the inbound pump wants sent
+    let (ctrl_tx, ctrl_rx) = mpsc::channel::<(u8, Vec<u8>)>(4);
+
+    tokio::spawn(async move {
+        if let Err(e) = pump_inbound(sock_rx, leftover, pipe_tx, ctrl_tx).await {
+            tracing::debug!("WebSocket reader ended: {e}");
+        }
+    });
+    tokio::spawn(async move {
+        if let Err(e) = pump_outbound(pipe_rx, sock_tx, ctrl_rx).await {
+            tracing::debug!("WebSocket writer ended: {e}");
+        }
+    });
+
+    Ok(rfb_side)
+}
+
+/// Read the HTTP request head. Returns it along with any bytes the client
+/// sent after the blank line.
+async fn read_request(stream: &mut TcpStream) -> Result<(String, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 1024];
+    loop {
+        if let Some(end) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            let leftover = buf.split_off(end + 4);
+            let request = String::from_utf8(buf).context("WebSocket request is not UTF-8")?;
+            return Ok((request, leftover));
+        }
+        if buf.len() > MAX_REQUEST_LEN {
+            bail!("WebSocket upgrade request too large");
+        }
+        let n = stream
+            .read(&mut chunk)
+            .await
+            .context("read WebSocket upgrade request")?;
+        if n == 0 {
+            bail!("Connection closed during WebSocket upgrade");
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Validate the upgrade request and build the 101 response.
+fn upgrade_response(request: &str) -> Result<String> {
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    if !request_line.starts_with("GET ") {
+        bail!("Unexpected WebSocket request: {request_line}");
+    }
+
+    let mut key = None;
+    let mut binary_protocol = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("sec-websocket-key") {
+            key = Some(value);
+        } else if name.eq_ignore_ascii_case("sec-websocket-protocol") {
+            binary_protocol = value.split(',').any(|p| p.trim() == "binary");
+        }
+    }
+    let key = key.context("WebSocket request has no Sec-WebSocket-Key")?;
+
+    let accept = base64_encode(&sha1(format!("{key}{WS_GUID}").as_bytes()));
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {accept}\r\n"
+    );
+    // Older noVNC versions ask for the "binary" subprotocol
+    if binary_protocol {
+        response.push_str("Sec-WebSocket-Protocol: binary\r\n");
+    }
+    response.push_str("\r\n");
+    Ok(response)
+}
+
+/// Unmask client frames and forward their payloads into the pipe.
+async fn pump_inbound(
+    mut sock: impl AsyncRead + Unpin,
+    mut buf: Vec<u8>,
+    mut pipe: impl AsyncWrite + Unpin,
+    ctrl_tx: mpsc::Sender<(u8, Vec<u8>)>,
+) -> Result<()> {
+    let mut chunk = vec![0u8; 16384];
+    loop {
+        while let Some((opcode, payload, used)) = parse_frame(&buf)? {
+            buf.drain(..used);
+            match opcode {
+                OP_BINARY | OP_TEXT | OP_CONTINUATION => {
+                    pipe.write_all(&payload)
+                        .await
+                        .context("write to RFB pipe")?;
+                }
+                OP_PING => {
+                    ctrl_tx.send((OP_PONG, payload)).await.ok();
+                }
+                OP_CLOSE => {
+                    ctrl_tx.send((OP_CLOSE, payload)).await.ok();
+                    return Ok(());
+                }
+                _ => {}
+            }
+        }
+        let n = sock
+            .read(&mut chunk)
+            .await
+            .context("read WebSocket frame")?;
+        if n == 0 {
+            return Ok(());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+/// Wrap bytes from the pipe into binary frames, interleaving control frames.
+async fn pump_outbound(
+    mut pipe: impl AsyncRead + Unpin,
+    mut sock: impl AsyncWrite + Unpin,
+    mut ctrl_rx: mpsc::Receiver<(u8, Vec<u8>)>,
+) -> Result<()> {
+    let mut chunk = vec![0u8; PIPE_SIZE];
+    let mut frame = Vec::with_capacity(PIPE_SIZE + 10);
+    loop {
+        tokio::select! {
+            ctrl = ctrl_rx.recv() => {
+                // The reader is gone once the client disconnects
+                let Some((opcode, payload)) = ctrl else { return Ok(()) };
+                write_frame_header(&mut frame, opcode, payload.len());
+                frame.extend_from_slice(&payload);
+                sock.write_all(&frame).await.context("send WebSocket control frame")?;
+                if opcode == OP_CLOSE {
+                    return Ok(());
+                }
+            }
+            n = pipe.read(&mut chunk) => {
+                let n = n.context("read from RFB pipe")?;
+                if n == 0 {
+                    // Session ended: close normally (status 1000)
+                    write_frame_header(&mut frame, OP_CLOSE, 2);
+                    frame.extend_from_slice(&1000u16.to_be_bytes());
+                    sock.write_all(&frame).await.ok();
+                    return Ok(());
+                }
+                write_frame_header(&mut frame, OP_BINARY, n);
+                frame.extend_from_slice(&chunk[..n]);
+                sock.write_all(&frame).await.context("send WebSocket frame")?;
+            }
+        }
+    }
+}
+
+/// Parse one complete frame from `buf`.
+/// Returns (opcode, unmasked payload, bytes consumed), or None if incomplete.
+fn parse_frame(buf: &[u8]) -> Result<Option<(u8, Vec<u8>, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    let opcode = buf[0] & 0x0F;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut pos) = match buf[1] & 0x7F {
+        126 if buf.len() >= 4 => (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4),
+        127 if buf.len() >= 10 => (u64::from_be_bytes(buf[2..10].try_into().unwrap()), 10),
+        126 | 127 => return Ok(None),
+        n => (n as u64, 2),
+    };
+    if !masked {
+        bail!("Client WebSocket frame is not masked");
+    }
+    if len > MAX_FRAME_LEN {
+        bail!("WebSocket frame too large ({len} bytes)");
+    }
+    let len = len as usize;
+    if buf.len() < pos + 4 + len {
+        return Ok(None);
+    }
+    let mask = [buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]];
+    pos += 4;
+    let payload = buf[pos..pos + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ mask[i % 4])
+        .collect();
+    Ok(Some((opcode, payload, pos + len)))
+}
+
+/// Start a new unmasked, final frame in `out` (clearing it first).
+fn write_frame_header(out: &mut Vec<u8>, opcode: u8, len: usize) {
+    out.clear();
+    out.push(0x80 | opcode);
+    if len < 126 {
+        out.push(len as u8);
+    } else if len <= u16::MAX as usize {
+        out.push(126);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(127);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+}
+
+/// SHA-1 digest (FIPS 180-4); only used for the handshake accept key.
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for block in msg.chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes(word.try_into().unwrap());
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, &wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let t = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+        for (hi, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hi = hi.wrapping_add(v);
+        }
+    }
+
+    let mut out = [0u8; 20];
+    for (chunk, v) in out.chunks_exact_mut(4).zip(h) {
+        chunk.copy_from_slice(&v.to_be_bytes());
+    }
+    out
+}
+
+/// Standard base64 with padding.
+fn base64_encode(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let b = [
+            chunk[0],
+            *chunk.get(1).unwrap_or(&0),
+            *chunk.get(2).unwrap_or(&0),
+        ];
+        let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A final client frame, masked with `mask` as RFC 6455 requires.
+    fn client_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+        let mut frame = Vec::new();
+        write_frame_header(&mut frame, opcode, payload.len());
+        frame[1] |= 0x80;
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn accept_key_rfc6455_example() {
+        let request = "GET /chat HTTP/1.1\r\n\
+                       Host: server.example.com\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Version: 13\r\n";
+        let response = upgrade_response(request).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101 "));
+        assert!(response.contains("\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!response.contains("Sec-WebSocket-Protocol"));
+        assert!(response.ends_with("\r\n\r\n"));
+
+        assert!(upgrade_response("GET / HTTP/1.1\r\nHost: x\r\n").is_err());
+        assert!(upgrade_response("POST / HTTP/1.1\r\nSec-WebSocket-Key: x\r\n").is_err());
+    }
+
+    #[test]
+    fn sha1_and_base64_known_answers() {
+        let hex: String = sha1(b"abc").iter().map(|b| format!("{b:02x}")).collect();
+        assert_eq!(hex, "a9993e364706816aba3e25717850c26c9cd0d89d");
+        // RFC 4648 section 10
+        for (input, encoded) in [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64_encode(input.as_bytes()), encoded);
+        }
+    }
+
+    #[test]
+    fn parse_frame_lengths() {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        // RFC 6455 section 5.7: a masked "Hello"
+        let hello = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        assert_eq!(client_frame(OP_TEXT, b"Hello", mask), hello);
+        assert_eq!(
+            parse_frame(&hello).unwrap(),
+            Some((OP_TEXT, b"Hello".to_vec(), hello.len()))
+        );
+
+        // 16-bit and 64-bit extended lengths
+        for len in [126, 300, u16::MAX as usize, 70000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = client_frame(OP_BINARY, &payload, mask);
+            assert_eq!(frame[1] & 0x7f, if len <= 0xffff { 126 } else { 127 });
+            assert_eq!(
+                parse_frame(&frame).unwrap(),
+                Some((OP_BINARY, payload, frame.len()))
+            );
+        }
+
+        // A second frame right behind the first is left in the buffer
+        let mut two = hello.to_vec();
+        two.extend_from_slice(&client_frame(OP_BINARY, b"!", mask));
+        assert_eq!(parse_frame(&two).unwrap().unwrap().2, hello.len());
+    }
+
+    #[test]
+    fn parse_frame_waits_for_the_whole_frame() {
+        let frame = client_frame(OP_BINARY, &[7; 300], [1, 2, 3, 4]);
+        // Cut in the extended length, the mask and the payload
+        for cut in [0, 1, 3, 5, 100, frame.len() - 1] {
+            assert_eq!(parse_frame(&frame[..cut]).unwrap(), None, "cut at {cut}");
+        }
+    }
+
+    #[test]
+    fn parse_frame_rejects_oversized_and_unmasked() {
+        // Refused from the header alone, before the payload arrives
+        let mut oversized = vec![0x82, 0xff];
+        oversized.extend_from_slice(&(MAX_FRAME_LEN + 1).to_be_bytes());
+        assert!(parse_frame(&oversized).is_err());
+        oversized[2..10].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(parse_frame(&oversized).is_err());
+
+        assert!(parse_frame(&[0x82, 0x01, 0xaa]).is_err());
+    }
+
+    #[tokio::test]
+    async fn frames_split_across_reads() {
+        let mask = [9, 8, 7, 6];
+        let mut stream = client_frame(OP_BINARY, b"RFB 003.008\n", mask);
+        stream.extend_from_slice(&client_frame(OP_PING, b"hi", mask));
+        stream.extend_from_slice(&client_frame(OP_BINARY, &[5; 1000], mask));
+        stream.extend_from_slice(&client_frame(OP_CLOSE, &[], mask));
+
+        let (mut client, sock) = tokio::io::duplex(64);
+        let (pipe_tx, mut pipe_rx) = tokio::io::duplex(4096);
+        let (ctrl_tx, mut ctrl_rx) = mpsc::channel(4);
+        // The first three bytes arrive along with the upgrade request
+        let pump = tokio::spawn(pump_inbound(sock, stream[..3].to_vec(), pipe_tx, ctrl_tx));
+        for piece in stream[3..].chunks(5) {
+            client.write_all(piece).await.unwrap();
+            tokio::task::yield_now().await;
+        }
+        pump.await.unwrap().unwrap();
+
+        let mut received = Vec::new();
+        pipe_rx.read_to_end(&mut received).await.unwrap();
+        let mut expected = b"RFB 003.008\n".to_vec();
+        expected.extend_from_slice(&[5; 1000]);
+        assert_eq!(received, expected);
+        assert_eq!(ctrl_rx.recv().await, Some((OP_PONG, b"hi".to_vec())));
+        assert_eq!(ctrl_rx.recv().await, Some((OP_CLOSE, Vec::new())));
+    }
+}
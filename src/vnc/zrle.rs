@@ -0,0 +1,319 @@
+use std::collections::hash_map::{Entry, HashMap};
+
+use crate::frame_diff::DirtyRect;
+use crate::zlib::ZlibStream;
+
+/// ZRLE encoding number.
+pub const ENC_ZRLE: i32 = 16;
+
+/// ZRLE tiles are 64x64 pixels, in row-major order within the rectangle.
+const ZRLE_TILE: usize = 64;
+
+/// Largest palette a tile may use (subencodings 130..=255 / 2..=16).
+const MAX_PALETTE: usize = 127;
+
+/// Per-client ZRLE state. The zlib stream persists for the whole connection,
+/// as the protocol requires.
+pub struct ZrleEncoder {
+    zlib: ZlibStream,
+    /// Uncompressed tile data of the rectangle being encoded.
+    tiles: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl ZrleEncoder {
+    pub fn new() -> Self {
+        Self {
+            zlib: ZlibStream::new(),
+            tiles: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+
+    /// Append `rect` as a ZRLE rectangle (header included).
+    ///
+    /// `put_cpixel` appends one BGRA pixel as a CPIXEL in the client's
+    /// format, `cpixel_len` bytes long.
+    pub fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        frame: &[u8],
+        stride: usize,
+        rect: &DirtyRect,
+        cpixel_len: usize,
+        put_cpixel: impl Fn(&mut Vec<u8>, [u8; 4]),
+    ) {
+        self.tiles.clear();
+        let (x0, y0) = (rect.x as usize, rect.y as usize);
+        let (w, h) = (rect.width as usize, rect.height as usize);
+        for ty in (0..h).step_by(ZRLE_TILE) {
+            for tx in (0..w).step_by(ZRLE_TILE) {
+                let tile = Tile {
+                    frame,
+                    stride,
+                    x: x0 + tx,
+                    y: y0 + ty,
+                    width: ZRLE_TILE.min(w - tx),
+                    height: ZRLE_TILE.min(h - ty),
+                };
+                encode_tile(&mut self.tiles, &tile, cpixel_len, &put_cpixel);
+            }
+        }
+
+        self.compressed.clear();
+        self.zlib.compress(&self.tiles, &mut self.compressed);
+
+        out.extend_from_slice(&rect.x.to_be_bytes());
+        out.extend_from_slice(&rect.y.to_be_bytes());
+        out.extend_from_slice(&rect.width.to_be_bytes());
+        out.extend_from_slice(&rect.height.to_be_bytes());
+        out.extend_from_slice(&ENC_ZRLE.to_be_bytes());
+        out.extend_from_slice(&(self.compressed.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.compressed);
+    }
+}
+
+struct Tile<'a> {
+    frame: &'a [u8],
+    stride: usize,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+impl Tile<'_> {
+    /// Colour of the pixel at (x, y) within the tile, ignoring the padding byte.
+    fn pixel(&self, x: usize, y: usize) -> u32 {
+        let off = (self.y + y) * self.stride + (self.x + x) * 4;
+        let f = self.frame;
+        u32::from_le_bytes([f[off], f[off + 1], f[off + 2], 0])
+    }
+
+    fn pixels(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.height).flat_map(move |y| (0..self.width).map(move |x| self.pixel(x, y)))
+    }
+}
+
+/// Encode one tile with whichever subencoding is smallest.
+fn encode_tile(
+    out: &mut Vec<u8>,
+    tile: &Tile,
+    cpixel_len: usize,
+    put_cpixel: &impl Fn(&mut Vec<u8>, [u8; 4]),
+) {
+    // Palette in order of first appearance (None once it grows too large),
+    // and the runs of identical pixels in scan order
+    let mut palette: Option<Vec<u32>> = Some(Vec::new());
+    let mut index: HashMap<u32, u8> = HashMap::new();
+    let mut runs: Vec<(u32, usize)> = Vec::new();
+    for px in tile.pixels() {
+        if let Some(p) = palette.as_mut() {
+            if let Entry::Vacant(e) = index.entry(px) {
+                if p.len() == MAX_PALETTE {
+                    palette = None;
+                } else {
+                    e.insert(p.len() as u8);
+                    p.push(px);
+                }
+            }
+        }
+        match runs.last_mut() {
+            Some((color, len)) if *color == px => *len += 1,
+            _ => runs.push((px, 1)),
+        }
+    }
+
+    let run_len_bytes = |len: usize| (len - 1) / 255 + 1;
+    let pixel_count = tile.width * tile.height;
+    let raw_size = pixel_count * cpixel_len;
+    let plain_rle_size: usize = runs
+        .iter()
+        .map(|&(_, len)| cpixel_len + run_len_bytes(len))
+        .sum();
+
+    let mut best = (raw_size, Subencoding::Raw);
+    if plain_rle_size < best.0 {
+        best = (plain_rle_size, Subencoding::PlainRle);
+    }
+    if let Some(ref palette) = palette {
+        let n = palette.len();
+        if n == 1 {
+            best = (cpixel_len, Subencoding::Solid);
+        } else {
+            if n <= 16 {
+                let row_bytes = (tile.width * packed_bits(n)).div_ceil(8);
+                let size = n * cpixel_len + row_bytes * tile.height;
+                if size < best.0 {
+                    best = (size, Subencoding::PackedPalette);
+                }
+            }
+            let size = n * cpixel_len
+                + runs
+                    .iter()
+                    .map(|&(_, len)| if len == 1 { 1 } else { 1 + run_len_bytes(len) })
+                    .sum::<usize>();
+            if size < best.0 {
+                best = (size, Subencoding::PaletteRle);
+            }
+        }
+    }
+
+    let put = |out: &mut Vec<u8>, color: u32| put_cpixel(out, bgra(color));
+    let palette = palette.unwrap_or_default();
+    match best.1 {
+        Subencoding::Raw => {
+            out.push(0);
+            for px in tile.pixels() {
+                put(out, px);
+            }
+        }
+        Subencoding::Solid => {
+            out.push(1);
+            put(out, palette[0]);
+        }
+        Subencoding::PackedPalette => {
+            out.push(palette.len() as u8);
+            for &color in &palette {
+                put(out, color);
+            }
+            let bits = packed_bits(palette.len());
+            for y in 0..tile.height {
+                // Indices are packed MSB first; each row starts on a byte
+                let (mut byte, mut used) = (0u8, 0);
+                for x in 0..tile.width {
+                    byte |= index[&tile.pixel(x, y)] << (8 - bits - used);
+                    used += bits;
+                    if used == 8 {
+                        out.push(byte);
+                        (byte, used) = (0, 0);
+                    }
+                }
+                if used > 0 {
+                    out.push(byte);
+                }
+            }
+        }
+        Subencoding::PlainRle => {
+            out.push(128);
+            for &(color, len) in &runs {
+                put(out, color);
+                put_run_length(out, len);
+            }
+        }
+        Subencoding::PaletteRle => {
+            out.push(128 + palette.len() as u8);
+            for &color in &palette {
+                put(out, color);
+            }
+            for &(color, len) in &runs {
+                if len == 1 {
+                    out.push(index[&color]);
+                } else {
+                    out.push(index[&color] | 0x80);
+                    put_run_length(out, len);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Subencoding {
+    Raw,
+    Solid,
+    PackedPalette,
+    PlainRle,
+    PaletteRle,
+}
+
+/// Bits per index in a packed-palette tile.
+fn packed_bits(palette_len: usize) -> usize {
+    match palette_len {
+        2 => 1,
+        3..=4 => 2,
+        _ => 4,
+    }
+}
+
+/// Run length as a sequence of 255s plus a final byte, summing to `len - 1`.
+fn put_run_length(out: &mut Vec<u8>, len: usize) {
+    let mut rest = len - 1;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn bgra(color: u32) -> [u8; 4] {
+    let [b, g, r, _] = color.to_le_bytes();
+    [b, g, r, 0xFF]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::zlib::tests::inflate;
+
+    /// 0x112233 as the frame stores it (BGRX) and as a 3-byte CPIXEL.
+    const A: [u8; 4] = [0x33, 0x22, 0x11, 0];
+    const B: [u8; 4] = [0x66, 0x55, 0x44, 0];
+
+    /// Encode `rect` of a `width`-pixel-wide `frame` with 3-byte CPIXELs,
+    /// returning the rect header and the compressed tile data.
+    fn encode(zrle: &mut ZrleEncoder, frame: &[u8], width: usize, rect: DirtyRect) -> Vec<u8> {
+        let mut out = Vec::new();
+        zrle.encode_rect(&mut out, frame, width * 4, &rect, 3, |out, [b, g, r, _]| {
+            out.extend_from_slice(&[b, g, r])
+        });
+        out
+    }
+
+    #[test]
+    fn solid_and_palette_tiles() {
+        let mut zrle = ZrleEncoder::new();
+
+        // One solid 64x64 tile
+        let solid = A.repeat(64 * 64);
+        let rect = DirtyRect {
+            x: 0,
+            y: 0,
+            width: 64,
+            height: 64,
+        };
+        let first = encode(&mut zrle, &solid, 64, rect);
+        assert_eq!(first[..12], [0, 0, 0, 0, 0, 64, 0, 64, 0, 0, 0, 16]);
+        let len = u32::from_be_bytes(first[12..16].try_into().unwrap()) as usize;
+        assert_eq!(first.len(), 16 + len);
+        assert_eq!(inflate(&first[16..]).0, [1, 0x33, 0x22, 0x11]);
+
+        // Two colours in vertical stripes: a packed palette, one bit per
+        // pixel, rows padded to a byte
+        let stripes = [A, A, B, B, A, A, B, B].concat();
+        let rect = DirtyRect {
+            x: 0,
+            y: 0,
+            width: 4,
+            height: 2,
+        };
+        let second = encode(&mut zrle, &stripes, 4, rect);
+        assert_eq!(second[..12], [0, 0, 0, 0, 0, 4, 0, 2, 0, 0, 0, 16]);
+        // The zlib stream goes on from the first rect
+        let stream = [&first[16..], &second[16..]].concat();
+        assert_eq!(
+            inflate(&stream).0[4..],
+            [
+                2,
+                0x33,
+                0x22,
+                0x11,
+                0x66,
+                0x55,
+                0x44,
+                0b0011_0000,
+                0b0011_0000
+            ]
+        );
+    }
+}
@@ -0,0 +1,50 @@
+use crate::frame_diff::DirtyRect;
+use crate::zstd::ZstdStream;
+
+/// Encoding number of the non-standard zstd encoding ("KMZS"), outside the
+/// registered ranges. Only clients that list it are sent it.
+pub const ENC_ZSTD: i32 = 0x4B4D_5A53;
+
+/// Per-client state of the zstd encoding. A rect's pixels are laid out as
+/// for Raw, in the client's pixel format, and compressed through one zstd
+/// frame that lasts the whole connection.
+pub struct ZstdEncoder {
+    zstd: ZstdStream,
+    /// Uncompressed pixels of the rectangle being encoded.
+    pixels: Vec<u8>,
+    compressed: Vec<u8>,
+}
+
+impl ZstdEncoder {
+    pub fn new() -> Self {
+        Self {
+            zstd: ZstdStream::new(),
+            pixels: Vec::new(),
+            compressed: Vec::new(),
+        }
+    }
+
+    /// Append `rect` as a zstd rectangle (header included): a u32 length,
+    /// then that many bytes of the frame, ending on a block boundary.
+    ///
+    /// `put_pixels` appends the rect's pixels as Raw would send them.
+    pub fn encode_rect(
+        &mut self,
+        out: &mut Vec<u8>,
+        rect: &DirtyRect,
+        put_pixels: impl FnOnce(&mut Vec<u8>),
+    ) {
+        self.pixels.clear();
+        put_pixels(&mut self.pixels);
+        self.compressed.clear();
+        self.zstd.compress(&self.pixels, &mut self.compressed);
+
+        out.extend_from_slice(&rect.x.to_be_bytes());
+        out.extend_from_slice(&rect.y.to_be_bytes());
+        out.extend_from_slice(&rect.width.to_be_bytes());
+        out.extend_from_slice(&rect.height.to_be_bytes());
+        out.extend_from_slice(&ENC_ZSTD.to_be_bytes());
+        out.extend_from_slice(&(self.compressed.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.compressed);
+    }
+}
@@ -0,0 +1,437 @@
+/// Maximum back-reference distance (deflate window size).
+const WINDOW_SIZE: usize = 32768;
+const HASH_BITS: u32 = 15;
+/// Hash chain links followed per position; bounds the cost of match search.
+const MAX_CHAIN: usize = 32;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+
+/// Base length and extra bits for length codes 257..=285.
+const LENGTH_BASE: [(u16, u8); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+/// Base distance and extra bits for distance codes 0..=29.
+const DIST_BASE: [(u16, u8); 30] = [
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 1),
+    (7, 1),
+    (9, 2),
+    (13, 2),
+    (17, 3),
+    (25, 3),
+    (33, 4),
+    (49, 4),
+    (65, 5),
+    (97, 5),
+    (129, 6),
+    (193, 6),
+    (257, 7),
+    (385, 7),
+    (513, 8),
+    (769, 8),
+    (1025, 9),
+    (1537, 9),
+    (2049, 10),
+    (3073, 10),
+    (4097, 11),
+    (6145, 11),
+    (8193, 12),
+    (12289, 12),
+    (16385, 13),
+    (24577, 13),
+];
+
+/// A zlib (RFC 1950) stream compressor using LZ77 with fixed Huffman codes.
+///
+/// The stream is persistent: each `compress` call continues the same deflate
+/// stream (back-references may reach into earlier calls' data) and ends with
+/// a sync flush, so the receiver can inflate everything written so far. This
+/// is what ZRLE requires; one-shot users call `compress` then `finish`.
+pub struct ZlibStream {
+    /// The last (up to) WINDOW_SIZE bytes of uncompressed input.
+    window: Vec<u8>,
+    adler: (u32, u32),
+    header_written: bool,
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    /// Compressed bytes not yet handed to the caller.
+    pending: Vec<u8>,
+    bits: u32,
+    nbits: u32,
+}
+
+impl ZlibStream {
+    pub fn new() -> Self {
+        Self {
+            window: Vec::new(),
+            adler: (1, 0),
+            header_written: false,
+            head: vec![u32::MAX; 1 << HASH_BITS],
+            prev: vec![u32::MAX; WINDOW_SIZE],
+            pending: Vec::new(),
+            bits: 0,
+            nbits: 0,
+        }
+    }
+
+    /// Compress `data` and append it to `out`, ending with a sync flush.
+    pub fn compress(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        if !self.header_written {
+            out.extend_from_slice(&[0x78, 0x01]);
+            self.header_written = true;
+        }
+        self.update_adler(data);
+
+        // Search over history + new data, emitting only the new part
+        let start = self.window.len();
+        let mut buf = std::mem::take(&mut self.window);
+        buf.extend_from_slice(data);
+
+        let mut head = std::mem::take(&mut self.head);
+        let mut prev = std::mem::take(&mut self.prev);
+        head.fill(u32::MAX);
+        for p in 0..start {
+            insert_hash(&buf, p, &mut head, &mut prev);
+        }
+
+        // Fixed-Huffman block (BFINAL=0, BTYPE=01)
+        self.put_bits(0b010, 3);
+        let mut pos = start;
+        while pos < buf.len() {
+            let (len, dist) = longest_match(&buf, pos, &head, &prev);
+            if len >= MIN_MATCH {
+                self.put_length(len);
+                self.put_distance(dist);
+                for p in pos..pos + len {
+                    insert_hash(&buf, p, &mut head, &mut prev);
+                }
+                pos += len;
+            } else {
+                self.put_literal(buf[pos] as u16);
+                insert_hash(&buf, pos, &mut head, &mut prev);
+                pos += 1;
+            }
+        }
+        self.put_literal(256); // end of block
+
+        // Sync flush: empty stored block, byte-aligned
+        self.put_bits(0, 3);
+        self.align();
+        self.pending.extend_from_slice(&[0x00, 0x00, 0xFF, 0xFF]);
+        out.append(&mut self.pending);
+
+        let keep = buf.len().saturating_sub(WINDOW_SIZE);
+        buf.drain(..keep);
+        self.window = buf;
+        self.head = head;
+        self.prev = prev;
+    }
+
+    /// Terminate the stream: final empty block plus the Adler-32 trailer.
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        if !self.header_written {
+            out.extend_from_slice(&[0x78, 0x01]);
+        }
+        self.put_bits(0b011, 3); // BFINAL=1, BTYPE=01
+        self.put_literal(256);
+        self.align();
+        out.append(&mut self.pending);
+        let (a, b) = self.adler;
+        out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    }
+
+    fn update_adler(&mut self, data: &[u8]) {
+        let (mut a, mut b) = self.adler;
+        for chunk in data.chunks(5552) {
+            for &byte in chunk {
+                a += byte as u32;
+                b += a;
+            }
+            a %= 65521;
+            b %= 65521;
+        }
+        self.adler = (a, b);
+    }
+
+    fn put_bits(&mut self, value: u32, count: u32) {
+        self.bits |= value << self.nbits;
+        self.nbits += count;
+        while self.nbits >= 8 {
+            self.pending.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    /// Write a Huffman code (stored MSB-first) into the LSB-first bit stream.
+    fn put_code(&mut self, code: u32, len: u32) {
+        self.put_bits(code.reverse_bits() >> (32 - len), len);
+    }
+
+    fn put_literal(&mut self, sym: u16) {
+        let sym = sym as u32;
+        match sym {
+            0..=143 => self.put_code(0x30 + sym, 8),
+            144..=255 => self.put_code(0x190 + sym - 144, 9),
+            256..=279 => self.put_code(sym - 256, 7),
+            _ => self.put_code(0xC0 + sym - 280, 8),
+        }
+    }
+
+    fn put_length(&mut self, len: usize) {
+        let idx = LENGTH_BASE
+            .iter()
+            .rposition(|&(base, _)| base as usize <= len)
+            .unwrap();
+        let (base, extra) = LENGTH_BASE[idx];
+        self.put_literal(257 + idx as u16);
+        self.put_bits(len as u32 - base as u32, extra as u32);
+    }
+
+    fn put_distance(&mut self, dist: usize) {
+        let idx = DIST_BASE
+            .iter()
+            .rposition(|&(base, _)| base as usize <= dist)
+            .unwrap();
+        let (base, extra) = DIST_BASE[idx];
+        self.put_code(idx as u32, 5);
+        self.put_bits(dist as u32 - base as u32, extra as u32);
+    }
+
+    /// Pad the bit stream with zeros to a byte boundary.
+    fn align(&mut self) {
+        if self.nbits > 0 {
+            self.put_bits(0, 8 - self.nbits);
+        }
+    }
+}
+
+fn hash3(buf: &[u8], p: usize) -> usize {
+    let v = (buf[p] as u32) << 16 | (buf[p + 1] as u32) << 8 | buf[p + 2] as u32;
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Link position `p` into the hash chains.
+fn insert_hash(buf: &[u8], p: usize, head: &mut [u32], prev: &mut [u32]) {
+    if p + MIN_MATCH <= buf.len() {
+        let h = hash3(buf, p);
+        prev[p % WINDOW_SIZE] = head[h];
+        head[h] = p as u32;
+    }
+}
+
+/// Find the longest match for `buf[pos..]` along the hash chain.
+/// Returns (length, distance); length < MIN_MATCH means no match.
+fn longest_match(buf: &[u8], pos: usize, head: &[u32], prev: &[u32]) -> (usize, usize) {
+    if pos + MIN_MATCH > buf.len() {
+        return (0, 0);
+    }
+    let max_len = MAX_MATCH.min(buf.len() - pos);
+
+    let mut best = (0, 0);
+    let mut cand = head[hash3(buf, pos)];
+    for _ in 0..MAX_CHAIN {
+        if cand == u32::MAX {
+            break;
+        }
+        let c = cand as usize;
+        if c >= pos || pos - c > WINDOW_SIZE {
+            break;
+        }
+        let len = buf[c..c + max_len]
+            .iter()
+            .zip(&buf[pos..pos + max_len])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if len > best.0 {
+            best = (len, pos - c);
+            if len == max_len {
+                break;
+            }
+        }
+        let next = prev[c % WINDOW_SIZE];
+        if next == u32::MAX || next >= cand {
+            break;
+        }
+        cand = next;
+    }
+    best
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Reads a deflate bit stream, LSB first.
+    struct BitReader<'a> {
+        data: &'a [u8],
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |v, i| {
+                let bit = self.data[self.pos / 8] >> (self.pos % 8) & 1;
+                self.pos += 1;
+                v | (bit as u32) << i
+            })
+        }
+
+        /// A Huffman code of `count` bits, stored MSB first.
+        fn code(&mut self, count: u32) -> u32 {
+            (0..count).fold(0, |v, _| v << 1 | self.bits(1))
+        }
+
+        fn literal(&mut self) -> u32 {
+            let code = self.code(7);
+            if code <= 0x17 {
+                return 256 + code;
+            }
+            let code = code << 1 | self.bits(1);
+            match code {
+                0x30..=0xbf => code - 0x30,
+                0xc0..=0xc7 => 280 + code - 0xc0,
+                _ => 144 + (code << 1 | self.bits(1)) - 0x190,
+            }
+        }
+    }
+
+    /// Inflate a zlib stream of stored and fixed-Huffman blocks, up to the
+    /// final block or, for a stream that is only flushed, the end of `data`.
+    /// Returns the data and the Adler-32 trailer, if there is one.
+    pub(crate) fn inflate(data: &[u8]) -> (Vec<u8>, Option<u32>) {
+        assert_eq!(u16::from_be_bytes([data[0], data[1]]) % 31, 0);
+        assert_eq!(data[0] & 0x0f, 8, "not deflate");
+        let mut r = BitReader { data, pos: 16 };
+        let mut out: Vec<u8> = Vec::new();
+        loop {
+            let last = r.bits(1) == 1;
+            match r.bits(2) {
+                0 => {
+                    r.pos = r.pos.div_ceil(8) * 8;
+                    let len = r.bits(16) as usize;
+                    assert_eq!(r.bits(16) as usize, !len & 0xffff);
+                    out.extend_from_slice(&data[r.pos / 8..r.pos / 8 + len]);
+                    r.pos += len * 8;
+                }
+                1 => loop {
+                    let sym = r.literal();
+                    match sym {
+                        0..=255 => out.push(sym as u8),
+                        256 => break,
+                        _ => {
+                            let (base, extra) = LENGTH_BASE[sym as usize - 257];
+                            let len = base as usize + r.bits(extra as u32) as usize;
+                            let (base, extra) = DIST_BASE[r.code(5) as usize];
+                            let dist = base as usize + r.bits(extra as u32) as usize;
+                            for _ in 0..len {
+                                out.push(out[out.len() - dist]);
+                            }
+                        }
+                    }
+                },
+                btype => panic!("unexpected block type {btype}"),
+            }
+            if last {
+                r.pos = r.pos.div_ceil(8) * 8;
+                let trailer = &data[r.pos / 8..];
+                return (out, Some(u32::from_be_bytes(trailer.try_into().unwrap())));
+            }
+            if r.pos == data.len() * 8 {
+                return (out, None);
+            }
+        }
+    }
+
+    fn adler32(data: &[u8]) -> u32 {
+        let mut z = ZlibStream::new();
+        z.update_adler(data);
+        let (a, b) = z.adler;
+        b << 16 | a
+    }
+
+    #[test]
+    fn adler32_known_answers() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        // Long enough for the deferred modulo to matter
+        assert_eq!(adler32(&[0xff; 100_000]), 0x149a_302c);
+    }
+
+    #[test]
+    fn one_shot_stream_matches_reference_bytes() {
+        let mut z = ZlibStream::new();
+        let mut out = Vec::new();
+        z.compress(b"abcabcabc", &mut out);
+        z.finish(&mut out);
+        // As zlib's inflate accepts it: a fixed block with one match, the
+        // sync flush, the final block and the Adler-32 trailer
+        assert_eq!(
+            out,
+            [
+                0x78, 0x01, 0x4a, 0x4c, 0x4a, 0x86, 0x20, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0x03,
+                0x00, 0x11, 0x3d, 0x03, 0x73,
+            ]
+        );
+        assert_eq!(
+            inflate(&out),
+            (b"abcabcabc".to_vec(), Some(adler32(b"abcabcabc")))
+        );
+    }
+
+    #[test]
+    fn flushed_calls_inflate_as_they_arrive() {
+        // Repetitive data with back-references into earlier calls
+        let chunks: Vec<Vec<u8>> = (0..4u32)
+            .map(|i| {
+                (0..20_000u32)
+                    .map(|j| ((j / 7 + i * 3) % 251) as u8 ^ (j % 3) as u8)
+                    .collect()
+            })
+            .collect();
+        let mut z = ZlibStream::new();
+        let mut out = Vec::new();
+        let mut expected = Vec::new();
+        for chunk in &chunks {
+            z.compress(chunk, &mut out);
+            expected.extend_from_slice(chunk);
+            // Every sync flush ends on a byte boundary with all input out
+            assert_eq!(inflate(&out), (expected.clone(), None));
+        }
+        assert!(out.len() < expected.len() / 2);
+        z.finish(&mut out);
+        assert_eq!(inflate(&out), (expected.clone(), Some(adler32(&expected))));
+    }
+}
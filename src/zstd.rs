@@ -0,0 +1,872 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::OnceLock;
+
+/// Frame magic number, written little-endian.
+const MAGIC: u32 = 0xFD2F_B528;
+/// The window the frame header announces; back-references stay within it.
+const WINDOW_LOG: u32 = 20;
+const WINDOW_SIZE: usize = 1 << WINDOW_LOG;
+/// Most data one block may regenerate.
+const MAX_BLOCK: usize = 128 * 1024;
+const HASH_BITS: u32 = 17;
+/// Hash chain links followed per position; bounds the cost of match search.
+const MAX_CHAIN: usize = 16;
+const MIN_MATCH: usize = 4;
+const NONE: u32 = u32::MAX;
+/// Longest literal Huffman code the format allows.
+const MAX_HUFFMAN_BITS: u8 = 11;
+/// Below this many literals a Huffman table costs more than it saves.
+const MIN_HUFFMAN_LITERALS: usize = 64;
+
+const BLOCK_RAW: u32 = 0;
+const BLOCK_COMPRESSED: u32 = 2;
+const LITERALS_RAW: u8 = 0;
+const LITERALS_RLE: u8 = 1;
+const LITERALS_COMPRESSED: u64 = 2;
+
+/// Baseline and extra bits of literal length codes 16..=35; codes 0..=15
+/// are the lengths themselves.
+const LL_BASE: [(u32, u32); 20] = [
+    (16, 1),
+    (18, 1),
+    (20, 1),
+    (22, 1),
+    (24, 2),
+    (28, 2),
+    (32, 3),
+    (40, 3),
+    (48, 4),
+    (64, 6),
+    (128, 7),
+    (256, 8),
+    (512, 9),
+    (1024, 10),
+    (2048, 11),
+    (4096, 12),
+    (8192, 13),
+    (16384, 14),
+    (32768, 15),
+    (65536, 16),
+];
+
+/// Baseline and extra bits of match length codes 32..=52; codes 0..=31 are
+/// lengths 3..=34.
+const ML_BASE: [(u32, u32); 21] = [
+    (35, 1),
+    (37, 1),
+    (39, 1),
+    (41, 1),
+    (43, 2),
+    (47, 2),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 5),
+    (131, 7),
+    (259, 8),
+    (515, 9),
+    (1027, 10),
+    (2051, 11),
+    (4099, 12),
+    (8195, 13),
+    (16387, 14),
+    (32771, 15),
+    (65539, 16),
+];
+
+/// Predefined FSE distributions (RFC 8878 section 3.1.1.3.2.2), used for
+/// every sequences section so no tables need describing.
+const LL_DEFAULT: [i16; 36] = [
+    4, 3, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 1, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 3, 2, 1, 1, 1, 1, 1,
+    -1, -1, -1, -1,
+];
+const ML_DEFAULT: [i16; 53] = [
+    1, 4, 3, 2, 2, 2, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+    1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1, -1, -1,
+];
+const OF_DEFAULT: [i16; 29] = [
+    1, 1, 1, 1, 1, 1, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, -1, -1, -1, -1, -1,
+];
+
+/// A zstd (RFC 8878) stream compressor: LZ matching over a 1 MiB window,
+/// Huffman-coded literals and sequences coded with the predefined FSE
+/// tables.
+///
+/// Like `ZlibStream`, the stream is persistent: all `compress` calls add
+/// blocks to one frame (back-references may reach into earlier calls'
+/// data), and each call's output ends on a block boundary, so a streaming
+/// decoder can return everything written so far. The frame is never
+/// closed; it ends with the connection.
+pub struct ZstdStream {
+    /// Input up to the current block; the last WINDOW_SIZE bytes or more.
+    history: Vec<u8>,
+    head: Vec<u32>,
+    prev: Vec<u32>,
+    /// Positions below this are linked into the hash chains.
+    hashed: usize,
+    /// Repeat offsets, as the decoder tracks them.
+    rep: [u32; 3],
+    header_written: bool,
+}
+
+/// One LZ sequence: literals copied, then a match.
+struct Sequence {
+    lit_len: u32,
+    match_len: u32,
+    /// The offset as coded: 1..=3 pick a repeat offset, others are the
+    /// distance plus 3.
+    offset_value: u32,
+}
+
+impl ZstdStream {
+    pub fn new() -> Self {
+        Self {
+            history: Vec::new(),
+            head: vec![NONE; 1 << HASH_BITS],
+            prev: vec![NONE; WINDOW_SIZE],
+            hashed: 0,
+            rep: [1, 4, 8],
+            header_written: false,
+        }
+    }
+
+    /// Compress `data` and append it to `out` as one or more blocks.
+    pub fn compress(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        if !self.header_written {
+            out.extend_from_slice(&MAGIC.to_le_bytes());
+            // No content size, checksum or dictionary; not single-segment
+            out.push(0);
+            out.push(((WINDOW_LOG - 10) << 3) as u8);
+            self.header_written = true;
+        }
+        for chunk in data.chunks(MAX_BLOCK) {
+            if self.history.len() > 2 * WINDOW_SIZE {
+                self.slide();
+            }
+            let start = self.history.len();
+            self.history.extend_from_slice(chunk);
+            self.compress_block(start, out);
+        }
+    }
+
+    /// Drop history that has left the window, keeping positions congruent
+    /// modulo WINDOW_SIZE so `prev` stays valid.
+    fn slide(&mut self) {
+        let shift = (self.history.len() - WINDOW_SIZE) / WINDOW_SIZE * WINDOW_SIZE;
+        self.history.drain(..shift);
+        self.hashed -= shift;
+        for p in self.head.iter_mut().chain(self.prev.iter_mut()) {
+            *p = match *p {
+                NONE => NONE,
+                p if (p as usize) < shift => NONE,
+                p => p - shift as u32,
+            };
+        }
+    }
+
+    /// Append the data from `start` to the end of the history as a block,
+    /// compressed unless that doesn't make it smaller.
+    fn compress_block(&mut self, start: usize, out: &mut Vec<u8>) {
+        let end = self.history.len();
+        let rep = self.rep;
+        let mut literals = Vec::new();
+        let mut sequences = Vec::new();
+        let (mut anchor, mut pos) = (start, start);
+        while pos < end {
+            self.insert_hashes(pos);
+            let (len, dist) = self.longest_match(pos, end);
+            if len >= MIN_MATCH {
+                literals.extend_from_slice(&self.history[anchor..pos]);
+                let lit_len = (pos - anchor) as u32;
+                sequences.push(Sequence {
+                    lit_len,
+                    match_len: len as u32,
+                    offset_value: self.offset_value(dist as u32, lit_len),
+                });
+                pos += len;
+                anchor = pos;
+            } else {
+                pos += 1;
+            }
+        }
+        literals.extend_from_slice(&self.history[anchor..end]);
+
+        let mut block = Vec::new();
+        encode_literals(&literals, &mut block);
+        encode_sequences(&sequences, &mut block);
+        if block.len() < end - start {
+            put_block_header(out, BLOCK_COMPRESSED, block.len());
+            out.extend_from_slice(&block);
+        } else {
+            // The decoder won't see these sequences' offsets
+            self.rep = rep;
+            put_block_header(out, BLOCK_RAW, end - start);
+            out.extend_from_slice(&self.history[start..end]);
+        }
+    }
+
+    /// Code `offset` for a sequence with `lit_len` literals, using and
+    /// updating the repeat offsets as the decoder will.
+    fn offset_value(&mut self, offset: u32, lit_len: u32) -> u32 {
+        let [r0, r1, r2] = self.rep;
+        let (value, rep) = match lit_len {
+            0 if offset == r1 => (1, [r1, r0, r2]),
+            0 if offset == r2 => (2, [r2, r0, r1]),
+            0 if offset + 1 == r0 => (3, [offset, r0, r1]),
+            1.. if offset == r0 => (1, [r0, r1, r2]),
+            1.. if offset == r1 => (2, [r1, r0, r2]),
+            1.. if offset == r2 => (3, [r2, r0, r1]),
+            _ => (offset + 3, [offset, r0, r1]),
+        };
+        self.rep = rep;
+        value
+    }
+
+    /// Link every position before `until` into the hash chains.
+    fn insert_hashes(&mut self, until: usize) {
+        let until = until.min((self.history.len() + 1).saturating_sub(MIN_MATCH));
+        while self.hashed < until {
+            let p = self.hashed;
+            let h = hash4(&self.history, p);
+            self.prev[p % WINDOW_SIZE] = self.head[h];
+            self.head[h] = p as u32;
+            self.hashed += 1;
+        }
+    }
+
+    /// Find the longest match for `history[pos..end]` along the hash chain.
+    /// Returns (length, distance); length < MIN_MATCH means no match.
+    fn longest_match(&self, pos: usize, end: usize) -> (usize, usize) {
+        if pos + MIN_MATCH > end {
+            return (0, 0);
+        }
+        let buf = &self.history;
+        let max_len = end - pos;
+
+        let mut best = (0, 0);
+        let mut cand = self.head[hash4(buf, pos)];
+        for _ in 0..MAX_CHAIN {
+            if cand == NONE {
+                break;
+            }
+            let c = cand as usize;
+            if c >= pos || pos - c > WINDOW_SIZE {
+                break;
+            }
+            let len = buf[c..c + max_len]
+                .iter()
+                .zip(&buf[pos..end])
+                .take_while(|(a, b)| a == b)
+                .count();
+            if len > best.0 {
+                best = (len, pos - c);
+                if len == max_len {
+                    break;
+                }
+            }
+            let next = self.prev[c % WINDOW_SIZE];
+            if next == NONE || next >= cand {
+                break;
+            }
+            cand = next;
+        }
+        best
+    }
+}
+
+fn hash4(buf: &[u8], p: usize) -> usize {
+    let v = u32::from_le_bytes([buf[p], buf[p + 1], buf[p + 2], buf[p + 3]]);
+    (v.wrapping_mul(2654435761) >> (32 - HASH_BITS)) as usize
+}
+
+/// Block header: not the last block, `kind`, `size` bytes.
+fn put_block_header(out: &mut Vec<u8>, kind: u32, size: usize) {
+    let header = kind << 1 | (size as u32) << 3;
+    out.extend_from_slice(&header.to_le_bytes()[..3]);
+}
+
+/// Append the literals section: Huffman-coded where that pays off,
+/// otherwise RLE or raw.
+fn encode_literals(literals: &[u8], out: &mut Vec<u8>) {
+    let mut freqs = [0u32; 256];
+    for &b in literals {
+        freqs[b as usize] += 1;
+    }
+    let used = freqs.iter().filter(|&&f| f > 0).count();
+    if used == 1 && literals.len() > 1 {
+        put_literals_header(out, LITERALS_RLE, literals.len());
+        out.push(literals[0]);
+        return;
+    }
+    if used > 1 && literals.len() >= MIN_HUFFMAN_LITERALS {
+        if let Some(compressed) = huffman_literals(literals, &freqs) {
+            if compressed.len() < literals.len() {
+                out.extend_from_slice(&compressed);
+                return;
+            }
+        }
+    }
+    put_literals_header(out, LITERALS_RAW, literals.len());
+    out.extend_from_slice(literals);
+}
+
+/// Header of a raw or RLE literals section regenerating `size` bytes.
+fn put_literals_header(out: &mut Vec<u8>, kind: u8, size: usize) {
+    match size {
+        0..=31 => out.push(kind | (size as u8) << 3),
+        32..=4095 => out.extend_from_slice(&[kind | 1 << 2 | (size as u8) << 4, (size >> 4) as u8]),
+        _ => {
+            let header = kind as u32 | 3 << 2 | (size as u32) << 4;
+            out.extend_from_slice(&header.to_le_bytes()[..3]);
+        }
+    }
+}
+
+/// The whole Huffman-coded literals section, or `None` if the table can't
+/// be described.
+fn huffman_literals(literals: &[u8], freqs: &[u32; 256]) -> Option<Vec<u8>> {
+    let lengths = huffman_lengths(freqs);
+    let max_bits = *lengths.iter().max().unwrap();
+    let weights: Vec<u8> = lengths
+        .iter()
+        .map(|&len| if len > 0 { max_bits + 1 - len } else { 0 })
+        .collect();
+
+    // Canonical codes: longest first, in symbol order within a length
+    let mut order: Vec<usize> = (0..256).filter(|&s| lengths[s] > 0).collect();
+    order.sort_by_key(|&s| (Reverse(lengths[s]), s));
+    let mut codes = [(0u32, 0u32); 256];
+    let mut next = 0u32;
+    for s in order {
+        let shift = (max_bits - lengths[s]) as u32;
+        codes[s] = (next >> shift, lengths[s] as u32);
+        next += 1 << shift;
+    }
+
+    let mut body = huffman_tree(&weights)?;
+    let single_stream = literals.len() <= 1023;
+    if single_stream {
+        body.extend_from_slice(&huffman_stream(literals, &codes));
+    } else {
+        let streams: Vec<Vec<u8>> = literals
+            .chunks(literals.len().div_ceil(4))
+            .map(|chunk| huffman_stream(chunk, &codes))
+            .collect();
+        for stream in &streams[..3] {
+            body.extend_from_slice(&(stream.len() as u16).to_le_bytes());
+        }
+        for stream in &streams {
+            body.extend_from_slice(stream);
+        }
+    }
+
+    let (regenerated, compressed) = (literals.len() as u64, body.len() as u64);
+    let (size_format, size_bits) = match regenerated.max(compressed) {
+        _ if single_stream => (0, 10),
+        0..=1023 => (1, 10),
+        1024..=16383 => (2, 14),
+        _ => (3, 18),
+    };
+    let header =
+        LITERALS_COMPRESSED | size_format << 2 | regenerated << 4 | compressed << (4 + size_bits);
+    let mut out = header.to_le_bytes()[..(4 + 2 * size_bits as usize).div_ceil(8)].to_vec();
+    out.append(&mut body);
+    Some(out)
+}
+
+/// Huffman code lengths for `freqs` (0 for unused symbols), at most
+/// MAX_HUFFMAN_BITS long. Needs two or more used symbols.
+fn huffman_lengths(freqs: &[u32; 256]) -> [u8; 256] {
+    let mut freqs = *freqs;
+    loop {
+        let mut heap: BinaryHeap<_> = (0..256)
+            .filter(|&s| freqs[s] > 0)
+            .map(|s| Reverse((freqs[s], s)))
+            .collect();
+        let mut parent = vec![usize::MAX; 256];
+        while heap.len() > 1 {
+            let Reverse((fa, a)) = heap.pop().unwrap();
+            let Reverse((fb, b)) = heap.pop().unwrap();
+            let node = parent.len();
+            parent.push(usize::MAX);
+            parent[a] = node;
+            parent[b] = node;
+            heap.push(Reverse((fa + fb, node)));
+        }
+
+        let mut lengths = [0u8; 256];
+        for s in (0..256).filter(|&s| freqs[s] > 0) {
+            let mut p = s;
+            while parent[p] != usize::MAX {
+                p = parent[p];
+                lengths[s] += 1;
+            }
+        }
+        if lengths.iter().all(|&len| len <= MAX_HUFFMAN_BITS) {
+            return lengths;
+        }
+        // Flatten the distribution until the tree is shallow enough
+        for f in freqs.iter_mut().filter(|f| **f > 0) {
+            *f = f.div_ceil(2);
+        }
+    }
+}
+
+/// Huffman tree description: the weights of every symbol up to the last
+/// used one, whose weight the decoder works out. Weights go as 4-bit
+/// values when there are few enough, FSE-compressed otherwise.
+fn huffman_tree(weights: &[u8]) -> Option<Vec<u8>> {
+    let last = weights.iter().rposition(|&w| w > 0).unwrap();
+    let described = &weights[..last];
+    if described.len() <= 128 {
+        let mut out = vec![127 + described.len() as u8];
+        for pair in described.chunks(2) {
+            out.push(pair[0] << 4 | pair.get(1).copied().unwrap_or(0));
+        }
+        return Some(out);
+    }
+
+    const LOG: u32 = 6;
+    let mut counts = [0u32; MAX_HUFFMAN_BITS as usize + 1];
+    for &w in described {
+        counts[w as usize] += 1;
+    }
+    let norm = normalize(&counts, LOG);
+    let table = FseTable::new(&norm, LOG);
+
+    let mut out = vec![0];
+    out.extend_from_slice(&write_ncount(&norm, LOG));
+    // Two interleaved states: even weights from the first, odd from the
+    // second, encoded back to front
+    let mut bits = BitWriter::default();
+    let mut states: [Option<FseState>; 2] = [None, None];
+    for (i, &w) in described.iter().enumerate().rev() {
+        match &mut states[i % 2] {
+            Some(state) => state.encode(&mut bits, w),
+            state => *state = Some(FseState::new(&table, w)),
+        }
+    }
+    let [first, second] = states;
+    second.unwrap().flush(&mut bits);
+    first.unwrap().flush(&mut bits);
+    out.extend_from_slice(&bits.close());
+
+    // The header byte holds the size and must stay below 128
+    out[0] = u8::try_from(out.len() - 1).ok().filter(|&n| n < 128)?;
+    Some(out)
+}
+
+/// Scale `counts` to sum to `1 << log`, keeping every used symbol and none
+/// above half the table. That way every state change costs at least a bit,
+/// which the decoder relies on to find the end of two-state streams.
+fn normalize(counts: &[u32], log: u32) -> Vec<i16> {
+    let size = 1i32 << log;
+    let total: u32 = counts.iter().sum();
+    let mut norm: Vec<i16> = counts
+        .iter()
+        .map(|&c| match c {
+            0 => 0,
+            c => (c as u64 * size as u64 / total as u64).clamp(1, size as u64 / 2) as i16,
+        })
+        .collect();
+    if norm.iter().filter(|&&n| n > 0).count() == 1 {
+        // A lone symbol would take the whole table; pair it with an unused one
+        let used = norm.iter().position(|&n| n > 0).unwrap();
+        let other = (used + 1) % norm.len();
+        norm[other] = 1;
+    }
+
+    let mut sum: i32 = norm.iter().map(|&n| n as i32).sum();
+    while sum != size {
+        let (i, n) = if sum > size {
+            norm.iter()
+                .enumerate()
+                .filter(|(_, &n)| n > 1)
+                .max_by_key(|(_, &n)| n)
+        } else {
+            norm.iter()
+                .enumerate()
+                .filter(|(_, &n)| n > 0 && (n as i32) < size / 2)
+                .max_by_key(|(_, &n)| n)
+        }
+        .map(|(i, &n)| (i, n))
+        .unwrap();
+        let step = if sum > size { -1 } else { 1 };
+        norm[i] = n + step as i16;
+        sum += step;
+    }
+    norm
+}
+
+/// FSE table description (RFC 8878 section 4.1.1) for `norm`.
+fn write_ncount(norm: &[i16], log: u32) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    bits.put(log - 5, 4);
+    let mut remaining = (1i32 << log) + 1;
+    let mut threshold = 1i32 << log;
+    let mut nb_bits = log + 1;
+    let mut symbol = 0;
+    let mut previous_zero = false;
+    while remaining > 1 {
+        if previous_zero {
+            // Zero counts after a zero are sent as a run length
+            let start = symbol;
+            while norm[symbol] == 0 {
+                symbol += 1;
+            }
+            let mut run = symbol - start;
+            while run >= 3 {
+                bits.put(3, 2);
+                run -= 3;
+            }
+            bits.put(run as u32, 2);
+        }
+        let count = norm[symbol] as i32;
+        symbol += 1;
+        let max = 2 * threshold - 1 - remaining;
+        remaining -= count.abs();
+        // Stored plus one, so -1 fits; small values drop the top bit
+        let mut value = count + 1;
+        if value >= threshold {
+            value += max;
+        }
+        bits.put(value as u32, nb_bits - u32::from(value < max));
+        previous_zero = value == 1;
+        while remaining < threshold {
+            nb_bits -= 1;
+            threshold >>= 1;
+        }
+    }
+    bits.finish()
+}
+
+/// Append the sequences section, coded with the predefined tables.
+fn encode_sequences(sequences: &[Sequence], out: &mut Vec<u8>) {
+    let n = sequences.len();
+    match n {
+        0..=127 => out.push(n as u8),
+        128..=0x7EFF => out.extend_from_slice(&[(n >> 8) as u8 + 128, n as u8]),
+        _ => {
+            out.push(0xFF);
+            out.extend_from_slice(&((n - 0x7F00) as u16).to_le_bytes());
+        }
+    }
+    let Some(last) = sequences.last() else {
+        return;
+    };
+    // Predefined mode for literal lengths, offsets and match lengths
+    out.push(0);
+
+    let tables = predefined_tables();
+    let mut bits = BitWriter::default();
+    let codes = |s: &Sequence| {
+        (
+            literal_length_code(s.lit_len),
+            match_length_code(s.match_len),
+            offset_code(s.offset_value),
+        )
+    };
+    let put_extra = |bits: &mut BitWriter, (ll, ml, of): Codes| {
+        bits.put(ll.1, ll.2);
+        bits.put(ml.1, ml.2);
+        bits.put(of.1, of.2);
+    };
+
+    // Back to front: the decoder starts from the final states
+    let (ll, ml, of) = codes(last);
+    let mut ml_state = FseState::new(&tables.ml, ml.0);
+    let mut of_state = FseState::new(&tables.of, of.0);
+    let mut ll_state = FseState::new(&tables.ll, ll.0);
+    put_extra(&mut bits, (ll, ml, of));
+    for s in sequences[..n - 1].iter().rev() {
+        let (ll, ml, of) = codes(s);
+        of_state.encode(&mut bits, of.0);
+        ml_state.encode(&mut bits, ml.0);
+        ll_state.encode(&mut bits, ll.0);
+        put_extra(&mut bits, (ll, ml, of));
+    }
+    ml_state.flush(&mut bits);
+    of_state.flush(&mut bits);
+    ll_state.flush(&mut bits);
+    out.extend_from_slice(&bits.close());
+}
+
+/// A value as (code, extra bits value, extra bits count).
+type Code = (u8, u32, u32);
+type Codes = (Code, Code, Code);
+
+fn literal_length_code(len: u32) -> Code {
+    if len < 16 {
+        return (len as u8, 0, 0);
+    }
+    let idx = LL_BASE.iter().rposition(|&(base, _)| base <= len).unwrap();
+    let (base, extra) = LL_BASE[idx];
+    (16 + idx as u8, len - base, extra)
+}
+
+fn match_length_code(len: u32) -> Code {
+    if len < 35 {
+        return ((len - 3) as u8, 0, 0);
+    }
+    let idx = ML_BASE.iter().rposition(|&(base, _)| base <= len).unwrap();
+    let (base, extra) = ML_BASE[idx];
+    (32 + idx as u8, len - base, extra)
+}
+
+fn offset_code(value: u32) -> Code {
+    let code = 31 - value.leading_zeros();
+    (code as u8, value - (1 << code), code)
+}
+
+struct PredefinedTables {
+    ll: FseTable,
+    ml: FseTable,
+    of: FseTable,
+}
+
+fn predefined_tables() -> &'static PredefinedTables {
+    static TABLES: OnceLock<PredefinedTables> = OnceLock::new();
+    TABLES.get_or_init(|| PredefinedTables {
+        ll: FseTable::new(&LL_DEFAULT, 6),
+        ml: FseTable::new(&ML_DEFAULT, 6),
+        of: FseTable::new(&OF_DEFAULT, 5),
+    })
+}
+
+/// FSE encoding table (RFC 8878 section 4.1) for normalized counts, where
+/// -1 stands for "less than one".
+struct FseTable {
+    log: u32,
+    /// Next state, by symbol and then by the state's high bits.
+    states: Vec<u16>,
+    /// Per symbol: (delta_nb_bits, delta_find_state).
+    symbols: Vec<(u32, i32)>,
+}
+
+impl FseTable {
+    fn new(norm: &[i16], log: u32) -> Self {
+        let size = 1usize << log;
+        let mask = size - 1;
+
+        // Spread symbols over the table as the decoder does, "less than
+        // one" symbols at the top
+        let mut table_symbol = vec![0u8; size];
+        let mut high = size - 1;
+        for (s, _) in norm.iter().enumerate().filter(|(_, &n)| n == -1) {
+            table_symbol[high] = s as u8;
+            high -= 1;
+        }
+        let step = (size >> 1) + (size >> 3) + 3;
+        let mut pos = 0;
+        for (s, &n) in norm.iter().enumerate() {
+            for _ in 0..n.max(0) {
+                table_symbol[pos] = s as u8;
+                pos = (pos + step) & mask;
+                while pos > high {
+                    pos = (pos + step) & mask;
+                }
+            }
+        }
+
+        let mut next = Vec::with_capacity(norm.len());
+        let mut total = 0;
+        for &n in norm {
+            next.push(total);
+            total += n.unsigned_abs() as usize;
+        }
+        let mut states = vec![0u16; size];
+        for (u, &s) in table_symbol.iter().enumerate() {
+            states[next[s as usize]] = (size + u) as u16;
+            next[s as usize] += 1;
+        }
+
+        let mut total = 0i32;
+        let symbols = norm
+            .iter()
+            .map(|&n| match n {
+                0 => (((log + 1) << 16) - size as u32, 0),
+                -1 | 1 => {
+                    total += 1;
+                    ((log << 16) - size as u32, total - 2)
+                }
+                n => {
+                    let n = n as u32;
+                    let max_bits_out = log - (31 - (n - 1).leading_zeros());
+                    let min_state_plus = n << max_bits_out;
+                    total += n as i32;
+                    ((max_bits_out << 16) - min_state_plus, total - 2 * n as i32)
+                }
+            })
+            .collect();
+        Self {
+            log,
+            states,
+            symbols,
+        }
+    }
+}
+
+/// One FSE encoder state.
+struct FseState<'a> {
+    table: &'a FseTable,
+    value: u32,
+}
+
+impl<'a> FseState<'a> {
+    /// The state the decoder ends on after decoding `symbol` last.
+    fn new(table: &'a FseTable, symbol: u8) -> Self {
+        let (delta_nb_bits, delta_find_state) = table.symbols[symbol as usize];
+        let nb_bits = (delta_nb_bits + (1 << 15)) >> 16;
+        let value = (nb_bits << 16) - delta_nb_bits;
+        let value = table.states[((value >> nb_bits) as i32 + delta_find_state) as usize];
+        Self {
+            table,
+            value: value as u32,
+        }
+    }
+
+    fn encode(&mut self, bits: &mut BitWriter, symbol: u8) {
+        let (delta_nb_bits, delta_find_state) = self.table.symbols[symbol as usize];
+        let nb_bits = (self.value + delta_nb_bits) >> 16;
+        bits.put(self.value, nb_bits);
+        let index = (self.value >> nb_bits) as i32 + delta_find_state;
+        self.value = self.table.states[index as usize] as u32;
+    }
+
+    /// Write the state for the decoder to start from.
+    fn flush(&self, bits: &mut BitWriter) {
+        bits.put(self.value, self.table.log);
+    }
+}
+
+/// Huffman-code `literals` as one stream, back to front.
+fn huffman_stream(literals: &[u8], codes: &[(u32, u32); 256]) -> Vec<u8> {
+    let mut bits = BitWriter::default();
+    for &b in literals.iter().rev() {
+        let (code, len) = codes[b as usize];
+        bits.put(code, len);
+    }
+    bits.close()
+}
+
+/// LSB-first bit writer. The entropy-coded streams are read back to front,
+/// starting after the marker bit `close` adds.
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    nbits: u32,
+}
+
+impl BitWriter {
+    /// Append the low `count` bits of `value`.
+    fn put(&mut self, value: u32, count: u32) {
+        self.bits |= (value as u64 & ((1 << count) - 1)) << self.nbits;
+        self.nbits += count;
+        while self.nbits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.nbits -= 8;
+        }
+    }
+
+    /// End a backward-read stream with its marker bit.
+    fn close(mut self) -> Vec<u8> {
+        self.put(1, 1);
+        self.finish()
+    }
+
+    /// Pad with zeros to a byte boundary.
+    fn finish(mut self) -> Vec<u8> {
+        if self.nbits > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn later_calls_match_earlier_data() {
+        // Incompressible data goes into a raw block after the frame header
+        let mut state = 1u32;
+        let noise: Vec<u8> = (0..1000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let mut zstd = ZstdStream::new();
+        let mut out = Vec::new();
+        zstd.compress(&noise, &mut out);
+        assert_eq!(out[..6], [0x28, 0xB5, 0x2F, 0xFD, 0x00, 0x50]);
+        assert_eq!(out[6..9], [0x40, 0x1F, 0x00]); // raw, 1000 bytes
+        assert_eq!(out.len(), 9 + 1000);
+
+        // The same data again is one match into the previous call
+        out.clear();
+        zstd.compress(&noise, &mut out);
+        assert_eq!(out[0] & 0b111, BLOCK_COMPRESSED as u8 * 2);
+        assert!(out.len() < 16, "{out:?}");
+    }
+
+    #[test]
+    fn block_matches_reference_bytes() {
+        // 200 literals over a skewed alphabet, then a match at distance 16
+        // that the next sequence repeats
+        let mut state = 7u32;
+        let mut data: Vec<u8> = (0..200)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                b"etaoinsh"[(state >> 16) as usize % 8]
+            })
+            .collect();
+        data.extend_from_slice(b"ABCDEFGH12345678ABCDEFGH87654321ABCDEFGH");
+        let mut zstd = ZstdStream::new();
+        let mut out = Vec::new();
+        zstd.compress(&data, &mut out);
+
+        // As the zstd CLI decodes it, once closed with an empty last block
+        // (01 00 00)
+        let expected: [u8; 184] = [
+            0x28, 0xb5, 0x2f, 0xfd, 0x00, 0x50, 0x7c, 0x05, 0x00, 0x02, 0x8d, 0x26, 0xf3, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x11, 0x11, 0x11, 0x10,
+            0x00, 0x00, 0x00, 0x01, 0x11, 0x11, 0x11, 0x10, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x04, 0x00, 0x55, 0x00, 0x00, 0x54, 0x00,
+            0x05, 0x80, 0x80, 0x60, 0x40, 0x28, 0x18, 0x0e, 0x07, 0x43, 0x81, 0x30, 0x10, 0x04,
+            0x00, 0x0f, 0x47, 0x83, 0xb1, 0x50, 0x24, 0x10, 0xd6, 0x9c, 0xb5, 0x58, 0x28, 0x15,
+            0xbd, 0x55, 0xef, 0xe9, 0x21, 0xe9, 0x99, 0xba, 0x69, 0x32, 0x23, 0xd2, 0xf9, 0x6d,
+            0x74, 0x6f, 0xee, 0xca, 0x91, 0x19, 0x79, 0x1d, 0xc9, 0x8f, 0x5c, 0x6e, 0x58, 0x71,
+            0xbd, 0x66, 0x11, 0xf1, 0xbf, 0xfe, 0xf3, 0xa7, 0x14, 0x9d, 0x94, 0x72, 0x24, 0x72,
+            0x4a, 0x5d, 0x73, 0xe4, 0x4f, 0xf7, 0x4b, 0x6c, 0x97, 0x1e, 0x45, 0x66, 0x28, 0xb9,
+            0xd6, 0x27, 0xd5, 0x86, 0xb4, 0xaf, 0xca, 0xd3, 0xdf, 0xea, 0x88, 0x66, 0x06, 0x00,
+            0x80, 0x70, 0xce, 0xa4, 0x57, 0x2a, 0x5c, 0x39, 0x21, 0x60, 0x67, 0x84, 0x24, 0x03,
+            0x65, 0x06,
+        ];
+        assert_eq!(out, expected);
+        // Huffman-coded literals: 208 regenerated from 154 bytes
+        assert_eq!(out[9] & 0b11, LITERALS_COMPRESSED as u8);
+        let header = u32::from_le_bytes([out[9], out[10], out[11], 0]);
+        assert_eq!((header >> 4 & 0x3ff, header >> 14 & 0x3ff), (208, 154));
+        // Six sequences in predefined mode, the last on repeat offset 1
+        assert_eq!(out[12 + 154..][..2], [6, 0]);
+        assert_eq!(zstd.rep, [16, 6, 75]);
+    }
+
+    #[test]
+    fn predefined_tables_use_every_state() {
+        let tables = predefined_tables();
+        for table in [&tables.ll, &tables.ml, &tables.of] {
+            let size = 1 << table.log;
+            let mut states = table.states.clone();
+            states.sort_unstable();
+            assert!(states.iter().copied().eq(size..2 * size));
+        }
+    }
+}
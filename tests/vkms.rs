@@ -0,0 +1,123 @@
+//! End-to-end capture test against the `vkms` virtual KMS driver.
+//!
+//! Needs root and the module loaded (`sudo modprobe vkms`), so it is ignored
+//! by default. Run with:
+//!
+//! ```bash
+//! sudo -E cargo test --test vkms -- --ignored
+//! ```
+//!
+//! The test becomes DRM master on the vkms card, scans out a dumb buffer with
+//! a known pattern, runs `kmsvnc --screenshot <file>.bgra` against the card
+//! and compares the captured pixels with the pattern.
+
+use std::fs::{self, File, OpenOptions};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::process::Command;
+
+use drm::buffer::{Buffer, DrmFourcc};
+use drm::control::{connector, Device as ControlDevice};
+use drm::Device;
+
+struct Card(File);
+
+impl AsFd for Card {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl Device for Card {}
+impl ControlDevice for Card {}
+
+/// Find and open the vkms card.
+fn open_vkms() -> Option<(Card, String)> {
+    let mut paths: Vec<_> = fs::read_dir("/dev/dri")
+        .ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| {
+            p.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| n.starts_with("card"))
+        })
+        .collect();
+    paths.sort();
+
+    paths.into_iter().find_map(|path| {
+        let file = OpenOptions::new().read(true).write(true).open(&path).ok()?;
+        let card = Card(file);
+        let driver = card.get_driver().ok()?;
+        (driver.name() == "vkms").then(|| (card, path.display().to_string()))
+    })
+}
+
+/// Test pattern in XRGB8888: red follows x, green follows y, blue mixes both.
+fn pattern(x: u32, y: u32) -> (u8, u8, u8) {
+    (x as u8, y as u8, (x ^ y) as u8)
+}
+
+#[test]
+#[ignore = "needs root and the vkms module"]
+fn capture_matches_scanned_out_pattern() {
+    let (card, path) = open_vkms().expect("no vkms card found (sudo modprobe vkms)");
+    card.acquire_master_lock()
+        .expect("become DRM master on vkms");
+
+    // vkms has no display server setting a mode: do it here
+    let res = card.resource_handles().expect("get resources");
+    let conn = res
+        .connectors()
+        .iter()
+        .filter_map(|&h| card.get_connector(h, true).ok())
+        .find(|c| c.state() == connector::State::Connected)
+        .expect("vkms connector");
+    let mode = *conn.modes().first().expect("connector mode");
+    let crtc = *res.crtcs().first().expect("vkms CRTC");
+    let (width, height) = (mode.size().0 as u32, mode.size().1 as u32);
+
+    let mut db = card
+        .create_dumb_buffer((width, height), DrmFourcc::Xrgb8888, 32)
+        .expect("create dumb buffer");
+    let pitch = db.pitch() as usize;
+    {
+        let mut map = card.map_dumb_buffer(&mut db).expect("map dumb buffer");
+        for y in 0..height {
+            for x in 0..width {
+                let (r, g, b) = pattern(x, y);
+                let off = y as usize * pitch + x as usize * 4;
+                map[off..off + 4].copy_from_slice(&[b, g, r, 0xFF]);
+            }
+        }
+    }
+    let fb = card.add_framebuffer(&db, 24, 32).expect("add framebuffer");
+    card.set_crtc(crtc, Some(fb), (0, 0), &[conn.handle()], Some(mode))
+        .expect("set CRTC");
+
+    let out = std::env::temp_dir().join(format!("kmsvnc-vkms-{}.bgra", std::process::id()));
+    let status = Command::new(env!("CARGO_BIN_EXE_kmsvnc"))
+        .args(["--device", &path, "--screenshot"])
+        .arg(&out)
+        .status()
+        .expect("run kmsvnc");
+    assert!(status.success(), "kmsvnc --screenshot failed");
+
+    let captured = fs::read(&out).expect("read screenshot");
+    fs::remove_file(&out).ok();
+    assert_eq!(captured.len(), (width * height * 4) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let off = (y * width + x) as usize * 4;
+            let (r, g, b) = pattern(x, y);
+            assert_eq!(
+                &captured[off..off + 3],
+                &[b, g, r],
+                "pixel mismatch at ({x}, {y})"
+            );
+        }
+    }
+
+    card.set_crtc(crtc, None, (0, 0), &[], None).ok();
+    card.destroy_framebuffer(fb).ok();
+    card.destroy_dumb_buffer(db).ok();
+}